@@ -2,9 +2,19 @@ use serde::{Deserialize, Serialize};
 
 use crate::{adapter::IntoClickhouseValue, errors};
 
+const COLUMN_VISIBILITY_RETRY_COUNT: usize = 5;
+const COLUMN_VISIBILITY_RETRY_DELAY_MILLIS: u64 = 500;
+/// Largest precision of Decimal256
+pub const MAX_DECIMAL_PRECISION: u32 = 76;
+/// Default name of the version column of `replacing_deleted_merge_tree` tables. Filled by its DEFAULT
+pub const VERSION_COLUMN: &str = "_clockpipe_version";
+/// Default name of the delete marker of `replacing_deleted_merge_tree` tables. Only written by deletes
+pub const IS_DELETED_COLUMN: &str = "_clockpipe_is_deleted";
+
 #[derive(Clone)]
 pub struct ClickhouseConnection {
     client: clickhouse::Client,
+    query_log: Option<QueryLog>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, clickhouse::Row)]
@@ -13,6 +23,7 @@ pub struct ClickhouseColumn {
     pub column_name: String,
     pub data_type: String,
     pub is_in_primary_key: bool,
+    pub comment: String,
 }
 
 // https://clickhouse.com/docs/sql-reference/data-types
@@ -35,7 +46,8 @@ pub enum ClickhouseType {
     Bool,
     String,
     FixedString(u64),
-    Decimal,
+    /// Decimal(P, S)
+    Decimal(u32, u32),
     Date,
     Date32,
     Time,
@@ -45,6 +57,7 @@ pub enum ClickhouseType {
     UUID,
     Array(Box<ClickhouseType>),
     Nullable(Box<ClickhouseType>),
+    LowCardinality(Box<ClickhouseType>),
     Unknown,
 }
 
@@ -57,6 +70,29 @@ impl ClickhouseType {
         ClickhouseType::Array(Box::new(self))
     }
 
+    /// For decimal values without a known precision. Bare `Decimal` would be Decimal(10, 0)
+    pub fn default_decimal() -> Self {
+        ClickhouseType::Decimal(38, 9)
+    }
+
+    /// LowCardinality must be the outer wrapper: LowCardinality(Nullable(String)).
+    /// Nullable(LowCardinality(String)) is rejected by ClickHouse.
+    pub fn low_cardinality(self) -> Self {
+        ClickhouseType::LowCardinality(Box::new(self))
+    }
+
+    /// Whether the type could not be inferred (e.g. from a NULL or an empty array), also when wrapped.
+    /// Such a type cannot be created in ClickHouse.
+    pub fn contains_unknown(&self) -> bool {
+        match self {
+            ClickhouseType::Unknown => true,
+            ClickhouseType::Array(inner_type)
+            | ClickhouseType::Nullable(inner_type)
+            | ClickhouseType::LowCardinality(inner_type) => inner_type.contains_unknown(),
+            _ => false,
+        }
+    }
+
     pub fn to_type_text(&self) -> String {
         match self {
             ClickhouseType::Int8 => "Int8".to_string(),
@@ -76,7 +112,7 @@ impl ClickhouseType {
             ClickhouseType::Bool => "Bool".to_string(),
             ClickhouseType::String => "String".to_string(),
             ClickhouseType::FixedString(size) => format!("FixedString({size})"),
-            ClickhouseType::Decimal => "Decimal".to_string(),
+            ClickhouseType::Decimal(precision, scale) => format!("Decimal({precision}, {scale})"),
             ClickhouseType::Date => "Date".to_string(),
             ClickhouseType::Date32 => "Date32".to_string(),
             ClickhouseType::Time => "Time".to_string(),
@@ -88,7 +124,11 @@ impl ClickhouseType {
             ClickhouseType::Nullable(inner_type) => {
                 format!("Nullable({})", inner_type.to_type_text())
             }
-            ClickhouseType::Unknown => "Unknown".to_string(),
+            ClickhouseType::LowCardinality(inner_type) => {
+                format!("LowCardinality({})", inner_type.to_type_text())
+            }
+            // Not a ClickHouse type. String can store any value, should such a column be created
+            ClickhouseType::Unknown => "String".to_string(),
         }
     }
 }
@@ -123,27 +163,164 @@ impl DateTime64 {
 }
 
 impl ClickhouseColumn {
+    pub fn is_date_time(&self) -> bool {
+        self.data_type.contains("DateTime")
+    }
+
+    /// Precision of a `DateTime64(P)` column, None for other columns.
+    pub fn date_time_precision(&self) -> Option<u32> {
+        let start = self.data_type.find("DateTime64(")? + "DateTime64(".len();
+        self.data_type[start..]
+            .split([',', ')'])
+            .next()?
+            .trim()
+            .parse()
+            .ok()
+    }
+
+    pub fn is_decimal(&self) -> bool {
+        self.data_type.contains("Decimal")
+    }
+
+    /// Float or Decimal column (or an array of those): the only columns a NaN/Infinity is special in.
+    pub fn is_float_or_decimal(&self) -> bool {
+        self.is_decimal() || self.data_type.contains("Float")
+    }
+
+    /// Scale of a Decimal column: `Decimal(P, S)` or `Decimal32(S)` (and 64/128/256).
+    /// `Decimal` and `Decimal(P)` have scale 0.
+    pub fn decimal_scale(&self) -> u32 {
+        let Some(start) = self.data_type.find("Decimal") else {
+            return 0;
+        };
+        let rest = &self.data_type[start + "Decimal".len()..];
+        let is_sized = rest.starts_with(|c: char| c.is_ascii_digit());
+
+        let Some(arguments) = rest
+            .split_once('(')
+            .and_then(|(_, arguments)| arguments.split_once(')'))
+            .map(|(arguments, _)| arguments)
+        else {
+            return 0;
+        };
+
+        let scale = match arguments.split_once(',') {
+            Some((_, scale)) => scale,
+            None if is_sized => arguments,
+            None => return 0,
+        };
+
+        scale.trim().parse().unwrap_or(0)
+    }
+
+    pub fn is_nullable(&self) -> bool {
+        self.data_type.starts_with("Nullable")
+            || self.data_type.starts_with("LowCardinality(Nullable")
+    }
+
+    /// `n` of a FixedString(n) column.
+    pub fn fixed_string_length(&self) -> Option<usize> {
+        unwrap_type_modifiers(&self.data_type)
+            .strip_prefix("FixedString(")
+            .and_then(|length| length.strip_suffix(')'))
+            .and_then(|length| length.trim().parse().ok())
+    }
+
+    /// Array and Map columns cannot be Nullable in ClickHouse. A NULL is written as an empty value.
+    pub fn is_array_or_map(&self) -> bool {
+        let type_text = unwrap_type_modifiers(&self.data_type);
+
+        array_item_type(type_text).is_some() || type_text.starts_with("Map(")
+    }
+
+    /// Value written into a non-nullable column that has no source value.
+    pub fn zero_value(&self) -> String {
+        let type_text = unwrap_type_modifiers(&self.data_type);
+
+        if array_item_type(type_text).is_some() {
+            return "[]".to_string();
+        }
+
+        if type_text.starts_with("Map(") {
+            return "{}".to_string();
+        }
+
+        match ValueKind::of(type_text) {
+            ValueKind::Integer | ValueKind::Float | ValueKind::Decimal => "0".to_string(),
+            ValueKind::Bool => "false".to_string(),
+            ValueKind::String => "''".to_string(),
+            ValueKind::Date => "toDate(0)".to_string(),
+            ValueKind::DateTime => "toDateTime(0)".to_string(),
+            ValueKind::Time | ValueKind::Uuid | ValueKind::Other => {
+                format!("defaultValueOfTypeName('{type_text}')")
+            }
+        }
+    }
+
     pub fn to_clickhouse_value(&self, value: impl IntoClickhouseValue) -> String {
-        if value.is_null() & self.data_type.starts_with("Nullable") {
+        if value.is_null() & self.is_nullable() {
             return "NULL".to_string();
         }
 
+        if value.is_null() && self.is_array_or_map() {
+            return self.zero_value();
+        }
+
         match self.data_type.as_str() {
             "Int8" | "Int16" | "Int32" | "Int64" | "Nullable(Int8)" | "Nullable(Int16)"
             | "Nullable(Int32)" | "Nullable(Int64)" => value.to_integer(),
+            "UInt8" | "UInt16" | "UInt32" | "UInt64" | "Nullable(UInt8)" | "Nullable(UInt16)"
+            | "Nullable(UInt32)" | "Nullable(UInt64)" => {
+                let value = value.to_integer();
+
+                // Unsigned columns may come from signed source columns (type overrides)
+                if value.trim_start().starts_with('-') {
+                    log::warn!(
+                        "Negative value {value} for unsigned column {}. Clamped to 0",
+                        self.column_name
+                    );
+                    return "0".to_string();
+                }
+
+                // e.g. 300 of an int2 column overridden to UInt8
+                let max_value = match self.data_type.as_str() {
+                    "UInt8" | "Nullable(UInt8)" => u64::from(u8::MAX),
+                    "UInt16" | "Nullable(UInt16)" => u64::from(u16::MAX),
+                    "UInt32" | "Nullable(UInt32)" => u64::from(u32::MAX),
+                    _ => u64::MAX,
+                };
+                if let Ok(number) = value.trim().parse::<u128>()
+                    && number > u128::from(max_value)
+                {
+                    log::warn!(
+                        "Value {value} overflows unsigned column {} ({}). Clamped to {max_value}",
+                        self.column_name,
+                        self.data_type
+                    );
+                    return max_value.to_string();
+                }
+
+                value
+            }
             "Float32" | "Float64" | "Nullable(Float32)" | "Nullable(Float64)" => value.to_real(),
             "Bool" | "Nullable(Bool)" => value.to_bool(),
-            "String" | "Nullable(String)" => value.to_string(),
+            "String"
+            | "Nullable(String)"
+            | "LowCardinality(String)"
+            | "LowCardinality(Nullable(String))" => value.to_string(),
             "Date" | "Date32" | "Nullable(Date)" | "Nullable(Date32)" => value.to_date(),
             "DateTime" | "DateTime64" | "Nullable(DateTime)" | "Nullable(DateTime64)" => {
                 value.to_datetime()
             }
             "Time" | "Time64" | "Nullable(Time)" | "Nullable(Time64)" => value.to_time(),
-            "Array(String)" => value.to_string_array(),
-            "Decimal" | "Nullable(Decimal)" => value.to_real(),
+            "UUID" | "Nullable(UUID)" => value.to_uuid(),
+            // Quoted elements are parsed as UUIDs
+            "Array(String)" | "Array(UUID)" => value.to_string_array(),
             _ => {
                 if self.data_type.starts_with("Array") {
                     value.to_array()
+                } else if self.is_decimal() {
+                    value.to_decimal(self.decimal_scale())
                 } else if self.data_type.contains("DateTime") {
                     value.to_datetime()
                 } else if self.data_type.contains("Time") {
@@ -159,20 +336,43 @@ impl ClickhouseColumn {
 }
 
 impl ClickhouseConnection {
-    pub fn new(config: &crate::config::ClickHouseConnectionConfig) -> Self {
-        let client = clickhouse::Client::default()
-            .with_url(format!("http://{}:{}", config.host, config.port))
+    pub fn new(config: &crate::config::ClickHouseConnectionConfig) -> errors::Result<Self> {
+        let url = config.url()?;
+        config.validate_session_settings()?;
+
+        let mut client = clickhouse::Client::default()
+            .with_url(url.as_str())
             .with_user(config.username.as_str())
             .with_password(config.password.as_str())
             .with_database(config.database.as_str());
 
-        log::info!(
-            "Created ClickHouse connection to {}:{}",
-            config.host,
-            config.port
-        );
+        for (name, value) in &config.session_settings {
+            client = client.with_option(name, value);
+        }
+
+        log::info!("Created ClickHouse connection to {url}");
 
-        ClickhouseConnection { client }
+        Ok(ClickhouseConnection {
+            client,
+            query_log: None,
+        })
+    }
+
+    /// Uncompressed responses, so that a test server can answer SELECTs with plain RowBinary.
+    #[cfg(test)]
+    pub fn without_compression(mut self) -> Self {
+        self.client = self.client.with_compression(clickhouse::Compression::None);
+        self
+    }
+
+    /// Records every query of `execute_query` before it runs.
+    pub fn with_query_log(
+        mut self,
+        config: Option<&crate::config::QueryLogConfig>,
+    ) -> errors::Result<Self> {
+        self.query_log = config.map(QueryLog::new).transpose()?;
+
+        Ok(self)
     }
 
     pub async fn ping(&self) -> errors::Result<()> {
@@ -200,7 +400,8 @@ impl ClickhouseConnection {
                     position as column_index,
                     name as column_name,
                     type as data_type,
-                    is_in_primary_key as is_primary_key
+                    is_in_primary_key as is_primary_key,
+                    comment
                 FROM system.columns 
                 WHERE table = ? AND database = ?
                 ORDER BY position
@@ -219,7 +420,36 @@ impl ClickhouseConnection {
         Ok(result)
     }
 
+    /// Lists the columns of a table that was just created or altered.
+    /// DDL can take a moment to become visible (e.g. replicated databases or a load balancer in
+    /// front of several nodes), so an empty result is retried before giving up instead of letting
+    /// the pipe run with an empty column list.
+    pub async fn wait_for_columns(
+        &self,
+        database_name: &str,
+        table_name: &str,
+    ) -> errors::Result<Vec<ClickhouseColumn>> {
+        let columns = retry_until_non_empty(
+            COLUMN_VISIBILITY_RETRY_COUNT,
+            std::time::Duration::from_millis(COLUMN_VISIBILITY_RETRY_DELAY_MILLIS),
+            || self.list_columns_by_tablename(database_name, table_name),
+        )
+        .await?;
+
+        if columns.is_empty() {
+            return Err(crate::errors::Errors::TableNotFoundError(format!(
+                "Table {database_name}.{table_name} has no visible columns in ClickHouse"
+            )));
+        }
+
+        Ok(columns)
+    }
+
     pub async fn execute_query(&self, query: &str) -> errors::Result<()> {
+        if let Some(query_log) = &self.query_log {
+            query_log.record(query);
+        }
+
         let query = query.replace("?", "??");
 
         self.client.query(&query).execute().await.map_err(|e| {
@@ -231,6 +461,21 @@ impl ClickhouseConnection {
         Ok(())
     }
 
+    /// Rows of a query that selects a single String column.
+    pub async fn fetch_strings(&self, query: &str) -> errors::Result<Vec<String>> {
+        let query = query.replace("?", "??");
+
+        self.client
+            .query(&query)
+            .fetch_all::<String>()
+            .await
+            .map_err(|e| {
+                crate::errors::Errors::DatabaseQueryError(format!(
+                    "Failed to fetch rows: {e}, query: {query}"
+                ))
+            })
+    }
+
     pub async fn table_is_not_empty(
         &self,
         schema_name: &str,
@@ -251,16 +496,633 @@ impl ClickhouseConnection {
 
         Ok(exists)
     }
+}
+
+/// Kind of value a ClickHouse type stores, ignoring size, precision and timezone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ValueKind {
+    Integer,
+    Float,
+    Decimal,
+    Bool,
+    String,
+    Date,
+    DateTime,
+    Time,
+    Uuid,
+    Other,
+}
+
+impl ValueKind {
+    fn of(type_text: &str) -> Self {
+        let is_integer = type_text
+            .trim_start_matches('U')
+            .strip_prefix("Int")
+            .is_some_and(|bits| bits.starts_with(|c: char| c.is_ascii_digit()));
+
+        if is_integer {
+            ValueKind::Integer
+        } else if type_text.starts_with("Float") {
+            ValueKind::Float
+        } else if type_text.starts_with("Decimal") {
+            ValueKind::Decimal
+        } else if type_text == "Bool" {
+            ValueKind::Bool
+        } else if type_text == "String" || type_text.starts_with("FixedString") {
+            ValueKind::String
+        } else if type_text.starts_with("DateTime") {
+            ValueKind::DateTime
+        } else if type_text.starts_with("Date") {
+            ValueKind::Date
+        } else if type_text.starts_with("Time") {
+            ValueKind::Time
+        } else if type_text == "UUID" {
+            ValueKind::Uuid
+        } else {
+            ValueKind::Other
+        }
+    }
+}
 
-    pub async fn truncate_table(&self, schema_name: &str, table_name: &str) -> errors::Result<()> {
-        let query = format!("TRUNCATE TABLE {schema_name}.{table_name}");
+/// `Nullable(T)`/`LowCardinality(T)` -> `T`
+fn unwrap_type_modifiers(type_text: &str) -> &str {
+    let mut type_text = type_text.trim();
 
-        self.execute_query(&query).await.map_err(|e| {
-            crate::errors::Errors::DatabaseQueryError(format!(
-                "Failed to truncate table {schema_name}.{table_name}: {e}"
-            ))
-        })?;
+    while let Some(inner) = ["Nullable(", "LowCardinality("]
+        .iter()
+        .find_map(|prefix| type_text.strip_prefix(prefix))
+        .and_then(|inner| inner.strip_suffix(')'))
+    {
+        type_text = inner.trim();
+    }
 
-        Ok(())
+    type_text
+}
+
+/// `Array(T)` -> `T`
+fn array_item_type(type_text: &str) -> Option<&str> {
+    type_text
+        .strip_prefix("Array(")
+        .and_then(|inner| inner.strip_suffix(')'))
+}
+
+/// Whether values of `source_type` can be written into an existing column of `column_type`.
+/// Values are formatted for the existing column, so an incompatible column fails the inserts or
+/// silently stores default values (e.g. text written into an Int32 column becomes 0).
+/// Types that are not recognized are assumed to be compatible.
+pub fn accepts_values_of(column_type: &str, source_type: &str) -> bool {
+    let column_type = unwrap_type_modifiers(column_type);
+    let source_type = unwrap_type_modifiers(source_type);
+
+    let (column_type, source_type) =
+        match (array_item_type(column_type), array_item_type(source_type)) {
+            (Some(column_inner), Some(source_inner)) => {
+                return accepts_values_of(column_inner, source_inner);
+            }
+            (None, None) => (column_type, source_type),
+            // Any value can be written as text
+            (None, Some(_)) => return ValueKind::of(column_type) == ValueKind::String,
+            (Some(_), None) => return false,
+        };
+
+    match (ValueKind::of(column_type), ValueKind::of(source_type)) {
+        (ValueKind::Other, _) | (_, ValueKind::Other) => true,
+        (ValueKind::Integer, ValueKind::Integer) => {
+            integer_range_contains(column_type, source_type)
+        }
+        (column_kind, source_kind) if column_kind == source_kind => true,
+        (ValueKind::String, _) => true,
+        (ValueKind::Float | ValueKind::Decimal, ValueKind::Integer)
+        | (ValueKind::Float, ValueKind::Decimal)
+        | (ValueKind::Decimal, ValueKind::Float) => true,
+        (ValueKind::Date, ValueKind::DateTime) | (ValueKind::DateTime, ValueKind::Date) => true,
+        _ => false,
+    }
+}
+
+/// Whether every value of the integer type `source_type` fits into `column_type`.
+/// A signed source needs a signed column at least as wide, an unsigned source an unsigned column
+/// at least as wide or a signed one that is wider.
+fn integer_range_contains(column_type: &str, source_type: &str) -> bool {
+    let bits_of = |type_text: &str| -> Option<(bool, u32)> {
+        let is_signed = !type_text.starts_with('U');
+        let bits = type_text.trim_start_matches('U').strip_prefix("Int")?;
+        Some((is_signed, bits.parse().ok()?))
+    };
+
+    let (Some((column_signed, column_bits)), Some((source_signed, source_bits))) =
+        (bits_of(column_type), bits_of(source_type))
+    else {
+        return true;
+    };
+
+    match (column_signed, source_signed) {
+        (true, true) | (false, false) => column_bits >= source_bits,
+        (true, false) => column_bits > source_bits,
+        (false, true) => false,
+    }
+}
+
+/// `toUUID('...')` for UUID text in any of the forms Postgres accepts (e.g. with braces, without
+/// hyphens), normalized to the hyphenated form. Other text becomes the all-zero UUID.
+pub fn uuid_literal(text: &str) -> String {
+    let text = text.trim();
+    let hex: String = text
+        .trim_start_matches('{')
+        .trim_end_matches('}')
+        .chars()
+        .filter(|c| *c != '-')
+        .collect();
+
+    let hex = if hex.len() == 32 && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        hex.to_ascii_lowercase()
+    } else {
+        log::warn!("Invalid UUID value {text:?}. Written as the all-zero UUID");
+        "0".repeat(32)
+    };
+
+    format!(
+        "toUUID('{}-{}-{}-{}-{}')",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+/// ClickHouse literal for NaN/Infinity/-Infinity, or None for finite values.
+pub fn non_finite_float_literal(value: f64) -> Option<&'static str> {
+    if value.is_nan() {
+        Some("nan")
+    } else if value == f64::INFINITY {
+        Some("inf")
+    } else if value == f64::NEG_INFINITY {
+        Some("-inf")
+    } else {
+        None
+    }
+}
+
+/// Converts decimal text (possibly in scientific notation, e.g. `1.05E+3`) to fixed-point text
+/// with exactly `scale` fractional digits (`1050.00` for scale 2). ClickHouse's Decimal parser
+/// rejects scientific notation. Extra fractional digits are truncated, as ClickHouse does.
+/// Returns None if the text is not a finite decimal number.
+pub fn plain_decimal_text(text: &str, scale: u32) -> Option<String> {
+    // Far beyond the 76 digits of Decimal256, and keeps the output size bounded
+    const MAX_EXPONENT: i64 = 10_000;
+
+    let text = text.trim();
+    let (negative, unsigned) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text.strip_prefix('+').unwrap_or(text)),
+    };
+
+    let (mantissa, exponent) = match unsigned.split_once(['e', 'E']) {
+        Some((mantissa, exponent)) => (mantissa, exponent.parse::<i64>().ok()?),
+        None => (unsigned, 0),
+    };
+    if exponent.abs() > MAX_EXPONENT {
+        return None;
+    }
+
+    let (integer_digits, fraction_digits) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    if (integer_digits.is_empty() && fraction_digits.is_empty())
+        || !integer_digits.chars().all(|c| c.is_ascii_digit())
+        || !fraction_digits.chars().all(|c| c.is_ascii_digit())
+    {
+        return None;
+    }
+
+    let digits = format!("{integer_digits}{fraction_digits}").into_bytes();
+    // Number of digits before the decimal point after applying the exponent
+    let point = integer_digits.len() as i64 + exponent;
+    let digit_at = |index: i64| {
+        usize::try_from(index)
+            .ok()
+            .and_then(|index| digits.get(index))
+            .map_or('0', |digit| *digit as char)
+    };
+
+    let integer_part: String = (0..point.max(0)).map(digit_at).collect();
+    let integer_part = match integer_part.trim_start_matches('0') {
+        "" => "0",
+        trimmed => trimmed,
+    };
+    let fraction_part: String = (point..point + scale as i64).map(digit_at).collect();
+
+    let is_zero = integer_part == "0" && fraction_part.chars().all(|c| c == '0');
+    let sign = if negative && !is_zero { "-" } else { "" };
+
+    if fraction_part.is_empty() {
+        Some(format!("{sign}{integer_part}"))
+    } else {
+        Some(format!("{sign}{integer_part}.{fraction_part}"))
+    }
+}
+
+/// Calls `fetch` until it returns a non-empty list, at most `attempts` times.
+/// The last (possibly empty) result is returned when all attempts are used up.
+pub async fn retry_until_non_empty<T, F, Fut>(
+    attempts: usize,
+    delay: std::time::Duration,
+    mut fetch: F,
+) -> errors::Result<Vec<T>>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = errors::Result<Vec<T>>>,
+{
+    let mut result = fetch().await?;
+
+    for attempt in 1..attempts {
+        if !result.is_empty() {
+            break;
+        }
+
+        log::warn!("Column list is empty, retrying... ({attempt}/{attempts})");
+        tokio::time::sleep(delay).await;
+
+        result = fetch().await?;
+    }
+
+    Ok(result)
+}
+
+/// Audit log of the executed queries, to reconstruct what was written after an incident.
+/// Unlike `dry_run_clickhouse_ddl`, the queries are still executed.
+#[derive(Clone)]
+pub struct QueryLog {
+    /// None logs the queries at info level
+    file: Option<std::sync::Arc<std::sync::Mutex<std::fs::File>>>,
+    mask_values: bool,
+}
+
+impl QueryLog {
+    pub fn new(config: &crate::config::QueryLogConfig) -> errors::Result<Self> {
+        let file = match &config.path {
+            Some(path) => Some(
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?,
+            ),
+            None => None,
+        };
+
+        Ok(Self {
+            file: file.map(|file| std::sync::Arc::new(std::sync::Mutex::new(file))),
+            mask_values: config.mask_values,
+        })
+    }
+
+    /// A failed write of the log is only warned about, it does not fail the query.
+    pub fn record(&self, query: &str) {
+        use std::io::Write;
+
+        let query = if self.mask_values {
+            mask_string_literals(query)
+        } else {
+            query.to_string()
+        };
+
+        let Some(file) = &self.file else {
+            log::info!("Executing query: {query}");
+            return;
+        };
+
+        let entry = format!("-- {}\n{query}\n", chrono::Utc::now().to_rfc3339());
+        let mut file = file.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        if let Err(error) = file.write_all(entry.as_bytes()) {
+            log::warn!("Failed to write the query log: {error}");
+        }
+    }
+}
+
+/// Replaces the content of each '...' literal with ***, keeping the rest of the query.
+/// Inside a literal a quote is escaped as `''` (see `escape_string`) and a backslash as `\\`.
+fn mask_string_literals(query: &str) -> String {
+    let mut masked = String::with_capacity(query.len());
+    let mut chars = query.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        masked.push(c);
+
+        if c != '\'' {
+            continue;
+        }
+
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => {
+                    chars.next();
+                }
+                '\'' if chars.peek() == Some(&'\'') => {
+                    chars.next();
+                }
+                '\'' => break,
+                _ => {}
+            }
+        }
+        masked.push_str("***'");
+    }
+
+    masked
+}
+
+/// ClickHouse write failures that will not go away by retrying and need an operator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockedWriteReason {
+    ReadOnly,
+    DiskFull,
+    QuotaExceeded,
+    MemoryLimitExceeded,
+}
+
+impl BlockedWriteReason {
+    pub fn operator_hint(&self) -> &'static str {
+        match self {
+            BlockedWriteReason::ReadOnly => {
+                "the ClickHouse server or table is read-only. Check the user's readonly setting and the replica/disk state"
+            }
+            BlockedWriteReason::DiskFull => {
+                "ClickHouse is out of disk space. Free up or add storage"
+            }
+            BlockedWriteReason::QuotaExceeded => {
+                "the ClickHouse user's quota is exhausted. Raise the quota or wait for the interval to reset"
+            }
+            BlockedWriteReason::MemoryLimitExceeded => {
+                "ClickHouse hit its memory limit. Raise max_memory_usage or lower peek_changes_limit"
+            }
+        }
+    }
+}
+
+/// Recognizes blocked writes by the exception names ClickHouse puts into its error messages,
+/// e.g. `Code: 164. DB::Exception: Cannot execute query in readonly mode. (READONLY)`.
+pub fn classify_write_error(message: &str) -> Option<BlockedWriteReason> {
+    // execute_query appends the query text, which may contain anything
+    let message = message.split(", query: ").next().unwrap_or(message);
+
+    let reasons = [
+        ("(READONLY)", BlockedWriteReason::ReadOnly),
+        ("(TABLE_IS_READ_ONLY)", BlockedWriteReason::ReadOnly),
+        ("(NOT_ENOUGH_SPACE)", BlockedWriteReason::DiskFull),
+        ("(QUOTA_EXCEEDED)", BlockedWriteReason::QuotaExceeded),
+        ("(QUOTA_EXPIRED)", BlockedWriteReason::QuotaExceeded),
+        (
+            "(MEMORY_LIMIT_EXCEEDED)",
+            BlockedWriteReason::MemoryLimitExceeded,
+        ),
+    ];
+
+    reasons
+        .into_iter()
+        .find(|(exception_name, _)| message.contains(exception_name))
+        .map(|(_, reason)| reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::{
+        BlockedWriteReason, ClickhouseColumn, ClickhouseConnection, ClickhouseType,
+        accepts_values_of, classify_write_error, mask_string_literals, plain_decimal_text,
+    };
+    use crate::config::{ClickHouseConnectionConfig, QueryLogConfig};
+
+    #[tokio::test]
+    async fn executed_queries_are_written_to_the_query_log() {
+        let path =
+            std::env::temp_dir().join(format!("clockpipe_query_log_{}.sql", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        // Nothing listens on port 1, so the query fails after it was logged
+        let connection = ClickhouseConnection::new(&ClickHouseConnectionConfig {
+            host: "127.0.0.1".to_string(),
+            port: 1,
+            username: "default".to_string(),
+            password: String::new(),
+            database: "db".to_string(),
+            scheme: Default::default(),
+            session_settings: Default::default(),
+        })
+        .unwrap()
+        .with_query_log(Some(&QueryLogConfig {
+            path: Some(path.to_str().unwrap().to_string()),
+            mask_values: true,
+        }))
+        .unwrap();
+
+        let _ = connection
+            .execute_query("INSERT INTO db.users (`id`, `email`) VALUES(1,'a@example.com')")
+            .await;
+        let _ = connection
+            .execute_query("ALTER TABLE db.users DELETE WHERE (`id` = 1)")
+            .await;
+
+        let query_log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(
+            query_log.contains("INSERT INTO db.users (`id`, `email`) VALUES(1,'***')\n"),
+            "{query_log}"
+        );
+        assert!(
+            query_log.contains("ALTER TABLE db.users DELETE WHERE (`id` = 1)\n"),
+            "{query_log}"
+        );
+        assert!(!query_log.contains("example.com"), "{query_log}");
+    }
+
+    #[test]
+    fn mask_string_literals_keeps_escaped_quotes_inside_the_literal() {
+        use crate::adapter::quote_string;
+
+        // As written by escape_string: 'it''s' and 'C:\\dir\\'
+        let query = format!(
+            "VALUES(1,{},toDateTime('2025-01-01 00:00:00'),'',{},2)",
+            quote_string("it's"),
+            quote_string(r"C:\dir\")
+        );
+        assert_eq!(
+            mask_string_literals(&query),
+            "VALUES(1,'***',toDateTime('***'),'***','***',2)"
+        );
+        assert_eq!(mask_string_literals("VALUES('''')"), "VALUES('***')");
+    }
+
+    #[test]
+    fn classify_write_error_recognizes_blocked_writes() {
+        let cases = [
+            (
+                "Failed to execute query: bad response: Code: 164. DB::Exception: default: Cannot execute query in readonly mode. (READONLY) (version 24.8.4.13 (official build)), query: INSERT INTO db.t (id) VALUES(1)",
+                Some(BlockedWriteReason::ReadOnly),
+            ),
+            (
+                "Failed to execute query: bad response: Code: 242. DB::Exception: Table is in readonly mode (replica path: /clickhouse/tables/01/t/replicas/r1). (TABLE_IS_READ_ONLY) (version 24.8.4.13 (official build)), query: INSERT INTO db.t (id) VALUES(1)",
+                Some(BlockedWriteReason::ReadOnly),
+            ),
+            (
+                "Failed to execute query: bad response: Code: 243. DB::Exception: Cannot reserve 1.00 MiB, not enough space. (NOT_ENOUGH_SPACE) (version 24.8.4.13 (official build)), query: INSERT INTO db.t (id) VALUES(1)",
+                Some(BlockedWriteReason::DiskFull),
+            ),
+            (
+                "Failed to execute query: bad response: Code: 201. DB::Exception: Quota for user `clockpipe` for 3600s has been exceeded: inserted_rows = 1000001/1000000. (QUOTA_EXCEEDED) (version 24.8.4.13 (official build)), query: INSERT INTO db.t (id) VALUES(1)",
+                Some(BlockedWriteReason::QuotaExceeded),
+            ),
+            (
+                "Failed to execute query: bad response: Code: 241. DB::Exception: Memory limit (total) exceeded: would use 3.60 GiB, maximum: 3.60 GiB. (MEMORY_LIMIT_EXCEEDED) (version 24.8.4.13 (official build)), query: INSERT INTO db.t (id) VALUES(1)",
+                Some(BlockedWriteReason::MemoryLimitExceeded),
+            ),
+            (
+                "Failed to execute query: network error: connection refused, query: INSERT INTO db.t (id) VALUES(1)",
+                None,
+            ),
+            // Inserted data is not an error message
+            (
+                "Failed to execute query: network error: timeout, query: INSERT INTO db.t (note) VALUES('(READONLY)')",
+                None,
+            ),
+        ];
+
+        for (message, expected) in cases {
+            assert_eq!(classify_write_error(message), expected, "{message}");
+        }
+    }
+
+    #[tokio::test]
+    async fn session_settings_are_sent_with_queries() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // Only captures the request line; the (compressed) response is not needed here
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 8192];
+            let length = stream.read(&mut request).await.unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .await
+                .unwrap();
+
+            String::from_utf8_lossy(&request[..length])
+                .lines()
+                .next()
+                .unwrap()
+                .to_string()
+        });
+
+        let connection = ClickhouseConnection::new(&ClickHouseConnectionConfig {
+            host: "127.0.0.1".to_string(),
+            port,
+            username: "default".to_string(),
+            password: String::new(),
+            database: "db".to_string(),
+            scheme: Default::default(),
+            session_settings: [
+                ("role".to_string(), "clockpipe_writer".to_string()),
+                ("profile".to_string(), "etl".to_string()),
+            ]
+            .into(),
+        })
+        .unwrap();
+        let _ = connection.ping().await;
+
+        let request_line = server.await.unwrap();
+        assert!(
+            request_line.contains("role=clockpipe_writer"),
+            "{request_line}"
+        );
+        assert!(request_line.contains("profile=etl"), "{request_line}");
+    }
+
+    #[test]
+    fn column_types_accept_values_of_compatible_source_types() {
+        let cases = [
+            ("Int32", "Nullable(String)", false),
+            ("String", "Int32", true),
+            ("Nullable(Int64)", "Int32", true),
+            ("UInt8", "Int16", false),
+            ("Int16", "Int32", false),
+            ("Int64", "UInt32", true),
+            ("Int32", "UInt32", false),
+            ("UInt64", "UInt16", true),
+            ("Float64", "Int64", true),
+            ("Decimal(18, 2)", "Float64", true),
+            ("Int64", "Float64", false),
+            ("Bool", "Int32", false),
+            ("DateTime64(3)", "Date", true),
+            ("LowCardinality(Nullable(String))", "UUID", true),
+            ("UUID", "String", false),
+            ("Array(String)", "Array(Int32)", true),
+            ("Array(Int32)", "Array(String)", false),
+            ("Int32", "Array(Int32)", false),
+            ("IntervalSecond", "String", true),
+            ("Map(String, String)", "String", true),
+        ];
+
+        for (column_type, source_type, expected) in cases {
+            assert_eq!(
+                accepts_values_of(column_type, source_type),
+                expected,
+                "{column_type} <- {source_type}"
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_type_text_is_a_valid_clickhouse_type() {
+        let cases = [
+            (ClickhouseType::Unknown, "String"),
+            (ClickhouseType::Unknown.array(), "Array(String)"),
+            (ClickhouseType::Unknown.nullable(), "Nullable(String)"),
+        ];
+
+        for (column_type, expected) in cases {
+            assert_eq!(column_type.to_type_text(), expected);
+            assert!(column_type.contains_unknown());
+        }
+    }
+
+    #[test]
+    fn decimal_text_never_uses_scientific_notation() {
+        let cases = [
+            ("1.05E+3", 2, "1050.00"),
+            ("1E+30", 2, "1000000000000000000000000000000.00"),
+            ("-1.5e10", 0, "-15000000000"),
+            ("1E-7", 10, "0.0000001000"),
+            ("-1E-12", 4, "0.0000"),
+            ("123.456", 2, "123.45"),
+            ("0.5", 3, "0.500"),
+            ("007", 1, "7.0"),
+        ];
+
+        for (text, scale, expected) in cases {
+            let plain = plain_decimal_text(text, scale).unwrap();
+            assert_eq!(plain, expected, "{text}");
+            assert!(!plain.contains(['e', 'E']), "{plain}");
+        }
+
+        assert_eq!(plain_decimal_text("NaN", 2), None);
+        assert_eq!(plain_decimal_text("1E+99999", 2), None);
+    }
+
+    #[test]
+    fn decimal_scale_is_read_from_the_column_type() {
+        let column = |data_type: &str| ClickhouseColumn {
+            column_index: 0,
+            column_name: "amount".to_string(),
+            data_type: data_type.to_string(),
+            is_in_primary_key: false,
+            comment: String::new(),
+        };
+
+        assert_eq!(column("Decimal(10, 2)").decimal_scale(), 2);
+        assert_eq!(column("Nullable(Decimal(38, 10))").decimal_scale(), 10);
+        assert_eq!(column("Decimal64(4)").decimal_scale(), 4);
+        assert_eq!(column("Decimal(18)").decimal_scale(), 0);
+        assert_eq!(column("Decimal").decimal_scale(), 0);
     }
 }
@@ -7,8 +7,11 @@ use std::collections::HashSet;
 use crate::{
     adapter::clickhouse::{ClickhouseColumn, ClickhouseType},
     config::{
-        ClickHouseConfig, ClickHouseTableOptions,
-        default::clickhouse::{INDEX_GRANULARITY, MIN_AGE_TO_FORCE_MERGE_SECONDS},
+        BinaryEncoding, ClickHouseConfig, ClickHouseTableOptions, DateTimeInputFormat,
+        FixedStringOverflow, IdentifierCase, MissingValues, NonFiniteNumbers, TableEngine,
+        default::clickhouse::{
+            INDEX_GRANULARITY, MAX_MUTATION_QUERY_BYTES, MIN_AGE_TO_FORCE_MERGE_SECONDS,
+        },
     },
 };
 
@@ -19,14 +22,22 @@ pub trait IntoClickhouseColumn {
     fn get_column_index(&self) -> usize;
     fn get_comment(&self) -> &str;
     fn is_in_primary_key(&self) -> bool;
+    /// Binary values, written with `to_bytes_literal` in the configured encoding
+    fn is_binary(&self) -> bool;
+    /// `DEFAULT` expression of the ClickHouse column, added when the column is created
+    fn default_expression(&self) -> Option<&str>;
 }
 
 /// Trait for converting source data row to Clickhouse row representation
 pub trait IntoClickhouseRow {
+    /// Finds the value for a ClickHouse column. Source and target columns are matched by name;
+    /// the source column index is only used to locate the value inside the source tuple.
+    /// Names are compared after applying `identifier_case` to the source name.
     fn find_value_by_column_name(
         &self,
         source_columns: &[impl IntoClickhouseColumn],
         column_name: &str,
+        identifier_case: IdentifierCase,
     ) -> Option<impl IntoClickhouseValue + Default>;
 
     fn debug_all(&self);
@@ -36,16 +47,28 @@ pub trait IntoClickhouseRow {
 pub trait IntoClickhouseValue {
     fn to_integer(self) -> String;
     fn to_real(self) -> String;
+    /// Quoted fixed-point text with `scale` fractional digits, never in scientific notation
+    fn to_decimal(self, scale: u32) -> String;
     fn to_bool(self) -> String;
     fn to_string(self) -> String;
     fn to_date(self) -> String;
     fn to_datetime(self) -> String;
     fn to_time(self) -> String;
+    /// `toUUID('...')`, or the all-zero UUID for values that are not a UUID
+    fn to_uuid(self) -> String;
     fn to_array(self) -> String;
     fn to_string_array(self) -> String;
+    /// Quoted text of binary data in `encoding` (an array of them for array values)
+    fn to_bytes_literal(self, encoding: BinaryEncoding) -> String;
     fn unknown_value(self) -> String;
+    /// The value as plain text without SQL quoting, or None for NULL
+    fn to_plain_text(self) -> Option<String>;
 
     fn is_null(&self) -> bool;
+    /// A value the source did not send because it did not change (e.g. a TOASTed column)
+    fn is_unchanged(&self) -> bool;
+    /// NaN, Infinity or -Infinity
+    fn is_non_finite(&self) -> bool;
     fn into_null(self) -> Self;
 }
 
@@ -60,35 +83,79 @@ pub trait IntoClickhouse {
         comment: &str,
     ) -> String {
         let database_name = &clickhouse_config.connection.database;
+        let identifier_case = clickhouse_config.identifier_case;
 
         let mut query = format!("CREATE TABLE {database_name}.{table_name}");
         query.push('(');
 
-        let column_definitions: Vec<String> = columns
+        let has_primary_key = columns.iter().any(|col| col.is_in_primary_key());
+
+        // Without a sorting key every row has the same key, so a replacing (or summing,
+        // aggregating) engine would collapse the whole table into one row
+        let table_engine = has_primary_key.then(|| table_options.engine.unwrap_or_default());
+
+        let mut column_definitions: Vec<String> = columns
             .iter()
             .map(|col| {
                 let clickhouse_type = col.to_clickhouse_type();
                 format!(
-                    "`{}` {} COMMENT '{}'",
-                    col.get_column_name(),
+                    "{} {}{} COMMENT '{}'",
+                    quote_identifier(&identifier_case.apply(col.get_column_name())),
                     clickhouse_type.to_type_text(),
-                    col.get_comment().replace("'", "\"")
+                    default_clause(col),
+                    column_comment(col)
                 )
             })
             .collect();
 
+        if table_engine == Some(TableEngine::ReplacingDeletedMergeTree) {
+            column_definitions.push(format!(
+                "{} UInt64 DEFAULT toUnixTimestamp64Nano(now64(9)) COMMENT 'Insert time of the row version'",
+                quote_identifier(table_options.version_column_name())
+            ));
+            column_definitions.push(format!(
+                "{} UInt8 DEFAULT 0 COMMENT '1 if the row was deleted'",
+                quote_identifier(table_options.is_deleted_column_name())
+            ));
+        }
+
         query.push_str(&column_definitions.join(", \n"));
 
         let primary_keys = columns
             .iter()
             .filter(|col| col.is_in_primary_key())
-            .map(|col| col.get_column_name())
+            .map(|col| quote_identifier(&identifier_case.apply(col.get_column_name())))
             .collect::<Vec<_>>()
             .join(", ");
 
+        let engine = match table_engine {
+            None => "MergeTree()".to_string(),
+            Some(TableEngine::ReplacingMergeTree) => "ReplacingMergeTree()".to_string(),
+            Some(TableEngine::ReplacingDeletedMergeTree) => format!(
+                "ReplacingMergeTree({}, {})",
+                quote_identifier(table_options.version_column_name()),
+                quote_identifier(table_options.is_deleted_column_name())
+            ),
+            Some(TableEngine::SummingMergeTree) => match &table_options.sum_columns {
+                Some(sum_columns) if !sum_columns.is_empty() => {
+                    let sum_columns = sum_columns
+                        .iter()
+                        .map(|column| quote_identifier(&identifier_case.apply(column)))
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    format!("SummingMergeTree(({sum_columns}))")
+                }
+                _ => "SummingMergeTree()".to_string(),
+            },
+            Some(TableEngine::AggregatingMergeTree) => "AggregatingMergeTree()".to_string(),
+        };
+
         query.push(')');
-        query.push_str(" ENGINE = ReplacingMergeTree()\n");
-        if !primary_keys.is_empty() {
+        query.push_str(format!(" ENGINE = {engine}\n").as_str());
+        // MergeTree tables require ORDER BY; tuple() is the documented form for "no sorting key"
+        if primary_keys.is_empty() {
+            query.push_str("ORDER BY tuple()\n");
+        } else {
             query.push_str(format!("ORDER BY ({primary_keys})\n").as_str());
         }
 
@@ -105,6 +172,10 @@ pub trait IntoClickhouse {
                 .as_str(),
         );
 
+        if table_options.lightweight_updates {
+            query.push_str(", enable_block_number_column = 1, enable_block_offset_column = 1\n");
+        }
+
         if let Some(storage_policy) = &table_options.storage_policy {
             query.push_str(
                 format!(
@@ -115,7 +186,7 @@ pub trait IntoClickhouse {
             );
         }
 
-        query.push_str(format!("COMMENT '{}'\n", comment.replace("'", "''")).as_str());
+        query.push_str(format!("COMMENT {}\n", quote_string(comment)).as_str());
 
         query.push(';');
 
@@ -129,17 +200,49 @@ pub trait IntoClickhouse {
         source_column: &impl IntoClickhouseColumn,
     ) -> String {
         let database_name = &clickhouse_config.connection.database;
-        let column_name = source_column.get_column_name();
+        let column_name = quote_identifier(
+            &clickhouse_config
+                .identifier_case
+                .apply(source_column.get_column_name()),
+        );
         let column_type = source_column.to_clickhouse_type().to_type_text();
-        let column_comment = source_column.get_comment().replace("'", "\"");
+        let default_clause = default_clause(source_column);
+        let column_comment = column_comment(source_column);
 
         let add_column_query = format!(
-            "ALTER TABLE {database_name}.{table_name} ADD COLUMN `{column_name}` {column_type} COMMENT '{column_comment}';"
+            "ALTER TABLE {database_name}.{table_name} ADD COLUMN IF NOT EXISTS {column_name} {column_type}{default_clause} COMMENT '{column_comment}';"
         );
 
         add_column_query
     }
 
+    /// Replaces the comment of an existing column with the comment of its source column.
+    fn generate_comment_column_query(
+        &self,
+        clickhouse_config: &ClickHouseConfig,
+        table_name: &str,
+        column_name: &str,
+        source_column: &impl IntoClickhouseColumn,
+    ) -> String {
+        let database_name = &clickhouse_config.connection.database;
+        let column_name = quote_identifier(column_name);
+        let column_comment = column_comment(source_column);
+
+        format!(
+            "ALTER TABLE {database_name}.{table_name} COMMENT COLUMN {column_name} '{column_comment}';"
+        )
+    }
+
+    fn generate_truncate_query(
+        &self,
+        clickhouse_config: &ClickHouseConfig,
+        table_name: &str,
+    ) -> String {
+        let database_name = &clickhouse_config.connection.database;
+
+        format!("TRUNCATE TABLE {database_name}.{table_name}")
+    }
+
     fn generate_insert_query(
         &self,
         clickhouse_config: &ClickHouseConfig,
@@ -153,49 +256,160 @@ pub trait IntoClickhouse {
             return String::new();
         }
 
-        let mut insert_query = format!(
-            "INSERT INTO {}.{table_name} ",
-            clickhouse_config.connection.database
+        let (mut insert_query, values) = insert_query_parts(
+            clickhouse_config,
+            clickhouse_columns,
+            source_columns,
+            mask_columns,
+            table_name,
+            rows,
         );
 
-        let mut columns = vec![];
-        let mut column_names = vec![];
-
-        for clickhouse_column in clickhouse_columns {
-            columns.push(clickhouse_column);
-            column_names.push(clickhouse_column.column_name.as_str());
+        // Every row was skipped for missing values
+        if values.is_empty() {
+            return String::new();
         }
 
-        insert_query.push_str(&format!("({}) ", column_names.join(", ")));
-        insert_query.push_str("VALUES");
+        insert_query.push_str(values.join(", ").as_str());
+
+        insert_query
+    }
 
-        let mut values = vec![];
+    /// Same as `generate_insert_query`, but split into several queries so that each one stays
+    /// within `max_insert_query_bytes`. A single row larger than the limit gets a query of its own.
+    fn generate_insert_queries(
+        &self,
+        clickhouse_config: &ClickHouseConfig,
+        clickhouse_columns: &[ClickhouseColumn],
+        source_columns: &[impl IntoClickhouseColumn],
+        mask_columns: &[String],
+        table_name: &str,
+        rows: &[impl IntoClickhouseRow],
+    ) -> Vec<String> {
+        if rows.is_empty() {
+            return vec![];
+        }
 
-        for row in rows {
-            let mut value = vec![];
+        let (header, values) = insert_query_parts(
+            clickhouse_config,
+            clickhouse_columns,
+            source_columns,
+            mask_columns,
+            table_name,
+            rows,
+        );
 
-            for clickhouse_column in columns.iter() {
-                let raw_value =
-                    row.find_value_by_column_name(source_columns, &clickhouse_column.column_name);
+        if values.is_empty() {
+            return vec![];
+        }
 
-                let mut raw_value = raw_value.unwrap_or_default();
+        let Some(max_bytes) = clickhouse_config.max_insert_query_bytes else {
+            return vec![format!("{header}{}", values.join(", "))];
+        };
 
-                if mask_columns.contains(&clickhouse_column.column_name) {
-                    raw_value = raw_value.into_null();
-                }
+        let mut queries = vec![];
+        let mut query = header.clone();
+        let mut row_count = 0;
 
-                let column_value = clickhouse_column.to_clickhouse_value(raw_value);
+        for value in values {
+            // ", " between two rows
+            let added_bytes = if row_count == 0 {
+                value.len()
+            } else {
+                value.len() + 2
+            };
 
-                value.push(column_value);
+            if row_count > 0 && query.len() + added_bytes > max_bytes {
+                queries.push(std::mem::replace(&mut query, header.clone()));
+                row_count = 0;
             }
 
-            let value = value.join(",");
-            values.push(format!("({value})"));
+            if row_count > 0 {
+                query.push_str(", ");
+            }
+            query.push_str(&value);
+            row_count += 1;
         }
 
-        insert_query.push_str(values.join(", ").as_str());
+        queries.push(query);
 
-        insert_query
+        queries
+    }
+
+    /// INSERT of `row` that takes the values of its unchanged (TOASTed) columns from the stored row
+    /// with the same primary key, so they are not overwritten with NULL. If there is no stored row,
+    /// those columns get their default value.
+    /// Empty if the row is skipped (see `MissingValues::SkipRow`).
+    fn generate_insert_keeping_unchanged_query<IntoClickhouseColumnType, IntoClickhouseRowType>(
+        &self,
+        clickhouse_config: &ClickHouseConfig,
+        clickhouse_columns: &[ClickhouseColumn],
+        source_columns: &[IntoClickhouseColumnType],
+        mask_columns: &[String],
+        table_name: &str,
+        row: &IntoClickhouseRowType,
+    ) -> String
+    where
+        IntoClickhouseColumnType: IntoClickhouseColumn,
+        IntoClickhouseRowType: IntoClickhouseRow,
+    {
+        if !has_common_columns(
+            clickhouse_columns,
+            source_columns,
+            clickhouse_config.identifier_case,
+            table_name,
+        ) {
+            return String::new();
+        }
+
+        let Some(values) = row_values(
+            clickhouse_config,
+            clickhouse_columns,
+            source_columns,
+            mask_columns,
+            table_name,
+            row,
+            DateTimeText::Parsed,
+        ) else {
+            return String::new();
+        };
+
+        let identifier_case = clickhouse_config.identifier_case;
+        let database_name = &clickhouse_config.connection.database;
+
+        let column_names: Vec<String> = clickhouse_columns
+            .iter()
+            .map(|column| quote_identifier(&column.column_name))
+            .collect();
+
+        // An aggregate without GROUP BY returns one row even if there is no stored row
+        let selected_values: Vec<String> = clickhouse_columns
+            .iter()
+            .zip(values)
+            .map(|(column, value)| {
+                let is_unchanged = row
+                    .find_value_by_column_name(source_columns, &column.column_name, identifier_case)
+                    .is_some_and(|value| value.is_unchanged());
+
+                if is_unchanged && !column.is_in_primary_key {
+                    format!("any({})", quote_identifier(&column.column_name))
+                } else {
+                    value
+                }
+            })
+            .collect();
+
+        let primary_key_columns: Vec<_> = clickhouse_columns
+            .iter()
+            .filter(|col| col.is_in_primary_key)
+            .collect();
+
+        format!(
+            "INSERT INTO {database_name}.{table_name} ({}) SELECT {} FROM {database_name}.{table_name} FINAL WHERE {}",
+            column_names.join(", "),
+            selected_values.join(", "),
+            primary_key_condition(clickhouse_config, &primary_key_columns, source_columns, row)
+        )
     }
 
     fn generate_delete_query<IntoClickhouseColumnType, IntoClickhouseRowType>(
@@ -210,60 +424,2258 @@ pub trait IntoClickhouse {
         IntoClickhouseColumnType: IntoClickhouseColumn,
         IntoClickhouseRowType: IntoClickhouseRow,
     {
-        if rows.is_empty() {
+        let primary_key_columns: Vec<_> = clickhouse_columns
+            .iter()
+            .filter(|col| col.is_in_primary_key)
+            .collect();
+
+        // Without a primary key there is no condition to find the deleted row by
+        if rows.is_empty() || primary_key_columns.is_empty() {
             return String::new();
         }
-
         let mut delete_query = format!(
             "ALTER TABLE {}.{table_name} DELETE WHERE ",
             clickhouse_config.connection.database
         );
 
+        let conditions: Vec<String> = rows
+            .iter()
+            .map(|row| {
+                primary_key_condition(clickhouse_config, &primary_key_columns, source_columns, row)
+            })
+            .collect();
+
+        delete_query.push_str(&conditions.join(" OR "));
+
+        delete_query
+    }
+
+    /// (replacing_deleted_merge_tree) Deletes rows by inserting a newer version of each row with
+    /// only its primary key set and `is_deleted_column` set to 1. Replaced on merge and left out by `FINAL`.
+    fn generate_delete_marker_query<IntoClickhouseColumnType, IntoClickhouseRowType>(
+        &self,
+        clickhouse_config: &ClickHouseConfig,
+        clickhouse_columns: &[ClickhouseColumn],
+        source_columns: &[IntoClickhouseColumnType],
+        is_deleted_column: &str,
+        table_name: &str,
+        rows: &[IntoClickhouseRowType],
+    ) -> String
+    where
+        IntoClickhouseColumnType: IntoClickhouseColumn,
+        IntoClickhouseRowType: IntoClickhouseRow,
+    {
         let primary_key_columns: Vec<_> = clickhouse_columns
             .iter()
             .filter(|col| col.is_in_primary_key)
             .collect();
 
-        let mut conditions = vec![];
+        if rows.is_empty() || primary_key_columns.is_empty() {
+            return String::new();
+        }
 
-        for row in rows.iter() {
-            let mut conditions_per_row = vec![];
+        let column_names = primary_key_columns
+            .iter()
+            .map(|column| quote_identifier(&column.column_name))
+            .chain([quote_identifier(is_deleted_column)])
+            .collect::<Vec<_>>();
 
-            for clickhouse_column in primary_key_columns.iter() {
-                let raw_value: Option<_> =
-                    row.find_value_by_column_name(source_columns, &clickhouse_column.column_name);
+        let values = rows
+            .iter()
+            .map(|row| {
+                let key_values = primary_key_columns
+                    .iter()
+                    .map(|column| {
+                        let raw_value: Option<_> = row.find_value_by_column_name(
+                            source_columns,
+                            &column.column_name,
+                            clickhouse_config.identifier_case,
+                        );
+                        encode_value(
+                            clickhouse_config,
+                            column,
+                            source_columns,
+                            raw_value.unwrap_or_default(),
+                            DateTimeText::Parsed,
+                        )
+                    })
+                    .chain(["1".to_string()])
+                    .collect::<Vec<_>>();
 
-                let column_value =
-                    clickhouse_column.to_clickhouse_value(raw_value.unwrap_or_default());
+                format!("({})", key_values.join(","))
+            })
+            .collect::<Vec<_>>();
 
-                conditions_per_row.push(format!(
-                    "{} = {}",
-                    clickhouse_column.column_name, column_value
-                ));
-            }
+        format!(
+            "INSERT INTO {}.{table_name} ({}) VALUES{}",
+            clickhouse_config.connection.database,
+            column_names.join(", "),
+            values.join(", ")
+        )
+    }
 
-            conditions.push(format!("({})", conditions_per_row.join(" AND ")));
+    /// Lightweight `UPDATE` setting the non-key columns of the row with the same primary key as `row`.
+    /// Unchanged (TOASTed) values are left out, so the stored value is kept.
+    /// Empty if the row is skipped (see `MissingValues::SkipRow`) or there is nothing to set.
+    fn generate_update_query<IntoClickhouseColumnType, IntoClickhouseRowType>(
+        &self,
+        clickhouse_config: &ClickHouseConfig,
+        clickhouse_columns: &[ClickhouseColumn],
+        source_columns: &[IntoClickhouseColumnType],
+        mask_columns: &[String],
+        table_name: &str,
+        row: &IntoClickhouseRowType,
+    ) -> String
+    where
+        IntoClickhouseColumnType: IntoClickhouseColumn,
+        IntoClickhouseRowType: IntoClickhouseRow,
+    {
+        if !has_common_columns(
+            clickhouse_columns,
+            source_columns,
+            clickhouse_config.identifier_case,
+            table_name,
+        ) {
+            return String::new();
         }
 
-        if conditions.is_empty() {
+        let Some(values) = row_values(
+            clickhouse_config,
+            clickhouse_columns,
+            source_columns,
+            mask_columns,
+            table_name,
+            row,
+            DateTimeText::Parsed,
+        ) else {
+            return String::new();
+        };
+
+        let identifier_case = clickhouse_config.identifier_case;
+        let is_unchanged = |column: &ClickhouseColumn| {
+            row.find_value_by_column_name(source_columns, &column.column_name, identifier_case)
+                .is_some_and(|value| value.is_unchanged())
+        };
+
+        // Key columns cannot be updated, and are the same anyway
+        let assignments: Vec<String> = clickhouse_columns
+            .iter()
+            .zip(values)
+            .filter(|(column, _)| !column.is_in_primary_key && !is_unchanged(column))
+            .map(|(column, value)| format!("{} = {value}", quote_identifier(&column.column_name)))
+            .collect();
+
+        let primary_key_columns: Vec<_> = clickhouse_columns
+            .iter()
+            .filter(|col| col.is_in_primary_key)
+            .collect();
+
+        if assignments.is_empty() || primary_key_columns.is_empty() {
             return String::new();
         }
 
-        delete_query.push_str(&conditions.join(" OR "));
+        format!(
+            "UPDATE {}.{table_name} SET {} WHERE {}",
+            clickhouse_config.connection.database,
+            assignments.join(", "),
+            primary_key_condition(clickhouse_config, &primary_key_columns, source_columns, row)
+        )
+    }
 
-        delete_query
+    /// Sets `column_name` of the rows with the same primary key as `rows`, leaving the other columns as they are.
+    /// `rows` only need to carry the primary key and that column.
+    /// Split into several mutations so that each one stays within `MAX_MUTATION_QUERY_BYTES`.
+    /// A single row larger than the limit gets a mutation of its own.
+    fn generate_update_column_queries<IntoClickhouseColumnType, IntoClickhouseRowType>(
+        &self,
+        clickhouse_config: &ClickHouseConfig,
+        clickhouse_columns: &[ClickhouseColumn],
+        source_columns: &[IntoClickhouseColumnType],
+        table_name: &str,
+        column_name: &str,
+        rows: &[IntoClickhouseRowType],
+    ) -> Vec<String>
+    where
+        IntoClickhouseColumnType: IntoClickhouseColumn,
+        IntoClickhouseRowType: IntoClickhouseRow,
+    {
+        let identifier_case = clickhouse_config.identifier_case;
+
+        let Some(target_column) = clickhouse_columns
+            .iter()
+            .find(|column| identifier_case.matches(column_name, &column.column_name))
+        else {
+            return vec![];
+        };
+
+        if rows.is_empty() {
+            return vec![];
+        }
+
+        let primary_key_columns: Vec<_> = clickhouse_columns
+            .iter()
+            .filter(|col| col.is_in_primary_key)
+            .collect();
+
+        let quoted_column = quote_identifier(&target_column.column_name);
+        let mutation = |branches: &[String], conditions: &[String]| {
+            format!(
+                "ALTER TABLE {}.{table_name} UPDATE {quoted_column} = multiIf({}, {quoted_column}) WHERE {}",
+                clickhouse_config.connection.database,
+                branches.join(", "),
+                conditions.join(" OR ")
+            )
+        };
+        let fixed_bytes = mutation(&[], &[]).len();
+
+        let mut queries = vec![];
+        let mut branches = vec![];
+        let mut conditions = vec![];
+        let mut query_bytes = fixed_bytes;
+
+        for row in rows {
+            let condition =
+                primary_key_condition(clickhouse_config, &primary_key_columns, source_columns, row);
+
+            let raw_value: Option<_> = row.find_value_by_column_name(
+                source_columns,
+                &target_column.column_name,
+                identifier_case,
+            );
+            let value = encode_value(
+                clickhouse_config,
+                target_column,
+                source_columns,
+                raw_value.unwrap_or_default(),
+                DateTimeText::Parsed,
+            );
+
+            let branch = format!(
+                "{condition}, CAST({value}, {})",
+                quote_string(&target_column.data_type)
+            );
+
+            // ", " between two branches and " OR " between two conditions
+            let added_bytes = branch.len() + condition.len() + 6;
+            if !branches.is_empty() && query_bytes + added_bytes > MAX_MUTATION_QUERY_BYTES {
+                queries.push(mutation(&branches, &conditions));
+                branches.clear();
+                conditions.clear();
+                query_bytes = fixed_bytes;
+            }
+
+            query_bytes += added_bytes;
+            branches.push(branch);
+            conditions.push(condition);
+        }
+
+        queries.push(mutation(&branches, &conditions));
+
+        queries
     }
 }
 
-/// Deduplicates rows by a key derived from each row, keeping the last occurrence per key.
-/// The relative order of first-seen keys is preserved.
-pub fn deduplicate_rows_keeping_last<T>(rows: Vec<T>, key_fn: impl Fn(&T) -> String) -> Vec<T> {
-    let mut seen = HashSet::new();
-    let mut result: Vec<T> = rows
-        .into_iter()
-        .rev()
-        .filter(|row| seen.insert(key_fn(row)))
+/// FixedString(n) holds n bytes. Longer text fails the whole insert, so it is truncated unless
+/// `FixedStringOverflow::Error` is configured.
+fn fit_fixed_string(
+    mut text: String,
+    length: usize,
+    column_name: &str,
+    overflow: FixedStringOverflow,
+) -> String {
+    if text.len() <= length {
+        return text;
+    }
+
+    match overflow {
+        FixedStringOverflow::Truncate => {
+            log::warn!(
+                "Value of column {column_name} is longer than FixedString({length}). Truncated to {length} bytes"
+            );
+
+            let mut end = length;
+            while !text.is_char_boundary(end) {
+                end -= 1;
+            }
+            text.truncate(end);
+        }
+        FixedStringOverflow::Error => {
+            log::error!(
+                "Value of column {column_name} is longer than FixedString({length}). ClickHouse rejects the insert until the column is widened"
+            );
+        }
+    }
+
+    text
+}
+
+/// Comment of the source column, escaped for the `COMMENT '...'` of its ClickHouse column.
+pub fn column_comment(column: &impl IntoClickhouseColumn) -> String {
+    escape_string(column.get_comment())
+}
+
+fn default_clause(column: &impl IntoClickhouseColumn) -> String {
+    column
+        .default_expression()
+        .map(|expression| format!(" DEFAULT {expression}"))
+        .unwrap_or_default()
+}
+
+/// `INSERT INTO ... VALUES` and the `(...)` value list of every row.
+fn insert_query_parts(
+    clickhouse_config: &ClickHouseConfig,
+    clickhouse_columns: &[ClickhouseColumn],
+    source_columns: &[impl IntoClickhouseColumn],
+    mask_columns: &[String],
+    table_name: &str,
+    rows: &[impl IntoClickhouseRow],
+) -> (String, Vec<String>) {
+    let mut insert_query = format!(
+        "INSERT INTO {}.{table_name} ",
+        clickhouse_config.connection.database
+    );
+
+    if !has_common_columns(
+        clickhouse_columns,
+        source_columns,
+        clickhouse_config.identifier_case,
+        table_name,
+    ) {
+        return (insert_query, vec![]);
+    }
+
+    let mut column_names = vec![];
+
+    for clickhouse_column in clickhouse_columns {
+        // Quoted the same way as in CREATE TABLE, so reserved words and special characters work
+        column_names.push(quote_identifier(&clickhouse_column.column_name));
+    }
+
+    insert_query.push_str(&format!("({}) ", column_names.join(", ")));
+
+    let date_time_best_effort =
+        clickhouse_config.date_time_input_format == DateTimeInputFormat::BestEffort;
+    if date_time_best_effort {
+        insert_query.push_str("SETTINGS date_time_input_format = 'best_effort' ");
+    }
+
+    insert_query.push_str("VALUES");
+
+    let values = rows
+        .iter()
+        .filter_map(|row| {
+            row_values(
+                clickhouse_config,
+                clickhouse_columns,
+                source_columns,
+                mask_columns,
+                table_name,
+                row,
+                DateTimeText::Literal,
+            )
+        })
+        .filter(|value| !value.is_empty())
+        .map(|value| format!("({})", value.join(",")))
         .collect();
-    result.reverse();
-    result
+
+    (insert_query, values)
+}
+
+/// False (with a warning) if none of the ClickHouse columns is a source column, e.g. because the
+/// schemas drifted apart. An INSERT would then have no columns or only default values.
+/// Without source columns (MongoDB has no fixed schema) only the ClickHouse columns are checked.
+fn has_common_columns(
+    clickhouse_columns: &[ClickhouseColumn],
+    source_columns: &[impl IntoClickhouseColumn],
+    identifier_case: IdentifierCase,
+    table_name: &str,
+) -> bool {
+    let is_source_column = |column_name: &str| {
+        source_columns.is_empty()
+            || source_columns.iter().any(|source_column| {
+                identifier_case.matches(source_column.get_column_name(), column_name)
+            })
+    };
+
+    if clickhouse_columns
+        .iter()
+        .any(|clickhouse_column| is_source_column(&clickhouse_column.column_name))
+    {
+        return true;
+    }
+
+    log::warn!(
+        "Skipping write to {table_name}: none of its ClickHouse columns {:?} is a source column {:?}. The schemas have drifted apart",
+        clickhouse_columns
+            .iter()
+            .map(|column| column.column_name.as_str())
+            .collect::<Vec<_>>(),
+        source_columns
+            .iter()
+            .map(|column| column.get_column_name())
+            .collect::<Vec<_>>()
+    );
+
+    false
+}
+
+/// Values of `row` in the order of `clickhouse_columns`, or None if the row is skipped.
+fn row_values(
+    clickhouse_config: &ClickHouseConfig,
+    clickhouse_columns: &[ClickhouseColumn],
+    source_columns: &[impl IntoClickhouseColumn],
+    mask_columns: &[String],
+    table_name: &str,
+    row: &impl IntoClickhouseRow,
+    date_time_text: DateTimeText,
+) -> Option<Vec<String>> {
+    let identifier_case = clickhouse_config.identifier_case;
+    let mut value = vec![];
+
+    for clickhouse_column in clickhouse_columns {
+        let raw_value = row.find_value_by_column_name(
+            source_columns,
+            &clickhouse_column.column_name,
+            identifier_case,
+        );
+
+        // A NULL array or map is an empty one, not a missing value. An unchanged (TOASTed) value
+        // is kept from the stored row, so it is not missing either
+        let is_missing = raw_value
+            .as_ref()
+            .is_none_or(|value| !value.is_unchanged() && value.is_null())
+            && !clickhouse_column.is_nullable()
+            && !clickhouse_column.is_array_or_map();
+        if is_missing && clickhouse_config.missing_values == MissingValues::SkipRow {
+            log::warn!(
+                "Skipping row of {table_name}: non-nullable column {} has no value",
+                clickhouse_column.column_name
+            );
+            return None;
+        }
+
+        let mut raw_value = raw_value.unwrap_or_default();
+
+        if mask_columns
+            .iter()
+            .any(|mask_column| identifier_case.matches(mask_column, &clickhouse_column.column_name))
+        {
+            raw_value = raw_value.into_null();
+        }
+
+        value.push(encode_value(
+            clickhouse_config,
+            clickhouse_column,
+            source_columns,
+            raw_value,
+            date_time_text,
+        ));
+    }
+
+    Some(value)
+}
+
+/// How a DateTime text is handed to ClickHouse under `DateTimeInputFormat::BestEffort`.
+#[derive(Debug, Clone, Copy)]
+enum DateTimeText {
+    /// As a string, parsed under the `date_time_input_format` setting of an INSERT ... VALUES
+    Literal,
+    /// Wrapped in `parseDateTimeBestEffort`, for queries the setting does not apply to
+    /// (UPDATE, WHERE, INSERT ... SELECT)
+    Parsed,
+}
+
+/// ClickHouse literal of a source value for `clickhouse_column`.
+/// Every place that writes or compares a source value goes through here, so a value is encoded
+/// the same way in an INSERT, an UPDATE and a WHERE on the primary key.
+fn encode_value(
+    clickhouse_config: &ClickHouseConfig,
+    clickhouse_column: &ClickhouseColumn,
+    source_columns: &[impl IntoClickhouseColumn],
+    mut raw_value: impl IntoClickhouseValue,
+    date_time_text: DateTimeText,
+) -> String {
+    let date_time_best_effort =
+        clickhouse_config.date_time_input_format == DateTimeInputFormat::BestEffort;
+    let identifier_case = clickhouse_config.identifier_case;
+
+    let is_binary_column = source_columns.iter().any(|source_column| {
+        source_column.is_binary()
+            && identifier_case.matches(
+                source_column.get_column_name(),
+                &clickhouse_column.column_name,
+            )
+    });
+
+    if raw_value.is_non_finite()
+        && clickhouse_column.is_float_or_decimal()
+        && (clickhouse_column.is_decimal()
+            || clickhouse_config.non_finite_numbers == NonFiniteNumbers::Null)
+    {
+        raw_value = raw_value.into_null();
+    }
+
+    if date_time_best_effort && clickhouse_column.is_date_time() && !raw_value.is_null() {
+        let Some(text) = raw_value.to_plain_text() else {
+            return "NULL".to_string();
+        };
+
+        match date_time_text {
+            DateTimeText::Parsed if !clickhouse_column.is_array_or_map() => {
+                match clickhouse_column.date_time_precision() {
+                    Some(precision) => format!(
+                        "parseDateTime64BestEffort({}, {precision})",
+                        quote_string(&text)
+                    ),
+                    None => format!("parseDateTimeBestEffort({})", quote_string(&text)),
+                }
+            }
+            // Parsed by ClickHouse under the best_effort setting
+            _ => quote_string(&text),
+        }
+    } else if is_binary_column && !raw_value.is_null() {
+        raw_value.to_bytes_literal(clickhouse_config.binary_encoding)
+    } else if let Some(length) = clickhouse_column.fixed_string_length()
+        && !raw_value.is_null()
+    {
+        quote_string(&fit_fixed_string(
+            raw_value.to_plain_text().unwrap_or_default(),
+            length,
+            &clickhouse_column.column_name,
+            clickhouse_config.fixed_string_overflow,
+        ))
+    } else if raw_value.is_null() && !clickhouse_column.is_nullable() {
+        clickhouse_column.zero_value()
+    } else {
+        clickhouse_column.to_clickhouse_value(raw_value)
+    }
+}
+
+/// `(key1 = value1 AND key2 = value2)` matching the primary key of `row`.
+fn primary_key_condition<IntoClickhouseColumnType, IntoClickhouseRowType>(
+    clickhouse_config: &ClickHouseConfig,
+    primary_key_columns: &[&ClickhouseColumn],
+    source_columns: &[IntoClickhouseColumnType],
+    row: &IntoClickhouseRowType,
+) -> String
+where
+    IntoClickhouseColumnType: IntoClickhouseColumn,
+    IntoClickhouseRowType: IntoClickhouseRow,
+{
+    let conditions: Vec<String> = primary_key_columns
+        .iter()
+        .map(|clickhouse_column| {
+            let raw_value: Option<_> = row.find_value_by_column_name(
+                source_columns,
+                &clickhouse_column.column_name,
+                clickhouse_config.identifier_case,
+            );
+
+            let column_value = encode_value(
+                clickhouse_config,
+                clickhouse_column,
+                source_columns,
+                raw_value.unwrap_or_default(),
+                DateTimeText::Parsed,
+            );
+
+            format!(
+                "{} = {}",
+                quote_identifier(&clickhouse_column.column_name),
+                column_value
+            )
+        })
+        .collect();
+
+    format!("({})", conditions.join(" AND "))
+}
+
+/// Quotes a column name as a ClickHouse identifier, so reserved words (e.g. `order`), uppercase
+/// letters and special characters work.
+pub fn quote_identifier(name: &str) -> String {
+    format!("`{}`", name.replace('\\', "\\\\").replace('`', "\\`"))
+}
+
+/// Quotes text as a ClickHouse string literal.
+pub fn quote_string(text: &str) -> String {
+    format!("'{}'", escape_string(text))
+}
+
+/// Escapes text for the inside of a ClickHouse string literal.
+/// Backslash starts an escape sequence there, so control characters are escaped as well.
+/// Otherwise a newline or a null byte in a value can break the parsing of the VALUES.
+pub fn escape_string(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '\'' => escaped.push_str("''"),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            '\0' => escaped.push_str("\\0"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Deduplicates rows by a key derived from each row, keeping the last occurrence per key.
+/// The relative order of first-seen keys is preserved.
+pub fn deduplicate_rows_keeping_last<T>(rows: Vec<T>, key_fn: impl Fn(&T) -> String) -> Vec<T> {
+    let mut seen = HashSet::new();
+    let mut result: Vec<T> = rows
+        .into_iter()
+        .rev()
+        .filter(|row| seen.insert(key_fn(row)))
+        .collect();
+    result.reverse();
+    result
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{IntoClickhouse, IntoClickhouseColumn, IntoClickhouseValue, quote_identifier};
+    use crate::{
+        adapter::{
+            clickhouse::ClickhouseColumn,
+            postgres::{PostgresColumn, PostgresCopyRow, pgoutput::PgOutputValue},
+        },
+        config::{
+            BinaryEncoding, ClickHouseConfig, ClickHouseConnectionConfig, ClickHouseTableOptions,
+            DateTimeInputFormat, FixedStringOverflow, IdentifierCase, MissingValues,
+            default::clickhouse::MAX_MUTATION_QUERY_BYTES,
+        },
+    };
+
+    pub(crate) struct TestClickhouse;
+
+    impl IntoClickhouse for TestClickhouse {}
+
+    pub(crate) fn clickhouse_config() -> ClickHouseConfig {
+        ClickHouseConfig {
+            connection: ClickHouseConnectionConfig {
+                host: "localhost".to_string(),
+                port: 8123,
+                username: "default".to_string(),
+                password: String::new(),
+                database: "db".to_string(),
+                scheme: Default::default(),
+                session_settings: Default::default(),
+            },
+            disable_sync_loop: false,
+            table_options: ClickHouseTableOptions::default(),
+            required: true,
+            non_finite_numbers: Default::default(),
+            identifier_case: Default::default(),
+            date_time_input_format: Default::default(),
+            max_insert_query_bytes: None,
+            missing_values: Default::default(),
+            fixed_string_overflow: Default::default(),
+            binary_encoding: Default::default(),
+            query_log: None,
+        }
+    }
+
+    pub(crate) fn postgres_column(
+        column_index: i32,
+        column_name: &str,
+        data_type: &str,
+        nullable: bool,
+        is_primary_key: bool,
+    ) -> PostgresColumn {
+        PostgresColumn {
+            column_index,
+            column_name: column_name.to_string(),
+            data_type: data_type.to_string(),
+            length: 0,
+            numeric_precision: 0,
+            numeric_scale: 0,
+            nullable,
+            is_primary_key,
+            comment: String::new(),
+            is_enum: false,
+            type_override: None,
+            default_expression: None,
+        }
+    }
+
+    /// CREATE TABLE bean_policy (
+    ///     id bigserial PRIMARY KEY,
+    ///     name varchar(255) NOT NULL,
+    ///     bean_count int4,
+    ///     is_active bool NOT NULL DEFAULT true,
+    ///     created_at timestamptz NOT NULL DEFAULT now()
+    /// );
+    fn bean_policy_columns() -> Vec<PostgresColumn> {
+        vec![
+            // bigserial is reported as int8 (with a nextval() default)
+            postgres_column(1, "id", "int8", false, true),
+            postgres_column(2, "name", "varchar", false, false),
+            postgres_column(3, "bean_count", "int4", true, false),
+            postgres_column(4, "is_active", "bool", false, false),
+            postgres_column(5, "created_at", "timestamptz", false, false),
+        ]
+    }
+
+    /// Mirrors what system.columns returns after the CREATE TABLE generated for `columns`.
+    pub(crate) fn clickhouse_column(
+        column_index: u64,
+        column_name: &str,
+        data_type: &str,
+        is_in_primary_key: bool,
+    ) -> ClickhouseColumn {
+        ClickhouseColumn {
+            column_index,
+            column_name: column_name.to_string(),
+            data_type: data_type.to_string(),
+            is_in_primary_key,
+            comment: String::new(),
+        }
+    }
+
+    fn clickhouse_columns_for(columns: &[PostgresColumn]) -> Vec<ClickhouseColumn> {
+        columns
+            .iter()
+            .map(|column| {
+                clickhouse_column(
+                    column.column_index as u64,
+                    &column.column_name,
+                    &column.to_clickhouse_type().to_type_text(),
+                    column.is_primary_key,
+                )
+            })
+            .collect()
+    }
+
+    fn text_row(values: &[Option<&str>]) -> PostgresCopyRow {
+        PostgresCopyRow {
+            columns: values
+                .iter()
+                .map(|value| match value {
+                    Some(value) => PgOutputValue::Text(value.to_string()),
+                    None => PgOutputValue::Null,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn bigserial_primary_key_becomes_int64_order_by() {
+        let query = TestClickhouse.generate_create_table_query(
+            &clickhouse_config(),
+            &ClickHouseTableOptions::default(),
+            "bean_policy",
+            &bean_policy_columns(),
+            "",
+        );
+
+        assert!(query.contains("`id` Int64 COMMENT"), "{query}");
+        assert!(query.contains("ORDER BY (`id`)"), "{query}");
+    }
+
+    #[test]
+    fn order_by_quotes_reserved_word_key_columns() {
+        let columns = vec![
+            postgres_column(1, "order", "int8", false, true),
+            postgres_column(2, "UserId", "int4", false, true),
+        ];
+
+        let query = TestClickhouse.generate_create_table_query(
+            &clickhouse_config(),
+            &ClickHouseTableOptions::default(),
+            "orders",
+            &columns,
+            "",
+        );
+
+        assert!(query.contains("ORDER BY (`order`, `UserId`)"), "{query}");
+    }
+
+    #[test]
+    fn create_table_query_is_valid_ddl() {
+        let columns = vec![
+            postgres_column(1, "id", "int8", false, true),
+            postgres_column(2, "name", "varchar", true, false),
+        ];
+
+        let query = TestClickhouse.generate_create_table_query(
+            &clickhouse_config(),
+            &ClickHouseTableOptions {
+                storage_policy: Some("basic_storage".to_string()),
+                ..Default::default()
+            },
+            "bean_policy",
+            &columns,
+            "bean's policy",
+        );
+
+        assert_eq!(
+            query,
+            "CREATE TABLE db.bean_policy(`id` Int64 COMMENT '', \n`name` Nullable(String) COMMENT '') ENGINE = ReplacingMergeTree()\n\
+             ORDER BY (`id`)\n\
+             SETTINGS\n\
+             index_granularity = 8192\n\
+             , min_age_to_force_merge_seconds = 60\n\
+             , storage_policy = 'basic_storage'\n\
+             COMMENT 'bean''s policy'\n;"
+        );
+
+        // Without a primary key ClickHouse still needs a sorting key, and a plain MergeTree does
+        // not collapse the rows that all share it
+        let query = TestClickhouse.generate_create_table_query(
+            &clickhouse_config(),
+            &ClickHouseTableOptions::default(),
+            "events",
+            &columns[1..],
+            "",
+        );
+
+        assert!(
+            query.contains("ENGINE = MergeTree()\nORDER BY tuple()\nSETTINGS\n"),
+            "{query}"
+        );
+    }
+
+    #[test]
+    fn summing_merge_tree_sums_the_configured_columns() {
+        // CREATE TABLE daily_sales (day date, shop_id int4, amount int8, orders int4,
+        //     PRIMARY KEY (day, shop_id))
+        let columns = vec![
+            postgres_column(1, "day", "date", false, true),
+            postgres_column(2, "shop_id", "int4", false, true),
+            postgres_column(3, "amount", "int8", false, false),
+            postgres_column(4, "orders", "int4", false, false),
+        ];
+        let table_options = serde_json::from_value::<ClickHouseTableOptions>(serde_json::json!({
+            "engine": "summing_merge_tree",
+            "sum_columns": ["amount"]
+        }))
+        .unwrap();
+
+        let query = TestClickhouse.generate_create_table_query(
+            &clickhouse_config(),
+            &table_options,
+            "daily_sales",
+            &columns,
+            "",
+        );
+
+        assert!(
+            query
+                .contains(") ENGINE = SummingMergeTree((`amount`))\nORDER BY (`day`, `shop_id`)\n"),
+            "{query}"
+        );
+
+        let mut operations = crate::config::ReplicatedOperations::default();
+        crate::pipes::disable_deletes_for_aggregating_engine(
+            "public.daily_sales",
+            &table_options,
+            &mut operations,
+        );
+        assert!(operations.insert && !operations.delete);
+    }
+
+    #[test]
+    fn replacing_deleted_merge_tree_deletes_by_inserting_a_marker_row() {
+        let postgres_columns = vec![
+            postgres_column(1, "id", "int8", false, true),
+            postgres_column(2, "name", "text", true, false),
+        ];
+        let table_options = serde_json::from_value::<ClickHouseTableOptions>(serde_json::json!({
+            "engine": "replacing_deleted_merge_tree"
+        }))
+        .unwrap();
+
+        let query = TestClickhouse.generate_create_table_query(
+            &clickhouse_config(),
+            &table_options,
+            "users",
+            &postgres_columns,
+            "",
+        );
+        assert!(
+            query.contains(
+                "`_clockpipe_version` UInt64 DEFAULT toUnixTimestamp64Nano(now64(9)) COMMENT 'Insert time of the row version', \n`_clockpipe_is_deleted` UInt8 DEFAULT 0 COMMENT '1 if the row was deleted') ENGINE = ReplacingMergeTree(`_clockpipe_version`, `_clockpipe_is_deleted`)\n"
+            ),
+            "{query}"
+        );
+
+        let mut clickhouse_columns = clickhouse_columns_for(&postgres_columns);
+        for (column_index, column_name, data_type) in [
+            (3, "_clockpipe_version", "UInt64"),
+            (4, "_clockpipe_is_deleted", "UInt8"),
+        ] {
+            clickhouse_columns.push(ClickhouseColumn {
+                column_index,
+                column_name: column_name.to_string(),
+                data_type: data_type.to_string(),
+                is_in_primary_key: false,
+                comment: String::new(),
+            });
+        }
+        // Loaded without the engine columns
+        crate::pipes::remove_engine_columns(&mut clickhouse_columns, &table_options);
+        let rows = vec![text_row(&[Some("1"), Some("foo")])];
+
+        // The version is left to its DEFAULT
+        let insert_query = TestClickhouse.generate_insert_query(
+            &clickhouse_config(),
+            &clickhouse_columns,
+            &postgres_columns,
+            &[],
+            "users",
+            &rows,
+        );
+        assert_eq!(
+            insert_query,
+            "INSERT INTO db.users (`id`, `name`) VALUES(1,'foo')"
+        );
+
+        // No ALTER TABLE ... DELETE mutation
+        assert!(table_options.deletes_by_marker());
+        let delete_query = TestClickhouse.generate_delete_marker_query(
+            &clickhouse_config(),
+            &clickhouse_columns,
+            &postgres_columns,
+            table_options.is_deleted_column_name(),
+            "users",
+            &[text_row(&[Some("1"), None]), text_row(&[Some("2"), None])],
+        );
+        assert_eq!(
+            delete_query,
+            "INSERT INTO db.users (`id`, `_clockpipe_is_deleted`) VALUES(1,1), (2,1)"
+        );
+    }
+
+    #[test]
+    fn replacing_deleted_merge_tree_uses_the_configured_column_names() {
+        let postgres_columns = vec![
+            postgres_column(1, "id", "int8", false, true),
+            postgres_column(2, "name", "text", true, false),
+        ];
+        let mut config = clickhouse_config();
+        config.table_options.engine = Some(crate::config::TableEngine::ReplacingDeletedMergeTree);
+        config.table_options.version_column = Some("row_version".to_string());
+
+        // The table overrides one name and inherits the other
+        let mut table_options = serde_json::from_value::<ClickHouseTableOptions>(
+            serde_json::json!({ "is_deleted_column": "is_deleted" }),
+        )
+        .unwrap();
+        table_options.inherit_from(&config.table_options);
+
+        let query = TestClickhouse.generate_create_table_query(
+            &config,
+            &table_options,
+            "users",
+            &postgres_columns,
+            "",
+        );
+        assert!(
+            query.contains(
+                "`row_version` UInt64 DEFAULT toUnixTimestamp64Nano(now64(9)) COMMENT 'Insert time of the row version', \n`is_deleted` UInt8 DEFAULT 0 COMMENT '1 if the row was deleted') ENGINE = ReplacingMergeTree(`row_version`, `is_deleted`)\n"
+            ),
+            "{query}"
+        );
+
+        let mut clickhouse_columns = clickhouse_columns_for(&postgres_columns);
+        for (column_index, column_name) in [(3, "row_version"), (4, "is_deleted")] {
+            clickhouse_columns.push(clickhouse_column(column_index, column_name, "UInt8", false));
+        }
+        crate::pipes::remove_engine_columns(&mut clickhouse_columns, &table_options);
+
+        let insert_query = TestClickhouse.generate_insert_query(
+            &config,
+            &clickhouse_columns,
+            &postgres_columns,
+            &[],
+            "users",
+            &[text_row(&[Some("1"), Some("foo")])],
+        );
+        assert_eq!(
+            insert_query,
+            "INSERT INTO db.users (`id`, `name`) VALUES(1,'foo')"
+        );
+
+        let delete_query = TestClickhouse.generate_delete_marker_query(
+            &config,
+            &clickhouse_columns,
+            &postgres_columns,
+            table_options.is_deleted_column_name(),
+            "users",
+            &[text_row(&[Some("1"), None])],
+        );
+        assert_eq!(
+            delete_query,
+            "INSERT INTO db.users (`id`, `is_deleted`) VALUES(1,1)"
+        );
+
+        let same_names = serde_json::from_value::<ClickHouseTableOptions>(serde_json::json!({
+            "version_column": "v",
+            "is_deleted_column": "V"
+        }))
+        .unwrap();
+        assert!(same_names.validate("public.users").is_err());
+    }
+
+    #[test]
+    fn table_min_age_to_force_merge_seconds_overrides_global() {
+        let mut config = clickhouse_config();
+        config.table_options.min_age_to_force_merge_seconds = Some(300);
+
+        let create_query = |config: &ClickHouseConfig, table_options: &ClickHouseTableOptions| {
+            let mut table_options = table_options.clone();
+            table_options.inherit_from(&config.table_options);
+
+            TestClickhouse.generate_create_table_query(
+                config,
+                &table_options,
+                "bean_policy",
+                &bean_policy_columns(),
+                "",
+            )
+        };
+
+        let query = create_query(&config, &ClickHouseTableOptions::default());
+        assert!(
+            query.contains(", min_age_to_force_merge_seconds = 300\n"),
+            "{query}"
+        );
+
+        let query = create_query(
+            &config,
+            &ClickHouseTableOptions {
+                min_age_to_force_merge_seconds: Some(3600),
+                ..Default::default()
+            },
+        );
+        assert!(
+            query.contains(", min_age_to_force_merge_seconds = 3600\n"),
+            "{query}"
+        );
+
+        config.table_options.min_age_to_force_merge_seconds = None;
+        let query = create_query(&config, &ClickHouseTableOptions::default());
+        assert!(
+            query.contains(", min_age_to_force_merge_seconds = 60\n"),
+            "{query}"
+        );
+    }
+
+    #[test]
+    fn bigserial_primary_key_rows_insert_and_deduplicate() {
+        let postgres_columns = bean_policy_columns();
+        let clickhouse_columns = clickhouse_columns_for(&postgres_columns);
+
+        let rows = vec![
+            text_row(&[
+                Some("1"),
+                Some("a"),
+                None,
+                Some("t"),
+                Some("2025-01-01 00:00:00+00"),
+            ]),
+            text_row(&[
+                Some("1"),
+                Some("b"),
+                Some("3"),
+                Some("f"),
+                Some("2025-01-01 00:00:00+00"),
+            ]),
+        ];
+        let rows =
+            super::deduplicate_rows_keeping_last(rows, |row| format!("{:?}", row.columns[0]));
+
+        let query = TestClickhouse.generate_insert_query(
+            &clickhouse_config(),
+            &clickhouse_columns,
+            &postgres_columns,
+            &[],
+            "bean_policy",
+            &rows,
+        );
+
+        assert_eq!(
+            query,
+            "INSERT INTO db.bean_policy (`id`, `name`, `bean_count`, `is_active`, `created_at`) VALUES(1,'b',3,FALSE,toDateTime('2025-01-01 00:00:00'))"
+        );
+    }
+
+    #[tokio::test]
+    async fn bean_policy_insert_waits_for_created_columns() {
+        let postgres_columns = bean_policy_columns();
+        let created_columns = clickhouse_columns_for(&postgres_columns);
+
+        // The first lookup right after CREATE TABLE does not see the new table yet.
+        let mut lookups = 0;
+        let clickhouse_columns = super::clickhouse::retry_until_non_empty(
+            3,
+            std::time::Duration::from_millis(1),
+            || {
+                lookups += 1;
+                let columns = if lookups == 1 {
+                    vec![]
+                } else {
+                    created_columns.clone()
+                };
+                async move { Ok(columns) }
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(lookups, 2);
+
+        let rows = vec![text_row(&[
+            Some("1"),
+            Some("a"),
+            None,
+            Some("t"),
+            Some("2025-01-01 00:00:00+00"),
+        ])];
+
+        let query = TestClickhouse.generate_insert_query(
+            &clickhouse_config(),
+            &clickhouse_columns,
+            &postgres_columns,
+            &[],
+            "bean_policy",
+            &rows,
+        );
+
+        assert_eq!(
+            query,
+            "INSERT INTO db.bean_policy (`id`, `name`, `bean_count`, `is_active`, `created_at`) VALUES(1,'a',NULL,TRUE,toDateTime('2025-01-01 00:00:00'))"
+        );
+    }
+
+    #[test]
+    fn insert_without_common_columns_is_empty_instead_of_malformed() {
+        let postgres_columns = bean_policy_columns();
+        let rows = vec![text_row(&[
+            Some("1"),
+            Some("a"),
+            Some("3"),
+            Some("t"),
+            Some("2025-01-01 00:00:00+00"),
+        ])];
+
+        // ClickHouse table of another schema, and no ClickHouse columns at all
+        let mut drifted_columns = clickhouse_columns_for(&postgres_columns);
+        for column in drifted_columns.iter_mut() {
+            column.column_name = format!("old_{}", column.column_name);
+        }
+
+        for clickhouse_columns in [drifted_columns, vec![]] {
+            let insert_query = TestClickhouse.generate_insert_query(
+                &clickhouse_config(),
+                &clickhouse_columns,
+                &postgres_columns,
+                &[],
+                "bean_policy",
+                &rows,
+            );
+            assert_eq!(insert_query, "");
+
+            let insert_queries = TestClickhouse.generate_insert_queries(
+                &clickhouse_config(),
+                &clickhouse_columns,
+                &postgres_columns,
+                &[],
+                "bean_policy",
+                &rows,
+            );
+            assert!(insert_queries.is_empty(), "{insert_queries:?}");
+
+            let keeping_unchanged_query = TestClickhouse.generate_insert_keeping_unchanged_query(
+                &clickhouse_config(),
+                &clickhouse_columns,
+                &postgres_columns,
+                &[],
+                "bean_policy",
+                &rows[0],
+            );
+            assert_eq!(keeping_unchanged_query, "");
+        }
+    }
+
+    #[test]
+    fn insert_matches_columns_by_name_when_clickhouse_order_differs() {
+        let postgres_columns = bean_policy_columns();
+
+        // e.g. columns re-added in ClickHouse after the table was created
+        let mut clickhouse_columns = clickhouse_columns_for(&postgres_columns);
+        clickhouse_columns.reverse();
+        for (index, column) in clickhouse_columns.iter_mut().enumerate() {
+            column.column_index = index as u64 + 1;
+        }
+
+        let rows = vec![text_row(&[
+            Some("1"),
+            Some("a"),
+            Some("3"),
+            Some("t"),
+            Some("2025-01-01 00:00:00+00"),
+        ])];
+
+        let insert_query = TestClickhouse.generate_insert_query(
+            &clickhouse_config(),
+            &clickhouse_columns,
+            &postgres_columns,
+            &[],
+            "bean_policy",
+            &rows,
+        );
+
+        assert_eq!(
+            insert_query,
+            "INSERT INTO db.bean_policy (`created_at`, `is_active`, `bean_count`, `name`, `id`) VALUES(toDateTime('2025-01-01 00:00:00'),TRUE,3,'a',1)"
+        );
+
+        let delete_query = TestClickhouse.generate_delete_query(
+            &clickhouse_config(),
+            &clickhouse_columns,
+            &postgres_columns,
+            "bean_policy",
+            &rows,
+        );
+
+        assert_eq!(
+            delete_query,
+            "ALTER TABLE db.bean_policy DELETE WHERE (`id` = 1)"
+        );
+    }
+
+    #[test]
+    fn update_sets_non_key_columns_of_the_row_with_the_same_primary_key() {
+        let postgres_columns = bean_policy_columns();
+        let clickhouse_columns = clickhouse_columns_for(&postgres_columns);
+
+        // UPDATE bean_policy SET name = 'b''s', bean_count = NULL WHERE id = 1
+        let row = text_row(&[
+            Some("1"),
+            Some("b's"),
+            None,
+            Some("t"),
+            Some("2025-01-01 00:00:00+00"),
+        ]);
+
+        let update_query = TestClickhouse.generate_update_query(
+            &clickhouse_config(),
+            &clickhouse_columns,
+            &postgres_columns,
+            &["name".to_string()],
+            "bean_policy",
+            &row,
+        );
+
+        assert_eq!(
+            update_query,
+            "UPDATE db.bean_policy SET `name` = '', `bean_count` = NULL, `is_active` = TRUE, `created_at` = toDateTime('2025-01-01 00:00:00') WHERE (`id` = 1)"
+        );
+
+        let create_query = TestClickhouse.generate_create_table_query(
+            &clickhouse_config(),
+            &ClickHouseTableOptions {
+                lightweight_updates: true,
+                ..Default::default()
+            },
+            "bean_policy",
+            &postgres_columns,
+            "",
+        );
+        assert!(
+            create_query
+                .contains(", enable_block_number_column = 1, enable_block_offset_column = 1\n"),
+            "{create_query}"
+        );
+    }
+
+    #[test]
+    fn update_leaves_out_unchanged_toast_columns() {
+        // CREATE TABLE documents (tenant_id int4, id int8, title text, body text, version int4,
+        //     PRIMARY KEY (tenant_id, id))
+        let postgres_columns = vec![
+            postgres_column(1, "tenant_id", "int4", false, true),
+            postgres_column(2, "id", "int8", false, true),
+            postgres_column(3, "title", "text", true, false),
+            postgres_column(4, "body", "text", true, false),
+            postgres_column(5, "version", "int4", false, false),
+        ];
+        let clickhouse_columns = clickhouse_columns_for(&postgres_columns);
+
+        // UPDATE documents SET title = 'new', version = 2, without touching the TOASTed body
+        let row = PostgresCopyRow {
+            columns: vec![
+                PgOutputValue::Text("7".to_string()),
+                PgOutputValue::Text("42".to_string()),
+                PgOutputValue::Text("new".to_string()),
+                PgOutputValue::Unchanged,
+                PgOutputValue::Text("2".to_string()),
+            ],
+        };
+
+        let update_query = TestClickhouse.generate_update_query(
+            &clickhouse_config(),
+            &clickhouse_columns,
+            &postgres_columns,
+            &[],
+            "documents",
+            &row,
+        );
+
+        assert_eq!(
+            update_query,
+            "UPDATE db.documents SET `title` = 'new', `version` = 2 WHERE (`tenant_id` = 7 AND `id` = 42)"
+        );
+
+        // A plain insert writes it as NULL. The pipe uses generate_insert_keeping_unchanged_query
+        // for such rows instead
+        let insert_query = TestClickhouse.generate_insert_query(
+            &clickhouse_config(),
+            &clickhouse_columns,
+            &postgres_columns,
+            &[],
+            "documents",
+            &[row],
+        );
+        assert!(
+            insert_query.ends_with("VALUES(7,42,'new',NULL,2)"),
+            "{insert_query}"
+        );
+    }
+
+    #[test]
+    fn insert_keeps_unchanged_toast_columns_from_the_stored_row() {
+        let postgres_columns = vec![
+            postgres_column(1, "id", "int8", false, true),
+            postgres_column(2, "title", "text", true, false),
+            postgres_column(3, "body", "text", true, false),
+        ];
+        let clickhouse_columns = clickhouse_columns_for(&postgres_columns);
+
+        let row = PostgresCopyRow {
+            columns: vec![
+                PgOutputValue::Text("1".to_string()),
+                PgOutputValue::Text("new".to_string()),
+                PgOutputValue::Unchanged,
+            ],
+        };
+
+        let query = TestClickhouse.generate_insert_keeping_unchanged_query(
+            &clickhouse_config(),
+            &clickhouse_columns,
+            &postgres_columns,
+            &[],
+            "documents",
+            &row,
+        );
+
+        assert_eq!(
+            query,
+            "INSERT INTO db.documents (`id`, `title`, `body`) SELECT 1, 'new', any(`body`) FROM db.documents FINAL WHERE (`id` = 1)"
+        );
+    }
+
+    #[test]
+    fn unchanged_non_nullable_column_is_not_missing_under_skip_row() {
+        let postgres_columns = vec![
+            postgres_column(1, "id", "int8", false, true),
+            postgres_column(2, "title", "text", false, false),
+            postgres_column(3, "body", "text", false, false),
+        ];
+        let clickhouse_columns = clickhouse_columns_for(&postgres_columns);
+        let mut config = clickhouse_config();
+        config.missing_values = MissingValues::SkipRow;
+
+        let row = PostgresCopyRow {
+            columns: vec![
+                PgOutputValue::Text("1".to_string()),
+                PgOutputValue::Text("new".to_string()),
+                PgOutputValue::Unchanged,
+            ],
+        };
+
+        let update_query = TestClickhouse.generate_update_query(
+            &config,
+            &clickhouse_columns,
+            &postgres_columns,
+            &[],
+            "documents",
+            &row,
+        );
+        assert_eq!(
+            update_query,
+            "UPDATE db.documents SET `title` = 'new' WHERE (`id` = 1)"
+        );
+
+        let insert_query = TestClickhouse.generate_insert_keeping_unchanged_query(
+            &config,
+            &clickhouse_columns,
+            &postgres_columns,
+            &[],
+            "documents",
+            &row,
+        );
+        assert_eq!(
+            insert_query,
+            "INSERT INTO db.documents (`id`, `title`, `body`) SELECT 1, 'new', any(`body`) FROM db.documents FINAL WHERE (`id` = 1)"
+        );
+    }
+
+    #[test]
+    fn column_default_is_added_to_create_and_add_column() {
+        let mut postgres_columns = vec![
+            postgres_column(1, "id", "int4", false, true),
+            postgres_column(2, "status", "text", false, false),
+        ];
+        postgres_columns[1].default_expression = Some("'active'".to_string());
+        let config = clickhouse_config();
+
+        let create_query = TestClickhouse.generate_create_table_query(
+            &config,
+            &ClickHouseTableOptions::default(),
+            "users",
+            &postgres_columns,
+            "",
+        );
+        assert!(
+            create_query.contains("`status` String DEFAULT 'active' COMMENT"),
+            "{create_query}"
+        );
+        assert!(
+            create_query.contains("`id` Int32 COMMENT"),
+            "{create_query}"
+        );
+
+        let add_column_query =
+            TestClickhouse.generate_add_column_query(&config, "users", &postgres_columns[1]);
+        assert!(
+            add_column_query.contains("`status` String DEFAULT 'active' COMMENT"),
+            "{add_column_query}"
+        );
+    }
+
+    #[test]
+    fn mixed_case_column_matches_under_both_identifier_cases() {
+        // CREATE TABLE users (id int4 PRIMARY KEY, "UserId" int4)
+        let postgres_columns = vec![
+            postgres_column(1, "id", "int4", false, true),
+            postgres_column(2, "UserId", "int4", true, false),
+        ];
+        let rows = vec![text_row(&[Some("1"), Some("42")])];
+
+        for (identifier_case, clickhouse_name) in [
+            (IdentifierCase::Preserve, "UserId"),
+            (IdentifierCase::Lowercase, "userid"),
+        ] {
+            let mut config = clickhouse_config();
+            config.identifier_case = identifier_case;
+
+            let create_query = TestClickhouse.generate_create_table_query(
+                &config,
+                &ClickHouseTableOptions::default(),
+                "users",
+                &postgres_columns,
+                "",
+            );
+            assert!(
+                create_query.contains(&format!("`{clickhouse_name}` Nullable(Int32)")),
+                "{create_query}"
+            );
+
+            let add_column_query =
+                TestClickhouse.generate_add_column_query(&config, "users", &postgres_columns[1]);
+            assert!(
+                add_column_query.contains(&format!("`{clickhouse_name}`")),
+                "{add_column_query}"
+            );
+
+            let mut clickhouse_columns = clickhouse_columns_for(&postgres_columns);
+            clickhouse_columns[1].column_name = clickhouse_name.to_string();
+
+            let insert_query = TestClickhouse.generate_insert_query(
+                &config,
+                &clickhouse_columns,
+                &postgres_columns,
+                &[],
+                "users",
+                &rows,
+            );
+            assert_eq!(
+                insert_query,
+                format!("INSERT INTO db.users (`id`, `{clickhouse_name}`) VALUES(1,42)")
+            );
+
+            let masked_insert_query = TestClickhouse.generate_insert_query(
+                &config,
+                &clickhouse_columns,
+                &postgres_columns,
+                &["UserId".to_string()],
+                "users",
+                &rows,
+            );
+            assert_eq!(
+                masked_insert_query,
+                format!("INSERT INTO db.users (`id`, `{clickhouse_name}`) VALUES(1,NULL)")
+            );
+        }
+    }
+
+    #[test]
+    fn non_finite_numbers_become_null_for_decimal_and_literals_for_float() {
+        let postgres_columns = vec![
+            postgres_column(1, "id", "int8", false, true),
+            postgres_column(2, "price", "numeric", true, false),
+            postgres_column(3, "score", "float8", false, false),
+        ];
+        let mut clickhouse_columns = clickhouse_columns_for(&postgres_columns);
+        // system.columns reports the precision
+        clickhouse_columns[1].data_type = "Nullable(Decimal(10, 2))".to_string();
+
+        let rows = vec![
+            text_row(&[Some("1"), Some("NaN"), Some("Infinity")]),
+            text_row(&[Some("2"), Some("1.5"), Some("-Infinity")]),
+        ];
+
+        let query = TestClickhouse.generate_insert_query(
+            &clickhouse_config(),
+            &clickhouse_columns,
+            &postgres_columns,
+            &[],
+            "prices",
+            &rows,
+        );
+
+        assert_eq!(
+            query,
+            "INSERT INTO db.prices (`id`, `price`, `score`) VALUES(1,NULL,inf), (2,'1.50',-inf)"
+        );
+
+        let mut config = clickhouse_config();
+        config.non_finite_numbers = crate::config::NonFiniteNumbers::Null;
+        clickhouse_columns[2].data_type = "Nullable(Float64)".to_string();
+
+        let query = TestClickhouse.generate_insert_query(
+            &config,
+            &clickhouse_columns,
+            &postgres_columns,
+            &[],
+            "prices",
+            &rows[..1],
+        );
+
+        assert_eq!(
+            query,
+            "INSERT INTO db.prices (`id`, `price`, `score`) VALUES(1,NULL,NULL)"
+        );
+    }
+
+    #[test]
+    fn non_finite_text_is_kept_in_string_columns() {
+        let postgres_columns = vec![
+            postgres_column(1, "id", "int8", false, true),
+            postgres_column(2, "note", "text", false, false),
+        ];
+        let clickhouse_columns = clickhouse_columns_for(&postgres_columns);
+        let rows = vec![text_row(&[Some("1"), Some("NaN")])];
+
+        let mut config = clickhouse_config();
+        config.non_finite_numbers = crate::config::NonFiniteNumbers::Null;
+
+        let query = TestClickhouse.generate_insert_query(
+            &config,
+            &clickhouse_columns,
+            &postgres_columns,
+            &[],
+            "notes",
+            &rows,
+        );
+
+        assert_eq!(query, "INSERT INTO db.notes (`id`, `note`) VALUES(1,'NaN')");
+    }
+
+    #[test]
+    fn insert_quotes_reserved_word_column_names() {
+        let postgres_columns = vec![
+            postgres_column(1, "id", "int8", false, true),
+            postgres_column(2, "order", "int4", false, false),
+            postgres_column(3, "from", "text", true, false),
+        ];
+        let clickhouse_columns = clickhouse_columns_for(&postgres_columns);
+        let rows = vec![text_row(&[Some("1"), Some("2"), Some("gate")])];
+
+        let query = TestClickhouse.generate_insert_query(
+            &clickhouse_config(),
+            &clickhouse_columns,
+            &postgres_columns,
+            &[],
+            "orders",
+            &rows,
+        );
+
+        assert_eq!(
+            query,
+            "INSERT INTO db.orders (`id`, `order`, `from`) VALUES(1,2,'gate')"
+        );
+    }
+
+    #[test]
+    fn delete_quotes_reserved_word_key_columns() {
+        let postgres_columns = vec![
+            postgres_column(1, "order", "int8", false, true),
+            postgres_column(2, "UserId", "int4", false, true),
+            postgres_column(3, "note", "text", true, false),
+        ];
+        let clickhouse_columns = clickhouse_columns_for(&postgres_columns);
+        let rows = vec![
+            text_row(&[Some("1"), Some("2"), None]),
+            text_row(&[Some("3"), Some("4"), None]),
+        ];
+
+        let query = TestClickhouse.generate_delete_query(
+            &clickhouse_config(),
+            &clickhouse_columns,
+            &postgres_columns,
+            "orders",
+            &rows,
+        );
+
+        assert_eq!(
+            query,
+            "ALTER TABLE db.orders DELETE WHERE (`order` = 1 AND `UserId` = 2) OR (`order` = 3 AND `UserId` = 4)"
+        );
+        assert_eq!(quote_identifier("odd`name"), r"`odd\`name`");
+    }
+
+    #[test]
+    fn delete_encodes_binary_and_fixed_string_keys_like_inserts() {
+        // CREATE TABLE blobs (hash bytea, code char(2), PRIMARY KEY (hash, code))
+        let postgres_columns = vec![
+            postgres_column(1, "hash", "bytea", false, true),
+            postgres_column(2, "code", "bpchar", false, true),
+        ];
+        let mut clickhouse_columns = clickhouse_columns_for(&postgres_columns);
+        clickhouse_columns[1].data_type = "FixedString(2)".to_string();
+        let rows = vec![text_row(&[Some(r"\x00ff"), Some("ab")])];
+
+        let insert_query = TestClickhouse.generate_insert_query(
+            &clickhouse_config(),
+            &clickhouse_columns,
+            &postgres_columns,
+            &[],
+            "blobs",
+            &rows,
+        );
+        let delete_query = TestClickhouse.generate_delete_query(
+            &clickhouse_config(),
+            &clickhouse_columns,
+            &postgres_columns,
+            "blobs",
+            &rows,
+        );
+
+        assert_eq!(
+            insert_query,
+            "INSERT INTO db.blobs (`hash`, `code`) VALUES('00ff','ab')"
+        );
+        assert_eq!(
+            delete_query,
+            "ALTER TABLE db.blobs DELETE WHERE (`hash` = '00ff' AND `code` = 'ab')"
+        );
+    }
+
+    #[test]
+    fn best_effort_sends_raw_timestamps_with_the_setting() {
+        let postgres_columns = vec![
+            postgres_column(1, "id", "int8", false, true),
+            postgres_column(2, "created_at", "timestamptz", true, false),
+        ];
+        let clickhouse_columns = clickhouse_columns_for(&postgres_columns);
+        let rows = vec![
+            text_row(&[Some("1"), Some("2025-01-01T12:30:00Z")]),
+            text_row(&[Some("2"), Some("2025-01-01 12:30:00.123456+09")]),
+            text_row(&[Some("3"), Some("Wed, 01 Jan 2025 12:30:00 GMT")]),
+            text_row(&[Some("4"), None]),
+        ];
+
+        let mut config = clickhouse_config();
+        config.date_time_input_format = DateTimeInputFormat::BestEffort;
+
+        let query = TestClickhouse.generate_insert_query(
+            &config,
+            &clickhouse_columns,
+            &postgres_columns,
+            &[],
+            "events",
+            &rows,
+        );
+
+        assert_eq!(
+            query,
+            "INSERT INTO db.events (`id`, `created_at`) SETTINGS date_time_input_format = 'best_effort' VALUES(1,'2025-01-01T12:30:00Z'), (2,'2025-01-01 12:30:00.123456+09'), (3,'Wed, 01 Jan 2025 12:30:00 GMT'), (4,NULL)"
+        );
+    }
+
+    #[test]
+    fn best_effort_timestamps_are_parsed_in_updates() {
+        let postgres_columns = vec![
+            postgres_column(1, "id", "int8", false, true),
+            postgres_column(2, "created_at", "timestamptz", true, false),
+        ];
+        let mut clickhouse_columns = clickhouse_columns_for(&postgres_columns);
+        let row = text_row(&[Some("1"), Some("2025-01-01T12:30:00Z")]);
+
+        let mut config = clickhouse_config();
+        config.date_time_input_format = DateTimeInputFormat::BestEffort;
+
+        // The date_time_input_format setting only applies to INSERT ... VALUES
+        let query = TestClickhouse.generate_update_query(
+            &config,
+            &clickhouse_columns,
+            &postgres_columns,
+            &[],
+            "events",
+            &row,
+        );
+        assert_eq!(
+            query,
+            "UPDATE db.events SET `created_at` = parseDateTimeBestEffort('2025-01-01T12:30:00Z') WHERE (`id` = 1)"
+        );
+
+        clickhouse_columns[1].data_type = "Nullable(DateTime64(6, 'UTC'))".to_string();
+        let query = TestClickhouse.generate_update_query(
+            &config,
+            &clickhouse_columns,
+            &postgres_columns,
+            &[],
+            "events",
+            &row,
+        );
+        assert_eq!(
+            query,
+            "UPDATE db.events SET `created_at` = parseDateTime64BestEffort('2025-01-01T12:30:00Z', 6) WHERE (`id` = 1)"
+        );
+    }
+
+    #[test]
+    fn update_column_query_sets_only_the_backfilled_column_of_existing_keys() {
+        let clickhouse_columns = clickhouse_columns_for(&bean_policy_columns());
+        // SELECT id, bean_count FROM bean_policy
+        let source_columns = vec![
+            postgres_column(1, "id", "int8", false, true),
+            postgres_column(2, "bean_count", "int4", true, false),
+        ];
+        let rows = vec![
+            text_row(&[Some("1"), Some("10")]),
+            text_row(&[Some("2"), None]),
+        ];
+
+        let queries = TestClickhouse.generate_update_column_queries(
+            &clickhouse_config(),
+            &clickhouse_columns,
+            &source_columns,
+            "bean_policy",
+            "bean_count",
+            &rows,
+        );
+
+        assert_eq!(
+            queries,
+            [
+                "ALTER TABLE db.bean_policy UPDATE `bean_count` = multiIf((`id` = 1), CAST(10, 'Nullable(Int32)'), (`id` = 2), CAST(NULL, 'Nullable(Int32)'), `bean_count`) WHERE (`id` = 1) OR (`id` = 2)"
+            ]
+        );
+
+        // Unknown column
+        assert!(
+            TestClickhouse
+                .generate_update_column_queries(
+                    &clickhouse_config(),
+                    &clickhouse_columns,
+                    &source_columns,
+                    "bean_policy",
+                    "missing",
+                    &rows,
+                )
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn update_column_query_encodes_values_like_inserts() {
+        // CREATE TABLE files (id int8 PRIMARY KEY, content bytea)
+        let source_columns = vec![
+            postgres_column(1, "id", "int8", false, true),
+            postgres_column(2, "content", "bytea", true, false),
+        ];
+        let clickhouse_columns = clickhouse_columns_for(&source_columns);
+        let rows = vec![text_row(&[Some("1"), Some(r"\x00ff")])];
+
+        let queries = TestClickhouse.generate_update_column_queries(
+            &clickhouse_config(),
+            &clickhouse_columns,
+            &source_columns,
+            "files",
+            "content",
+            &rows,
+        );
+
+        assert_eq!(
+            queries,
+            [
+                "ALTER TABLE db.files UPDATE `content` = multiIf((`id` = 1), CAST('00ff', 'Nullable(String)'), `content`) WHERE (`id` = 1)"
+            ]
+        );
+    }
+
+    #[test]
+    fn update_column_queries_are_split_to_stay_under_the_max_query_size() {
+        // CREATE TABLE documents (id int8 PRIMARY KEY, body text), with bodies of 90 KB
+        let source_columns = vec![
+            postgres_column(1, "id", "int8", false, true),
+            postgres_column(2, "body", "text", true, false),
+        ];
+        let clickhouse_columns = clickhouse_columns_for(&source_columns);
+        let body = "x".repeat(90_000);
+        let rows = (1..=5)
+            .map(|id| text_row(&[Some(&id.to_string()), Some(&body)]))
+            .collect::<Vec<_>>();
+
+        let queries = TestClickhouse.generate_update_column_queries(
+            &clickhouse_config(),
+            &clickhouse_columns,
+            &source_columns,
+            "documents",
+            "body",
+            &rows,
+        );
+
+        // Two rows per mutation
+        assert_eq!(
+            queries.len(),
+            3,
+            "{:?}",
+            queries.iter().map(String::len).collect::<Vec<_>>()
+        );
+        assert!(
+            queries
+                .iter()
+                .all(|query| query.len() <= MAX_MUTATION_QUERY_BYTES)
+        );
+        assert!(
+            queries[2].ends_with("WHERE (`id` = 5)"),
+            "{}",
+            &queries[2][..100]
+        );
+        assert_eq!(
+            queries
+                .iter()
+                .map(|query| query.matches(" OR ").count())
+                .sum::<usize>(),
+            2
+        );
+    }
+
+    #[test]
+    fn nullable_array_columns_write_null_and_empty_arrays_as_empty() {
+        // ClickHouse does not allow Nullable(Array(T)), so NULL arrays become empty arrays
+        let postgres_columns = vec![
+            postgres_column(1, "scores", "_int4", true, false),
+            postgres_column(2, "tags", "_text", true, false),
+        ];
+        let clickhouse_columns = clickhouse_columns_for(&postgres_columns);
+
+        assert_eq!(clickhouse_columns[0].data_type, "Array(Int32)");
+        assert_eq!(clickhouse_columns[1].data_type, "Array(String)");
+
+        for value in [PgOutputValue::Null, PgOutputValue::Text("{}".to_string())] {
+            assert_eq!(
+                clickhouse_columns[0].to_clickhouse_value(value.clone()),
+                "[]"
+            );
+            assert_eq!(clickhouse_columns[1].to_clickhouse_value(value), "[]");
+        }
+
+        assert_eq!(
+            clickhouse_columns[0].to_clickhouse_value(PgOutputValue::Text("{1,2}".to_string())),
+            "[1,2]"
+        );
+    }
+
+    #[test]
+    fn non_nullable_columns_without_value_follow_the_missing_values_policy() {
+        let postgres_columns = vec![
+            postgres_column(1, "id", "int8", false, true),
+            postgres_column(2, "created_on", "date", false, false),
+        ];
+        let mut clickhouse_columns = clickhouse_columns_for(&postgres_columns);
+        // Dropped from the source table
+        clickhouse_columns.push(ClickhouseColumn {
+            column_index: 3,
+            column_name: "name".to_string(),
+            data_type: "String".to_string(),
+            is_in_primary_key: false,
+            comment: String::new(),
+        });
+        let rows = vec![
+            text_row(&[Some("1"), Some("2024-01-02")]),
+            text_row(&[Some("2"), None]),
+        ];
+
+        let mut config = clickhouse_config();
+        let query = TestClickhouse.generate_insert_query(
+            &config,
+            &clickhouse_columns,
+            &postgres_columns,
+            &[],
+            "events",
+            &rows,
+        );
+        assert!(
+            query.ends_with("VALUES(1,toDate('2024-01-02'),''), (2,toDate(0),'')"),
+            "{query}"
+        );
+
+        config.missing_values = MissingValues::SkipRow;
+        let query = TestClickhouse.generate_insert_query(
+            &config,
+            &clickhouse_columns[..2],
+            &postgres_columns,
+            &[],
+            "events",
+            &rows,
+        );
+        assert!(query.ends_with("VALUES(1,toDate('2024-01-02'))"), "{query}");
+
+        // Every row lacks `name`
+        let query = TestClickhouse.generate_insert_query(
+            &config,
+            &clickhouse_columns,
+            &postgres_columns,
+            &[],
+            "events",
+            &rows,
+        );
+        assert_eq!(query, "");
+    }
+
+    #[test]
+    fn control_characters_are_escaped_in_string_values_of_both_sources() {
+        let text = "line 1\nline 2\r\n\tit's a \\ and a \0 byte";
+        let expected = r"'line 1\nline 2\r\n\tit''s a \\ and a \0 byte'";
+
+        let postgres_value = PgOutputValue::Text(text.to_string());
+        let mongodb_value = crate::adapter::mongodb::MongoDBColumn {
+            column_name: "body".to_string(),
+            bson_value: mongodb::bson::Bson::String(text.to_string()),
+        };
+
+        assert_eq!(postgres_value.to_string(), expected);
+        assert_eq!(mongodb_value.to_string(), expected);
+        assert_eq!(super::quote_string(text), expected);
+    }
+
+    #[test]
+    fn uuid_columns_are_written_as_uuid_literals() {
+        // CREATE TABLE sessions (id uuid PRIMARY KEY, parent_id uuid, related_ids uuid[])
+        let postgres_columns = vec![
+            postgres_column(1, "id", "uuid", false, true),
+            postgres_column(2, "parent_id", "uuid", true, false),
+            postgres_column(3, "related_ids", "_uuid", true, false),
+        ];
+        let clickhouse_columns = clickhouse_columns_for(&postgres_columns);
+        assert_eq!(
+            clickhouse_columns
+                .iter()
+                .map(|column| column.data_type.as_str())
+                .collect::<Vec<_>>(),
+            vec!["UUID", "Nullable(UUID)", "Array(UUID)"]
+        );
+
+        let rows = vec![
+            text_row(&[
+                Some("A0EEBC99-9C0B-4EF8-BB6D-6BB9BD380A11"),
+                Some("{00000000-0000-0000-0000-000000000000}"),
+                Some("{a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a12}"),
+            ]),
+            text_row(&[Some("a0eebc999c0b4ef8bb6d6bb9bd380a13"), None, None]),
+        ];
+
+        let insert_query = TestClickhouse.generate_insert_query(
+            &clickhouse_config(),
+            &clickhouse_columns,
+            &postgres_columns,
+            &[],
+            "sessions",
+            &rows,
+        );
+
+        assert_eq!(
+            insert_query,
+            "INSERT INTO db.sessions (`id`, `parent_id`, `related_ids`) VALUES(toUUID('a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11'),toUUID('00000000-0000-0000-0000-000000000000'),['a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a12']), (toUUID('a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a13'),NULL,[])"
+        );
+    }
+
+    #[test]
+    fn bytea_values_are_encoded_in_the_configured_encoding() {
+        // CREATE TABLE files (id int8 PRIMARY KEY, content bytea, chunks bytea[])
+        let postgres_columns = vec![
+            postgres_column(1, "id", "int8", false, true),
+            postgres_column(2, "content", "bytea", true, false),
+            postgres_column(3, "chunks", "_bytea", true, false),
+        ];
+        let clickhouse_columns = clickhouse_columns_for(&postgres_columns);
+
+        let rows = vec![
+            // '\x00ff27'::bytea, ARRAY['\x00'::bytea, '\x5c']
+            text_row(&[Some("1"), Some(r"\x00ff27"), Some(r#"{"\\x00","\\x5c"}"#)]),
+            PostgresCopyRow {
+                columns: vec![
+                    PgOutputValue::Text("2".to_string()),
+                    PgOutputValue::Binary(vec![0xde, 0xad, 0x00]),
+                    PgOutputValue::Null,
+                ],
+            },
+            text_row(&[Some("3"), None, Some("{}")]),
+        ];
+
+        let mut config = clickhouse_config();
+        let query = TestClickhouse.generate_insert_query(
+            &config,
+            &clickhouse_columns,
+            &postgres_columns,
+            &[],
+            "files",
+            &rows,
+        );
+        assert_eq!(
+            query,
+            "INSERT INTO db.files (`id`, `content`, `chunks`) VALUES(1,'00ff27',['00', '5c']), (2,'dead00',[]), (3,NULL,[])"
+        );
+
+        config.binary_encoding = BinaryEncoding::Base64;
+        let query = TestClickhouse.generate_insert_query(
+            &config,
+            &clickhouse_columns,
+            &postgres_columns,
+            &[],
+            "files",
+            &rows,
+        );
+        assert_eq!(
+            query,
+            "INSERT INTO db.files (`id`, `content`, `chunks`) VALUES(1,'AP8n',['AA==', 'XA==']), (2,'3q0A',[]), (3,NULL,[])"
+        );
+    }
+
+    #[test]
+    fn jsonb_null_and_sql_null_are_written_differently() {
+        // CREATE TABLE events (id int8 PRIMARY KEY, payload jsonb)
+        let postgres_columns = vec![
+            postgres_column(1, "id", "int8", false, true),
+            postgres_column(2, "payload", "jsonb", true, false),
+        ];
+        let clickhouse_columns = clickhouse_columns_for(&postgres_columns);
+
+        // INSERT INTO events VALUES (1, 'null'::jsonb), (2, NULL), (3, '{"a": null}')
+        let rows = vec![
+            text_row(&[Some("1"), Some("null")]),
+            text_row(&[Some("2"), None]),
+            text_row(&[Some("3"), Some(r#"{"a": null}"#)]),
+        ];
+
+        let query = TestClickhouse.generate_insert_query(
+            &clickhouse_config(),
+            &clickhouse_columns,
+            &postgres_columns,
+            &[],
+            "events",
+            &rows,
+        );
+
+        assert_eq!(
+            query,
+            r#"INSERT INTO db.events (`id`, `payload`) VALUES(1,'null'), (2,NULL), (3,'{"a": null}')"#
+        );
+    }
+
+    #[test]
+    fn range_values_are_written_as_their_text() {
+        // CREATE TABLE bookings (id int8 PRIMARY KEY, during tstzrange, seats int4range)
+        let postgres_columns = vec![
+            postgres_column(1, "id", "int8", false, true),
+            postgres_column(2, "during", "tstzrange", true, false),
+            postgres_column(3, "seats", "int4range", false, false),
+        ];
+        let clickhouse_columns = clickhouse_columns_for(&postgres_columns);
+        assert_eq!(clickhouse_columns[1].data_type, "Nullable(String)");
+        assert_eq!(clickhouse_columns[2].data_type, "String");
+
+        // COPY text of a bounded, an unbounded and an empty range
+        let rows = vec![
+            text_row(&[
+                Some("1"),
+                Some(r#"["2025-01-01 00:00:00+00","2025-02-01 00:00:00+00")"#),
+                Some("[1,10)"),
+            ]),
+            text_row(&[
+                Some("2"),
+                Some(r#"["2025-01-01 00:00:00+00",)"#),
+                Some("[1,)"),
+            ]),
+            text_row(&[Some("3"), None, Some("empty")]),
+        ];
+
+        let query = TestClickhouse.generate_insert_query(
+            &clickhouse_config(),
+            &clickhouse_columns,
+            &postgres_columns,
+            &[],
+            "bookings",
+            &rows,
+        );
+
+        assert_eq!(
+            query,
+            r#"INSERT INTO db.bookings (`id`, `during`, `seats`) VALUES(1,'["2025-01-01 00:00:00+00","2025-02-01 00:00:00+00")','[1,10)'), (2,'["2025-01-01 00:00:00+00",)','[1,)'), (3,NULL,'empty')"#
+        );
+    }
+
+    #[test]
+    fn char_columns_map_to_fixed_string_of_the_same_length() {
+        // CREATE TABLE countries (iso char(8) PRIMARY KEY, region char(8))
+        let postgres_columns = vec![
+            PostgresColumn {
+                length: 8,
+                ..postgres_column(1, "iso", "bpchar", false, true)
+            },
+            PostgresColumn {
+                length: 8,
+                ..postgres_column(2, "region", "bpchar", true, false)
+            },
+        ];
+        let clickhouse_columns = clickhouse_columns_for(&postgres_columns);
+
+        assert_eq!(clickhouse_columns[0].data_type, "FixedString(8)");
+        assert_eq!(clickhouse_columns[1].data_type, "Nullable(FixedString(8))");
+    }
+
+    #[test]
+    fn overlong_fixed_string_values_are_truncated_unless_configured_to_error() {
+        let postgres_columns = vec![
+            postgres_column(1, "id", "int8", false, true),
+            PostgresColumn {
+                length: 4,
+                ..postgres_column(2, "code", "bpchar", true, false)
+            },
+        ];
+        let clickhouse_columns = clickhouse_columns_for(&postgres_columns);
+        assert_eq!(clickhouse_columns[1].data_type, "Nullable(FixedString(4))");
+
+        let rows = vec![
+            text_row(&[Some("1"), Some("AB")]),
+            text_row(&[Some("2"), Some("ABCDEF")]),
+            // 'é' is 2 bytes and would be cut in half at byte 4
+            text_row(&[Some("3"), Some("ABCé")]),
+            text_row(&[Some("4"), None]),
+        ];
+
+        let mut config = clickhouse_config();
+        let query = TestClickhouse.generate_insert_query(
+            &config,
+            &clickhouse_columns,
+            &postgres_columns,
+            &[],
+            "codes",
+            &rows,
+        );
+        assert_eq!(
+            query,
+            "INSERT INTO db.codes (`id`, `code`) VALUES(1,'AB'), (2,'ABCD'), (3,'ABC'), (4,NULL)"
+        );
+
+        config.fixed_string_overflow = FixedStringOverflow::Error;
+        let query = TestClickhouse.generate_insert_query(
+            &config,
+            &clickhouse_columns,
+            &postgres_columns,
+            &[],
+            "codes",
+            &rows,
+        );
+        assert!(query.contains("(2,'ABCDEF')"), "{query}");
+    }
+
+    #[test]
+    fn insert_is_split_into_queries_within_the_byte_limit() {
+        let postgres_columns = vec![
+            postgres_column(1, "id", "int8", false, true),
+            postgres_column(2, "body", "text", false, false),
+        ];
+        let clickhouse_columns = clickhouse_columns_for(&postgres_columns);
+        let body = "x".repeat(1000);
+        let rows: Vec<_> = (1..=10)
+            .map(|id| text_row(&[Some(id.to_string().as_str()), Some(body.as_str())]))
+            .collect();
+
+        let mut config = clickhouse_config();
+
+        // Without a limit, one query like generate_insert_query
+        let queries = TestClickhouse.generate_insert_queries(
+            &config,
+            &clickhouse_columns,
+            &postgres_columns,
+            &[],
+            "posts",
+            &rows,
+        );
+        assert_eq!(
+            queries,
+            vec![TestClickhouse.generate_insert_query(
+                &config,
+                &clickhouse_columns,
+                &postgres_columns,
+                &[],
+                "posts",
+                &rows,
+            )]
+        );
+
+        config.max_insert_query_bytes = Some(3500);
+        let queries = TestClickhouse.generate_insert_queries(
+            &config,
+            &clickhouse_columns,
+            &postgres_columns,
+            &[],
+            "posts",
+            &rows,
+        );
+
+        assert!(queries.len() > 1, "{}", queries.len());
+        for query in &queries {
+            assert!(query.len() <= 3500, "{}", query.len());
+            assert!(query.starts_with("INSERT INTO db.posts (`id`, `body`) VALUES("));
+        }
+        let inserted_rows: usize = queries
+            .iter()
+            .map(|query| query.matches(&body).count())
+            .sum();
+        assert_eq!(inserted_rows, 10);
+
+        // A row larger than the limit is still inserted, alone
+        config.max_insert_query_bytes = Some(100);
+        let queries = TestClickhouse.generate_insert_queries(
+            &config,
+            &clickhouse_columns,
+            &postgres_columns,
+            &[],
+            "posts",
+            &rows,
+        );
+        assert_eq!(queries.len(), 10);
+    }
 }
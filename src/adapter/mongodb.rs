@@ -1,48 +1,324 @@
-use std::{path::PathBuf, time::Duration};
+use std::{
+    io::{Read, Write},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
 
 use base64::Engine;
-use futures::StreamExt;
+use futures::{StreamExt, TryStreamExt};
 use mongodb::{
     Client,
-    bson::{Bson, Document, doc, spec::ElementType},
+    bson::{
+        Bson, Document, Timestamp, doc,
+        spec::{BinarySubtype, ElementType},
+    },
     change_stream::event::{OperationType, ResumeToken},
     options::{CursorType, FindOptions, ServerApi, ServerApiVersion},
 };
-use tokio::sync::oneshot;
 
 use crate::{
     adapter::{
-        IntoClickhouseColumn, IntoClickhouseRow, IntoClickhouseValue, clickhouse::ClickhouseType,
+        IntoClickhouseColumn, IntoClickhouseRow, IntoClickhouseValue,
+        clickhouse::{
+            ClickhouseConnection, ClickhouseType, DateTime64, non_finite_float_literal,
+            plain_decimal_text, uuid_literal,
+        },
+        escape_string,
+    },
+    config::{
+        BinaryEncoding, ClickHouseConfig, IdentifierCase, MongoDBConfig, MongoDBStartAt,
+        MongoDBWatchScope, ResumeTokenStorageType,
     },
-    config::MongoDBConfig,
     errors,
 };
 
 #[derive(Debug, Clone)]
 pub struct MongoDBConnection {
     client: Client,
-    resume_token_storage: ResumeTokenStorage,
+    resume_token_cursor: ResumeTokenCursor,
     copy_batch_size: u32,
+    start_at: MongoDBStartAt,
+    watch_scope: MongoDBWatchScope,
+    exclude_databases: Vec<String>,
+    read_timeout: Duration,
 }
 
 #[derive(Debug, Clone)]
 pub enum ResumeTokenStorage {
-    File(PathBuf),
+    File { path: PathBuf, compress: bool },
+    ClickHouse(Box<ClickhouseResumeTokenTable>),
 }
 
-impl MongoDBConnection {
-    pub async fn new(config: &MongoDBConfig) -> errors::Result<Self> {
-        println!("{:?}", config);
+impl ResumeTokenStorage {
+    pub fn from_config(
+        config: &MongoDBConfig,
+        clickhouse_config: &ClickHouseConfig,
+        clickhouse_connection: &ClickhouseConnection,
+    ) -> Self {
+        match config.resume_token_storage {
+            ResumeTokenStorageType::File => ResumeTokenStorage::File {
+                path: PathBuf::from(config.resume_token_path.clone()),
+                compress: config.compress_resume_token,
+            },
+            ResumeTokenStorageType::ClickHouse => {
+                ResumeTokenStorage::ClickHouse(Box::new(ClickhouseResumeTokenTable {
+                    connection: clickhouse_connection.clone(),
+                    table: format!(
+                        "{}.{}",
+                        clickhouse_config.connection.database, config.resume_token_table
+                    ),
+                    key: config
+                        .resume_token_key
+                        .clone()
+                        .unwrap_or_else(|| config.connection.database.clone()),
+                }))
+            }
+        }
+    }
+
+    async fn write(&self, token: &ResumeToken) -> errors::Result<()> {
+        let json = serde_json::to_string(token).map_err(|e| {
+            errors::Errors::ResumeTokenStorageFailed(format!(
+                "Failed to serialize resume token: {e}"
+            ))
+        })?;
+
+        match self {
+            ResumeTokenStorage::File { path, compress } => {
+                let contents = if *compress {
+                    gzip(json.as_bytes())?
+                } else {
+                    json.into_bytes()
+                };
+
+                std::fs::write(path, contents).map_err(|e| {
+                    errors::Errors::ResumeTokenStorageFailed(format!(
+                        "Failed to write resume token to file: {e}"
+                    ))
+                })?;
+
+                Ok(())
+            }
+            ResumeTokenStorage::ClickHouse(table) => {
+                table
+                    .connection
+                    .execute_query(&table.insert_query(&json))
+                    .await
+            }
+        }
+    }
+
+    async fn read(&self) -> errors::Result<Option<ResumeToken>> {
+        let json = match self {
+            ResumeTokenStorage::File { path, .. } => {
+                // if not exists, return None
+                if !path.exists() {
+                    return Ok(None);
+                }
+
+                let contents = std::fs::read(path).map_err(|e| {
+                    errors::Errors::ResumeTokenStorageFailed(format!(
+                        "Failed to read resume token file: {e}"
+                    ))
+                })?;
+
+                // Detected by content, so switching `compress_resume_token` keeps the stored token
+                if contents.starts_with(&GZIP_MAGIC) {
+                    gunzip(&contents)?
+                } else {
+                    contents
+                }
+            }
+            ResumeTokenStorage::ClickHouse(table) => {
+                let rows = table
+                    .connection
+                    .fetch_strings(&table.select_query())
+                    .await?;
+
+                // No row yet, like a missing file
+                match rows.into_iter().next() {
+                    Some(json) => json.into_bytes(),
+                    None => return Ok(None),
+                }
+            }
+        };
+
+        let token: ResumeToken = serde_json::from_slice(&json)
+            .map_err(|e| errors::Errors::ResumeTokenParseError(e.to_string()))?;
+
+        Ok(Some(token))
+    }
+}
+
+/// Table in the ClickHouse target with the resume token of each pipe. Every write inserts a row,
+/// and the one with the highest version is read. ReplacingMergeTree drops the older ones on merge.
+#[derive(Clone)]
+pub struct ClickhouseResumeTokenTable {
+    connection: ClickhouseConnection,
+    /// `database.table`
+    table: String,
+    key: String,
+}
+
+impl std::fmt::Debug for ClickhouseResumeTokenTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClickhouseResumeTokenTable")
+            .field("table", &self.table)
+            .field("key", &self.key)
+            .finish()
+    }
+}
+
+impl ClickhouseResumeTokenTable {
+    pub fn create_table_query(&self) -> String {
+        format!(
+            "CREATE TABLE IF NOT EXISTS {} (`key` String, `resume_token` String, `version` UInt64) ENGINE = ReplacingMergeTree(`version`) ORDER BY `key`",
+            self.table
+        )
+    }
+
+    fn select_query(&self) -> String {
+        format!(
+            "SELECT `resume_token` FROM {} WHERE `key` = '{}' ORDER BY `version` DESC LIMIT 1",
+            self.table,
+            escape_string(&self.key)
+        )
+    }
+
+    /// The version is the write time in nanoseconds, so a later write wins
+    fn insert_query(&self, json: &str) -> String {
+        let version = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+
+        format!(
+            "INSERT INTO {} (`key`, `resume_token`, `version`) VALUES('{}','{}',{version})",
+            self.table,
+            escape_string(&self.key),
+            escape_string(json)
+        )
+    }
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// `data` as a gzip file.
+fn gzip(data: &[u8]) -> errors::Result<Vec<u8>> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(data)?;
+
+    Ok(encoder.finish()?)
+}
+
+/// Data of the gzip file `input`, checked against its CRC.
+fn gunzip(input: &[u8]) -> errors::Result<Vec<u8>> {
+    let mut data = vec![];
+    flate2::read::GzDecoder::new(input)
+        .read_to_end(&mut data)
+        .map_err(|e| {
+            errors::Errors::ResumeTokenParseError(format!(
+                "Failed to decompress resume token file: {e}"
+            ))
+        })?;
+
+    Ok(data)
+}
+
+/// Latest resume token of the change stream. It is always advanced in memory, but only written to
+/// the storage when the flush interval or batch count is reached, or on `flush`.
+#[derive(Debug, Clone)]
+struct ResumeTokenCursor {
+    storage: ResumeTokenStorage,
+    flush_interval: Option<Duration>,
+    flush_every_batches: Option<u32>,
+    latest: Option<ResumeToken>,
+    unflushed_batches: u32,
+    last_flushed_at: Instant,
+}
+
+impl ResumeTokenCursor {
+    fn new(
+        storage: ResumeTokenStorage,
+        flush_interval_millis: Option<u64>,
+        flush_every_batches: Option<u32>,
+    ) -> Self {
+        Self {
+            storage,
+            flush_interval: flush_interval_millis.map(Duration::from_millis),
+            flush_every_batches,
+            latest: None,
+            unflushed_batches: 0,
+            last_flushed_at: Instant::now(),
+        }
+    }
+
+    async fn load(&self) -> errors::Result<Option<ResumeToken>> {
+        match &self.latest {
+            Some(token) => Ok(Some(token.clone())),
+            None => self.storage.read().await,
+        }
+    }
+
+    async fn advance(&mut self, token: &ResumeToken) -> errors::Result<()> {
+        self.latest = Some(token.clone());
+        self.unflushed_batches += 1;
+
+        if self.is_flush_due() {
+            self.flush().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Without any throttle option, every advance is written.
+    fn is_flush_due(&self) -> bool {
+        if self.flush_interval.is_none() && self.flush_every_batches.is_none() {
+            return true;
+        }
+
+        let interval_elapsed = self
+            .flush_interval
+            .is_some_and(|interval| self.last_flushed_at.elapsed() >= interval);
+        let batches_reached = self
+            .flush_every_batches
+            .is_some_and(|batches| self.unflushed_batches >= batches);
+
+        interval_elapsed || batches_reached
+    }
+
+    async fn flush(&mut self) -> errors::Result<()> {
+        if self.unflushed_batches == 0 {
+            return Ok(());
+        }
+
+        if let Some(token) = &self.latest {
+            self.storage.write(token).await?;
+        }
+
+        self.unflushed_batches = 0;
+        self.last_flushed_at = Instant::now();
 
+        Ok(())
+    }
+}
+
+impl MongoDBConnection {
+    pub async fn new(
+        config: &MongoDBConfig,
+        resume_token_storage: ResumeTokenStorage,
+    ) -> errors::Result<Self> {
         let connection_config = &config.connection;
 
-        let connection_string = format!(
-            "mongodb+srv://{}:{}@{}/{}",
-            connection_config.username,
-            connection_config.password,
-            connection_config.host,
-            connection_config.database
-        );
+        let connection_string = match &connection_config.uri {
+            Some(uri) => uri.clone(),
+            None => format!(
+                "mongodb+srv://{}:{}@{}/{}",
+                connection_config.username,
+                connection_config.password,
+                connection_config.host,
+                connection_config.database
+            ),
+        };
 
         let mut client_options = mongodb::options::ClientOptions::parse(&connection_string)
             .await
@@ -55,18 +331,29 @@ impl MongoDBConnection {
         let server_api = ServerApi::builder().version(ServerApiVersion::V1).build();
         client_options.server_api = Some(server_api);
         client_options.app_name = Some(connection_config.app_name.clone());
+        client_options.connect_timeout = Some(Duration::from_millis(
+            connection_config.connect_timeout_millis,
+        ));
+        client_options.server_selection_timeout = Some(Duration::from_millis(
+            connection_config.server_selection_timeout_millis,
+        ));
 
         let client = Client::with_options(client_options).map_err(|e| {
             errors::Errors::DatabaseConnectionError(format!("Failed to create MongoDB client: {e}"))
         })?;
 
-        let resume_token_storage =
-            ResumeTokenStorage::File(PathBuf::from(config.resume_token_path.clone()));
-
         Ok(Self {
             client,
-            resume_token_storage,
+            resume_token_cursor: ResumeTokenCursor::new(
+                resume_token_storage,
+                config.resume_token_flush_interval_millis,
+                config.resume_token_flush_every_batches,
+            ),
             copy_batch_size: config.copy_batch_size,
+            start_at: config.start_at,
+            watch_scope: config.watch_scope.clone(),
+            exclude_databases: config.exclude_databases.clone(),
+            read_timeout: Duration::from_millis(connection_config.read_timeout_millis),
         })
     }
 
@@ -107,6 +394,37 @@ impl MongoDBConnection {
     // The `batch_size` parameter specifies how many documents to fetch at once.
     // Returns a vector of documents.
     // If the collection does not exist, it returns an empty vector.
+    /// Up to `limit` documents of the collection, e.g. to infer its columns.
+    pub async fn sample_documents(
+        &self,
+        database_name: &str,
+        collection_name: &str,
+        limit: i64,
+    ) -> errors::Result<Vec<MongoDBCopyRow>> {
+        let collection = self
+            .client
+            .database(database_name)
+            .collection::<Document>(collection_name);
+
+        let documents: Vec<Document> = collection
+            .find(doc! {})
+            .limit(limit)
+            .await
+            .map_err(|e| {
+                errors::Errors::DatabaseQueryError(format!("Failed to create cursor: {e}"))
+            })?
+            .try_collect()
+            .await
+            .map_err(|e| {
+                errors::Errors::DatabaseQueryError(format!("Failed to fetch documents: {e}"))
+            })?;
+
+        Ok(documents
+            .iter()
+            .map(MongoDBCopyRow::from_document)
+            .collect())
+    }
+
     pub async fn copy_collection(
         &self,
         database_name: &str,
@@ -138,15 +456,7 @@ impl MongoDBConnection {
                         continue;
                     }
                     Ok(doc) => {
-                        let copy_row = MongoDBCopyRow {
-                            columns: doc
-                                .iter()
-                                .map(|(k, v)| MongoDBColumn {
-                                    column_name: k.clone(),
-                                    bson_value: v.clone(),
-                                })
-                                .collect(),
-                        };
+                        let copy_row = MongoDBCopyRow::from_document(&doc);
 
                         if let Err(e) = sender.send(copy_row).await {
                             log::error!("Failed to send document: {}", e);
@@ -166,38 +476,69 @@ impl MongoDBConnection {
     // The `limit` parameter specifies the maximum number of changes to return.
     // The `timeout_ms` parameter specifies the maximum time to wait for changes.
     // If no changes are available within the timeout, an empty vector is returned.
+    /// `collections` are the (database, collection) pairs whose changes are returned.
+    /// `database_name` is the database watched with the `database` watch scope.
     pub async fn peek_changes(
-        &self,
+        &mut self,
         database_name: &str,
-        collection_names: &[&str],
+        collections: &[(&str, &str)],
         limit: u64,
         timeout_ms: u64,
     ) -> errors::Result<PeekMongoChangesResult> {
         let database = self.client.database(database_name);
 
-        let mut watch = database.watch();
+        let mut watch = match &self.watch_scope {
+            MongoDBWatchScope::Database => database.watch(),
+            scope => self
+                .client
+                .watch()
+                .pipeline(watch_pipeline(scope, &self.exclude_databases)),
+        };
 
         watch = watch.full_document(mongodb::options::FullDocumentType::UpdateLookup);
 
-        let mut resume_token = if let Some(resume_token) = self.load_resume_token()? {
+        let mut resume_token = if let Some(resume_token) = self.load_resume_token().await? {
             log::debug!("Resume token found, resuming from it");
             Some(resume_token)
         } else {
-            log::info!("No resume token found, starting from the beginning");
             None
         };
 
         if let Some(token) = &resume_token {
             watch = watch.start_after(token.clone());
+        } else {
+            match self.start_operation_time().await? {
+                Some(operation_time) => {
+                    log::info!(
+                        "No resume token found, starting at cluster time {}",
+                        operation_time.time
+                    );
+                    watch = watch.start_at_operation_time(operation_time);
+                }
+                None => log::info!("No resume token found, starting from now"),
+            }
         }
 
-        let mut watch = watch.await.map_err(|e| {
-            errors::Errors::PeekChangesFailed(format!("Failed to start watching changes: {e}"))
-        })?;
+        let mut watch = self
+            .with_read_timeout("starting to watch changes", async {
+                watch.await.map_err(|e| {
+                    errors::Errors::PeekChangesFailed(format!(
+                        "Failed to start watching changes: {e}"
+                    ))
+                })
+            })
+            .await?;
 
         // If no resume token is available, we will try to get it from the watch.
+        // It is stored right away to pin the start point. Otherwise every peek until the first
+        // write would start anew, and changes made between peeks would be missed.
         if resume_token.is_none() {
             resume_token = watch.resume_token();
+
+            if let Some(token) = &resume_token {
+                self.store_resume_token(token).await?;
+                self.flush_resume_token().await?;
+            }
         }
 
         let mut resume_token = resume_token.ok_or_else(|| {
@@ -206,49 +547,44 @@ impl MongoDBConnection {
 
         let mut changes = Vec::with_capacity(limit as usize);
 
-        let (timeout_sender, mut timeout_receiver) = oneshot::channel();
-
-        tokio::spawn(async move {
-            tokio::time::sleep(Duration::from_millis(timeout_ms)).await;
-            let _ = timeout_sender.send(());
-        });
+        collect_batch(
+            &mut watch,
+            limit as usize,
+            Duration::from_millis(timeout_ms),
+            |watch, event| {
+                let event = event.map_err(|e| {
+                    errors::Errors::PeekChangesFailed(format!("Failed to get next event: {e}"))
+                })?;
 
-        loop {
-            tokio::select! {
-                _ = (&mut timeout_receiver) => {
-                    log::debug!("Timeout reached");
-                    break;
+                log::debug!("Change event: {:?}", event);
+                let operation_type = event.operation_type;
+                let document_key = event.document_key;
+                let full_document = event.full_document;
+
+                let (event_database_name, collection_name) = event
+                    .ns
+                    .map(|ns| (ns.db, ns.coll.unwrap_or_default()))
+                    .unwrap_or_default();
+                let is_watched = collections.iter().any(|&(database_name, name)| {
+                    database_name == event_database_name && name == collection_name
+                });
+                if is_watched {
+                    changes.push(PeekMongoChange {
+                        operation_type,
+                        document_key,
+                        full_document,
+                        collection_name,
+                    });
                 }
-                Some(event) = watch.next() => {
-                    let event = event.map_err(|e| {
-                        errors::Errors::PeekChangesFailed(format!("Failed to get next event: {e}"))
-                    })?;
-
-                    log::debug!("Change event: {:?}", event);
-                    let operation_type = event.operation_type;
-                    let document_key = event.document_key;
-                    let full_document = event.full_document;
-
-                    let collection_name = event.ns.and_then(|ns| ns.coll).unwrap_or_default();
-                    if collection_names.iter().any(|&name| name == collection_name) {
-                        changes.push(PeekMongoChange {
-                            operation_type,
-                            document_key,
-                            full_document,
-                            collection_name,
-                        });
-                    }
 
-                    resume_token = watch.resume_token().ok_or_else(|| {
-                        errors::Errors::PeekChangesFailed("Failed to get resume token".to_string())
-                    })?;
+                resume_token = watch.resume_token().ok_or_else(|| {
+                    errors::Errors::PeekChangesFailed("Failed to get resume token".to_string())
+                })?;
 
-                    if changes.len()  >= limit as usize {
-                        break;
-                    }
-                }
-            }
-        }
+                Ok(is_watched)
+            },
+        )
+        .await?;
 
         Ok(PeekMongoChangesResult {
             changes,
@@ -256,50 +592,128 @@ impl MongoDBConnection {
         })
     }
 
-    pub fn store_resume_token(&self, token: &ResumeToken) -> errors::Result<()> {
-        match &self.resume_token_storage {
-            ResumeTokenStorage::File(path) => {
-                let json = serde_json::to_string(token).map_err(|e| {
-                    errors::Errors::DatabaseConnectionError(format!(
-                        "Failed to serialize resume token: {e}"
-                    ))
-                })?;
+    /// Start point of the change stream without a resume token. None means now.
+    async fn start_operation_time(&self) -> errors::Result<Option<Timestamp>> {
+        if self.start_at != MongoDBStartAt::Beginning {
+            return Ok(start_operation_time(self.start_at, None));
+        }
 
-                std::fs::write(path, json).map_err(|e| {
-                    errors::Errors::DatabaseConnectionError(format!(
-                        "Failed to write resume token to file: {e}"
-                    ))
-                })?;
+        let oldest_entry = self
+            .with_read_timeout("reading the oldest oplog entry", async {
+                self.client
+                    .database("local")
+                    .collection::<Document>("oplog.rs")
+                    .find_one(doc! {})
+                    .sort(doc! { "$natural": 1 })
+                    .await
+                    .map_err(|e| {
+                        errors::Errors::PeekChangesFailed(format!(
+                            "Failed to read the oldest oplog entry: {e}"
+                        ))
+                    })
+            })
+            .await?;
 
-                Ok(())
-            }
-        }
+        let oldest_time = oldest_entry.and_then(|entry| entry.get_timestamp("ts").ok());
+
+        Ok(start_operation_time(self.start_at, oldest_time))
     }
 
-    fn load_resume_token(&self) -> errors::Result<Option<ResumeToken>> {
-        match &self.resume_token_storage {
-            ResumeTokenStorage::File(path) => {
-                // if not exists, return None
-                if !path.exists() {
-                    return Ok(None);
-                }
+    /// Fails instead of hanging when the server does not answer within the read timeout.
+    async fn with_read_timeout<T>(
+        &self,
+        operation: &str,
+        future: impl Future<Output = errors::Result<T>>,
+    ) -> errors::Result<T> {
+        tokio::time::timeout(self.read_timeout, future)
+            .await
+            .map_err(|_| {
+                errors::Errors::PeekChangesFailed(format!(
+                    "Timed out after {}ms while {operation}",
+                    self.read_timeout.as_millis()
+                ))
+            })?
+    }
 
-                let json = std::fs::read_to_string(path).map_err(|e| {
-                    errors::Errors::DatabaseConnectionError(format!(
-                        "Failed to read resume token file: {e}"
-                    ))
-                })?;
+    /// Advances the resume token. It is written to the storage according to the flush options.
+    pub async fn store_resume_token(&mut self, token: &ResumeToken) -> errors::Result<()> {
+        self.resume_token_cursor.advance(token).await
+    }
 
-                let token: ResumeToken = serde_json::from_str(&json).map_err(|e| {
-                    errors::Errors::DatabaseConnectionError(format!(
-                        "Failed to parse resume token: {e}"
-                    ))
-                })?;
+    /// Writes the latest resume token if it has not been written yet. Called on shutdown.
+    pub async fn flush_resume_token(&mut self) -> errors::Result<()> {
+        self.resume_token_cursor.flush().await
+    }
+
+    async fn load_resume_token(&self) -> errors::Result<Option<ResumeToken>> {
+        self.resume_token_cursor.load().await
+    }
+}
 
-                Ok(Some(token))
+/// Reads `stream` until `max_changes` items were accepted or `max_wait` has passed, whichever comes first.
+/// A full batch is returned right away; an idle stream is waited on for up to `max_wait`.
+/// `accept` handles each item and returns whether it counts towards the batch.
+async fn collect_batch<S>(
+    stream: &mut S,
+    max_changes: usize,
+    max_wait: Duration,
+    mut accept: impl FnMut(&S, S::Item) -> errors::Result<bool>,
+) -> errors::Result<()>
+where
+    S: futures::Stream + Unpin,
+{
+    let mut collected = 0;
+
+    let timeout = tokio::time::sleep(max_wait);
+    tokio::pin!(timeout);
+
+    while collected < max_changes {
+        tokio::select! {
+            _ = &mut timeout => {
+                log::debug!("Timeout reached");
+                break;
+            }
+            Some(item) = stream.next() => {
+                if accept(stream, item)? {
+                    collected += 1;
+                }
             }
         }
     }
+
+    Ok(())
+}
+
+/// Server-side filter of a cluster-wide change stream, so that events of other databases are not sent.
+fn watch_pipeline(scope: &MongoDBWatchScope, exclude_databases: &[String]) -> Vec<Document> {
+    let database_filter = match scope {
+        MongoDBWatchScope::Database => return vec![],
+        MongoDBWatchScope::Databases(databases) => {
+            let databases: Vec<&String> = databases
+                .iter()
+                .filter(|database| !exclude_databases.contains(database))
+                .collect();
+            doc! { "$in": databases }
+        }
+        MongoDBWatchScope::Cluster => doc! { "$nin": exclude_databases },
+    };
+
+    vec![doc! { "$match": { "ns.db": database_filter } }]
+}
+
+/// Operation time to start a change stream at. `oldest_oplog_time` is only used for `Beginning`.
+fn start_operation_time(
+    start_at: MongoDBStartAt,
+    oldest_oplog_time: Option<Timestamp>,
+) -> Option<Timestamp> {
+    match start_at {
+        MongoDBStartAt::Now => None,
+        MongoDBStartAt::Beginning => oldest_oplog_time,
+        MongoDBStartAt::ClusterTime(seconds) => Some(Timestamp {
+            time: seconds,
+            increment: 0,
+        }),
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -313,28 +727,14 @@ pub struct PeekMongoChange {
 impl PeekMongoChange {
     pub fn to_copy_row(&self) -> Option<MongoDBCopyRow> {
         match self.operation_type {
-            OperationType::Delete => self.document_key.as_ref().map(|doc| MongoDBCopyRow {
-                columns: doc
-                    .iter()
-                    .map(|(k, v)| MongoDBColumn {
-                        column_name: k.clone(),
-                        bson_value: v.clone(),
-                    })
-                    .collect(),
-            }),
-            OperationType::Insert | OperationType::Update => {
-                self.full_document
-                    .as_ref()
-                    .map(|doc: &Document| MongoDBCopyRow {
-                        columns: doc
-                            .iter()
-                            .map(|(k, v)| MongoDBColumn {
-                                column_name: k.clone(),
-                                bson_value: v.clone(),
-                            })
-                            .collect(),
-                    })
-            }
+            OperationType::Delete => self
+                .document_key
+                .as_ref()
+                .map(MongoDBCopyRow::from_document),
+            OperationType::Insert | OperationType::Update => self
+                .full_document
+                .as_ref()
+                .map(MongoDBCopyRow::from_document),
             _ => None,
         }
     }
@@ -354,22 +754,44 @@ pub struct MongoDBColumn {
 
 impl IntoClickhouseValue for MongoDBColumn {
     fn to_integer(self) -> String {
+        let is_id = self.is_id();
+
         match self.bson_value {
             Bson::Int32(v) => v.to_string(),
             Bson::Int64(v) => v.to_string(),
             Bson::Decimal128(v) => v.to_string(),
+            // An integer `_id` column may also receive numeric string keys
+            Bson::String(v) if is_id && v.parse::<i64>().is_ok() => v,
             _ => "0".to_string(),
         }
     }
 
     fn to_real(self) -> String {
         match self.bson_value {
-            Bson::Double(v) => v.to_string(),
+            Bson::Double(v) => non_finite_float_literal(v)
+                .map(ToString::to_string)
+                .unwrap_or_else(|| v.to_string()),
             Bson::Decimal128(v) => v.to_string(),
             _ => "0.0".to_string(),
         }
     }
 
+    fn to_decimal(self, scale: u32) -> String {
+        // Decimal128 may format as scientific notation (e.g. `1.05E+3`). f64 Display never does.
+        let text = match self.bson_value {
+            Bson::Decimal128(v) => v.to_string(),
+            Bson::Double(v) => v.to_string(),
+            Bson::Int32(v) => v.to_string(),
+            Bson::Int64(v) => v.to_string(),
+            _ => "0".to_string(),
+        };
+        let plain = plain_decimal_text(&text, scale)
+            .or_else(|| plain_decimal_text("0", scale))
+            .unwrap_or_default();
+
+        format!("'{plain}'")
+    }
+
     fn to_bool(self) -> String {
         self.bson_value
             .as_bool()
@@ -377,8 +799,16 @@ impl IntoClickhouseValue for MongoDBColumn {
     }
 
     fn to_string(self) -> String {
+        let is_id = self.is_id();
+
         match self.bson_value {
             Bson::ObjectId(oid) => format!("'{}'", oid.to_hex()),
+            // `_id` is created as String. Numeric keys are written as their text, so that inserts
+            // and deletes of the same document use the same value
+            Bson::Int32(v) if is_id => format!("'{v}'"),
+            Bson::Int64(v) if is_id => format!("'{v}'"),
+            Bson::Double(v) if is_id => format!("'{v}'"),
+            Bson::Decimal128(v) if is_id => format!("'{v}'"),
             Bson::DateTime(dt) => format!(
                 "'{}'",
                 chrono::DateTime::<chrono::Utc>::from_timestamp_millis(dt.timestamp_millis())
@@ -397,34 +827,40 @@ impl IntoClickhouseValue for MongoDBColumn {
                     base64::engine::general_purpose::STANDARD.encode(bin.bytes)
                 )
             }
+            Bson::Document(document) => format!(
+                "'{}'",
+                escape_string(&Bson::Document(document).into_relaxed_extjson().to_string())
+            ),
             _ => self
                 .bson_value
                 .as_str()
-                .map(|s| format!("'{}'", Self::escape_string(s)))
+                .map(|s| format!("'{}'", escape_string(s)))
                 .unwrap_or_else(|| "' '".to_string()),
         }
     }
 
     fn to_date(self) -> String {
-        format!(
-            "toDate({})",
-            self.bson_value.as_datetime().map_or("0".to_string(), |dt| {
-                let utc = dt.timestamp_millis() / 1000;
+        let utc = match self.bson_value {
+            Bson::DateTime(dt) => dt.timestamp_millis() / 1000,
+            Bson::Timestamp(ts) => ts.time as i64,
+            _ => 0,
+        };
 
-                utc.to_string()
-            })
-        )
+        format!("toDate({utc})")
     }
 
+    /// Works for both DateTime and DateTime64 columns, since the field type may differ between documents.
     fn to_datetime(self) -> String {
-        format!(
-            "toDateTime({})",
-            self.bson_value.as_datetime().map_or("0".to_string(), |dt| {
-                let utc = dt.timestamp_millis() / 1000;
-
-                utc.to_string()
-            })
-        )
+        match self.bson_value {
+            // Keeps the milliseconds
+            Bson::DateTime(dt) => format!(
+                "fromUnixTimestamp64Milli(toInt64({}))",
+                dt.timestamp_millis()
+            ),
+            // The increment only orders operations within a second, so it is dropped
+            Bson::Timestamp(ts) => format!("toDateTime({})", ts.time),
+            _ => "toDateTime(0)".to_string(),
+        }
     }
 
     fn to_time(self) -> String {
@@ -440,31 +876,58 @@ impl IntoClickhouseValue for MongoDBColumn {
         )
     }
 
+    fn to_bytes_literal(self, encoding: BinaryEncoding) -> String {
+        match self.bson_value {
+            Bson::Binary(bin) => format!("'{}'", encoding.encode(&bin.bytes)),
+            _ => self.to_string(),
+        }
+    }
+
+    fn to_uuid(self) -> String {
+        match self.bson_value {
+            Bson::Binary(binary) if binary.subtype == BinarySubtype::Uuid => {
+                let hex: String = binary.bytes.iter().map(|b| format!("{b:02x}")).collect();
+                uuid_literal(&hex)
+            }
+            Bson::String(v) => uuid_literal(&v),
+            _ => uuid_literal(""),
+        }
+    }
+
     fn to_array(self) -> String {
         if let Some(array) = self.bson_value.as_array() {
-            match array.first().map(|v| v.element_type()) {
-                Some(ElementType::Int32) | Some(ElementType::Int64) => {
+            match array_element_type(array) {
+                Some(ClickhouseType::Int64) => {
                     let array_values = array
                         .iter()
-                        .map(|v| v.as_i64().map_or("0".to_string(), |i| i.to_string()))
+                        .map(|v| match v {
+                            Bson::Int32(i) => i.to_string(),
+                            Bson::Int64(i) => i.to_string(),
+                            _ => "0".to_string(),
+                        })
                         .collect::<Vec<String>>();
 
                     return format!("[{}]", array_values.join(", "));
                 }
-                Some(ElementType::Double) => {
+                Some(ClickhouseType::Float64) => {
                     let array_values = array
                         .iter()
-                        .map(|v| v.as_f64().map_or("0.0".to_string(), |f| f.to_string()))
+                        .map(|v| match v {
+                            Bson::Int32(i) => i.to_string(),
+                            Bson::Int64(i) => i.to_string(),
+                            Bson::Double(f) => f.to_string(),
+                            _ => "0.0".to_string(),
+                        })
                         .collect::<Vec<String>>();
 
                     return format!("[{}]", array_values.join(", "));
                 }
-                Some(ElementType::String) => {
+                Some(ClickhouseType::String) => {
                     let array_values = array
                         .iter()
                         .map(|v| {
                             v.as_str()
-                                .map(|s| format!("'{}'", Self::escape_string(s)))
+                                .map(|s| format!("'{}'", escape_string(s)))
                                 .unwrap_or_else(|| "' '".to_string())
                         })
                         .collect::<Vec<String>>();
@@ -482,7 +945,14 @@ impl IntoClickhouseValue for MongoDBColumn {
         if let Some(array) = self.bson_value.as_array() {
             let array_values = array
                 .iter()
-                .filter_map(|v| v.as_str().map(|s| format!("'{}'", Self::escape_string(s))))
+                .map(|v| match v.as_str() {
+                    Some(s) => format!("'{}'", escape_string(s)),
+                    // Other elements of a mixed array are kept as JSON text
+                    None => format!(
+                        "'{}'",
+                        escape_string(&v.clone().into_relaxed_extjson().to_string())
+                    ),
+                })
                 .collect::<Vec<String>>();
 
             return format!("[{}]", array_values.join(", "));
@@ -501,10 +971,40 @@ impl IntoClickhouseValue for MongoDBColumn {
         )
     }
 
+    fn is_unchanged(&self) -> bool {
+        false
+    }
+
+    fn is_non_finite(&self) -> bool {
+        match &self.bson_value {
+            Bson::Double(v) => !v.is_finite(),
+            Bson::Decimal128(v) => matches!(
+                v.to_string().to_ascii_lowercase().as_str(),
+                "nan" | "inf" | "-inf" | "infinity" | "-infinity"
+            ),
+            _ => false,
+        }
+    }
+
     fn unknown_value(self) -> String {
         "NULL".to_string()
     }
 
+    fn to_plain_text(self) -> Option<String> {
+        match self.bson_value {
+            Bson::Null | Bson::Undefined => None,
+            Bson::String(value) => Some(value),
+            Bson::ObjectId(oid) => Some(oid.to_hex()),
+            Bson::DateTime(dt) => Some(
+                chrono::DateTime::<chrono::Utc>::from_timestamp_millis(dt.timestamp_millis())
+                    .unwrap_or_default()
+                    .to_rfc3339(),
+            ),
+            Bson::Binary(bin) => Some(base64::engine::general_purpose::STANDARD.encode(bin.bytes)),
+            other => Some(other.into_relaxed_extjson().to_string()),
+        }
+    }
+
     fn into_null(self) -> Self {
         Self {
             bson_value: Bson::Null,
@@ -513,9 +1013,29 @@ impl IntoClickhouseValue for MongoDBColumn {
     }
 }
 
+/// Element type of an array field, widened over all elements: integers with doubles become
+/// Float64, anything else mixed becomes String. None for an empty array.
+fn array_element_type(array: &[Bson]) -> Option<ClickhouseType> {
+    array
+        .iter()
+        .map(|value| match value.element_type() {
+            ElementType::Int32 | ElementType::Int64 => ClickhouseType::Int64,
+            ElementType::Double => ClickhouseType::Float64,
+            _ => ClickhouseType::String,
+        })
+        .reduce(|widened, element_type| match (widened, element_type) {
+            (ClickhouseType::Int64, ClickhouseType::Int64) => ClickhouseType::Int64,
+            (
+                ClickhouseType::Int64 | ClickhouseType::Float64,
+                ClickhouseType::Int64 | ClickhouseType::Float64,
+            ) => ClickhouseType::Float64,
+            _ => ClickhouseType::String,
+        })
+}
+
 impl MongoDBColumn {
-    pub fn escape_string(input: &str) -> String {
-        input.replace('\'', "''").replace("\\", "\\\\")
+    fn is_id(&self) -> bool {
+        self.column_name == "_id"
     }
 }
 
@@ -523,19 +1043,23 @@ impl IntoClickhouseColumn for MongoDBColumn {
     fn to_clickhouse_type(&self) -> ClickhouseType {
         match self.bson_value {
             Bson::String(_) => ClickhouseType::nullable(ClickhouseType::String),
-            Bson::Array(_) => {
-                ClickhouseType::nullable(ClickhouseType::Array(Box::new(ClickhouseType::Unknown)))
-            }
+            // ClickHouse does not allow Nullable(Array(T)), so a NULL array is written as []
+            Bson::Array(ref array) => match array_element_type(array) {
+                None => ClickhouseType::Unknown,
+                Some(element_type) => element_type.array(),
+            },
             Bson::Document(_) => ClickhouseType::nullable(ClickhouseType::String),
             Bson::Boolean(_) => ClickhouseType::nullable(ClickhouseType::Bool),
             Bson::Null => ClickhouseType::nullable(ClickhouseType::Unknown),
             Bson::Int32(_) => ClickhouseType::nullable(ClickhouseType::Int32),
             Bson::Int64(_) => ClickhouseType::nullable(ClickhouseType::Int64),
             Bson::Double(_) => ClickhouseType::nullable(ClickhouseType::Float64),
-            Bson::Decimal128(_) => ClickhouseType::nullable(ClickhouseType::Decimal),
-            Bson::DateTime(_) => {
-                ClickhouseType::nullable(ClickhouseType::DateTime(Default::default()))
-            }
+            Bson::Decimal128(_) => ClickhouseType::nullable(ClickhouseType::default_decimal()),
+            Bson::DateTime(_) => ClickhouseType::nullable(ClickhouseType::DateTime64(DateTime64 {
+                precision: 3,
+                timezone: None,
+            })),
+            // Internal (oplog) type with second precision and an increment
             Bson::Timestamp(_) => {
                 ClickhouseType::nullable(ClickhouseType::DateTime(Default::default()))
             }
@@ -573,6 +1097,15 @@ impl IntoClickhouseColumn for MongoDBColumn {
     fn is_in_primary_key(&self) -> bool {
         self.column_name == "_id"
     }
+
+    // Binary values are always written as base64 by `to_string`
+    fn is_binary(&self) -> bool {
+        false
+    }
+
+    fn default_expression(&self) -> Option<&str> {
+        None
+    }
 }
 
 #[derive(Debug, Clone, Default)]
@@ -580,14 +1113,126 @@ pub struct MongoDBCopyRow {
     pub columns: Vec<MongoDBColumn>,
 }
 
+impl MongoDBCopyRow {
+    /// One column per field of the document.
+    /// BSON allows duplicate keys, but they are already merged when the driver decodes a `Document`
+    /// (the last value wins, like in MongoDB), so each column name appears only once.
+    pub fn from_document(document: &Document) -> Self {
+        MongoDBCopyRow {
+            columns: document
+                .iter()
+                .map(|(k, v)| MongoDBColumn {
+                    column_name: k.clone(),
+                    bson_value: v.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Expands embedded documents into one column per field, named by its path (`address.city`),
+    /// so that each field gets its own type. Documents nested deeper than `max_depth` levels stay
+    /// whole and are written as JSON text, like empty documents and a document `_id`.
+    pub fn flatten_documents(&mut self, max_depth: usize) {
+        for column in std::mem::take(&mut self.columns) {
+            let depth = if column.is_id() { 0 } else { max_depth };
+            flatten_column(column, depth, &mut self.columns);
+        }
+    }
+
+    /// Truncates values longer than `max_bytes`, so that one huge value does not fail the whole
+    /// insert: strings, binary data and the JSON text of embedded documents to `max_bytes`, arrays
+    /// to their leading elements that fit into it.
+    /// Embedded documents are replaced with their JSON text, so it is not serialized again when
+    /// the row is written. Returns the names of the truncated columns.
+    pub fn truncate_large_values(&mut self, max_bytes: usize) -> Vec<String> {
+        let mut truncated_columns = vec![];
+
+        for column in &mut self.columns {
+            let truncated = match &mut column.bson_value {
+                Bson::Document(document) => {
+                    let mut json = Bson::Document(std::mem::take(document))
+                        .into_relaxed_extjson()
+                        .to_string();
+                    let truncated = truncate_text(&mut json, max_bytes);
+                    column.bson_value = Bson::String(json);
+                    truncated
+                }
+                Bson::String(text) => truncate_text(text, max_bytes),
+                Bson::Binary(binary) if binary.bytes.len() > max_bytes => {
+                    binary.bytes.truncate(max_bytes);
+                    true
+                }
+                Bson::Array(array) => {
+                    let mut total_bytes = 0;
+                    let fitting_elements = array
+                        .iter()
+                        .take_while(|element| {
+                            total_bytes += match *element {
+                                Bson::String(text) => text.len(),
+                                element => element.clone().into_relaxed_extjson().to_string().len(),
+                            };
+                            total_bytes <= max_bytes
+                        })
+                        .count();
+
+                    let truncated = fitting_elements < array.len();
+                    array.truncate(fitting_elements);
+                    truncated
+                }
+                _ => false,
+            };
+
+            if truncated {
+                truncated_columns.push(column.column_name.clone());
+            }
+        }
+
+        truncated_columns
+    }
+}
+
+/// Truncates `text` to at most `max_bytes`, at a UTF-8 boundary. Returns true if it was longer.
+fn truncate_text(text: &mut String, max_bytes: usize) -> bool {
+    if text.len() <= max_bytes {
+        return false;
+    }
+
+    let mut end = max_bytes;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    text.truncate(end);
+
+    true
+}
+
+fn flatten_column(column: MongoDBColumn, depth: usize, columns: &mut Vec<MongoDBColumn>) {
+    match column.bson_value {
+        Bson::Document(document) if depth > 0 && !document.is_empty() => {
+            for (key, value) in document {
+                let field = MongoDBColumn {
+                    column_name: format!("{}.{key}", column.column_name),
+                    bson_value: value,
+                };
+                flatten_column(field, depth - 1, columns);
+            }
+        }
+        bson_value => columns.push(MongoDBColumn {
+            column_name: column.column_name,
+            bson_value,
+        }),
+    }
+}
+
 impl IntoClickhouseRow for MongoDBCopyRow {
     fn find_value_by_column_name(
         &self,
         _: &[impl IntoClickhouseColumn],
         column_name: &str,
+        identifier_case: IdentifierCase,
     ) -> Option<impl IntoClickhouseValue + Default> {
         for column in &self.columns {
-            if column.column_name == column_name {
+            if identifier_case.matches(&column.column_name, column_name) {
                 return Some(column.clone());
             }
         }
@@ -604,3 +1249,453 @@ impl IntoClickhouseRow for MongoDBCopyRow {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use mongodb::bson::{Bson, DateTime, Decimal128, Document, RawDocumentBuf, Timestamp, doc};
+
+    use mongodb::change_stream::event::ResumeToken;
+
+    use super::{
+        GZIP_MAGIC, MongoDBColumn, MongoDBCopyRow, ResumeTokenCursor, ResumeTokenStorage,
+        collect_batch, gzip, start_operation_time,
+    };
+    use crate::adapter::{IntoClickhouseColumn, IntoClickhouseValue};
+    use crate::config::MongoDBStartAt;
+
+    fn column(bson_value: Bson) -> MongoDBColumn {
+        MongoDBColumn {
+            column_name: "updated_at".to_string(),
+            bson_value,
+        }
+    }
+
+    #[test]
+    fn datetime_and_timestamp_have_distinct_types_and_values() {
+        // 2023-11-14 22:13:20.123 UTC
+        let datetime = column(Bson::DateTime(DateTime::from_millis(1_700_000_000_123)));
+        let timestamp = column(Bson::Timestamp(Timestamp {
+            time: 1_700_000_000,
+            increment: 7,
+        }));
+
+        assert_eq!(
+            datetime.to_clickhouse_type().to_type_text(),
+            "Nullable(DateTime64(3))"
+        );
+        assert_eq!(
+            timestamp.to_clickhouse_type().to_type_text(),
+            "Nullable(DateTime)"
+        );
+
+        assert_eq!(
+            datetime.clone().to_datetime(),
+            "fromUnixTimestamp64Milli(toInt64(1700000000123))"
+        );
+        assert_eq!(timestamp.clone().to_datetime(), "toDateTime(1700000000)");
+
+        assert_eq!(datetime.to_date(), "toDate(1700000000)");
+        assert_eq!(timestamp.to_date(), "toDate(1700000000)");
+    }
+
+    #[test]
+    fn duplicate_keys_become_one_column_with_the_last_value() {
+        let mut raw = RawDocumentBuf::new();
+        raw.append("_id", 1);
+        raw.append("name", "first");
+        raw.append("name", "last");
+        // The cursors and change streams decode each document like this
+        let document: Document = mongodb::bson::from_slice(raw.as_bytes()).unwrap();
+
+        let row = MongoDBCopyRow::from_document(&document);
+
+        let columns: Vec<(&str, &Bson)> = row
+            .columns
+            .iter()
+            .map(|column| (column.column_name.as_str(), &column.bson_value))
+            .collect();
+        assert_eq!(
+            columns,
+            vec![
+                ("_id", &Bson::Int32(1)),
+                ("name", &Bson::String("last".to_string()))
+            ]
+        );
+    }
+
+    #[test]
+    fn embedded_documents_are_flattened_up_to_the_max_depth() {
+        let mut row = MongoDBCopyRow::from_document(&doc! {
+            "_id": { "tenant": 1, "id": 2 },
+            "address": { "city": "Seoul", "zip": 4524, "geo": { "lat": 37.5, "point": { "x": 1 } } },
+            "tags": {},
+        });
+
+        row.flatten_documents(2);
+
+        let columns: Vec<(&str, String)> = row
+            .columns
+            .iter()
+            .map(|column| {
+                (
+                    column.column_name.as_str(),
+                    column.to_clickhouse_type().to_type_text(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            columns,
+            vec![
+                ("_id", "Nullable(String)".to_string()),
+                ("address.city", "Nullable(String)".to_string()),
+                ("address.zip", "Nullable(Int32)".to_string()),
+                ("address.geo.lat", "Nullable(Float64)".to_string()),
+                // Beyond the depth limit, written as JSON text
+                ("address.geo.point", "Nullable(String)".to_string()),
+                ("tags", "Nullable(String)".to_string()),
+            ]
+        );
+        assert_eq!(row.columns[4].clone().to_string(), r#"'{"x":1}'"#);
+    }
+
+    #[test]
+    fn large_values_are_truncated() {
+        let large_text = "x".repeat(3 * 1024 * 1024);
+        let mut row = MongoDBCopyRow {
+            columns: vec![
+                MongoDBColumn {
+                    column_name: "large".to_string(),
+                    bson_value: Bson::Document(doc! { "payload": large_text }),
+                },
+                MongoDBColumn {
+                    column_name: "small".to_string(),
+                    bson_value: Bson::Document(doc! { "a": 1 }),
+                },
+                MongoDBColumn {
+                    column_name: "text".to_string(),
+                    bson_value: Bson::String("y".repeat(2 * 1024 * 1024)),
+                },
+                MongoDBColumn {
+                    column_name: "tags".to_string(),
+                    bson_value: Bson::Array(vec![Bson::String("z".repeat(700 * 1024)); 3]),
+                },
+            ],
+        };
+
+        let truncated_columns = row.truncate_large_values(1024 * 1024);
+
+        assert_eq!(truncated_columns, vec!["large", "text", "tags"]);
+        match &row.columns[0].bson_value {
+            Bson::String(json) => {
+                assert_eq!(json.len(), 1024 * 1024);
+                assert!(json.starts_with(r#"{"payload":"xxx"#), "{}", &json[..20]);
+            }
+            other => panic!("expected a truncated JSON string, got {other:?}"),
+        }
+        // Written from the JSON of the guard, without serializing it again
+        assert_eq!(
+            row.columns[1].bson_value,
+            Bson::String(r#"{"a":1}"#.to_string())
+        );
+        assert_eq!(row.columns[1].clone().to_string(), r#"'{"a":1}'"#);
+        assert_eq!(
+            row.columns[2].bson_value.as_str().unwrap().len(),
+            1024 * 1024
+        );
+        assert_eq!(row.columns[3].bson_value.as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn array_type_is_widened_over_all_elements() {
+        let type_text = |column: &MongoDBColumn| column.to_clickhouse_type().to_type_text();
+
+        let integers = column(Bson::Array(vec![Bson::Int32(1), Bson::Int64(2)]));
+        assert_eq!(type_text(&integers), "Array(Int64)");
+        assert_eq!(integers.to_array(), "[1, 2]");
+
+        let numbers = column(Bson::Array(vec![Bson::Int32(1), Bson::Double(2.5)]));
+        assert_eq!(type_text(&numbers), "Array(Float64)");
+        assert_eq!(numbers.to_array(), "[1, 2.5]");
+
+        let mixed = column(Bson::Array(vec![
+            Bson::Int32(1),
+            Bson::Double(2.5),
+            Bson::String("x".to_string()),
+        ]));
+        assert_eq!(type_text(&mixed), "Array(String)");
+        assert_eq!(mixed.to_string_array(), "['1', '2.5', 'x']");
+    }
+
+    #[test]
+    fn decimal128_is_written_in_fixed_point_notation() {
+        let decimal = |text: &str| column(Bson::Decimal128(text.parse::<Decimal128>().unwrap()));
+
+        assert_eq!(decimal("1.05E+3").to_decimal(2), "'1050.00'");
+        assert_eq!(
+            decimal("1E+25").to_decimal(0),
+            "'10000000000000000000000000'"
+        );
+        assert_eq!(decimal("1E-8").to_decimal(9), "'0.000000010'");
+        assert_eq!(decimal("-12.345").to_decimal(2), "'-12.34'");
+    }
+
+    #[test]
+    fn start_point_is_used_only_when_configured() {
+        let oldest = Timestamp {
+            time: 1_600_000_000,
+            increment: 3,
+        };
+
+        // No operation time: the change stream only sees changes made after it is opened
+        assert_eq!(
+            start_operation_time(MongoDBStartAt::Now, Some(oldest)),
+            None
+        );
+        assert_eq!(
+            start_operation_time(MongoDBStartAt::Beginning, Some(oldest)),
+            Some(oldest)
+        );
+        assert_eq!(
+            start_operation_time(MongoDBStartAt::ClusterTime(1_700_000_000), Some(oldest)),
+            Some(Timestamp {
+                time: 1_700_000_000,
+                increment: 0
+            })
+        );
+
+        let start_at: MongoDBStartAt =
+            serde_json::from_str(r#"{"cluster_time": 1700000000}"#).unwrap();
+        assert_eq!(start_at, MongoDBStartAt::ClusterTime(1_700_000_000));
+    }
+
+    #[tokio::test]
+    async fn resume_token_is_written_per_batch_count_and_on_flush() {
+        let path = std::env::temp_dir().join(format!(
+            "clockpipe-resume-token-test-{}.json",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        let token =
+            |data: &str| serde_json::from_value(serde_json::json!({ "_data": data })).unwrap();
+
+        let storage = ResumeTokenStorage::File {
+            path: path.clone(),
+            compress: false,
+        };
+        let mut cursor = ResumeTokenCursor::new(storage, None, Some(3));
+
+        cursor.advance(&token("01")).await.unwrap();
+        cursor.advance(&token("02")).await.unwrap();
+        assert!(!path.exists());
+        // The next peek still resumes from the latest token
+        assert_eq!(cursor.load().await.unwrap(), Some(token("02")));
+
+        cursor.advance(&token("03")).await.unwrap();
+        assert_eq!(cursor.storage.read().await.unwrap(), Some(token("03")));
+
+        cursor.advance(&token("04")).await.unwrap();
+        assert_eq!(cursor.storage.read().await.unwrap(), Some(token("03")));
+
+        // Shutdown
+        cursor.flush().await.unwrap();
+        assert_eq!(cursor.storage.read().await.unwrap(), Some(token("04")));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn compressed_resume_token_round_trips_and_plaintext_files_still_load() {
+        let path = std::env::temp_dir().join(format!(
+            "clockpipe-compressed-resume-token-test-{}.json",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        let token: ResumeToken =
+            serde_json::from_value(serde_json::json!({ "_data": "8265A1B2C3000000012B" })).unwrap();
+        let compressed = ResumeTokenStorage::File {
+            path: path.clone(),
+            compress: true,
+        };
+        let plaintext = ResumeTokenStorage::File {
+            path: path.clone(),
+            compress: false,
+        };
+
+        compressed.write(&token).await.unwrap();
+        assert!(std::fs::read(&path).unwrap().starts_with(&GZIP_MAGIC));
+        assert_eq!(compressed.read().await.unwrap(), Some(token.clone()));
+        // Compression can be turned off again without losing the position
+        assert_eq!(plaintext.read().await.unwrap(), Some(token.clone()));
+
+        // A file written before compression was enabled
+        std::fs::write(&path, r#"{"_data":"8265A1B2C3000000012B"}"#).unwrap();
+        assert_eq!(compressed.read().await.unwrap(), Some(token));
+
+        // A corrupted file is an error, not a missing token
+        let mut corrupted = gzip(b"{}").unwrap();
+        let last = corrupted.len() - 5;
+        corrupted[last] ^= 0xff;
+        std::fs::write(&path, corrupted).unwrap();
+        assert!(compressed.read().await.is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn clickhouse_resume_token_is_inserted_and_missing_row_is_none() {
+        let (port, mut queries) = crate::pipes::tests::recording_clickhouse().await;
+
+        let config = serde_json::from_value::<crate::config::MongoDBConfig>(serde_json::json!({
+            "connection": {
+                "host": "localhost",
+                "username": "user",
+                "password": "password",
+                "database": "shop"
+            },
+            "collections": [],
+            "resume_token_storage": "clickhouse"
+        }))
+        .unwrap();
+        let clickhouse_config =
+            serde_json::from_value::<crate::config::ClickHouseConfig>(serde_json::json!({
+                "connection": {
+                    "host": "127.0.0.1",
+                    "port": port,
+                    "username": "default",
+                    "password": "",
+                    "database": "db"
+                }
+            }))
+            .unwrap();
+        let connection =
+            crate::adapter::clickhouse::ClickhouseConnection::new(&clickhouse_config.connection)
+                .unwrap();
+
+        let storage = ResumeTokenStorage::from_config(&config, &clickhouse_config, &connection);
+        let ResumeTokenStorage::ClickHouse(table) = &storage else {
+            panic!("{storage:?}");
+        };
+        assert_eq!(
+            table.create_table_query(),
+            "CREATE TABLE IF NOT EXISTS db.clockpipe_resume_tokens (`key` String, `resume_token` String, `version` UInt64) ENGINE = ReplacingMergeTree(`version`) ORDER BY `key`"
+        );
+
+        assert_eq!(
+            table.select_query(),
+            "SELECT `resume_token` FROM db.clockpipe_resume_tokens WHERE `key` = 'shop' ORDER BY `version` DESC LIMIT 1"
+        );
+
+        // The server has no rows. The SELECT is sent in the URL, the body is empty
+        assert_eq!(storage.read().await.unwrap(), None);
+        assert_eq!(queries.recv().await.unwrap(), "");
+
+        let token: ResumeToken =
+            serde_json::from_value(serde_json::json!({ "_data": "8265A1B2C3000000012B" })).unwrap();
+        storage.write(&token).await.unwrap();
+        let query = queries.recv().await.unwrap();
+        assert!(
+            query.starts_with(
+                r#"INSERT INTO db.clockpipe_resume_tokens (`key`, `resume_token`, `version`) VALUES('shop','{"_data":"8265A1B2C3000000012B"}',"#
+            ),
+            "{query}"
+        );
+    }
+
+    #[tokio::test]
+    async fn stalled_connection_errors_within_the_timeout() {
+        // Accepts connections but never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut sockets = vec![];
+            while let Ok((socket, _)) = listener.accept().await {
+                sockets.push(socket);
+            }
+        });
+
+        let config: crate::config::MongoDBConfig = serde_json::from_value(serde_json::json!({
+            "connection": {
+                "host": "127.0.0.1",
+                "username": "",
+                "password": "",
+                "database": "clockpipe",
+                "uri": format!("mongodb://127.0.0.1:{port}/?directConnection=true"),
+                "connect_timeout_millis": 200,
+                "server_selection_timeout_millis": 500
+            },
+            "collections": []
+        }))
+        .unwrap();
+        config.validate().unwrap();
+
+        let storage = ResumeTokenStorage::File {
+            path: std::env::temp_dir().join("clockpipe-unused-resume-token.json"),
+            compress: false,
+        };
+        let connection = super::MongoDBConnection::new(&config, storage)
+            .await
+            .unwrap();
+
+        let started_at = std::time::Instant::now();
+        let result = connection.ping().await;
+
+        assert!(result.is_err());
+        assert!(
+            started_at.elapsed() < std::time::Duration::from_secs(5),
+            "{:?}",
+            started_at.elapsed()
+        );
+    }
+
+    #[tokio::test]
+    async fn batch_is_returned_at_max_changes_without_waiting_for_max_wait() {
+        // Plenty of changes, then an idle stream
+        let mut stream = futures::stream::iter(1..=10).chain(futures::stream::pending());
+        let mut batch = vec![];
+        let started_at = std::time::Instant::now();
+
+        collect_batch(
+            &mut stream,
+            3,
+            std::time::Duration::from_secs(30),
+            |_, change| {
+                batch.push(change);
+                Ok(true)
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(batch, vec![1, 2, 3]);
+        assert!(started_at.elapsed() < std::time::Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn idle_stream_is_waited_on_until_max_wait() {
+        let mut stream = futures::stream::iter(1..=4).chain(futures::stream::pending());
+        let mut batch = vec![];
+        let started_at = std::time::Instant::now();
+
+        // Odd changes belong to another collection and do not count
+        collect_batch(
+            &mut stream,
+            3,
+            std::time::Duration::from_millis(200),
+            |_, change| {
+                let is_counted = change % 2 == 0;
+                if is_counted {
+                    batch.push(change);
+                }
+                Ok(is_counted)
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(batch, vec![2, 4]);
+        assert!(started_at.elapsed() >= std::time::Duration::from_millis(200));
+    }
+}
@@ -1,12 +1,14 @@
 use sqlx::postgres::PgConnectOptions;
+pub mod copy_buffer;
 pub mod pgoutput;
 
 use crate::{
     adapter::{
-        IntoClickhouseColumn, IntoClickhouseRow, IntoClickhouseValue, clickhouse::ClickhouseType,
-        postgres::pgoutput::PgOutputValue,
+        IntoClickhouseColumn, IntoClickhouseRow, IntoClickhouseValue,
+        clickhouse::{ClickhouseType, MAX_DECIMAL_PRECISION},
+        postgres::{copy_buffer::CopyReceiver, pgoutput::PgOutputValue},
     },
-    config::PostgresConnectionConfig,
+    config::{ColumnTypeOverride, IdentifierCase, PostgresConnectionConfig},
     errors,
 };
 
@@ -18,6 +20,41 @@ pub struct PostgresConnection {
 
 impl PostgresConnection {
     pub async fn new(config: &PostgresConnectionConfig) -> errors::Result<Self> {
+        let result = sqlx::postgres::PgPoolOptions::new()
+            .max_connections(5)
+            .connect_with(Self::connect_options(config))
+            .await;
+
+        match result {
+            Ok(pool) => {
+                log::info!("Successfully connected to Postgres database");
+
+                Ok(PostgresConnection {
+                    pool,
+                    config: config.clone(),
+                })
+            }
+            Err(e) => Err(errors::Errors::DatabaseConnectionError(format!(
+                "Failed to connect to Postgres database: {e}"
+            ))),
+        }
+    }
+
+    /// A connection that only connects when it is used, for tests that must not reach Postgres.
+    #[cfg(test)]
+    pub fn lazy(config: &PostgresConnectionConfig) -> Self {
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .max_connections(1)
+            .acquire_timeout(std::time::Duration::from_secs(1))
+            .connect_lazy_with(Self::connect_options(config));
+
+        PostgresConnection {
+            pool,
+            config: config.clone(),
+        }
+    }
+
+    fn connect_options(config: &PostgresConnectionConfig) -> PgConnectOptions {
         let mut options = PgConnectOptions::new()
             .host(&config.host)
             .port(config.port)
@@ -52,24 +89,7 @@ impl PostgresConnection {
             options = options.ssl_root_cert(ssl_root_cert);
         }
 
-        let result = sqlx::postgres::PgPoolOptions::new()
-            .max_connections(5)
-            .connect_with(options)
-            .await;
-
-        match result {
-            Ok(pool) => {
-                log::info!("Successfully connected to Postgres database");
-
-                Ok(PostgresConnection {
-                    pool,
-                    config: config.clone(),
-                })
-            }
-            Err(e) => Err(errors::Errors::DatabaseConnectionError(format!(
-                "Failed to connect to Postgres database: {e}"
-            ))),
-        }
+        options
     }
 
     pub async fn ping(&self) -> errors::Result<()> {
@@ -97,6 +117,8 @@ pub struct PostgreColumn {
 pub struct ReplicationSlot {
     pub slot_name: String,
     pub wal_status: String,
+    /// A connection is currently streaming from (or peeking) the slot
+    pub active: bool,
 }
 
 #[derive(Debug, Clone, sqlx::FromRow)]
@@ -155,6 +177,36 @@ impl PostgresConnection {
         Ok(result[0].0 as u32)
     }
 
+    /// Relation ids of all partitions of a partitioned table, including sub-partitions.
+    /// pgoutput sends the changes of a partitioned table under these ids.
+    pub async fn get_partition_relation_ids(&self, relation_id: u32) -> errors::Result<Vec<u32>> {
+        let result: Vec<(i32,)> = sqlx::query_as(
+            r#"
+            WITH RECURSIVE partitions AS (
+                SELECT i.inhrelid
+                FROM pg_catalog.pg_inherits i
+                JOIN pg_catalog.pg_class c ON c.oid = i.inhrelid
+                WHERE i.inhparent = $1::oid AND c.relispartition
+                UNION ALL
+                SELECT i.inhrelid
+                FROM pg_catalog.pg_inherits i
+                JOIN partitions p ON i.inhparent = p.inhrelid
+            )
+            SELECT inhrelid::INTEGER FROM partitions
+            "#,
+        )
+        .bind(relation_id as i64)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| {
+            errors::Errors::TableNotFoundError(format!(
+                "Failed to get partitions of relation ID {relation_id}: {e}"
+            ))
+        })?;
+
+        Ok(result.into_iter().map(|(oid,)| oid as u32).collect())
+    }
+
     pub async fn get_columns_by_relation_id(
         &self,
         relation_id: i64,
@@ -196,6 +248,18 @@ pub struct Publication {
 pub struct PublicationTable {
     pub schema_name: String,
     pub table_name: String,
+    /// Top-most partitioned table of a partition, the table itself otherwise
+    pub root_schema_name: String,
+    pub root_table_name: String,
+}
+
+impl PublicationTable {
+    /// Whether changes of the table are published. Without `publish_via_partition_root`, a
+    /// partitioned table is listed by its partitions only.
+    pub fn publishes(&self, schema_name: &str, table_name: &str) -> bool {
+        (self.schema_name == schema_name && self.table_name == table_name)
+            || (self.root_schema_name == schema_name && self.root_table_name == table_name)
+    }
 }
 
 #[derive(Debug, Clone, sqlx::FromRow)]
@@ -204,13 +268,59 @@ pub struct PostgresColumn {
     pub column_name: String,
     pub data_type: String,
     pub length: i32,
+    /// Precision and scale of `numeric(p, s)`. 0 if not specified
+    #[sqlx(default)]
+    pub numeric_precision: i32,
+    #[sqlx(default)]
+    pub numeric_scale: i32,
     pub nullable: bool,
     pub is_primary_key: bool,
     pub comment: String,
+    /// User-defined enum type. Mapped to LowCardinality(String)
+    #[sqlx(default)]
+    pub is_enum: bool,
+    #[sqlx(skip)]
+    pub type_override: Option<ColumnTypeOverride>,
+    /// ClickHouse `DEFAULT` expression from `column_defaults`
+    #[sqlx(skip)]
+    pub default_expression: Option<String>,
 }
 
 impl IntoClickhouseColumn for PostgresColumn {
     fn to_clickhouse_type(&self) -> ClickhouseType {
+        if let Some(type_override) = self.type_override
+            && self.supports_type_override(type_override)
+        {
+            let clickhouse_type = match type_override {
+                ColumnTypeOverride::UInt8 => ClickhouseType::UInt8,
+                ColumnTypeOverride::UInt16 => ClickhouseType::UInt16,
+                ColumnTypeOverride::UInt32 => ClickhouseType::UInt32,
+                ColumnTypeOverride::UInt64 => ClickhouseType::UInt64,
+                ColumnTypeOverride::LowCardinality => ClickhouseType::String,
+            };
+
+            let clickhouse_type = if self.nullable {
+                ClickhouseType::nullable(clickhouse_type)
+            } else {
+                clickhouse_type
+            };
+
+            return if type_override == ColumnTypeOverride::LowCardinality {
+                clickhouse_type.low_cardinality()
+            } else {
+                clickhouse_type
+            };
+        }
+
+        // Enum values come from a small fixed set, which is what LowCardinality is for
+        if self.is_enum {
+            return if self.nullable {
+                ClickhouseType::nullable(ClickhouseType::String).low_cardinality()
+            } else {
+                ClickhouseType::String.low_cardinality()
+            };
+        }
+
         match self.data_type.as_str() {
             "int2" => {
                 if self.nullable {
@@ -236,6 +346,15 @@ impl IntoClickhouseColumn for PostgresColumn {
                 }
             }
             "_int8" => ClickhouseType::array(ClickhouseType::Int64),
+            // oid is an unsigned 4-byte integer
+            "oid" => {
+                if self.nullable {
+                    ClickhouseType::nullable(ClickhouseType::UInt32)
+                } else {
+                    ClickhouseType::UInt32
+                }
+            }
+            "_oid" => ClickhouseType::array(ClickhouseType::UInt32),
             "float4" => {
                 if self.nullable {
                     ClickhouseType::nullable(ClickhouseType::Float32)
@@ -254,12 +373,12 @@ impl IntoClickhouseColumn for PostgresColumn {
             "_float8" => ClickhouseType::array(ClickhouseType::Float64),
             "numeric" => {
                 if self.nullable {
-                    ClickhouseType::nullable(ClickhouseType::Decimal)
+                    ClickhouseType::nullable(self.decimal_type())
                 } else {
-                    ClickhouseType::Decimal
+                    self.decimal_type()
                 }
             }
-            "_numeric" => ClickhouseType::array(ClickhouseType::Decimal),
+            "_numeric" => ClickhouseType::array(self.decimal_type()),
             // varchar
             "varchar" | "text" | "json" | "jsonb" => {
                 if self.nullable {
@@ -268,7 +387,34 @@ impl IntoClickhouseColumn for PostgresColumn {
                     ClickhouseType::String
                 }
             }
+            // char(n). n is a number of characters, FixedString(n) holds n bytes
+            "bpchar" if self.length > 0 => {
+                if self.nullable {
+                    ClickhouseType::nullable(ClickhouseType::FixedString(self.length as u64))
+                } else {
+                    ClickhouseType::FixedString(self.length as u64)
+                }
+            }
             "_varchar" => ClickhouseType::array(ClickhouseType::String),
+            // Range types keep their text form, e.g. `[1,10)`, `["2025-01-01 00:00:00+00",)` or `empty`
+            "int4range" | "int8range" | "numrange" | "tsrange" | "tstzrange" | "daterange"
+            | "int4multirange" | "int8multirange" | "nummultirange" | "tsmultirange"
+            | "tstzmultirange" | "datemultirange" => {
+                if self.nullable {
+                    ClickhouseType::nullable(ClickhouseType::String)
+                } else {
+                    ClickhouseType::String
+                }
+            }
+            // Encoded as text, see `binary_encoding`
+            "bytea" => {
+                if self.nullable {
+                    ClickhouseType::nullable(ClickhouseType::String)
+                } else {
+                    ClickhouseType::String
+                }
+            }
+            "_bytea" => ClickhouseType::array(ClickhouseType::String),
             "_text" => ClickhouseType::array(ClickhouseType::String),
             // Boolean
             "bool" => {
@@ -279,6 +425,14 @@ impl IntoClickhouseColumn for PostgresColumn {
                 }
             }
             "_bool" => ClickhouseType::array(ClickhouseType::Bool),
+            "uuid" => {
+                if self.nullable {
+                    ClickhouseType::nullable(ClickhouseType::UUID)
+                } else {
+                    ClickhouseType::UUID
+                }
+            }
+            "_uuid" => ClickhouseType::array(ClickhouseType::UUID),
             // time
             "timestamp" | "timestamptz" => {
                 if self.nullable {
@@ -324,6 +478,56 @@ impl IntoClickhouseColumn for PostgresColumn {
     fn is_in_primary_key(&self) -> bool {
         self.is_primary_key
     }
+
+    fn is_binary(&self) -> bool {
+        matches!(self.data_type.as_str(), "bytea" | "_bytea")
+    }
+
+    fn default_expression(&self) -> Option<&str> {
+        self.default_expression.as_deref()
+    }
+}
+
+impl PostgresColumn {
+    /// `Decimal(P, S)` for `numeric(p, s)`, within the limits of ClickHouse (P <= 76, 0 <= S <= P).
+    /// An unconstrained `numeric` gets `Decimal(38, 9)`.
+    fn decimal_type(&self) -> ClickhouseType {
+        if self.numeric_precision <= 0 {
+            return ClickhouseType::default_decimal();
+        }
+
+        // numeric(p, s) allows a negative scale since PostgreSQL 15, which rounds to tens, hundreds, ...
+        // numeric(5, -2) holds integers of up to 7 digits, so it needs Decimal(7, 0).
+        if self.numeric_scale < 0 {
+            let precision = (self.numeric_precision as u32 + self.numeric_scale.unsigned_abs())
+                .min(MAX_DECIMAL_PRECISION);
+
+            return ClickhouseType::Decimal(precision, 0);
+        }
+
+        let precision = (self.numeric_precision as u32).min(MAX_DECIMAL_PRECISION);
+        let scale = (self.numeric_scale as u32).min(precision);
+
+        ClickhouseType::Decimal(precision, scale)
+    }
+
+    /// Unsigned overrides only make sense for integer columns (and UInt8 for bool columns, written
+    /// as 0/1), LowCardinality for text columns
+    pub fn supports_type_override(&self, type_override: ColumnTypeOverride) -> bool {
+        match type_override {
+            ColumnTypeOverride::UInt8 => {
+                matches!(self.data_type.as_str(), "int2" | "int4" | "int8" | "bool")
+            }
+            ColumnTypeOverride::UInt16
+            | ColumnTypeOverride::UInt32
+            | ColumnTypeOverride::UInt64 => {
+                matches!(self.data_type.as_str(), "int2" | "int4" | "int8")
+            }
+            ColumnTypeOverride::LowCardinality => {
+                self.is_enum || matches!(self.data_type.as_str(), "varchar" | "text")
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone, Default)]
@@ -336,10 +540,11 @@ impl IntoClickhouseRow for PostgresCopyRow {
         &self,
         source_columns: &[impl IntoClickhouseColumn],
         column_name: &str,
+        identifier_case: IdentifierCase,
     ) -> Option<impl IntoClickhouseValue + Default> {
         let Some(source_column) = source_columns
             .iter()
-            .find(|col| col.get_column_name() == column_name)
+            .find(|col| identifier_case.matches(col.get_column_name(), column_name))
         else {
             return Some(PgOutputValue::Null);
         };
@@ -426,14 +631,13 @@ impl PostgresConnection {
                         + index
                         + 1;
 
-                    if hex_end > index + 1 {
-                        if let Ok(hex) = std::str::from_utf8(&input[index + 1..hex_end]) {
-                            if let Ok(value) = u8::from_str_radix(hex, 16) {
-                                decoded.push(value);
-                                index = hex_end;
-                                continue;
-                            }
-                        }
+                    if hex_end > index + 1
+                        && let Ok(hex) = std::str::from_utf8(&input[index + 1..hex_end])
+                        && let Ok(value) = u8::from_str_radix(hex, 16)
+                    {
+                        decoded.push(value);
+                        index = hex_end;
+                        continue;
                     }
 
                     decoded.push(b'x');
@@ -448,12 +652,12 @@ impl PostgresConnection {
                         .count()
                         + start;
 
-                    if let Ok(octal) = std::str::from_utf8(&input[start..octal_end]) {
-                        if let Ok(value) = u8::from_str_radix(octal, 8) {
-                            decoded.push(value);
-                            index = octal_end;
-                            continue;
-                        }
+                    if let Ok(octal) = std::str::from_utf8(&input[start..octal_end])
+                        && let Ok(value) = u8::from_str_radix(octal, 8)
+                    {
+                        decoded.push(value);
+                        index = octal_end;
+                        continue;
                     }
 
                     decoded.push(input[index]);
@@ -557,15 +761,26 @@ impl PostgresConnection {
         publication_name: &str,
     ) -> errors::Result<Vec<PublicationTable>> {
         let result: Vec<PublicationTable> = sqlx::query_as(
-            "SELECT schemaname as schema_name, tablename as table_name FROM pg_publication_tables WHERE pubname = $1",
+            r#"
+            SELECT
+                t.schemaname AS schema_name,
+                t.tablename AS table_name,
+                rn.nspname AS root_schema_name,
+                rc.relname AS root_table_name
+            FROM pg_publication_tables t
+            JOIN pg_class rc ON rc.oid = COALESCE(
+                pg_partition_root(format('%I.%I', t.schemaname, t.tablename)::regclass),
+                format('%I.%I', t.schemaname, t.tablename)::regclass
+            )
+            JOIN pg_namespace rn ON rn.oid = rc.relnamespace
+            WHERE t.pubname = $1
+            "#,
         )
         .bind(publication_name)
         .fetch_all(&self.pool)
         .await
         .map_err(|e| {
-            errors::Errors::PublicationFindFailed(format!(
-                "Failed to get publication tables: {e}"
-            ))
+            errors::Errors::PublicationFindFailed(format!("Failed to get publication tables: {e}"))
         })?;
 
         Ok(result)
@@ -575,14 +790,12 @@ impl PostgresConnection {
         &self,
         publication_name: &str,
         table_names: &[String],
+        publish_via_partition_root: bool,
     ) -> errors::Result<()> {
         log::debug!("Creating publication {publication_name} for tables: {table_names:?}");
 
-        let query = format!(
-            "CREATE PUBLICATION {} FOR TABLE {}",
-            publication_name,
-            table_names.join(", ")
-        );
+        let query =
+            create_publication_query(publication_name, table_names, publish_via_partition_root);
 
         sqlx::query(&query).execute(&self.pool).await.map_err(|e| {
             errors::Errors::PublicationCreateFailed(format!("Failed to create publication: {e}"))
@@ -611,6 +824,23 @@ impl PostgresConnection {
         Ok(())
     }
 
+    /// Sets `publish_via_partition_root` on an existing publication.
+    pub async fn set_publish_via_partition_root(
+        &self,
+        publication_name: &str,
+    ) -> errors::Result<()> {
+        let query =
+            format!("ALTER PUBLICATION {publication_name} SET (publish_via_partition_root = true)");
+
+        sqlx::query(&query).execute(&self.pool).await.map_err(|e| {
+            errors::Errors::PublicationCreateFailed(format!(
+                "Failed to set publish_via_partition_root on publication: {e}"
+            ))
+        })?;
+
+        Ok(())
+    }
+
     pub async fn create_replication_slot(&self, slot_name: &str) -> errors::Result<()> {
         log::debug!("Creating replication slot: {slot_name}");
 
@@ -654,6 +884,19 @@ impl PostgresConnection {
         Ok(Some(row))
     }
 
+    pub async fn list_replication_slots(&self) -> errors::Result<Vec<ReplicationSlot>> {
+        let rows: Vec<ReplicationSlot> = sqlx::query_as(
+            "select slot_name, coalesce(wal_status, '') as wal_status, active from pg_replication_slots;",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| {
+            errors::Errors::ReplicationNotFound(format!("Failed to list replication slots: {e}"))
+        })?;
+
+        Ok(rows)
+    }
+
     pub async fn get_comment_from_table(
         &self,
         database_name: &str,
@@ -702,8 +945,42 @@ impl PostgresConnection {
              SELECT
                 c.ordinal_position as column_index,
                 c.column_name as column_name,
-                c.udt_name as data_type,
+                -- Domains (also nested ones and arrays of them) are resolved to their base type
+                coalesce((
+                    WITH RECURSIVE column_type AS (
+                        SELECT pt.oid, pt.typelem, pt.typcategory = 'A' AS is_array
+                        FROM pg_catalog.pg_type pt
+                        JOIN pg_catalog.pg_namespace ptn ON ptn.oid = pt.typnamespace
+                        WHERE pt.typname = c.udt_name AND ptn.nspname = c.udt_schema
+                    ),
+                    type_chain AS (
+                        SELECT pt.typname, pt.typtype, pt.typbasetype
+                        FROM pg_catalog.pg_type pt
+                        JOIN column_type ct
+                            ON pt.oid = CASE WHEN ct.is_array THEN ct.typelem ELSE ct.oid END
+                        UNION ALL
+                        SELECT bt.typname, bt.typtype, bt.typbasetype
+                        FROM pg_catalog.pg_type bt
+                        JOIN type_chain tc ON bt.oid = tc.typbasetype
+                        WHERE tc.typtype = 'd'
+                    )
+                    SELECT CASE WHEN ct.is_array THEN '_' ELSE '' END || tc.typname
+                    FROM type_chain tc, column_type ct
+                    WHERE tc.typtype <> 'd'
+                ), c.udt_name::text) as data_type,
                 coalesce(c.character_maximum_length, 0) as length,
+                -- information_schema leaves them NULL for arrays. numeric[] columns keep the
+                -- typmod of the element in pg_attribute
+                coalesce(
+                    c.numeric_precision,
+                    information_schema._pg_numeric_precision('numeric'::regtype, pga.atttypmod),
+                    0
+                )::int4 as numeric_precision,
+                coalesce(
+                    c.numeric_scale,
+                    information_schema._pg_numeric_scale('numeric'::regtype, pga.atttypmod),
+                    0
+                )::int4 as numeric_scale,
                 c.is_nullable = 'YES' as nullable,
                 EXISTS(
                     SELECT 1
@@ -713,13 +990,20 @@ impl PostgresConnection {
                         information_schema.key_column_usage kcu
                         ON tc.constraint_name = kcu.constraint_name
                         AND tc.table_schema = kcu.table_schema
+                        AND tc.table_name = kcu.table_name
                     WHERE 1=1
                         AND tc.constraint_type = 'PRIMARY KEY'
                         AND tc.table_schema = c.table_schema
                         AND tc.table_name = c.table_name
                         AND kcu.column_name = c.column_name
                 ) as is_primary_key,
-                coalesce(pgd.description, '') as comment
+                coalesce(pgd.description, '') as comment,
+                EXISTS(
+                    SELECT 1
+                    FROM pg_catalog.pg_type pt
+                    JOIN pg_catalog.pg_namespace ptn ON ptn.oid = pt.typnamespace
+                    WHERE pt.typname = c.udt_name AND ptn.nspname = c.udt_schema AND pt.typtype = 'e'
+                ) as is_enum
             FROM
                 information_schema.columns c
             LEFT JOIN
@@ -727,9 +1011,21 @@ impl PostgresConnection {
             ON pgd.objsubid = c.ordinal_position
             AND
                 pgd.objoid = (
-                    SELECT oid
-                    FROM pg_catalog.pg_class
-                    WHERE relname = c.table_name
+                    SELECT pc.oid
+                    FROM pg_catalog.pg_class pc
+                    JOIN pg_catalog.pg_namespace pn ON pn.oid = pc.relnamespace
+                    WHERE pc.relname = c.table_name AND pn.nspname = c.table_schema
+                )
+            LEFT JOIN
+                pg_catalog.pg_attribute pga
+            ON c.udt_name = '_numeric'
+            AND pga.attname = c.column_name
+            AND
+                pga.attrelid = (
+                    SELECT pc.oid
+                    FROM pg_catalog.pg_class pc
+                    JOIN pg_catalog.pg_namespace pn ON pn.oid = pc.relnamespace
+                    WHERE pc.relname = c.table_name AND pn.nspname = c.table_schema
                 )
             WHERE c.table_name = $1 AND c.table_schema = $2
             ORDER BY c.ordinal_position ASC
@@ -788,16 +1084,22 @@ impl PostgresConnection {
         &self,
         publication_name: &str,
         replication_slot_name: &str,
+        upto_lsn: Option<u64>,
         limit: u64, // recommendation: 65536
     ) -> errors::Result<Vec<PeekWalChangeResult>> {
         log::debug!(
-            "Peeking WAL changes for publication: {publication_name}, slot: {replication_slot_name}, limit: {limit}"
+            "Peeking WAL changes for publication: {publication_name}, slot: {replication_slot_name}, upto_lsn: {upto_lsn:?}, limit: {limit}"
         );
 
+        let upto_lsn = match upto_lsn {
+            Some(lsn) => format!("'{}'", format_lsn(lsn)),
+            None => "NULL".to_string(),
+        };
+
         let rows: Vec<PeekWalChangeResult> = sqlx::query_as(
             format!(r#"
                 SELECT lsn::text as lsn, xid::text, data
-		        FROM pg_logical_slot_peek_binary_changes('{replication_slot_name}', NULL, {limit}, 'proto_version', '1', 'publication_names', '{publication_name}')
+		        FROM pg_logical_slot_peek_binary_changes('{replication_slot_name}', {upto_lsn}, {limit}, 'proto_version', '1', 'publication_names', '{publication_name}')
             "#,
         )
         .as_str(),
@@ -811,6 +1113,24 @@ impl PostgresConnection {
         Ok(rows)
     }
 
+    /// WAL bytes between the current WAL position and what the slot has confirmed
+    pub async fn get_replication_lag_bytes(
+        &self,
+        replication_slot_name: &str,
+    ) -> errors::Result<i64> {
+        let (lag_bytes,): (i64,) = sqlx::query_as(
+            "SELECT coalesce(pg_wal_lsn_diff(pg_current_wal_lsn(), confirmed_flush_lsn), 0)::int8 FROM pg_replication_slots WHERE slot_name = $1",
+        )
+        .bind(replication_slot_name)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| {
+            errors::Errors::ReplicationNotFound(format!("Failed to get replication lag: {e}"))
+        })?;
+
+        Ok(lag_bytes)
+    }
+
     pub async fn advance_replication_slot(
         &self,
         replication_slot_name: &str,
@@ -833,9 +1153,39 @@ impl PostgresConnection {
         &self,
         schema_name: &str,
         table_name: &str,
-    ) -> errors::Result<tokio::sync::mpsc::Receiver<Vec<PostgresCopyRow>>> {
+        max_in_flight_bytes: Option<usize>,
+    ) -> errors::Result<CopyReceiver> {
         let query = format!("COPY (SELECT * FROM {schema_name}.{table_name}) TO STDOUT");
 
+        self.copy_query_to_stdout(query, table_name, max_in_flight_bytes)
+            .await
+    }
+
+    /// Same as `copy_table_to_stdout`, but only with `column_names` (in that order).
+    pub async fn copy_columns_to_stdout(
+        &self,
+        schema_name: &str,
+        table_name: &str,
+        column_names: &[&str],
+        max_in_flight_bytes: Option<usize>,
+    ) -> errors::Result<CopyReceiver> {
+        let columns = column_names
+            .iter()
+            .map(|name| format!("\"{}\"", name.replace('"', "\"\"")))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let query = format!("COPY (SELECT {columns} FROM {schema_name}.{table_name}) TO STDOUT");
+
+        self.copy_query_to_stdout(query, table_name, max_in_flight_bytes)
+            .await
+    }
+
+    async fn copy_query_to_stdout(
+        &self,
+        query: String,
+        table_name: &str,
+        max_in_flight_bytes: Option<usize>,
+    ) -> errors::Result<CopyReceiver> {
         log::debug!("Executing COPY TO STDOUT query: {query}");
 
         let connection_string = self.config.connection_string();
@@ -863,7 +1213,7 @@ impl PostgresConnection {
             ))
         })?;
 
-        let (sender, receiver) = tokio::sync::mpsc::channel(10000);
+        let (sender, receiver) = copy_buffer::copy_channel(max_in_flight_bytes);
 
         let table_name = table_name.to_string();
 
@@ -895,7 +1245,7 @@ impl PostgresConnection {
                 );
 
                 sender
-                    .send(rows)
+                    .send(rows, bytes.len())
                     .await
                     .map_err(|e| {
                         errors::Errors::CopyTableFailed(format!(
@@ -910,10 +1260,53 @@ impl PostgresConnection {
     }
 }
 
+/// `CREATE PUBLICATION` for the tables.
+/// With `publish_via_partition_root`, changes of a partitioned table are published under the
+/// identity of the partitioned table instead of the partition they were written to.
+pub fn create_publication_query(
+    publication_name: &str,
+    table_names: &[String],
+    publish_via_partition_root: bool,
+) -> String {
+    let mut query = format!(
+        "CREATE PUBLICATION {} FOR TABLE {}",
+        publication_name,
+        table_names.join(", ")
+    );
+
+    if publish_via_partition_root {
+        query.push_str(" WITH (publish_via_partition_root = true)");
+    }
+
+    query
+}
+
+/// Parses a textual LSN (e.g. `16/B374D848`) into its 64-bit WAL position.
+pub fn parse_lsn(lsn: &str) -> errors::Result<u64> {
+    let Some((high, low)) = lsn.trim().split_once('/') else {
+        return Err(errors::Errors::LsnParseError(format!(
+            "LSN must be in the form XXX/XXX: {lsn}"
+        )));
+    };
+
+    let high = u32::from_str_radix(high, 16)
+        .map_err(|e| errors::Errors::LsnParseError(format!("Invalid LSN {lsn}: {e}")))?;
+    let low = u32::from_str_radix(low, 16)
+        .map_err(|e| errors::Errors::LsnParseError(format!("Invalid LSN {lsn}: {e}")))?;
+
+    Ok(((high as u64) << 32) | low as u64)
+}
+
+pub fn format_lsn(lsn: u64) -> String {
+    format!("{:X}/{:X}", lsn >> 32, lsn & 0xFFFF_FFFF)
+}
+
 #[cfg(test)]
 mod tests {
-    use super::PostgresConnection;
-    use crate::adapter::postgres::pgoutput::PgOutputValue;
+    use super::{
+        PostgresColumn, PostgresConnection, create_publication_query, format_lsn, parse_lsn,
+    };
+    use crate::adapter::{postgres::pgoutput::PgOutputValue, tests::postgres_column};
 
     fn decode_copy_text_field_before_fix(input: &str) -> String {
         input.to_string()
@@ -1095,4 +1488,286 @@ mod tests {
             PgOutputValue::Text(value) if value == "1"
         ));
     }
+
+    #[test]
+    fn parse_copy_chunks_keeps_escaped_tabs_and_newlines_in_one_field() {
+        // COPY (SELECT E'line1\nline2', E'a\tb', E'back\\slash', '\N', NULL, E'cr\r') TO STDOUT,
+        // followed by a second row split across chunks
+        let rows = PostgresConnection::parse_copy_chunks(&[
+            b"line1\\nline2\ta\\tb\tback\\\\slash\t\\\\N\t\\N\tcr\\r\n2\t\\",
+            b"N\n",
+        ]);
+
+        assert_eq!(rows.len(), 2);
+
+        let texts = rows[0]
+            .columns
+            .iter()
+            .map(|value| value.text_ref_or("NULL"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            texts,
+            ["line1\nline2", "a\tb", "back\\slash", "\\N", "NULL", "cr\r"]
+        );
+
+        assert_eq!(rows[1].columns.len(), 2);
+        assert!(matches!(rows[1].columns[1], PgOutputValue::Null));
+    }
+
+    #[test]
+    fn parse_lsn_round_trips() {
+        let lsn = parse_lsn("16/B374D848").unwrap();
+
+        assert_eq!(lsn, (0x16 << 32) | 0xB374D848);
+        assert_eq!(format_lsn(lsn), "16/B374D848");
+        assert_eq!(parse_lsn("0/0").unwrap(), 0);
+    }
+
+    #[test]
+    fn parse_lsn_rejects_malformed_input() {
+        assert!(parse_lsn("16B374D848").is_err());
+        assert!(parse_lsn("0/'; DROP TABLE x; --").is_err());
+    }
+
+    #[test]
+    fn publish_via_partition_root_is_set_on_created_publication() {
+        let tables = vec!["public.events".to_string(), "public.users".to_string()];
+
+        assert_eq!(
+            create_publication_query("clockpipe_publication", &tables, false),
+            "CREATE PUBLICATION clockpipe_publication FOR TABLE public.events, public.users"
+        );
+        assert_eq!(
+            create_publication_query("clockpipe_publication", &tables, true),
+            "CREATE PUBLICATION clockpipe_publication FOR TABLE public.events, public.users WITH (publish_via_partition_root = true)"
+        );
+    }
+
+    #[test]
+    fn oid_maps_to_uint32() {
+        use crate::adapter::{IntoClickhouseColumn, clickhouse::ClickhouseColumn};
+
+        let column = postgres_column(1, "large_object", "oid", false, false);
+
+        let clickhouse_type = column.to_clickhouse_type().to_type_text();
+        assert_eq!(clickhouse_type, "UInt32");
+
+        let clickhouse_column = ClickhouseColumn {
+            column_index: 1,
+            column_name: "large_object".to_string(),
+            data_type: clickhouse_type,
+            is_in_primary_key: false,
+            comment: String::new(),
+        };
+        assert_eq!(
+            clickhouse_column.to_clickhouse_value(PgOutputValue::Text("4294967295".to_string())),
+            "4294967295"
+        );
+    }
+
+    #[test]
+    fn int_column_overridden_to_uint64() {
+        use crate::{
+            adapter::{IntoClickhouseColumn, clickhouse::ClickhouseColumn},
+            config::ColumnTypeOverride,
+        };
+
+        let column = PostgresColumn {
+            type_override: Some(ColumnTypeOverride::UInt64),
+            ..postgres_column(1, "view_count", "int8", true, false)
+        };
+
+        let clickhouse_type = column.to_clickhouse_type().to_type_text();
+        assert_eq!(clickhouse_type, "Nullable(UInt64)");
+
+        let clickhouse_column = ClickhouseColumn {
+            column_index: 1,
+            column_name: "view_count".to_string(),
+            data_type: clickhouse_type,
+            is_in_primary_key: false,
+            comment: String::new(),
+        };
+        assert_eq!(
+            clickhouse_column.to_clickhouse_value(PgOutputValue::Text("42".to_string())),
+            "42"
+        );
+        assert_eq!(
+            clickhouse_column.to_clickhouse_value(PgOutputValue::Text("-3".to_string())),
+            "0"
+        );
+        assert_eq!(
+            clickhouse_column.to_clickhouse_value(PgOutputValue::Null),
+            "NULL"
+        );
+
+        // An int2 value beyond a narrower unsigned type
+        let clickhouse_column = ClickhouseColumn {
+            data_type: "Nullable(UInt8)".to_string(),
+            ..clickhouse_column
+        };
+        assert_eq!(
+            clickhouse_column.to_clickhouse_value(PgOutputValue::Text("300".to_string())),
+            "255"
+        );
+        assert_eq!(
+            clickhouse_column.to_clickhouse_value(PgOutputValue::Text("200".to_string())),
+            "200"
+        );
+    }
+
+    #[test]
+    fn bool_column_overridden_to_uint8_is_written_as_0_or_1() {
+        use crate::{
+            adapter::{IntoClickhouseColumn, clickhouse::ClickhouseColumn},
+            config::ColumnTypeOverride,
+        };
+
+        let column = PostgresColumn {
+            type_override: Some(ColumnTypeOverride::UInt8),
+            ..postgres_column(1, "is_active", "bool", false, false)
+        };
+
+        let clickhouse_type = column.to_clickhouse_type().to_type_text();
+        assert_eq!(clickhouse_type, "UInt8");
+
+        let clickhouse_column = ClickhouseColumn {
+            column_index: 1,
+            column_name: "is_active".to_string(),
+            data_type: clickhouse_type,
+            is_in_primary_key: false,
+            comment: String::new(),
+        };
+        assert_eq!(
+            clickhouse_column.to_clickhouse_value(PgOutputValue::Text("t".to_string())),
+            "1"
+        );
+        assert_eq!(
+            clickhouse_column.to_clickhouse_value(PgOutputValue::Text("f".to_string())),
+            "0"
+        );
+        assert!(!column.supports_type_override(ColumnTypeOverride::UInt16));
+    }
+
+    #[test]
+    fn numeric_columns_map_to_decimal_with_their_precision_and_scale() {
+        use crate::adapter::{IntoClickhouseColumn, clickhouse::ClickhouseColumn};
+
+        let column = |precision: i32, scale: i32| PostgresColumn {
+            numeric_precision: precision,
+            numeric_scale: scale,
+            ..postgres_column(1, "amount", "numeric", false, false)
+        };
+        let type_text = |column: PostgresColumn| column.to_clickhouse_type().to_type_text();
+
+        assert_eq!(type_text(column(12, 4)), "Decimal(12, 4)");
+        // numeric without precision
+        assert_eq!(type_text(column(0, 0)), "Decimal(38, 9)");
+        assert_eq!(type_text(column(100, 2)), "Decimal(76, 2)");
+        // numeric(5, -2), PostgreSQL 15+
+        assert_eq!(type_text(column(5, -2)), "Decimal(7, 0)");
+        assert_eq!(type_text(column(76, -3)), "Decimal(76, 0)");
+        assert_eq!(
+            type_text(PostgresColumn {
+                nullable: true,
+                ..column(10, 2)
+            }),
+            "Nullable(Decimal(10, 2))"
+        );
+
+        // Written from the text of the value, not through a float
+        let clickhouse_column = ClickhouseColumn {
+            column_index: 1,
+            column_name: "amount".to_string(),
+            data_type: type_text(column(0, 0)),
+            is_in_primary_key: false,
+            comment: String::new(),
+        };
+        assert_eq!(
+            clickhouse_column
+                .to_clickhouse_value(PgOutputValue::Text("123456789.123456789".to_string())),
+            "'123456789.123456789'"
+        );
+    }
+
+    #[test]
+    fn numeric_array_elements_keep_their_precision_and_scale() {
+        use crate::adapter::{IntoClickhouseColumn, clickhouse::ClickhouseColumn};
+
+        let column = |precision: i32, scale: i32| PostgresColumn {
+            numeric_precision: precision,
+            numeric_scale: scale,
+            ..postgres_column(1, "prices", "_numeric", true, false)
+        };
+
+        let type_text = column(18, 4).to_clickhouse_type().to_type_text();
+        assert_eq!(type_text, "Array(Decimal(18, 4))");
+        // numeric[] without precision
+        assert_eq!(
+            column(0, 0).to_clickhouse_type().to_type_text(),
+            "Array(Decimal(38, 9))"
+        );
+
+        // The digits of the elements are written as they are, not through a float
+        let clickhouse_column = ClickhouseColumn {
+            column_index: 1,
+            column_name: "prices".to_string(),
+            data_type: type_text,
+            is_in_primary_key: false,
+            comment: String::new(),
+        };
+        assert_eq!(
+            clickhouse_column.to_clickhouse_value(PgOutputValue::Text(
+                "{12345678901234.5678,0.0001}".to_string()
+            )),
+            "[12345678901234.5678,0.0001]"
+        );
+    }
+
+    #[test]
+    fn text_and_enum_columns_map_to_low_cardinality() {
+        use crate::{
+            adapter::{IntoClickhouseColumn, clickhouse::ClickhouseColumn},
+            config::ColumnTypeOverride,
+        };
+
+        let column = |data_type: &str, nullable: bool, is_enum: bool| PostgresColumn {
+            is_enum,
+            type_override: Some(ColumnTypeOverride::LowCardinality),
+            ..postgres_column(1, "status", data_type, nullable, false)
+        };
+
+        assert_eq!(
+            column("varchar", false, false)
+                .to_clickhouse_type()
+                .to_type_text(),
+            "LowCardinality(String)"
+        );
+        // CREATE TYPE order_status AS ENUM (...), detected without an override
+        let enum_column = PostgresColumn {
+            type_override: None,
+            ..column("order_status", true, true)
+        };
+        let clickhouse_type = enum_column.to_clickhouse_type().to_type_text();
+        assert_eq!(clickhouse_type, "LowCardinality(Nullable(String))");
+
+        // Not a text column: the override is ignored
+        let int_column = column("int4", false, false);
+        assert!(!int_column.supports_type_override(ColumnTypeOverride::LowCardinality));
+
+        let clickhouse_column = ClickhouseColumn {
+            column_index: 1,
+            column_name: "status".to_string(),
+            data_type: clickhouse_type,
+            is_in_primary_key: false,
+            comment: String::new(),
+        };
+        assert_eq!(
+            clickhouse_column.to_clickhouse_value(PgOutputValue::Text("shipped".to_string())),
+            "'shipped'"
+        );
+        assert_eq!(
+            clickhouse_column.to_clickhouse_value(PgOutputValue::Null),
+            "NULL"
+        );
+    }
 }
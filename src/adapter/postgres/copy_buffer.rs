@@ -0,0 +1,183 @@
+use std::sync::Arc;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, mpsc};
+
+use crate::adapter::postgres::PostgresCopyRow;
+
+/// Number of parsed chunks the channel holds, whatever their size
+const CHANNEL_CAPACITY: usize = 10000;
+
+/// Channel between the COPY reader and the inserts into ClickHouse.
+/// With `max_in_flight_bytes`, each chunk holds its size in bytes of a shared budget until the
+/// consumer drops it, i.e. after the rows were inserted. The reader waits while the budget is
+/// used up, so a slow ClickHouse bounds the memory of the copy instead of the rows piling up.
+pub fn copy_channel(max_in_flight_bytes: Option<usize>) -> (CopySender, CopyReceiver) {
+    let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+    let budget = max_in_flight_bytes.map(|max_bytes| {
+        let max_bytes = max_bytes.clamp(2, Semaphore::MAX_PERMITS);
+        (Arc::new(Semaphore::new(max_bytes)), max_bytes)
+    });
+
+    (
+        CopySender {
+            sender,
+            budget: budget.clone(),
+        },
+        CopyReceiver { receiver, budget },
+    )
+}
+
+/// Rows of one COPY chunk. Its bytes count against the budget until it is dropped.
+pub struct CopyChunk {
+    rows: Vec<PostgresCopyRow>,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+/// Received rows that were not inserted yet. They keep their bytes of the budget until `clear`.
+#[derive(Default)]
+pub struct CopyBatch {
+    pub rows: Vec<PostgresCopyRow>,
+    permits: Vec<OwnedSemaphorePermit>,
+}
+
+impl CopyBatch {
+    pub fn push(&mut self, chunk: CopyChunk) {
+        self.rows.extend(chunk.rows);
+        self.permits.extend(chunk.permit);
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.permits.iter().map(|permit| permit.num_permits()).sum()
+    }
+
+    /// Called after the rows were inserted, which lets the reader continue.
+    pub fn clear(&mut self) {
+        self.rows.clear();
+        self.permits.clear();
+    }
+}
+
+pub struct CopySender {
+    sender: mpsc::Sender<CopyChunk>,
+    budget: Option<(Arc<Semaphore>, usize)>,
+}
+
+impl CopySender {
+    /// Waits until `bytes` fit into the budget. A chunk counts as at most half of the budget, so
+    /// that it can always be sent once the consumer has flushed what it holds. The in-flight bytes
+    /// can therefore exceed the budget by what a larger chunk holds beyond that half.
+    pub async fn send(&self, rows: Vec<PostgresCopyRow>, bytes: usize) -> Result<(), String> {
+        let permit = match &self.budget {
+            Some((semaphore, max_bytes)) => {
+                let permits = bytes.clamp(1, max_bytes / 2);
+                let permits = u32::try_from(permits).unwrap_or(u32::MAX);
+
+                Some(
+                    semaphore
+                        .clone()
+                        .acquire_many_owned(permits)
+                        .await
+                        .map_err(|e| e.to_string())?,
+                )
+            }
+            None => None,
+        };
+
+        self.sender
+            .send(CopyChunk { rows, permit })
+            .await
+            .map_err(|e| e.to_string())
+    }
+}
+
+pub struct CopyReceiver {
+    receiver: mpsc::Receiver<CopyChunk>,
+    budget: Option<(Arc<Semaphore>, usize)>,
+}
+
+impl CopyReceiver {
+    pub async fn recv(&mut self) -> Option<CopyChunk> {
+        self.receiver.recv().await
+    }
+
+    /// Whether the batch should be inserted before receiving more, even if it is not full yet.
+    /// Otherwise the reader could wait for bytes that are only released by the insert.
+    pub fn should_flush(&self, batch: &CopyBatch) -> bool {
+        self.budget
+            .as_ref()
+            .is_some_and(|(_, max_bytes)| batch.bytes() >= max_bytes / 2)
+    }
+
+    /// Bytes of the chunks that were sent and not dropped yet
+    pub fn in_flight_bytes(&self) -> usize {
+        self.budget.as_ref().map_or(0, |(semaphore, max_bytes)| {
+            max_bytes - semaphore.available_permits()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{CopyBatch, copy_channel};
+    use crate::adapter::postgres::PostgresCopyRow;
+
+    #[tokio::test]
+    async fn in_flight_bytes_stay_bounded_with_a_slow_consumer() {
+        let (sender, mut receiver) = copy_channel(Some(1000));
+
+        let producer = tokio::spawn(async move {
+            for _ in 0..50 {
+                let rows = vec![PostgresCopyRow { columns: vec![] }];
+                sender.send(rows, 100).await.unwrap();
+            }
+        });
+
+        let mut batch = CopyBatch::default();
+        let mut inserted_rows = 0;
+        let mut max_in_flight_bytes = 0;
+
+        while let Some(chunk) = receiver.recv().await {
+            batch.push(chunk);
+            max_in_flight_bytes = max_in_flight_bytes.max(receiver.in_flight_bytes());
+
+            if receiver.should_flush(&batch) {
+                // A slow insert, the reader has to wait meanwhile
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                inserted_rows += batch.rows.len();
+                batch.clear();
+            }
+        }
+        inserted_rows += batch.rows.len();
+
+        producer.await.unwrap();
+        assert_eq!(inserted_rows, 50);
+        // The reader waited for the inserts instead of reading all 5000 bytes ahead
+        assert!(
+            (500..=1000).contains(&max_in_flight_bytes),
+            "{max_in_flight_bytes}"
+        );
+    }
+
+    #[tokio::test]
+    async fn chunk_larger_than_the_budget_is_not_stuck() {
+        let (sender, mut receiver) = copy_channel(Some(100));
+
+        let producer = tokio::spawn(async move {
+            sender.send(vec![], 10_000).await.unwrap();
+            sender.send(vec![], 10_000).await.unwrap();
+        });
+
+        let mut chunks = 0;
+        while let Some(chunk) = receiver.recv().await {
+            let mut batch = CopyBatch::default();
+            batch.push(chunk);
+            chunks += 1;
+            assert!(receiver.should_flush(&batch));
+        }
+
+        producer.await.unwrap();
+        assert_eq!(chunks, 2);
+    }
+}
@@ -3,7 +3,15 @@ use std::io::Read;
 use byteorder::ReadBytesExt;
 use serde::{Deserialize, Serialize};
 
-use crate::{adapter::IntoClickhouseValue, errors};
+use crate::{
+    adapter::{
+        IntoClickhouseValue,
+        clickhouse::{non_finite_float_literal, plain_decimal_text, uuid_literal},
+        escape_string,
+    },
+    config::BinaryEncoding,
+    errors,
+};
 
 #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 #[repr(u8)]
@@ -66,6 +74,29 @@ pub struct PgOutput {
     pub tuple_type: Option<PgTupleType>,
     pub payload: Vec<PgOutputValue>,
     pub old_values: Option<Vec<PgOutputValue>>,
+    /// 'O' if `old_values` has all columns (REPLICA IDENTITY FULL), 'K' if only the key columns
+    pub old_tuple_type: Option<PgTupleType>,
+}
+
+/// Table as described by a Relation message. pgoutput sends one before the first change of
+/// a table in a decoding session, and again after its schema changed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PgRelation {
+    pub relation_id: u32,
+    pub schema_name: String,
+    pub table_name: String,
+    /// `relreplident`: b'd' default, b'n' nothing, b'f' full, b'i' index
+    pub replica_identity: u8,
+    /// In the order of the values in the tuples of this relation
+    pub columns: Vec<PgRelationColumn>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PgRelationColumn {
+    pub name: String,
+    pub type_oid: u32,
+    /// Part of the replica identity
+    pub is_key: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -82,27 +113,42 @@ impl IntoClickhouseValue for PgOutputValue {
     fn to_integer(self) -> String {
         let text = self.text_or("0".to_string());
         if text.trim().parse::<i64>().is_ok() || text.trim().parse::<u64>().is_ok() {
-            text
-        } else {
-            "0".to_string()
+            return text;
+        }
+
+        // bool columns created as UInt8 (`bool_as_uint8`)
+        match text.as_str() {
+            "t" => "1".to_string(),
+            _ => "0".to_string(),
         }
     }
 
     fn to_real(self) -> String {
         let text = self.text_or("0.0".to_string());
-        if text.trim().parse::<f64>().is_ok() {
-            text
-        } else {
-            "0.0".to_string()
+        match text.trim().parse::<f64>() {
+            Ok(value) => match non_finite_float_literal(value) {
+                Some(literal) => literal.to_string(),
+                None => text,
+            },
+            Err(_) => "0.0".to_string(),
         }
     }
 
+    fn to_decimal(self, scale: u32) -> String {
+        let text = self.text_or("0".to_string());
+        let plain = plain_decimal_text(&text, scale)
+            .or_else(|| plain_decimal_text("0", scale))
+            .unwrap_or_default();
+
+        format!("'{plain}'")
+    }
+
     fn to_bool(self) -> String {
         Self::parse_bool(&self.text_or("false".to_string()))
     }
 
     fn to_string(self) -> String {
-        format!("'{}'", Self::escape_string(&self.text_or("".to_string())))
+        format!("'{}'", escape_string(&self.text_or("".to_string())))
     }
 
     fn to_date(self) -> String {
@@ -126,6 +172,29 @@ impl IntoClickhouseValue for PgOutputValue {
         )
     }
 
+    fn to_bytes_literal(self, encoding: BinaryEncoding) -> String {
+        match self {
+            PgOutputValue::Binary(bytes) => format!("'{}'", encoding.encode(&bytes)),
+            // bytea[] text output, e.g. {"\\x0102","\\x03"}
+            PgOutputValue::Text(text) if text.starts_with('{') => {
+                let items = Self::parse_string_array(&text)
+                    .iter()
+                    .map(|item| format!("'{}'", encoding.encode(&Self::bytea_bytes(item))))
+                    .collect::<Vec<String>>();
+
+                format!("[{}]", items.join(", "))
+            }
+            PgOutputValue::Text(text) => {
+                format!("'{}'", encoding.encode(&Self::bytea_bytes(&text)))
+            }
+            _ => "''".to_string(),
+        }
+    }
+
+    fn to_uuid(self) -> String {
+        uuid_literal(&self.text_or(String::new()))
+    }
+
     fn to_array(self) -> String {
         format!("[{}]", self.array_value().unwrap_or_default(),)
     }
@@ -134,18 +203,44 @@ impl IntoClickhouseValue for PgOutputValue {
         let text = self.array_value().unwrap_or_default();
         let array_values = Self::parse_string_array(&text)
             .into_iter()
-            .map(|s| format!("'{}'", Self::escape_string(&s)))
+            .map(|s| format!("'{}'", escape_string(&s)))
             .collect::<Vec<String>>();
 
         format!("[{}]", array_values.join(", "))
     }
 
     fn is_null(&self) -> bool {
-        matches!(self, PgOutputValue::Null)
+        // An unresolved TOAST value is written like NULL
+        matches!(self, PgOutputValue::Null | PgOutputValue::Unchanged)
+    }
+
+    fn is_unchanged(&self) -> bool {
+        matches!(self, PgOutputValue::Unchanged)
+    }
+
+    fn is_non_finite(&self) -> bool {
+        // numeric/float4/float8 text output: NaN, Infinity, -Infinity
+        match self {
+            PgOutputValue::Text(value) => matches!(
+                value.trim().to_ascii_lowercase().as_str(),
+                "nan" | "infinity" | "-infinity" | "inf" | "-inf"
+            ),
+            _ => false,
+        }
     }
 
     fn unknown_value(self) -> String {
-        format!("'{}'", Self::escape_string(&self.text_or("".to_string())))
+        format!("'{}'", escape_string(&self.text_or("".to_string())))
+    }
+
+    fn to_plain_text(self) -> Option<String> {
+        match self {
+            PgOutputValue::Text(value) => Some(value),
+            PgOutputValue::Binary(bytes) => {
+                Some(bytes.iter().map(|byte| format!("{byte:02x}")).collect())
+            }
+            PgOutputValue::Unit | PgOutputValue::Null | PgOutputValue::Unchanged => None,
+        }
     }
 
     fn into_null(self) -> Self {
@@ -163,7 +258,7 @@ impl PgOutputValue {
     }
 
     pub fn is_null(&self) -> bool {
-        matches!(self, PgOutputValue::Null)
+        matches!(self, PgOutputValue::Null | PgOutputValue::Unchanged)
     }
 
     pub fn text_ref_or(&self, default: &'static str) -> &str {
@@ -192,23 +287,36 @@ impl PgOutputValue {
         }
     }
 
+    /// Bytes of bytea text output in the hex format (`\x0102`, the default `bytea_output`).
+    /// Other text, e.g. in the escape format, is taken as it is.
+    pub fn bytea_bytes(text: &str) -> Vec<u8> {
+        // Array elements keep the backslash escaped
+        let hex = text
+            .strip_prefix(r"\\x")
+            .or_else(|| text.strip_prefix(r"\x"));
+
+        let decoded = hex.filter(|hex| hex.len() % 2 == 0).and_then(|hex| {
+            (0..hex.len())
+                .step_by(2)
+                .map(|index| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok())
+                .collect::<Option<Vec<u8>>>()
+        });
+
+        decoded.unwrap_or_else(|| text.as_bytes().to_vec())
+    }
+
     pub fn parse_string_array(value: &str) -> Vec<String> {
         let value = value.trim_matches(|c| c == '{' || c == '}');
 
+        if value.is_empty() {
+            return vec![];
+        }
+
         let trimmed = value.trim_matches('"');
         let items: Vec<String> = trimmed.split("\",\"").map(|s| s.to_string()).collect();
         items
     }
 
-    pub fn escape_string(input: &str) -> String {
-        input
-            .replace('\'', "''")
-            .replace("\\", "\\\\")
-            .replace('\n', "\\n")
-            .replace('\r', "\\r")
-            .replace('\0', "\\0")
-    }
-
     /*
     다앙한 형태의 datetime 타입을 '2025-08-18 03:56:32' 형태로 변환하는 함수
     입력 예시
@@ -268,6 +376,103 @@ pub fn parse_pg_output(bytes: &[u8]) -> errors::Result<Option<PgOutput>> {
     }
 }
 
+/// Relation message: relation id, namespace, name, replica identity setting and the columns
+/// (flags, name, type oid, type modifier).
+pub fn parse_pg_relation(bytes: &[u8]) -> errors::Result<PgRelation> {
+    let mut cursor = std::io::Cursor::new(bytes.get(1..).unwrap_or_default()); // Skip the message type
+
+    let relation_id = cursor.read_u32::<byteorder::BigEndian>().map_err(|e| {
+        errors::Errors::PgOutputParseError(format!("Failed to read relation ID: {e}"))
+    })?;
+    let schema_name = read_cstring(&mut cursor)?;
+    let table_name = read_cstring(&mut cursor)?;
+
+    let replica_identity = cursor.read_u8().map_err(|e| {
+        errors::Errors::PgOutputParseError(format!("Failed to read replica identity: {e}"))
+    })?;
+
+    let column_count = cursor.read_u16::<byteorder::BigEndian>().map_err(|e| {
+        errors::Errors::PgOutputParseError(format!("Failed to read column count: {e}"))
+    })? as usize;
+
+    let mut columns = Vec::with_capacity(column_count);
+
+    for _ in 0..column_count {
+        let flags = cursor.read_u8().map_err(|e| {
+            errors::Errors::PgOutputParseError(format!("Failed to read column flags: {e}"))
+        })?;
+        let name = read_cstring(&mut cursor)?;
+        let type_oid = cursor.read_u32::<byteorder::BigEndian>().map_err(|e| {
+            errors::Errors::PgOutputParseError(format!("Failed to read column type: {e}"))
+        })?;
+        let _type_modifier = cursor.read_i32::<byteorder::BigEndian>().map_err(|e| {
+            errors::Errors::PgOutputParseError(format!("Failed to read column type modifier: {e}"))
+        })?;
+
+        columns.push(PgRelationColumn {
+            name,
+            type_oid,
+            is_key: flags & 1 == 1,
+        });
+    }
+
+    Ok(PgRelation {
+        relation_id,
+        schema_name,
+        table_name,
+        replica_identity,
+        columns,
+    })
+}
+
+/// Ids of the relations in a Truncate message. One TRUNCATE statement of several tables
+/// (or of a table with `CASCADE`) is sent as one message.
+pub fn parse_pg_truncate(bytes: &[u8]) -> errors::Result<Vec<u32>> {
+    let mut cursor = std::io::Cursor::new(bytes.get(1..).unwrap_or_default()); // Skip the message type
+
+    let numbers_of_relation = cursor.read_u32::<byteorder::BigEndian>().map_err(|e| {
+        errors::Errors::PgOutputParseError(format!("Failed to read number of relations: {e}"))
+    })?;
+
+    if numbers_of_relation == 0 {
+        return Err(errors::Errors::PgOutputParseError(
+            "Truncate message with zero relations is invalid".to_string(),
+        ));
+    }
+
+    // CASCADE / RESTART IDENTITY
+    let _options = cursor.read_u8().map_err(|e| {
+        errors::Errors::PgOutputParseError(format!("Failed to read truncate options: {e}"))
+    })?;
+
+    (0..numbers_of_relation)
+        .map(|_| {
+            cursor.read_u32::<byteorder::BigEndian>().map_err(|e| {
+                errors::Errors::PgOutputParseError(format!("Failed to read relation ID: {e}"))
+            })
+        })
+        .collect()
+}
+
+fn read_cstring(cursor: &mut std::io::Cursor<&[u8]>) -> errors::Result<String> {
+    let mut buffer = vec![];
+
+    loop {
+        let byte = cursor
+            .read_u8()
+            .map_err(|e| errors::Errors::PgOutputParseError(format!("Unterminated string: {e}")))?;
+
+        if byte == 0 {
+            break;
+        }
+
+        buffer.push(byte);
+    }
+
+    String::from_utf8(buffer)
+        .map_err(|e| errors::Errors::PgOutputParseError(format!("Invalid UTF-8 in string: {e}")))
+}
+
 fn read_tuple(cursor: &mut std::io::Cursor<&[u8]>) -> errors::Result<Vec<PgOutputValue>> {
     let column_count = cursor.read_u16::<byteorder::BigEndian>().map_err(|e| {
         errors::Errors::PgOutputParseError(format!(
@@ -348,6 +553,7 @@ fn parse_pg_output_write(message_type: MessageType, bytes: &[u8]) -> errors::Res
         tuple_type: None,
         payload: Vec::new(),
         old_values: None,
+        old_tuple_type: None,
     };
 
     match message_type {
@@ -403,6 +609,7 @@ fn parse_pg_output_write(message_type: MessageType, bytes: &[u8]) -> errors::Res
                 }
                 pg_output.tuple_type = Some(new_tuple_type);
                 pg_output.old_values = Some(old_values);
+                pg_output.old_tuple_type = Some(tuple_type);
             } else {
                 pg_output.tuple_type = Some(tuple_type);
             }
@@ -423,23 +630,8 @@ fn parse_pg_output_write(message_type: MessageType, bytes: &[u8]) -> errors::Res
             })?);
         }
         MessageType::Truncate => {
-            let numbers_of_relation = cursor.read_u32::<byteorder::BigEndian>().map_err(|e| {
-                errors::Errors::PgOutputParseError(format!("Failed to read truncate flags: {e}"))
-            })?;
-
-            if numbers_of_relation == 0 {
-                return Err(errors::Errors::PgOutputParseError(
-                    "Truncate message with zero relations is invalid".to_string(),
-                ));
-            }
-
-            let _ = cursor.read_u8().map_err(|e| {
-                errors::Errors::PgOutputParseError(format!("Failed to eat first byte: {e}"))
-            })?;
-
-            pg_output.relation_id = cursor.read_u32::<byteorder::BigEndian>().map_err(|e| {
-                errors::Errors::PgOutputParseError(format!("Failed to read relation ID: {e}"))
-            })?;
+            // The first of the truncated relations. See parse_pg_truncate for all of them
+            pg_output.relation_id = parse_pg_truncate(bytes)?[0];
 
             // No tuple data for truncate
             return Ok(pg_output);
@@ -514,42 +706,34 @@ fn parse_pg_output_write(message_type: MessageType, bytes: &[u8]) -> errors::Res
         }
     }
 
-    // Fill Unchanged columns from old_values (TOAST fallback)
-    if let Some(old_values) = &pg_output.old_values {
-        for (i, value) in pg_output.payload.iter_mut().enumerate() {
-            if matches!(value, PgOutputValue::Unchanged) {
-                if let Some(old_value) = old_values.get(i) {
-                    *value = old_value.clone();
-                } else {
-                    log::warn!(
-                        "TOAST: Unchanged column at index {i} could not be resolved from old_values (relation_id={})",
-                        pg_output.relation_id
-                    );
-                    *value = PgOutputValue::Null;
-                }
-            }
+    // Fill Unchanged columns from old_values (TOAST fallback).
+    // Only an 'O' tuple (REPLICA IDENTITY FULL) has the values of non-key columns. A 'K' tuple
+    // has NULL there, which must not replace the stored value.
+    // Unresolved columns stay Unchanged, so that the stored value is kept in ClickHouse.
+    let old_values = pg_output.old_values.as_deref().unwrap_or_default();
+    let mut unresolved = vec![];
+
+    for (i, value) in pg_output.payload.iter_mut().enumerate() {
+        if !matches!(value, PgOutputValue::Unchanged) {
+            continue;
         }
-    } else {
-        let unresolved: Vec<usize> = pg_output
-            .payload
-            .iter()
-            .enumerate()
-            .filter(|(_, v)| matches!(v, PgOutputValue::Unchanged))
-            .map(|(i, _)| i)
-            .collect();
-
-        if !unresolved.is_empty() {
-            log::warn!(
-                "TOAST: Unchanged columns at indexes {:?} could not be resolved — no old_values available (relation_id={}). Consider enabling REPLICA IDENTITY FULL. Falling back to NULL.",
-                unresolved,
-                pg_output.relation_id
-            );
-            for i in unresolved {
-                pg_output.payload[i] = PgOutputValue::Null;
+
+        match old_values.get(i) {
+            Some(old_value @ (PgOutputValue::Text(_) | PgOutputValue::Binary(_))) => {
+                *value = old_value.clone();
             }
+            _ => unresolved.push(i),
         }
     }
 
+    if !unresolved.is_empty() {
+        log::debug!(
+            "TOAST: Unchanged columns at indexes {:?} are not in the old tuple (relation_id={}). Their stored values are kept",
+            unresolved,
+            pg_output.relation_id
+        );
+    }
+
     Ok(pg_output)
 }
 
@@ -583,6 +767,10 @@ mod tests {
                     "White stripes on collar, cuffs, and hem".to_string(),
                 ],
             },
+            TestCase {
+                input: "{}",
+                expected: vec![],
+            },
         ];
 
         for test_case in test_cases {
@@ -626,4 +814,117 @@ mod tests {
             );
         }
     }
+
+    /// Tuple data: number of columns, then 'n' (NULL), 'u' (unchanged TOAST) or 't' + text
+    fn tuple(values: &[Option<&str>]) -> Vec<u8> {
+        let mut bytes = (values.len() as u16).to_be_bytes().to_vec();
+
+        for value in values {
+            match value {
+                None => bytes.push(b'n'),
+                Some("u") => bytes.push(b'u'),
+                Some(text) => {
+                    bytes.push(b't');
+                    bytes.extend((text.len() as u32).to_be_bytes());
+                    bytes.extend(text.as_bytes());
+                }
+            }
+        }
+
+        bytes
+    }
+
+    #[test]
+    fn update_with_unchanged_toast_column_keeps_it_unchanged_unless_the_old_tuple_has_it() {
+        use super::{MessageType, parse_pg_output};
+
+        // UPDATE documents SET title = 'new' WHERE id = 1, with a TOASTed body
+        let update = |old_tuple: Option<(u8, Vec<u8>)>| {
+            let mut bytes = vec![b'U'];
+            bytes.extend(16384u32.to_be_bytes());
+            if let Some((tuple_type, old_tuple)) = old_tuple {
+                bytes.push(tuple_type);
+                bytes.extend(old_tuple);
+            }
+            bytes.push(b'N');
+            bytes.extend(tuple(&[Some("1"), Some("new"), Some("u")]));
+
+            parse_pg_output(&bytes).unwrap().unwrap()
+        };
+
+        // REPLICA IDENTITY DEFAULT, primary key not changed: no old tuple
+        let output = update(None);
+        assert_eq!(output.message_type, MessageType::Update);
+        assert!(matches!(
+            output.payload.as_slice(),
+            [
+                PgOutputValue::Text(id),
+                PgOutputValue::Text(title),
+                PgOutputValue::Unchanged
+            ] if id == "1" && title == "new"
+        ));
+
+        // Primary key changed: the key tuple has NULL for the other columns, which is not the body
+        let output = update(Some((b'K', tuple(&[Some("0"), None, None]))));
+        assert!(matches!(output.payload[2], PgOutputValue::Unchanged));
+
+        // REPLICA IDENTITY FULL: the old tuple has the body
+        let output = update(Some((
+            b'O',
+            tuple(&[Some("1"), Some("old"), Some("long body")]),
+        )));
+        assert!(matches!(&output.payload[2], PgOutputValue::Text(body) if body == "long body"));
+    }
+
+    #[test]
+    fn truncate_message_lists_all_truncated_relations() {
+        use super::{MessageType, parse_pg_output, parse_pg_truncate};
+
+        // TRUNCATE accounts, orders
+        let mut bytes = vec![b'T'];
+        bytes.extend(2u32.to_be_bytes());
+        bytes.push(0);
+        bytes.extend(16384u32.to_be_bytes());
+        bytes.extend(16390u32.to_be_bytes());
+
+        assert_eq!(parse_pg_truncate(&bytes).unwrap(), [16384, 16390]);
+
+        let output = parse_pg_output(&bytes).unwrap().unwrap();
+        assert_eq!(output.message_type, MessageType::Truncate);
+        assert_eq!(output.relation_id, 16384);
+
+        // Cut off after the first relation
+        assert!(parse_pg_truncate(&bytes[..10]).is_err());
+    }
+
+    #[test]
+    fn relation_message_lists_the_columns_in_tuple_order() {
+        use super::parse_pg_relation;
+
+        let mut bytes = vec![b'R'];
+        bytes.extend(16384u32.to_be_bytes());
+        bytes.extend(b"public\0documents\0");
+        bytes.push(b'd'); // REPLICA IDENTITY DEFAULT
+        bytes.extend(2u16.to_be_bytes());
+        for (flags, name, type_oid) in [(1u8, "id", 20u32), (0, "title", 25)] {
+            bytes.push(flags);
+            bytes.extend(name.as_bytes());
+            bytes.push(0);
+            bytes.extend(type_oid.to_be_bytes());
+            bytes.extend((-1i32).to_be_bytes());
+        }
+
+        let relation = parse_pg_relation(&bytes).unwrap();
+
+        assert_eq!(relation.relation_id, 16384);
+        assert_eq!(relation.schema_name, "public");
+        assert_eq!(relation.table_name, "documents");
+
+        let columns = relation
+            .columns
+            .iter()
+            .map(|column| (column.name.as_str(), column.type_oid, column.is_key))
+            .collect::<Vec<_>>();
+        assert_eq!(columns, [("id", 20, true), ("title", 25, false)]);
+    }
 }
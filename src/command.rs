@@ -10,6 +10,11 @@ pub struct Command {
 #[derive(clap::Subcommand, Debug)]
 pub enum SubCommand {
     Run(run::Command),
+    ReplayWal(replay_wal::Command),
+    DecodeWal(decode_wal::Command),
+    BackfillColumn(backfill_column::Command),
+    PrintCreate(print_create::Command),
+    SelfTest(self_test::Command),
 }
 
 pub mod run {
@@ -54,3 +59,119 @@ pub mod run {
         pub value: ConfigOptions,
     }
 }
+
+pub mod replay_wal {
+    use clap::Args;
+
+    use crate::command::run::ConfigOptions;
+
+    #[derive(Clone, Debug, Args)]
+    #[clap(
+        name = "replay-wal",
+        about = "Print decoded WAL changes in an LSN range without advancing the slot or writing to ClickHouse"
+    )]
+    pub struct Command {
+        #[clap(flatten)]
+        pub value: ConfigOptions,
+
+        #[clap(long, help = "skip changes before this LSN (e.g. 16/B374D848)")]
+        pub start_lsn: Option<String>,
+
+        #[clap(long, help = "stop reading at this LSN (e.g. 16/B374DA00)")]
+        pub end_lsn: Option<String>,
+
+        #[clap(
+            long,
+            default_value_t = 1000,
+            help = "maximum number of changes to read"
+        )]
+        pub limit: u64,
+    }
+}
+
+pub mod decode_wal {
+    use clap::Args;
+
+    use crate::command::run::ConfigOptions;
+
+    #[derive(Clone, Debug, Args)]
+    #[clap(
+        name = "decode-wal",
+        about = "Print the next WAL changes decoded, without advancing the slot or writing to ClickHouse"
+    )]
+    pub struct Command {
+        #[clap(flatten)]
+        pub value: ConfigOptions,
+
+        #[clap(long, default_value_t = 100, help = "number of changes to peek")]
+        pub limit: u64,
+    }
+}
+
+pub mod backfill_column {
+    use clap::Args;
+
+    use crate::command::run::ConfigOptions;
+
+    #[derive(Clone, Debug, Args)]
+    #[clap(
+        name = "backfill-column",
+        about = "Fill one column of the rows already in ClickHouse from the Postgres source table, without copying the whole table again"
+    )]
+    pub struct Command {
+        #[clap(flatten)]
+        pub value: ConfigOptions,
+
+        #[clap(long, help = "configured table (schema_name.table_name or table_name)")]
+        pub table: String,
+
+        #[clap(long, help = "column to backfill")]
+        pub column: String,
+
+        #[clap(
+            long,
+            default_value_t = 1000,
+            help = "number of rows updated per ClickHouse mutation"
+        )]
+        pub batch_size: usize,
+    }
+}
+
+pub mod print_create {
+    use clap::Args;
+
+    use crate::command::run::ConfigOptions;
+
+    #[derive(Clone, Debug, Args)]
+    #[clap(
+        name = "print-create",
+        about = "Print the ClickHouse CREATE TABLE queries for the configured tables without touching ClickHouse"
+    )]
+    pub struct Command {
+        #[clap(flatten)]
+        pub value: ConfigOptions,
+
+        #[clap(
+            long,
+            default_value_t = 1000,
+            help = "(MongoDB) number of documents per collection to infer columns from"
+        )]
+        pub sample_size: i64,
+    }
+}
+
+pub mod self_test {
+    use clap::Args;
+
+    use crate::command::run::ConfigOptions;
+
+    #[derive(Clone, Debug, Args)]
+    #[clap(
+        name = "self-test",
+        about = "Create a temporary canary table in each ClickHouse target, insert a row, read it back and drop the table"
+    )]
+    pub struct Command {
+        #[clap(flatten)]
+        pub value: ConfigOptions,
+    }
+}
@@ -1,5 +1,10 @@
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    adapter::clickhouse::{IS_DELETED_COLUMN, VERSION_COLUMN},
+    errors,
+};
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Configuraion {
     pub source: Source,
@@ -11,16 +16,100 @@ pub struct Configuraion {
     pub sleep_millis_when_peek_is_empty: u64,
     #[serde(default = "default::sleep_millis_when_write_failed")]
     pub sleep_millis_when_write_failed: u64,
+    #[serde(default = "default::sleep_millis_when_write_blocked")]
+    pub sleep_millis_when_write_blocked: u64,
     #[serde(default = "default::sleep_millis_after_sync_iteration")]
     pub sleep_millis_after_sync_iteration: u64,
     #[serde(default = "default::sleep_millis_after_sync_write")]
     pub sleep_millis_after_sync_write: u64,
     #[serde(default = "default::peek_changes_limit")]
     pub peek_changes_limit: u64,
+    #[serde(default = "default::min_peek_changes_limit")]
+    pub min_peek_changes_limit: u64,
+    #[serde(default = "default::target_write_millis_per_iteration")]
+    pub target_write_millis_per_iteration: u64,
     #[serde(default = "default::peek_changes_timeout_millis")]
     pub peek_changes_timeout_millis: u64,
     #[serde(default = "default::copy_batch_size")]
     pub copy_batch_size: usize,
+    /// Approximate upper bound of the copied bytes that are read from Postgres but not inserted
+    /// yet. A chunk of the COPY stream counts as at most half of it, so a larger chunk can exceed
+    /// the bound by the difference
+    #[serde(default)]
+    pub copy_max_in_flight_bytes: Option<usize>,
+    /// CREATE/ALTER queries are only logged, not run. The tables and columns must already exist
+    #[serde(default)]
+    pub dry_run_clickhouse_ddl: bool,
+    /// How long to keep retrying the source connection at startup before giving up
+    #[serde(default = "default::startup_retry_millis")]
+    pub startup_retry_millis: u64,
+    /// Restarts a sync iteration that made no progress for this long (e.g. a hung query)
+    #[serde(default)]
+    pub watchdog_stall_timeout_millis: Option<u64>,
+}
+
+impl Configuraion {
+    /// The sleep settings of a source take precedence over the global ones
+    pub fn with_sleep_overrides(mut self, overrides: &SleepOverrides) -> Self {
+        let apply = |value: &mut u64, source_value: Option<u64>| {
+            if let Some(source_value) = source_value {
+                *value = source_value;
+            }
+        };
+
+        apply(
+            &mut self.sleep_millis_when_peek_failed,
+            overrides.sleep_millis_when_peek_failed,
+        );
+        apply(
+            &mut self.sleep_millis_when_peek_is_empty,
+            overrides.sleep_millis_when_peek_is_empty,
+        );
+        apply(
+            &mut self.sleep_millis_when_write_failed,
+            overrides.sleep_millis_when_write_failed,
+        );
+        apply(
+            &mut self.sleep_millis_when_write_blocked,
+            overrides.sleep_millis_when_write_blocked,
+        );
+        apply(
+            &mut self.sleep_millis_after_sync_iteration,
+            overrides.sleep_millis_after_sync_iteration,
+        );
+        apply(
+            &mut self.sleep_millis_after_sync_write,
+            overrides.sleep_millis_after_sync_write,
+        );
+
+        self
+    }
+}
+
+/// Per-source values of the global `sleep_millis_*` settings. Unset ones fall back to the global value.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct SleepOverrides {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sleep_millis_when_peek_failed: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sleep_millis_when_peek_is_empty: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sleep_millis_when_write_failed: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sleep_millis_when_write_blocked: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sleep_millis_after_sync_iteration: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sleep_millis_after_sync_write: Option<u64>,
+}
+
+/// Bounds a wait in the sync loop by the source's max flush interval,
+/// so that a single change is written to ClickHouse within that interval.
+pub fn bounded_by_flush_interval(millis: u64, max_flush_interval_millis: Option<u64>) -> u64 {
+    match max_flush_interval_millis {
+        Some(max_flush_interval_millis) => millis.min(max_flush_interval_millis),
+        None => millis,
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -34,6 +123,16 @@ pub struct Source {
 pub struct Target {
     pub target_type: TargetType,
     pub clickhouse: Option<ClickHouseConfig>,
+    /// Additional ClickHouse targets that receive every write (fan-out)
+    #[serde(default)]
+    pub additional_clickhouse: Vec<ClickHouseConfig>,
+    /// Archive of every change as local Parquet files
+    pub parquet: Option<ParquetSinkConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ParquetSinkConfig {
+    pub directory: String,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -48,10 +147,153 @@ pub enum SourceType {
 pub struct PostgresConfig {
     pub connection: PostgresConnectionConfig,
     pub tables: Vec<PostgresSource>,
-    #[serde(default = "default::postgres::publication_name")]
-    pub publication_name: String,
-    #[serde(default = "default::postgres::replication_slot_name")]
-    pub replication_slot_name: String,
+    #[serde(default)]
+    pub instance_id: Option<String>,
+    #[serde(default)]
+    pub publication_name: Option<String>,
+    #[serde(default)]
+    pub replication_slot_name: Option<String>,
+    #[serde(default)]
+    pub max_flush_interval_millis: Option<u64>,
+    /// Publishes changes of partitioned tables under the id of the partitioned table (PostgreSQL 13+)
+    #[serde(default)]
+    pub publish_via_partition_root: bool,
+    /// A TRUNCATE of a source table truncates its ClickHouse table
+    #[serde(default = "default::postgres::propagate_truncate")]
+    pub propagate_truncate: bool,
+    /// Creates the publication and replication slot (and adds tables to the publication).
+    /// When disabled, they must be provisioned beforehand and are only checked at startup.
+    #[serde(default = "default::postgres::manage_replication")]
+    pub manage_replication: bool,
+    /// File storing the LSN of the last batch written to ClickHouse, so that changes replayed
+    /// after a crash are skipped instead of applied twice
+    #[serde(default)]
+    pub applied_lsn_path: Option<String>,
+    /// Upper bound on the distinct tables whose changes are written in one sync iteration.
+    /// Later transactions are left for the next iteration
+    #[serde(default)]
+    pub max_tables_per_iteration: Option<usize>,
+    /// Creates `bool` columns as UInt8 (0/1) instead of Bool, for readers that expect numbers
+    #[serde(default)]
+    pub bool_as_uint8: bool,
+    #[serde(flatten)]
+    pub sleep_overrides: SleepOverrides,
+}
+
+impl PostgresConfig {
+    /// Explicit name > name derived from instance_id > global default
+    pub fn publication_name(&self) -> String {
+        match (&self.publication_name, &self.instance_id) {
+            (Some(name), _) => name.clone(),
+            (None, Some(instance_id)) => format!("clockpipe_{instance_id}_publication"),
+            (None, None) => default::postgres::publication_name(),
+        }
+    }
+
+    /// Explicit name > name derived from instance_id > global default
+    pub fn replication_slot_name(&self) -> String {
+        match (&self.replication_slot_name, &self.instance_id) {
+            (Some(name), _) => name.clone(),
+            (None, Some(instance_id)) => format!("clockpipe_{instance_id}_replication_slot"),
+            (None, None) => default::postgres::replication_slot_name(),
+        }
+    }
+
+    pub fn validate(&self) -> errors::Result<()> {
+        if let Some(instance_id) = &self.instance_id {
+            // Replication slot names may only contain lower case letters, numbers and underscores
+            let is_valid = !instance_id.is_empty()
+                && instance_id
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+
+            if !is_valid {
+                return Err(errors::Errors::ConfigValidationError(format!(
+                    "postgres.instance_id '{instance_id}' may only contain lower case letters, numbers and underscores"
+                )));
+            }
+        }
+
+        for table in &self.tables {
+            let table_name = format!("{}.{}", table.schema_name, table.table_name);
+
+            table.operations.validate(&table_name)?;
+            table.table_options.validate(&table_name)?;
+
+            // A lightweight UPDATE only changes stored rows, so rows that were never copied stay missing
+            if table.skip_copy && table.update_mode.is_lightweight() {
+                return Err(errors::Errors::ConfigValidationError(format!(
+                    "{table_name}: update_mode {:?} requires the initial copy, skip_copy cannot be set",
+                    table.update_mode
+                )));
+            }
+
+            for (column_name, expression) in &table.column_defaults {
+                validate_default_expression(expression).map_err(|reason| {
+                    errors::Errors::ConfigValidationError(format!(
+                        "column_defaults of {table_name}.{column_name}: {reason}"
+                    ))
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Basic sanity check of a `DEFAULT` expression, which is put into the DDL as it is.
+/// It must be a single expression: no statement separator or comment, and balanced quotes and
+/// parentheses, so it cannot end the column definition.
+pub fn validate_default_expression(expression: &str) -> Result<(), String> {
+    if expression.trim().is_empty() {
+        return Err("the expression is empty".to_string());
+    }
+
+    let mut depth = 0_i32;
+    let mut quote = None;
+    let mut chars = expression.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if let Some(open_quote) = quote {
+            match c {
+                '\\' => {
+                    chars.next();
+                }
+                c if c == open_quote => quote = None,
+                _ => {}
+            }
+            continue;
+        }
+
+        match c {
+            '\'' | '"' | '`' => quote = Some(c),
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth < 0 {
+                    return Err("unbalanced parentheses".to_string());
+                }
+            }
+            ';' => return Err("';' is not allowed".to_string()),
+            ',' if depth == 0 => {
+                return Err("',' is only allowed inside parentheses".to_string());
+            }
+            '-' if chars.peek() == Some(&'-') => return Err("comments are not allowed".to_string()),
+            '/' if chars.peek() == Some(&'*') => return Err("comments are not allowed".to_string()),
+            '#' => return Err("comments are not allowed".to_string()),
+            '\n' | '\r' => return Err("line breaks are not allowed".to_string()),
+            _ => {}
+        }
+    }
+
+    if quote.is_some() {
+        return Err("unterminated quote".to_string());
+    }
+    if depth != 0 {
+        return Err("unbalanced parentheses".to_string());
+    }
+
+    Ok(())
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -62,10 +304,78 @@ pub struct MongoDBConfig {
     pub resume_token_path: String,
     #[serde(default = "ResumeTokenStorageType::default")]
     pub resume_token_storage: ResumeTokenStorageType,
+    /// Table in the ClickHouse target database that stores the resume token (`clickhouse` storage)
+    #[serde(default = "default::mongodb::resume_token_table")]
+    pub resume_token_table: String,
+    /// Row of this pipe in `resume_token_table`, for pipes sharing the table.
+    /// Defaults to `connection.database`
+    #[serde(default)]
+    pub resume_token_key: Option<String>,
+    /// Writes the resume token file gzip-compressed. Uncompressed files are still read
+    #[serde(default)]
+    pub compress_resume_token: bool,
+    /// Writes the resume token at most once per this interval instead of every sync iteration
+    #[serde(default)]
+    pub resume_token_flush_interval_millis: Option<u64>,
+    /// Writes the resume token once per this many synced batches instead of every sync iteration
+    #[serde(default)]
+    pub resume_token_flush_every_batches: Option<u32>,
+    /// Where the change stream starts when there is no stored resume token
+    #[serde(default)]
+    pub start_at: MongoDBStartAt,
+    /// Databases the change stream is opened on
+    #[serde(default)]
+    pub watch_scope: MongoDBWatchScope,
+    /// Never watched by the `databases` and `cluster` scopes
+    #[serde(default = "default::mongodb::exclude_databases")]
+    pub exclude_databases: Vec<String>,
     #[serde(default = "default::mongodb::copy_batch_size")]
     pub copy_batch_size: u32,
-    #[serde(default = "default::mongodb::peek_timeout_millis")]
-    pub peek_timeout_millis: u64,
+    /// How long a peek waits for changes before the collected ones are flushed
+    #[serde(
+        default = "default::mongodb::batch_max_wait_millis",
+        alias = "peek_timeout_millis"
+    )]
+    pub batch_max_wait_millis: u64,
+    /// A peek returns as soon as this many changes were collected, without waiting for `batch_max_wait_millis`
+    #[serde(default)]
+    pub batch_max_changes: Option<u64>,
+    #[serde(default)]
+    pub max_flush_interval_millis: Option<u64>,
+    /// Strings, binary values, arrays and the JSON text of embedded documents longer than this
+    /// are truncated
+    #[serde(default = "default::mongodb::max_document_value_bytes")]
+    pub max_document_value_bytes: usize,
+    #[serde(flatten)]
+    pub sleep_overrides: SleepOverrides,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, Default, PartialEq, Eq)]
+pub enum MongoDBStartAt {
+    /// Changes made after the change stream is opened
+    #[serde(rename = "now")]
+    #[default]
+    Now,
+    /// The oldest entry still in the oplog (needs read access to the `local` database)
+    #[serde(rename = "beginning")]
+    Beginning,
+    /// A cluster time in unix seconds
+    #[serde(rename = "cluster_time")]
+    ClusterTime(u32),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, Default, PartialEq, Eq)]
+pub enum MongoDBWatchScope {
+    /// Only `connection.database`
+    #[serde(rename = "database")]
+    #[default]
+    Database,
+    /// A cluster-wide change stream limited to these databases
+    #[serde(rename = "databases")]
+    Databases(Vec<String>),
+    /// A cluster-wide change stream over all databases except `exclude_databases`
+    #[serde(rename = "cluster")]
+    Cluster,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, Default)]
@@ -73,6 +383,9 @@ pub enum ResumeTokenStorageType {
     #[serde(rename = "file")]
     #[default]
     File,
+    /// A row in `resume_token_table` of the ClickHouse target. Survives restarts on ephemeral disks
+    #[serde(rename = "clickhouse")]
+    ClickHouse,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -83,17 +396,162 @@ pub struct MongoDBConnectionConfig {
     pub database: String,
     #[serde(default = "default::mongodb::app_name")]
     pub app_name: String,
+    /// Full connection string. Used instead of the `mongodb+srv://` string built from host and credentials
+    #[serde(default)]
+    pub uri: Option<String>,
+    #[serde(default = "default::mongodb::connect_timeout_millis")]
+    pub connect_timeout_millis: u64,
+    /// How long an operation waits for a reachable server before failing
+    #[serde(default = "default::mongodb::server_selection_timeout_millis")]
+    pub server_selection_timeout_millis: u64,
+    /// Upper bound on a single request to the server (e.g. opening the change stream).
+    /// The driver has no socket timeout, so a stalled connection would otherwise hang
+    #[serde(default = "default::mongodb::read_timeout_millis")]
+    pub read_timeout_millis: u64,
+}
+
+impl MongoDBConnectionConfig {
+    pub fn validate(&self) -> errors::Result<()> {
+        for (name, value) in [
+            ("connect_timeout_millis", self.connect_timeout_millis),
+            (
+                "server_selection_timeout_millis",
+                self.server_selection_timeout_millis,
+            ),
+            ("read_timeout_millis", self.read_timeout_millis),
+        ] {
+            if value == 0 {
+                return Err(errors::Errors::ConfigValidationError(format!(
+                    "mongodb.connection.{name} must be greater than 0"
+                )));
+            }
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct MongoDBSource {
     pub collection_name: String,
+    /// Database of the collection. Defaults to `connection.database`
     #[serde(default)]
-    pub skip_copy: bool,
+    pub database_name: Option<String>,
     #[serde(default)]
+    pub skip_copy: bool,
+    /// `mask_fields` is accepted as well, it was used in the documentation
+    #[serde(default, alias = "mask_fields")]
     pub mask_columns: Vec<String>,
+    /// Embedded documents are expanded into one column per field (`address.city`) instead of JSON text
+    #[serde(default)]
+    pub flatten_documents: bool,
+    /// Levels of embedded documents that are expanded. Deeper documents stay JSON text
+    #[serde(default = "default::mongodb::flatten_max_depth")]
+    pub flatten_max_depth: usize,
     #[serde(default)]
     pub table_options: ClickHouseTableOptions,
+    #[serde(default)]
+    pub operations: ReplicatedOperations,
+}
+
+impl MongoDBConfig {
+    pub fn validate(&self) -> errors::Result<()> {
+        self.connection.validate()?;
+
+        if self.watch_scope == MongoDBWatchScope::Databases(vec![]) {
+            return Err(errors::Errors::ConfigValidationError(
+                "watch_scope.databases must not be empty".to_string(),
+            ));
+        }
+
+        for (index, collection) in self.collections.iter().enumerate() {
+            collection
+                .operations
+                .validate(&collection.collection_name)?;
+            collection
+                .table_options
+                .validate(&collection.collection_name)?;
+
+            let database_name = self.collection_database(collection);
+            if !self.is_watched_database(database_name) {
+                return Err(errors::Errors::ConfigValidationError(format!(
+                    "Collection {database_name}.{} is outside of the watch scope",
+                    collection.collection_name
+                )));
+            }
+
+            // ClickHouse tables are named after the collection only
+            if self.collections[..index]
+                .iter()
+                .any(|other| other.collection_name == collection.collection_name)
+            {
+                return Err(errors::Errors::ConfigValidationError(format!(
+                    "Collection {} is configured more than once",
+                    collection.collection_name
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn collection_database<'a>(&'a self, collection: &'a MongoDBSource) -> &'a str {
+        collection
+            .database_name
+            .as_deref()
+            .unwrap_or(&self.connection.database)
+    }
+
+    /// Whether changes of the database are received with the configured watch scope.
+    pub fn is_watched_database(&self, database_name: &str) -> bool {
+        let is_excluded = self
+            .exclude_databases
+            .iter()
+            .any(|excluded| excluded == database_name);
+
+        match &self.watch_scope {
+            MongoDBWatchScope::Database => database_name == self.connection.database,
+            MongoDBWatchScope::Databases(databases) => {
+                !is_excluded && databases.iter().any(|database| database == database_name)
+            }
+            MongoDBWatchScope::Cluster => !is_excluded,
+        }
+    }
+}
+
+/// Kinds of changes that are replicated for a table/collection.
+/// e.g. inserts only for an append-only event log.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ReplicatedOperations {
+    #[serde(default = "default::replicated_operation")]
+    pub insert: bool,
+    #[serde(default = "default::replicated_operation")]
+    pub update: bool,
+    #[serde(default = "default::replicated_operation")]
+    pub delete: bool,
+}
+
+impl Default for ReplicatedOperations {
+    fn default() -> Self {
+        ReplicatedOperations {
+            insert: default::REPLICATED_OPERATION,
+            update: default::REPLICATED_OPERATION,
+            delete: default::REPLICATED_OPERATION,
+        }
+    }
+}
+
+impl ReplicatedOperations {
+    pub fn validate(&self, table_name: &str) -> errors::Result<()> {
+        // Deletes would only ever remove rows that were never inserted
+        if self.delete && !self.insert {
+            return Err(errors::Errors::ConfigValidationError(format!(
+                "operations of {table_name}: delete cannot be enabled while insert is disabled"
+            )));
+        }
+
+        Ok(())
+    }
 }
 
 pub mod default {
@@ -107,6 +565,18 @@ pub mod default {
         pub fn replication_slot_name() -> String {
             REPLICATION_SLOT_NAME.to_string()
         }
+
+        pub const REPLICATION_SLOT_PREFIX: &str = "clockpipe";
+
+        pub const PROPAGATE_TRUNCATE: bool = true;
+        pub fn propagate_truncate() -> bool {
+            PROPAGATE_TRUNCATE
+        }
+
+        pub const MANAGE_REPLICATION: bool = true;
+        pub fn manage_replication() -> bool {
+            MANAGE_REPLICATION
+        }
     }
 
     pub mod clickhouse {
@@ -119,6 +589,22 @@ pub mod default {
         pub fn index_granularity() -> u64 {
             INDEX_GRANULARITY
         }
+
+        pub const NATIVE_PROTOCOL_PORTS: [u16; 2] = [9000, 9440];
+
+        /// Upper bound of a generated ALTER TABLE ... UPDATE, below the default `max_query_size`
+        /// (262144 bytes) of ClickHouse. Unlike the data of an INSERT, all of it is parsed as SQL
+        pub const MAX_MUTATION_QUERY_BYTES: usize = 200_000;
+
+        pub const REQUIRED: bool = true;
+        pub fn required() -> bool {
+            REQUIRED
+        }
+    }
+
+    pub const REPLICATED_OPERATION: bool = true;
+    pub fn replicated_operation() -> bool {
+        REPLICATED_OPERATION
     }
 
     pub mod mongodb {
@@ -127,20 +613,55 @@ pub mod default {
             RESUME_TOKEN_FILE_PATH.to_string()
         }
 
+        pub const RESUME_TOKEN_TABLE: &str = "clockpipe_resume_tokens";
+        pub fn resume_token_table() -> String {
+            RESUME_TOKEN_TABLE.to_string()
+        }
+
         pub const COPY_BATCH_SIZE: u32 = 1000;
         pub fn copy_batch_size() -> u32 {
             COPY_BATCH_SIZE
         }
 
-        pub const PEEK_TIMEOUT_MILLIS: u64 = 5000;
-        pub fn peek_timeout_millis() -> u64 {
-            PEEK_TIMEOUT_MILLIS
+        pub const BATCH_MAX_WAIT_MILLIS: u64 = 5000;
+        pub fn batch_max_wait_millis() -> u64 {
+            BATCH_MAX_WAIT_MILLIS
         }
 
         pub const APP_NAME: &str = "clockpipe";
         pub fn app_name() -> String {
             APP_NAME.to_string()
         }
+
+        pub const MAX_DOCUMENT_VALUE_BYTES: usize = 1024 * 1024;
+        pub fn max_document_value_bytes() -> usize {
+            MAX_DOCUMENT_VALUE_BYTES
+        }
+
+        pub const FLATTEN_MAX_DEPTH: usize = 3;
+        pub fn flatten_max_depth() -> usize {
+            FLATTEN_MAX_DEPTH
+        }
+
+        pub const CONNECT_TIMEOUT_MILLIS: u64 = 10000;
+        pub fn connect_timeout_millis() -> u64 {
+            CONNECT_TIMEOUT_MILLIS
+        }
+
+        pub const SERVER_SELECTION_TIMEOUT_MILLIS: u64 = 10000;
+        pub fn server_selection_timeout_millis() -> u64 {
+            SERVER_SELECTION_TIMEOUT_MILLIS
+        }
+
+        pub const READ_TIMEOUT_MILLIS: u64 = 30000;
+        pub fn read_timeout_millis() -> u64 {
+            READ_TIMEOUT_MILLIS
+        }
+
+        pub const EXCLUDE_DATABASES: [&str; 3] = ["admin", "local", "config"];
+        pub fn exclude_databases() -> Vec<String> {
+            EXCLUDE_DATABASES.map(String::from).to_vec()
+        }
     }
 
     pub const PEEK_CHANGES_LIMIT: u64 = 65536;
@@ -148,6 +669,16 @@ pub mod default {
         PEEK_CHANGES_LIMIT
     }
 
+    pub const MIN_PEEK_CHANGES_LIMIT: u64 = 1024;
+    pub fn min_peek_changes_limit() -> u64 {
+        MIN_PEEK_CHANGES_LIMIT
+    }
+
+    pub const TARGET_WRITE_MILLIS_PER_ITERATION: u64 = 10000;
+    pub fn target_write_millis_per_iteration() -> u64 {
+        TARGET_WRITE_MILLIS_PER_ITERATION
+    }
+
     pub const PEEK_CHANGES_TIMEOUT_MILLIS: u64 = 5000;
     pub fn peek_changes_timeout_millis() -> u64 {
         PEEK_CHANGES_TIMEOUT_MILLIS
@@ -168,6 +699,11 @@ pub mod default {
         SLEEP_MILLIS_WHEN_WRITE_FAILED
     }
 
+    pub const SLEEP_MILLIS_WHEN_WRITE_BLOCKED: u64 = 60000;
+    pub fn sleep_millis_when_write_blocked() -> u64 {
+        SLEEP_MILLIS_WHEN_WRITE_BLOCKED
+    }
+
     pub const SLEEP_MILLIS_AFTER_SYNC_ITERATION: u64 = 100;
     pub fn sleep_millis_after_sync_iteration() -> u64 {
         SLEEP_MILLIS_AFTER_SYNC_ITERATION
@@ -182,6 +718,11 @@ pub mod default {
     pub fn copy_batch_size() -> usize {
         COPY_BATCH_SIZE
     }
+
+    pub const STARTUP_RETRY_MILLIS: u64 = 30000;
+    pub fn startup_retry_millis() -> u64 {
+        STARTUP_RETRY_MILLIS
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -231,6 +772,61 @@ pub struct PostgresSource {
     pub mask_columns: Vec<String>,
     #[serde(default)]
     pub table_options: ClickHouseTableOptions,
+    /// Column name -> ClickHouse type to use instead of the default mapping
+    #[serde(default)]
+    pub column_type_overrides: std::collections::HashMap<String, ColumnTypeOverride>,
+    /// Column name -> ClickHouse `DEFAULT` expression of the column, used when the column is created
+    #[serde(default)]
+    pub column_defaults: std::collections::HashMap<String, String>,
+    /// Columns used as the ClickHouse key (ORDER BY, deletes and deduplication) when the source
+    /// table has no primary key
+    #[serde(default)]
+    pub dedup_key: Vec<String>,
+    #[serde(default)]
+    pub operations: ReplicatedOperations,
+    #[serde(default)]
+    pub update_mode: UpdateMode,
+    /// Position in the initial copy, e.g. parent tables before the tables referencing them.
+    /// Tables without it are copied afterwards, in config order
+    #[serde(default)]
+    pub copy_order: Option<u32>,
+}
+
+/// How an UPDATE of the source table is applied to ClickHouse
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Default)]
+pub enum UpdateMode {
+    /// Inserted as a new version of the row, deduplicated by ReplacingMergeTree on merge
+    #[serde(rename = "insert")]
+    #[default]
+    Insert,
+    /// Applied with a lightweight `UPDATE ... WHERE <primary key>`, visible without FINAL
+    #[serde(rename = "lightweight_update")]
+    LightweightUpdate,
+    /// Like `LightweightUpdate`, but sets only the columns whose value differs from the old row.
+    /// Requires REPLICA IDENTITY FULL, otherwise all columns are set
+    #[serde(rename = "changed_columns")]
+    ChangedColumns,
+}
+
+impl UpdateMode {
+    /// Whether updates are applied with a lightweight `UPDATE` instead of an insert
+    pub fn is_lightweight(self) -> bool {
+        matches!(
+            self,
+            UpdateMode::LightweightUpdate | UpdateMode::ChangedColumns
+        )
+    }
+}
+
+/// Unsigned types for integer columns that are known to be non-negative (e.g. CHECK (x >= 0)),
+/// or LowCardinality for text columns with few distinct values (status, country, ...)
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+pub enum ColumnTypeOverride {
+    UInt8,
+    UInt16,
+    UInt32,
+    UInt64,
+    LowCardinality,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -239,21 +835,25 @@ pub enum TargetType {
     ClickHouse,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, Default)]
 pub struct ClickHouseTableOptions {
     pub storage_policy: Option<String>,
     pub granularity: Option<u64>,
     pub min_age_to_force_merge_seconds: Option<u64>,
-}
-
-impl Default for ClickHouseTableOptions {
-    fn default() -> Self {
-        ClickHouseTableOptions {
-            storage_policy: None,
-            granularity: None,
-            min_age_to_force_merge_seconds: None,
-        }
-    }
+    /// Runs `OPTIMIZE TABLE ... FINAL` on the table at this interval while the sync loop runs
+    pub optimize_interval_seconds: Option<u64>,
+    /// Engine of the created table. ReplacingMergeTree if not set
+    pub engine: Option<TableEngine>,
+    /// (summing_merge_tree) Columns to sum. All numeric non-key columns if not set
+    pub sum_columns: Option<Vec<String>>,
+    /// (replacing_deleted_merge_tree) Name of the version column. `_clockpipe_version` if not set
+    pub version_column: Option<String>,
+    /// (replacing_deleted_merge_tree) Name of the delete marker column. `_clockpipe_is_deleted` if not set
+    pub is_deleted_column: Option<String>,
+    /// Adds the block number/offset columns that lightweight updates require.
+    /// Set for tables with `update_mode: lightweight_update`, not configurable.
+    #[serde(skip)]
+    pub lightweight_updates: bool,
 }
 
 impl ClickHouseTableOptions {
@@ -265,6 +865,97 @@ impl ClickHouseTableOptions {
         if self.granularity.is_none() {
             self.granularity = parent.granularity;
         }
+
+        if self.min_age_to_force_merge_seconds.is_none() {
+            self.min_age_to_force_merge_seconds = parent.min_age_to_force_merge_seconds;
+        }
+
+        if self.optimize_interval_seconds.is_none() {
+            self.optimize_interval_seconds = parent.optimize_interval_seconds;
+        }
+
+        if self.engine.is_none() {
+            self.engine = parent.engine;
+        }
+
+        if self.sum_columns.is_none() {
+            self.sum_columns = parent.sum_columns.clone();
+        }
+
+        if self.version_column.is_none() {
+            self.version_column = parent.version_column.clone();
+        }
+
+        if self.is_deleted_column.is_none() {
+            self.is_deleted_column = parent.is_deleted_column.clone();
+        }
+    }
+
+    pub fn version_column_name(&self) -> &str {
+        self.version_column.as_deref().unwrap_or(VERSION_COLUMN)
+    }
+
+    pub fn is_deleted_column_name(&self) -> &str {
+        self.is_deleted_column
+            .as_deref()
+            .unwrap_or(IS_DELETED_COLUMN)
+    }
+
+    /// Names of the columns added for `replacing_deleted_merge_tree`. A source column cannot have them
+    pub fn engine_column_names(&self) -> [&str; 2] {
+        [self.version_column_name(), self.is_deleted_column_name()]
+    }
+
+    /// Whether deletes insert a row marked as deleted instead of running a DELETE mutation
+    pub fn deletes_by_marker(&self) -> bool {
+        self.engine == Some(TableEngine::ReplacingDeletedMergeTree)
+    }
+
+    pub fn validate(&self, table_name: &str) -> errors::Result<()> {
+        let [version_column, is_deleted_column] = self.engine_column_names();
+
+        if version_column.is_empty() || is_deleted_column.is_empty() {
+            return Err(errors::Errors::ConfigValidationError(format!(
+                "table_options of {table_name}: version_column and is_deleted_column must not be empty"
+            )));
+        }
+
+        if version_column.eq_ignore_ascii_case(is_deleted_column) {
+            return Err(errors::Errors::ConfigValidationError(format!(
+                "table_options of {table_name}: version_column and is_deleted_column must differ"
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// Engine of the tables created by clockpipe
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Default)]
+pub enum TableEngine {
+    /// Keeps the last version of each row
+    #[serde(rename = "replacing_merge_tree")]
+    #[default]
+    ReplacingMergeTree,
+    /// Sums the numeric columns of the rows with the same key on merge
+    #[serde(rename = "summing_merge_tree")]
+    SummingMergeTree,
+    /// Merges the AggregateFunction columns of the rows with the same key on merge
+    #[serde(rename = "aggregating_merge_tree")]
+    AggregatingMergeTree,
+    /// ReplacingMergeTree with a version and an is_deleted column. Deletes insert a row marked as
+    /// deleted instead of running an `ALTER TABLE ... DELETE` mutation
+    #[serde(rename = "replacing_deleted_merge_tree")]
+    ReplacingDeletedMergeTree,
+}
+
+impl TableEngine {
+    /// Whether rows with the same key are combined into an aggregate instead of replaced
+    pub fn aggregates(self) -> bool {
+        matches!(
+            self,
+            TableEngine::SummingMergeTree | TableEngine::AggregatingMergeTree
+        )
     }
 }
 
@@ -275,6 +966,146 @@ pub struct ClickHouseConfig {
     pub disable_sync_loop: bool,
     #[serde(default)]
     pub table_options: ClickHouseTableOptions,
+    /// (fan-out targets only) If false, write failures on this target are logged and skipped
+    #[serde(default = "default::clickhouse::required")]
+    pub required: bool,
+    #[serde(default)]
+    pub non_finite_numbers: NonFiniteNumbers,
+    #[serde(default)]
+    pub identifier_case: IdentifierCase,
+    #[serde(default)]
+    pub date_time_input_format: DateTimeInputFormat,
+    /// A batch whose INSERT query would be larger than this is split into several INSERT queries
+    #[serde(default)]
+    pub max_insert_query_bytes: Option<usize>,
+    /// What is written when a non-nullable column has no value in the source row
+    #[serde(default)]
+    pub missing_values: MissingValues,
+    /// What is done with a value longer than its FixedString(n) column
+    #[serde(default)]
+    pub fixed_string_overflow: FixedStringOverflow,
+    /// How binary source values (Postgres bytea) are encoded into String columns
+    #[serde(default)]
+    pub binary_encoding: BinaryEncoding,
+    /// Writes every query executed on this target to a file or the log before it runs
+    #[serde(default)]
+    pub query_log: Option<QueryLogConfig>,
+}
+
+/// Audit log of the queries executed on a ClickHouse target.
+#[derive(Debug, Clone, Deserialize, Serialize, Default)]
+pub struct QueryLogConfig {
+    /// File the queries are appended to. Without it they are logged at info level
+    #[serde(default)]
+    pub path: Option<String>,
+    /// Replaces string literals (values, comments) with '***'
+    #[serde(default)]
+    pub mask_values: bool,
+}
+
+/// How values longer than their FixedString(n) column (n bytes) are written.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, Default, PartialEq, Eq)]
+pub enum FixedStringOverflow {
+    /// Cut to n bytes (at a character boundary), with a warning
+    #[serde(rename = "truncate")]
+    #[default]
+    Truncate,
+    /// Written as they are, so ClickHouse rejects the insert and the pipe retries it until the column is widened
+    #[serde(rename = "error")]
+    Error,
+}
+
+/// Text encoding of binary values, which are stored in String columns.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, Default, PartialEq, Eq)]
+pub enum BinaryEncoding {
+    /// Lowercase hex digits without a prefix, readable with `unhex()`
+    #[serde(rename = "hex")]
+    #[default]
+    Hex,
+    /// Standard base64 with padding, readable with `base64Decode()`
+    #[serde(rename = "base64")]
+    Base64,
+}
+
+impl BinaryEncoding {
+    pub fn encode(&self, bytes: &[u8]) -> String {
+        use base64::Engine;
+
+        match self {
+            BinaryEncoding::Hex => bytes.iter().map(|byte| format!("{byte:02x}")).collect(),
+            BinaryEncoding::Base64 => base64::engine::general_purpose::STANDARD.encode(bytes),
+        }
+    }
+}
+
+/// How DateTime values are handed to ClickHouse on insert.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, Default, PartialEq, Eq)]
+pub enum DateTimeInputFormat {
+    /// clockpipe normalizes the source text and converts it with toDateTime
+    #[serde(rename = "basic")]
+    #[default]
+    Basic,
+    /// The source text is sent as it is, and ClickHouse parses it with
+    /// `date_time_input_format = 'best_effort'` (ISO 8601 with `T`, time zone offsets, ...). Slower
+    #[serde(rename = "best_effort")]
+    BestEffort,
+}
+
+/// How source column names are written as ClickHouse column names.
+/// ClickHouse identifiers are case-sensitive.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, Default, PartialEq, Eq)]
+pub enum IdentifierCase {
+    /// Keep the source name as it is (e.g. quoted `UserId` stays `UserId`)
+    #[serde(rename = "preserve")]
+    #[default]
+    Preserve,
+    /// Lowercase the source name (`UserId` becomes `userid`)
+    #[serde(rename = "lowercase")]
+    Lowercase,
+}
+
+impl IdentifierCase {
+    /// Returns the ClickHouse column name for a source column name.
+    pub fn apply(&self, source_name: &str) -> String {
+        match self {
+            IdentifierCase::Preserve => source_name.to_string(),
+            IdentifierCase::Lowercase => source_name.to_lowercase(),
+        }
+    }
+
+    /// Whether a source column corresponds to a ClickHouse column under this policy.
+    pub fn matches(&self, source_name: &str, clickhouse_name: &str) -> bool {
+        match self {
+            IdentifierCase::Preserve => source_name == clickhouse_name,
+            IdentifierCase::Lowercase => source_name.to_lowercase() == clickhouse_name,
+        }
+    }
+}
+
+/// How a row is written when a non-nullable ClickHouse column has no value in it
+/// (e.g. the source column was dropped or is NULL).
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, Default, PartialEq, Eq)]
+pub enum MissingValues {
+    /// The zero value of the column type (0, '', toDate(0), ...)
+    #[serde(rename = "zero")]
+    #[default]
+    Zero,
+    /// The row is not written, with a warning
+    #[serde(rename = "skip_row")]
+    SkipRow,
+}
+
+/// How NaN/Infinity/-Infinity source values are written.
+/// Decimal columns cannot hold them, so they always become NULL (or 0 if not nullable).
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, Default, PartialEq, Eq)]
+pub enum NonFiniteNumbers {
+    /// nan/inf/-inf literals for Float columns
+    #[serde(rename = "literal")]
+    #[default]
+    Literal,
+    /// NULL for Float columns as well
+    #[serde(rename = "null")]
+    Null,
 }
 
 impl ClickHouseConfig {
@@ -290,4 +1121,437 @@ pub struct ClickHouseConnectionConfig {
     pub username: String,
     pub password: String,
     pub database: String,
+    #[serde(default)]
+    pub scheme: ClickHouseScheme,
+    /// Settings sent with every query of this connection (e.g. `role`, `profile`)
+    #[serde(default)]
+    pub session_settings: std::collections::BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, Default, PartialEq)]
+pub enum ClickHouseScheme {
+    #[serde(rename = "http")]
+    #[default]
+    Http,
+    #[serde(rename = "https")]
+    Https,
+}
+
+impl ClickHouseScheme {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ClickHouseScheme::Http => "http",
+            ClickHouseScheme::Https => "https",
+        }
+    }
+}
+
+impl ClickHouseConnectionConfig {
+    /// Builds the HTTP(S) endpoint URL used by the ClickHouse client.
+    /// The `clickhouse` crate only speaks the HTTP interface, so native protocol ports are rejected.
+    pub fn url(&self) -> errors::Result<String> {
+        let host = self.host.trim();
+
+        if host.is_empty() {
+            return Err(errors::Errors::ConfigValidationError(
+                "ClickHouse host must not be empty".to_string(),
+            ));
+        }
+
+        if host.contains("://") {
+            return Err(errors::Errors::ConfigValidationError(format!(
+                "ClickHouse host must not contain a scheme ({host}). Use the `scheme` option instead"
+            )));
+        }
+
+        if self.port == 0 {
+            return Err(errors::Errors::ConfigValidationError(
+                "ClickHouse port must not be 0".to_string(),
+            ));
+        }
+
+        if default::clickhouse::NATIVE_PROTOCOL_PORTS.contains(&self.port) {
+            return Err(errors::Errors::ConfigValidationError(format!(
+                "ClickHouse port {} is the native TCP protocol port, but clockpipe connects through the HTTP interface. Use the HTTP port instead (default: 8123, or 8443 for https)",
+                self.port
+            )));
+        }
+
+        // IPv6 literals must be bracketed inside a URL. Any other ':' would be read as a port
+        let unbracketed_host = host
+            .strip_prefix('[')
+            .and_then(|host| host.strip_suffix(']'))
+            .unwrap_or(host);
+        let host = if unbracketed_host.parse::<std::net::Ipv6Addr>().is_ok() {
+            format!("[{unbracketed_host}]")
+        } else if host.contains([':', '[', ']']) {
+            return Err(errors::Errors::ConfigValidationError(format!(
+                "ClickHouse host {host} is neither a host name nor an IP address. Set the port with the `port` option"
+            )));
+        } else {
+            host.to_string()
+        };
+
+        Ok(format!("{}://{}:{}", self.scheme.as_str(), host, self.port))
+    }
+
+    /// Session settings are sent as URL parameters, so only plain `name = value` pairs are allowed.
+    pub fn validate_session_settings(&self) -> errors::Result<()> {
+        for (name, value) in &self.session_settings {
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(errors::Errors::ConfigValidationError(format!(
+                    "ClickHouse session setting name '{name}' must only contain letters, digits and underscores"
+                )));
+            }
+
+            if value.trim().is_empty() || value.chars().any(|c| c.is_control()) {
+                return Err(errors::Errors::ConfigValidationError(format!(
+                    "ClickHouse session setting '{name}' must have a single-line, non-empty value"
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ClickHouseConnectionConfig, ClickHouseScheme, Configuraion, MongoDBConfig,
+        MongoDBConnectionConfig, PostgresConfig, bounded_by_flush_interval,
+    };
+
+    fn postgres_config(json: &str) -> PostgresConfig {
+        let mut value: serde_json::Value = serde_json::from_str(json).unwrap();
+        value["connection"] = serde_json::json!({
+            "host": "localhost",
+            "port": 5432,
+            "username": "user",
+            "password": "password",
+            "database": "postgres"
+        });
+        value["tables"] = serde_json::json!([]);
+
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn postgres_names_default_without_instance_id() {
+        let config = postgres_config("{}");
+
+        assert_eq!(config.publication_name(), "clockpipe_publication");
+        assert_eq!(config.replication_slot_name(), "clockpipe_replication_slot");
+    }
+
+    #[test]
+    fn postgres_names_are_derived_from_instance_id() {
+        let config = postgres_config(r#"{"instance_id": "analytics"}"#);
+
+        assert_eq!(config.publication_name(), "clockpipe_analytics_publication");
+        assert_eq!(
+            config.replication_slot_name(),
+            "clockpipe_analytics_replication_slot"
+        );
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn postgres_explicit_names_win_over_instance_id() {
+        let config = postgres_config(
+            r#"{"instance_id": "analytics", "publication_name": "my_pub", "replication_slot_name": "my_slot"}"#,
+        );
+
+        assert_eq!(config.publication_name(), "my_pub");
+        assert_eq!(config.replication_slot_name(), "my_slot");
+    }
+
+    #[test]
+    fn deletes_without_inserts_are_rejected() {
+        let config = |operations: &str| {
+            let mut config = postgres_config("{}");
+            config.tables = serde_json::from_str(&format!(
+                r#"[{{"schema_name": "public", "table_name": "events", "operations": {operations}}}]"#
+            ))
+            .unwrap();
+            config
+        };
+
+        assert!(config("{}").validate().is_ok());
+        assert!(
+            config(r#"{"update": false, "delete": false}"#)
+                .validate()
+                .is_ok()
+        );
+
+        let error = config(r#"{"insert": false}"#)
+            .validate()
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("public.events"), "{error}");
+    }
+
+    #[test]
+    fn lightweight_updates_without_the_initial_copy_are_rejected() {
+        let config = |table: &str| {
+            let mut config = postgres_config("{}");
+            config.tables = serde_json::from_str(&format!(
+                r#"[{{"schema_name": "public", "table_name": "events", {table}}}]"#
+            ))
+            .unwrap();
+            config
+        };
+
+        assert!(
+            config(r#""skip_copy": true, "update_mode": "insert""#)
+                .validate()
+                .is_ok()
+        );
+        assert!(
+            config(r#""skip_copy": false, "update_mode": "lightweight_update""#)
+                .validate()
+                .is_ok()
+        );
+        assert!(
+            config(r#""skip_copy": true, "update_mode": "changed_columns""#)
+                .validate()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn column_defaults_must_be_single_expressions() {
+        let config = |expression: &str| {
+            let mut config = postgres_config("{}");
+            config.tables = serde_json::from_value(serde_json::json!([{
+                "schema_name": "public",
+                "table_name": "users",
+                "column_defaults": { "status": expression }
+            }]))
+            .unwrap();
+            config
+        };
+
+        for expression in [
+            "'active'",
+            "now()",
+            "toDateTime('2024-01-01 00:00:00', 'UTC')",
+            "'a;b'",
+        ] {
+            assert!(config(expression).validate().is_ok(), "{expression}");
+        }
+
+        for expression in [
+            "",
+            "1; DROP TABLE users",
+            "1 -- comment",
+            "1 /* comment */",
+            "'unterminated",
+            "now())",
+            "(1",
+            "1, `other` String",
+            "1\nCOMMENT 'x'",
+        ] {
+            let error = config(expression).validate().unwrap_err().to_string();
+            assert!(error.contains("public.users.status"), "{error}");
+        }
+    }
+
+    #[test]
+    fn postgres_instance_id_must_be_a_valid_slot_name() {
+        let config = postgres_config(r#"{"instance_id": "Analytics-1"}"#);
+
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn mongodb_timeouts_have_defaults_and_must_be_positive() {
+        let connection = |timeouts: serde_json::Value| {
+            let mut value = serde_json::json!({
+                "host": "localhost",
+                "username": "user",
+                "password": "password",
+                "database": "db"
+            });
+            value
+                .as_object_mut()
+                .unwrap()
+                .extend(timeouts.as_object().unwrap().clone());
+            serde_json::from_value::<MongoDBConnectionConfig>(value).unwrap()
+        };
+
+        let defaults = connection(serde_json::json!({}));
+        assert_eq!(defaults.connect_timeout_millis, 10000);
+        assert_eq!(defaults.server_selection_timeout_millis, 10000);
+        assert_eq!(defaults.read_timeout_millis, 30000);
+        assert!(defaults.validate().is_ok());
+
+        assert!(
+            connection(serde_json::json!({ "read_timeout_millis": 0 }))
+                .validate()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn system_databases_are_not_watched() {
+        let mongodb_config = |watch_scope: serde_json::Value| {
+            serde_json::from_value::<MongoDBConfig>(serde_json::json!({
+                "connection": {
+                    "host": "localhost",
+                    "username": "user",
+                    "password": "password",
+                    "database": "shop"
+                },
+                "collections": [
+                    { "collection_name": "orders" },
+                    { "collection_name": "events", "database_name": "analytics" }
+                ],
+                "watch_scope": watch_scope
+            }))
+            .unwrap()
+        };
+
+        let cluster = mongodb_config(serde_json::json!("cluster"));
+        assert!(cluster.validate().is_ok());
+        for database in ["admin", "local", "config"] {
+            assert!(!cluster.is_watched_database(database), "{database}");
+        }
+        assert!(cluster.is_watched_database("analytics"));
+
+        let databases = mongodb_config(serde_json::json!({
+            "databases": ["shop", "analytics", "admin"]
+        }));
+        assert!(databases.validate().is_ok());
+        assert!(!databases.is_watched_database("admin"));
+        assert!(!databases.is_watched_database("billing"));
+
+        // The default scope only watches connection.database
+        let database = mongodb_config(serde_json::json!("database"));
+        assert!(database.is_watched_database("shop"));
+        assert!(!database.is_watched_database("analytics"));
+        assert!(database.validate().is_err());
+    }
+
+    fn clickhouse_connection(host: &str, port: u16) -> ClickHouseConnectionConfig {
+        ClickHouseConnectionConfig {
+            host: host.to_string(),
+            port,
+            username: "user".to_string(),
+            password: "password".to_string(),
+            database: "default".to_string(),
+            scheme: ClickHouseScheme::default(),
+            session_settings: Default::default(),
+        }
+    }
+
+    #[test]
+    fn clickhouse_session_settings_must_be_simple_pairs() {
+        let mut config = clickhouse_connection("localhost", 8123);
+        config
+            .session_settings
+            .insert("role".to_string(), "clockpipe_writer".to_string());
+        config
+            .session_settings
+            .insert("max_insert_threads".to_string(), "4".to_string());
+        assert!(config.validate_session_settings().is_ok());
+
+        config
+            .session_settings
+            .insert("role; DROP".to_string(), "x".to_string());
+        assert!(config.validate_session_settings().is_err());
+
+        config.session_settings.remove("role; DROP");
+        config
+            .session_settings
+            .insert("profile".to_string(), "etl\nrole=admin".to_string());
+        assert!(config.validate_session_settings().is_err());
+    }
+
+    #[test]
+    fn clickhouse_url_uses_custom_port_and_scheme() {
+        let mut config = clickhouse_connection("localhost", 18123);
+        assert_eq!(config.url().unwrap(), "http://localhost:18123");
+
+        config.scheme = ClickHouseScheme::Https;
+        config.port = 8443;
+        assert_eq!(config.url().unwrap(), "https://localhost:8443");
+    }
+
+    #[test]
+    fn clickhouse_url_brackets_ipv6_host() {
+        let config = clickhouse_connection("::1", 8123);
+        assert_eq!(config.url().unwrap(), "http://[::1]:8123");
+
+        let config = clickhouse_connection("[::1]", 8123);
+        assert_eq!(config.url().unwrap(), "http://[::1]:8123");
+    }
+
+    #[test]
+    fn clickhouse_url_rejects_port_in_host() {
+        for host in ["localhost:8123", "[localhost]", "10.0.0.1:8123"] {
+            let config = clickhouse_connection(host, 8123);
+            assert!(config.url().is_err(), "{host}");
+        }
+    }
+
+    #[test]
+    fn clickhouse_url_rejects_native_protocol_port() {
+        let config = clickhouse_connection("localhost", 9000);
+        let error = config.url().unwrap_err().to_string();
+        assert!(error.contains("native TCP protocol"), "{error}");
+    }
+
+    #[test]
+    fn clickhouse_url_rejects_scheme_in_host() {
+        let config = clickhouse_connection("http://localhost", 8123);
+        assert!(config.url().is_err());
+    }
+
+    #[test]
+    fn clickhouse_scheme_defaults_to_http() {
+        let config: ClickHouseConnectionConfig = serde_json::from_str(
+            r#"{"host": "localhost", "port": 8123, "username": "u", "password": "p", "database": "d"}"#,
+        )
+        .unwrap();
+        assert_eq!(config.scheme, ClickHouseScheme::Http);
+    }
+
+    #[test]
+    fn max_flush_interval_bounds_sync_loop_waits() {
+        let config = postgres_config(r#"{"max_flush_interval_millis": 200}"#);
+
+        // A single change peeked right after an empty poll is flushed within the interval
+        assert_eq!(
+            bounded_by_flush_interval(1000, config.max_flush_interval_millis),
+            200
+        );
+        assert_eq!(
+            bounded_by_flush_interval(50, config.max_flush_interval_millis),
+            50
+        );
+        assert_eq!(bounded_by_flush_interval(1000, None), 1000);
+    }
+
+    #[test]
+    fn source_sleep_settings_take_precedence_over_global_ones() {
+        let config: Configuraion = serde_json::from_value(serde_json::json!({
+            "source": { "source_type": "postgres" },
+            "target": { "target_type": "clickhouse" },
+            "sleep_millis_when_peek_is_empty": 3000,
+            "sleep_millis_after_sync_write": 300
+        }))
+        .unwrap();
+        let postgres_config = postgres_config(
+            r#"{"sleep_millis_when_peek_is_empty": 500, "sleep_millis_when_write_failed": 1000}"#,
+        );
+
+        let config = config.with_sleep_overrides(&postgres_config.sleep_overrides);
+
+        assert_eq!(config.sleep_millis_when_peek_is_empty, 500);
+        assert_eq!(config.sleep_millis_when_write_failed, 1000);
+        // Not set for the source
+        assert_eq!(config.sleep_millis_after_sync_write, 300);
+        assert_eq!(config.sleep_millis_when_peek_failed, 5000);
+    }
 }
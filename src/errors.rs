@@ -1,12 +1,18 @@
 #[derive(Debug)]
 pub enum Errors {
     ConfigReadError(String),
+    ConfigValidationError(String),
     IOError(std::io::Error),
     ClickhouseDatabaseError(String),
+    FanOutWriteFailed(String),
+    SinkWriteFailed(String),
     DatabaseConnectionError(String),
     DatabaseQueryError(String),
     DatabasePingError(String),
     TableNotFoundError(String),
+    PrimaryKeyMismatch(String),
+    ColumnTypeMismatch(String),
+    DdlDisabled(String),
     GetTableNameFailed(String),
     GetTableCommentFailed(String),
     ListTableColumnsFailed(String),
@@ -18,9 +24,12 @@ pub enum Errors {
     PeekChangesFailed(String),
     ReplicationSlotAdvanceFailed(String),
     PgOutputParseError(String),
+    LsnParseError(String),
     CopyTableFailed(String),
     CountTableRowsFailed(String),
     ResumeTokenParseError(String),
+    ResumeTokenStorageFailed(String),
+    SyncStalled(String),
 }
 
 pub type Result<T> = std::result::Result<T, Errors>;
@@ -35,11 +44,18 @@ impl std::fmt::Display for Errors {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Errors::ConfigReadError(msg) => write!(f, "Configuration read error: {msg}"),
+            Errors::ConfigValidationError(msg) => write!(f, "Invalid configuration: {msg}"),
             Errors::IOError(err) => write!(f, "I/O error: {err}"),
             Errors::DatabaseQueryError(msg) => write!(f, "Database query error: {msg}"),
             Errors::DatabaseConnectionError(msg) => write!(f, "Database connection error: {msg}"),
             Errors::DatabasePingError(msg) => write!(f, "Database ping error: {msg}"),
             Errors::TableNotFoundError(msg) => write!(f, "Table not found: {msg}"),
+            Errors::PrimaryKeyMismatch(msg) => write!(f, "Primary key mismatch: {msg}"),
+            Errors::ColumnTypeMismatch(msg) => write!(f, "Column type mismatch: {msg}"),
+            Errors::DdlDisabled(msg) => write!(
+                f,
+                "ClickHouse DDL is disabled by dry_run_clickhouse_ddl. Run it before starting clockpipe: {msg}"
+            ),
             Errors::GetTableNameFailed(msg) => write!(f, "Failed to get table name: {msg}"),
             Errors::GetTableCommentFailed(msg) => write!(f, "Failed to get table comment: {msg}"),
             Errors::PublicationCreateFailed(msg) => {
@@ -58,13 +74,22 @@ impl std::fmt::Display for Errors {
                 write!(f, "Failed to advance replication slot: {msg}")
             }
             Errors::PgOutputParseError(msg) => write!(f, "Failed to parse PgOutput: {msg}"),
+            Errors::LsnParseError(msg) => write!(f, "Failed to parse LSN: {msg}"),
             Errors::ListTableColumnsFailed(msg) => {
                 write!(f, "Failed to list table columns: {msg}")
             }
             Errors::ClickhouseDatabaseError(msg) => write!(f, "ClickHouse database error: {msg}"),
+            Errors::FanOutWriteFailed(msg) => {
+                write!(f, "Failed to write to required ClickHouse targets: {msg}")
+            }
+            Errors::SinkWriteFailed(msg) => write!(f, "Failed to write to sink: {msg}"),
             Errors::CopyTableFailed(msg) => write!(f, "Failed to copy table data: {msg}"),
             Errors::CountTableRowsFailed(msg) => write!(f, "Failed to count table rows: {msg}"),
             Errors::ResumeTokenParseError(msg) => write!(f, "Failed to parse resume token: {msg}"),
+            Errors::ResumeTokenStorageFailed(msg) => {
+                write!(f, "Resume token storage failed: {msg}")
+            }
+            Errors::SyncStalled(msg) => write!(f, "Sync iteration stalled: {msg}"),
         }
     }
 }
@@ -39,7 +39,7 @@ async fn main() {
                 config::SourceType::Postgres => {
                     log::info!("Start Postgres pipe");
 
-                    pipes::postgres::run_postgres_pipe(config).await;
+                    pipes::postgres::run_postgres_pipe(config, command.value).await;
                 }
                 config::SourceType::MongoDB => {
                     log::info!("Start MongoDB pipe");
@@ -48,5 +48,81 @@ async fn main() {
                 }
             }
         }
+        command::SubCommand::ReplayWal(command) => {
+            let config = command
+                .value
+                .read_config_from_file()
+                .expect("Failed to read configuration");
+
+            if let Err(error) = pipes::postgres::run_replay_wal(
+                config,
+                command.start_lsn.as_deref(),
+                command.end_lsn.as_deref(),
+                command.limit,
+            )
+            .await
+            {
+                log::error!("Failed to replay WAL: {error}");
+                std::process::exit(1);
+            }
+        }
+        command::SubCommand::DecodeWal(command) => {
+            let config = command
+                .value
+                .read_config_from_file()
+                .expect("Failed to read configuration");
+
+            if let Err(error) = pipes::postgres::run_decode_wal(config, command.limit).await {
+                log::error!("Failed to decode WAL: {error}");
+                std::process::exit(1);
+            }
+        }
+        command::SubCommand::BackfillColumn(command) => {
+            let config = command
+                .value
+                .read_config_from_file()
+                .expect("Failed to read configuration");
+
+            if let Err(error) = pipes::postgres::run_backfill_column(
+                config,
+                &command.table,
+                &command.column,
+                command.batch_size,
+            )
+            .await
+            {
+                log::error!("Failed to backfill column: {error}");
+                std::process::exit(1);
+            }
+        }
+        command::SubCommand::PrintCreate(command) => {
+            let config = command
+                .value
+                .read_config_from_file()
+                .expect("Failed to read configuration");
+
+            let result = match config.source.source_type {
+                config::SourceType::Postgres => pipes::postgres::run_print_create(config).await,
+                config::SourceType::MongoDB => {
+                    pipes::mongodb::run_print_create(config, command.sample_size).await
+                }
+            };
+
+            if let Err(error) = result {
+                log::error!("Failed to print CREATE TABLE queries: {error}");
+                std::process::exit(1);
+            }
+        }
+        command::SubCommand::SelfTest(command) => {
+            let config = command
+                .value
+                .read_config_from_file()
+                .expect("Failed to read configuration");
+
+            if let Err(error) = pipes::self_test::run_self_test(config).await {
+                log::error!("{error}");
+                std::process::exit(1);
+            }
+        }
     }
 }
@@ -0,0 +1,85 @@
+use std::path::PathBuf;
+
+use crate::{
+    adapter::postgres::{format_lsn, parse_lsn},
+    errors,
+};
+
+/// Commit LSN of the last batch that was written to ClickHouse, kept in a file.
+/// The slot is advanced after the writes, so a crash in between makes the next peek return the
+/// same changes again. Transactions committed at or before the stored LSN are skipped then,
+/// instead of running their deletes and updates a second time.
+#[derive(Debug, Clone, Default)]
+pub struct AppliedLsn {
+    /// None disables it: nothing is stored and nothing is skipped
+    path: Option<PathBuf>,
+    lsn: Option<u64>,
+}
+
+impl AppliedLsn {
+    /// Reads the stored LSN. A missing file means nothing was applied yet.
+    pub fn load(path: Option<&str>) -> errors::Result<Self> {
+        let Some(path) = path.map(PathBuf::from) else {
+            return Ok(Self::default());
+        };
+
+        let lsn = match std::fs::read_to_string(&path) {
+            Ok(contents) => Some(parse_lsn(&contents)?),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => None,
+            Err(error) => return Err(error.into()),
+        };
+
+        Ok(Self {
+            path: Some(path),
+            lsn,
+        })
+    }
+
+    pub fn lsn(&self) -> Option<u64> {
+        self.lsn
+    }
+
+    /// Records the commit LSN of a batch after it was written to ClickHouse.
+    /// The file is replaced atomically, so a crash while writing keeps the previous LSN.
+    pub fn store(&mut self, lsn: u64) -> errors::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let temp_path = path.with_extension("tmp");
+        std::fs::write(&temp_path, format_lsn(lsn))?;
+        std::fs::rename(&temp_path, path)?;
+
+        self.lsn = Some(lsn);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AppliedLsn;
+
+    #[test]
+    fn stored_lsn_is_loaded_again() {
+        let path =
+            std::env::temp_dir().join(format!("clockpipe_applied_lsn_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let path_text = path.to_str().unwrap();
+
+        let mut applied_lsn = AppliedLsn::load(Some(path_text)).unwrap();
+        assert_eq!(applied_lsn.lsn(), None);
+
+        applied_lsn.store(0x16_B374_D848).unwrap();
+        assert_eq!(
+            AppliedLsn::load(Some(path_text)).unwrap().lsn(),
+            Some(0x16_B374_D848)
+        );
+
+        std::fs::remove_file(&path).unwrap();
+
+        let mut disabled = AppliedLsn::load(None).unwrap();
+        disabled.store(0x100).unwrap();
+        assert_eq!(disabled.lsn(), None);
+    }
+}
@@ -0,0 +1,118 @@
+use std::time::Duration;
+
+/// Adapts the number of changes peeked per sync iteration to how fast ClickHouse writes are.
+/// Slow writes halve the limit (down to `min_limit`) and fast writes double it (up to `max_limit`),
+/// so that the time spent writing one iteration's batch stays around `target_write_duration`.
+#[derive(Debug, Clone)]
+pub struct PeekLimitController {
+    min_limit: u64,
+    max_limit: u64,
+    current_limit: u64,
+    target_write_duration: Duration,
+}
+
+impl PeekLimitController {
+    pub fn new(min_limit: u64, max_limit: u64, target_write_millis: u64) -> Self {
+        let max_limit = max_limit.max(1);
+        let min_limit = min_limit.clamp(1, max_limit);
+
+        Self {
+            min_limit,
+            max_limit,
+            current_limit: max_limit,
+            target_write_duration: Duration::from_millis(target_write_millis),
+        }
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.current_limit
+    }
+
+    /// Records how long the writes of one iteration took and adjusts the limit for the next peek.
+    pub fn record_write(&mut self, elapsed: Duration) {
+        if elapsed > self.target_write_duration {
+            self.shrink();
+        } else if elapsed < self.target_write_duration / 2 {
+            self.grow();
+        }
+    }
+
+    /// A failed write is treated like a slow one, since oversized batches are a common cause of timeouts.
+    pub fn record_failure(&mut self) {
+        self.shrink();
+    }
+
+    fn shrink(&mut self) {
+        let next_limit = (self.current_limit / 2).max(self.min_limit);
+
+        if next_limit != self.current_limit {
+            log::warn!(
+                "ClickHouse writes are falling behind. Reducing peek limit {} -> {}",
+                self.current_limit,
+                next_limit
+            );
+        }
+
+        self.current_limit = next_limit;
+    }
+
+    fn grow(&mut self) {
+        let next_limit = self.current_limit.saturating_mul(2).min(self.max_limit);
+
+        if next_limit != self.current_limit {
+            log::info!(
+                "ClickHouse writes caught up. Increasing peek limit {} -> {}",
+                self.current_limit,
+                next_limit
+            );
+        }
+
+        self.current_limit = next_limit;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::PeekLimitController;
+
+    #[test]
+    fn slow_writes_shrink_the_limit_down_to_min() {
+        let mut controller = PeekLimitController::new(1000, 65536, 1000);
+        assert_eq!(controller.limit(), 65536);
+
+        controller.record_write(Duration::from_millis(3000));
+        assert_eq!(controller.limit(), 32768);
+
+        for _ in 0..10 {
+            controller.record_write(Duration::from_millis(3000));
+        }
+        assert_eq!(controller.limit(), 1000);
+    }
+
+    #[test]
+    fn fast_writes_grow_the_limit_back_up_to_max() {
+        let mut controller = PeekLimitController::new(1000, 65536, 1000);
+
+        controller.record_failure();
+        controller.record_failure();
+        assert_eq!(controller.limit(), 16384);
+
+        // Within the target but not clearly fast: keep the limit
+        controller.record_write(Duration::from_millis(800));
+        assert_eq!(controller.limit(), 16384);
+
+        for _ in 0..10 {
+            controller.record_write(Duration::from_millis(10));
+        }
+        assert_eq!(controller.limit(), 65536);
+    }
+
+    #[test]
+    fn min_limit_never_exceeds_max_limit() {
+        let controller = PeekLimitController::new(5000, 100, 1000);
+
+        assert_eq!(controller.limit(), 100);
+    }
+}
@@ -2,7 +2,34 @@ pub mod postgres;
 
 pub mod mongodb;
 
-use crate::errors::Errors;
+pub mod applied_lsn;
+
+pub mod backpressure;
+
+pub mod optimize;
+
+pub mod pause;
+
+pub mod reload;
+
+pub mod self_test;
+
+pub mod shutdown;
+
+pub mod sink;
+
+pub mod stats;
+
+pub mod watchdog;
+
+use crate::{
+    adapter::clickhouse::{ClickhouseColumn, ClickhouseConnection, classify_write_error},
+    config::{
+        ClickHouseConfig, ClickHouseTableOptions, Configuraion, ReplicatedOperations, Target,
+    },
+    errors::Errors,
+    pipes::watchdog::Watchdog,
+};
 
 #[async_trait::async_trait]
 pub trait IPipe {
@@ -20,9 +47,649 @@ pub trait IPipe {
     async fn sync_loop(&mut self);
 }
 
+/// Result of one successful sync iteration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncOutcome {
+    NoChanges,
+    Synced,
+    /// Skipped because the pipe is paused. Nothing was peeked or written.
+    Paused,
+}
+
+/// Why a sync iteration failed. Decides how long the loop waits before retrying.
+#[derive(Debug)]
+pub enum SyncError {
+    Peek(Errors),
+    Write(Errors),
+    Cursor(Errors),
+    /// Dropped by the watchdog because it made no progress
+    Stalled(Errors),
+}
+
+impl std::fmt::Display for SyncError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SyncError::Peek(error)
+            | SyncError::Write(error)
+            | SyncError::Cursor(error)
+            | SyncError::Stalled(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+/// Runs one sync iteration, or drops it if the watchdog reports that it stalled.
+/// Nothing of a dropped iteration is committed (the cursor only advances at the end),
+/// so the next iteration peeks the same changes again.
+pub async fn watched_iteration(
+    watchdog: &Watchdog,
+    iteration: impl std::future::Future<Output = Result<SyncOutcome, SyncError>>,
+) -> Result<SyncOutcome, SyncError> {
+    watchdog.touch();
+
+    tokio::select! {
+        outcome = iteration => outcome,
+        idle_time = watchdog.stalled() => {
+            log::error!(
+                "Sync iteration made no progress for {}ms. Restarting it",
+                idle_time.as_millis()
+            );
+
+            Err(SyncError::Stalled(Errors::SyncStalled(format!(
+                "no progress for {}ms",
+                idle_time.as_millis()
+            ))))
+        }
+    }
+}
+
+/// How long to wait after a failed write. Writes that ClickHouse blocks until someone intervenes
+/// (read-only, disk full, quota, memory limit) are reported as such and retried less often.
+pub fn sleep_millis_after_write_error(config: &Configuraion, error: &Errors) -> u64 {
+    match classify_write_error(&error.to_string()) {
+        Some(reason) => {
+            log::error!(
+                "ClickHouse is rejecting writes ({reason:?}): {}. Retrying in {}ms",
+                reason.operator_hint(),
+                config.sleep_millis_when_write_blocked
+            );
+            config.sleep_millis_when_write_blocked
+        }
+        None => config.sleep_millis_when_write_failed,
+    }
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct WriteCounter {
     pub insert_count: usize,
     pub update_count: usize,
     pub delete_count: usize,
 }
+
+/// An additional ClickHouse database that receives every write of the pipe.
+#[derive(Clone)]
+pub struct FanOutTarget {
+    pub config: ClickHouseConfig,
+    pub connection: ClickhouseConnection,
+}
+
+impl FanOutTarget {
+    pub fn new(config: ClickHouseConfig) -> Result<Self, Errors> {
+        let connection = ClickhouseConnection::new(&config.connection)?
+            .with_query_log(config.query_log.as_ref())?;
+
+        Ok(Self { config, connection })
+    }
+
+    /// The main ClickHouse target in the same form, for writes that pick their targets one by one.
+    /// It is always required.
+    pub fn main(config: &ClickHouseConfig, connection: &ClickhouseConnection) -> Self {
+        Self {
+            config: ClickHouseConfig {
+                required: true,
+                ..config.clone()
+            },
+            connection: connection.clone(),
+        }
+    }
+
+    pub fn from_target(target: &Target) -> Result<Vec<Self>, Errors> {
+        target
+            .additional_clickhouse
+            .iter()
+            .cloned()
+            .map(Self::new)
+            .collect()
+    }
+
+    pub fn name(&self) -> String {
+        target_name(&self.config)
+    }
+}
+
+/// `host:port/database` of a ClickHouse target, also the key of per-target state such as the
+/// columns of a table.
+pub fn target_name(clickhouse_config: &ClickHouseConfig) -> String {
+    let connection = &clickhouse_config.connection;
+
+    format!(
+        "{}:{}/{}",
+        connection.host, connection.port, connection.database
+    )
+}
+
+/// Runs `write` against every fan-out target.
+/// A failure on an optional target is only logged, so it never blocks the other targets;
+/// an error is returned if any required target failed, so the caller does not advance the source cursor.
+pub async fn fan_out<'a, F, Fut>(targets: &'a [FanOutTarget], write: F) -> Result<(), Errors>
+where
+    F: Fn(&'a FanOutTarget) -> Fut,
+    Fut: std::future::Future<Output = Result<(), Errors>>,
+{
+    let mut failed_targets = vec![];
+
+    for target in targets {
+        if let Err(error) = write(target).await {
+            if target.config.required {
+                log::error!(
+                    "Write to ClickHouse target {} failed: {error}",
+                    target.name()
+                );
+                failed_targets.push(target.name());
+            } else {
+                log::warn!(
+                    "Write to optional ClickHouse target {} failed, skipping: {error}",
+                    target.name()
+                );
+            }
+        }
+    }
+
+    if !failed_targets.is_empty() {
+        return Err(Errors::FanOutWriteFailed(failed_targets.join(", ")));
+    }
+
+    Ok(())
+}
+
+/// Runs a CREATE/ALTER query on a ClickHouse target.
+/// With `dry_run_clickhouse_ddl` the query is only logged and `DdlDisabled` is returned,
+/// so that the pipe fails instead of writing to a table or column that does not exist.
+pub async fn execute_ddl(
+    config: &Configuraion,
+    clickhouse_connection: &ClickhouseConnection,
+    query: &str,
+) -> Result<(), Errors> {
+    if config.dry_run_clickhouse_ddl {
+        log::warn!("dry_run_clickhouse_ddl is set, not running: {query}");
+        return Err(Errors::DdlDisabled(query.to_string()));
+    }
+
+    clickhouse_connection.execute_query(query).await
+}
+
+/// Executes a write on the main ClickHouse target and then on every fan-out target.
+/// The query is generated per target, since each target may use a different database.
+pub async fn execute_write(
+    clickhouse_config: &ClickHouseConfig,
+    clickhouse_connection: &ClickhouseConnection,
+    fan_out_targets: &[FanOutTarget],
+    generate_query: impl Fn(&ClickHouseConfig) -> String,
+) -> Result<(), Errors> {
+    execute_writes(
+        clickhouse_config,
+        clickhouse_connection,
+        fan_out_targets,
+        |clickhouse_config| vec![generate_query(clickhouse_config)],
+    )
+    .await
+}
+
+/// Same as `execute_write` for a write made of several queries (e.g. a split INSERT), run in order.
+pub async fn execute_writes(
+    clickhouse_config: &ClickHouseConfig,
+    clickhouse_connection: &ClickhouseConnection,
+    fan_out_targets: &[FanOutTarget],
+    generate_queries: impl Fn(&ClickHouseConfig) -> Vec<String>,
+) -> Result<(), Errors> {
+    let queries = non_empty_queries(generate_queries(clickhouse_config));
+
+    if queries.is_empty() {
+        return Ok(());
+    }
+
+    for query in &queries {
+        clickhouse_connection.execute_query(query).await?;
+    }
+
+    execute_writes_on(fan_out_targets, generate_queries).await
+}
+
+/// Executes a write made of several queries on each of `targets`, failing like `fan_out`.
+pub async fn execute_writes_on(
+    targets: &[FanOutTarget],
+    generate_queries: impl Fn(&ClickHouseConfig) -> Vec<String>,
+) -> Result<(), Errors> {
+    fan_out(targets, |target| {
+        let queries = non_empty_queries(generate_queries(&target.config));
+
+        async move {
+            for query in &queries {
+                target.connection.execute_query(query).await?;
+            }
+
+            Ok(())
+        }
+    })
+    .await
+}
+
+/// Targets (of `targets`) on which the ClickHouse table `table_name` has no rows yet, so that the
+/// initial copy of `source_name` writes only to those.
+/// An optional target that cannot be checked is left out, like a failed write to it.
+pub async fn empty_table_targets(
+    targets: impl IntoIterator<Item = FanOutTarget>,
+    source_name: &str,
+    table_name: &str,
+) -> Result<Vec<FanOutTarget>, Errors> {
+    let mut empty_targets = vec![];
+    for target in targets {
+        match target
+            .connection
+            .table_is_not_empty(&target.config.connection.database, table_name)
+            .await
+        {
+            Ok(false) => empty_targets.push(target),
+            Ok(true) => log::info!(
+                "Table {source_name} is not empty in ClickHouse target {}, not copying it there",
+                target.name()
+            ),
+            Err(error) if !target.config.required => log::warn!(
+                "Failed to check table {source_name} on optional ClickHouse target {}, not copying it there: {error}",
+                target.name()
+            ),
+            Err(error) => return Err(error),
+        }
+    }
+
+    Ok(empty_targets)
+}
+
+/// Turns off deletes for a table with an aggregating engine (SummingMergeTree,
+/// AggregatingMergeTree). Its rows are aggregates of all source rows with the same key, so a
+/// delete of one source row would remove the others with it.
+pub fn disable_deletes_for_aggregating_engine(
+    table_name: &str,
+    table_options: &ClickHouseTableOptions,
+    operations: &mut ReplicatedOperations,
+) {
+    let engine = table_options.engine.unwrap_or_default();
+    if !engine.aggregates() {
+        return;
+    }
+
+    if operations.delete {
+        log::warn!("[{table_name}] Deletes are not replicated into a {engine:?} table");
+        operations.delete = false;
+    }
+    if operations.update {
+        log::warn!(
+            "[{table_name}] Updates are inserted as new rows into a {engine:?} table and aggregated with the previous ones. Set operations.update to false for insert-only sources"
+        );
+    }
+}
+
+/// Options of a table on the target of `clickhouse_config`. The global options of a fan-out
+/// target can differ from the main one.
+pub fn table_options_on(
+    table_options: &ClickHouseTableOptions,
+    clickhouse_config: &ClickHouseConfig,
+) -> ClickHouseTableOptions {
+    let mut table_options = table_options.clone();
+    table_options.inherit_from(&clickhouse_config.table_options);
+
+    table_options
+}
+
+/// Leaves out the version and is_deleted columns of `replacing_deleted_merge_tree`.
+/// They are filled by their DEFAULT or by deletes, never from a source value.
+pub fn remove_engine_columns(
+    clickhouse_columns: &mut Vec<ClickhouseColumn>,
+    table_options: &ClickHouseTableOptions,
+) {
+    let engine_column_names = table_options.engine_column_names();
+
+    clickhouse_columns.retain(|column| !engine_column_names.contains(&column.column_name.as_str()));
+}
+
+/// First wait between two startup connection attempts. Doubled after every failure.
+const STARTUP_RETRY_INITIAL_BACKOFF_MILLIS: u64 = 200;
+const STARTUP_RETRY_MAX_BACKOFF_MILLIS: u64 = 5000;
+
+/// Runs `connect` until it succeeds, with backoff, for up to `retry_millis`.
+/// Lets the pipe start before its database is reachable (e.g. containers started together).
+/// The last error is returned once the window has passed.
+pub async fn retry_at_startup<T, F, Fut>(
+    name: &str,
+    retry_millis: u64,
+    mut connect: F,
+) -> Result<T, Errors>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, Errors>>,
+{
+    let deadline = std::time::Instant::now() + std::time::Duration::from_millis(retry_millis);
+    let mut backoff_millis = STARTUP_RETRY_INITIAL_BACKOFF_MILLIS;
+
+    loop {
+        match connect().await {
+            Ok(connection) => return Ok(connection),
+            Err(error) => {
+                let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+
+                if remaining.is_zero() {
+                    return Err(error);
+                }
+
+                let wait = std::time::Duration::from_millis(backoff_millis).min(remaining);
+                log::warn!(
+                    "Failed to connect to {name}: {error}. Retrying in {}ms",
+                    wait.as_millis()
+                );
+
+                tokio::time::sleep(wait).await;
+                backoff_millis = (backoff_millis * 2).min(STARTUP_RETRY_MAX_BACKOFF_MILLIS);
+            }
+        }
+    }
+}
+
+fn non_empty_queries(queries: Vec<String>) -> Vec<String> {
+    queries
+        .into_iter()
+        .filter(|query| !query.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{FanOutTarget, execute_ddl, execute_write, fan_out, retry_at_startup};
+    use crate::{
+        adapter::clickhouse::ClickhouseConnection,
+        config::{
+            ClickHouseConfig, ClickHouseConnectionConfig, ClickHouseTableOptions, Configuraion,
+        },
+        errors::Errors,
+    };
+
+    fn target(host: &str, required: bool) -> FanOutTarget {
+        FanOutTarget::new(ClickHouseConfig {
+            connection: ClickHouseConnectionConfig {
+                host: host.to_string(),
+                port: 8123,
+                username: "default".to_string(),
+                password: String::new(),
+                database: "db".to_string(),
+                scheme: Default::default(),
+                session_settings: Default::default(),
+            },
+            disable_sync_loop: false,
+            table_options: ClickHouseTableOptions::default(),
+            required,
+            non_finite_numbers: Default::default(),
+            identifier_case: Default::default(),
+            date_time_input_format: Default::default(),
+            max_insert_query_bytes: None,
+            missing_values: Default::default(),
+            fixed_string_overflow: Default::default(),
+            binary_encoding: Default::default(),
+            query_log: None,
+        })
+        .unwrap()
+    }
+
+    /// Answers every request with an empty 200 response and sends the request bodies to the channel.
+    pub(crate) async fn recording_clickhouse() -> (u16, tokio::sync::mpsc::UnboundedReceiver<String>)
+    {
+        recording_clickhouse_answering(b"").await
+    }
+
+    /// Same as `recording_clickhouse`, but answers with `response` (RowBinary rows of a SELECT).
+    pub(crate) async fn recording_clickhouse_answering(
+        response: &'static [u8],
+    ) -> (u16, tokio::sync::mpsc::UnboundedReceiver<String>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    let mut request = String::new();
+                    let mut buffer = vec![0; 65536];
+
+                    // Until the headers and `content-length` bytes of body are read
+                    let body = loop {
+                        let length = stream.read(&mut buffer).await.unwrap_or(0);
+                        if length == 0 {
+                            return;
+                        }
+                        request.push_str(&String::from_utf8_lossy(&buffer[..length]));
+
+                        let Some((headers, body)) = request.split_once("\r\n\r\n") else {
+                            continue;
+                        };
+                        let content_length = headers
+                            .lines()
+                            .find_map(|line| {
+                                line.to_lowercase()
+                                    .strip_prefix("content-length: ")
+                                    .map(str::to_string)
+                            })
+                            .and_then(|value| value.trim().parse::<usize>().ok())
+                            .unwrap_or(0);
+                        if body.len() >= content_length {
+                            break body.to_string();
+                        }
+                    };
+                    let _ = sender.send(body);
+                    let header = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
+                        response.len()
+                    );
+                    let _ = stream.write_all(header.as_bytes()).await;
+                    let _ = stream.write_all(response).await;
+                });
+            }
+        });
+
+        (port, receiver)
+    }
+
+    #[tokio::test]
+    async fn dry_run_ddl_is_not_run_but_writes_are() {
+        let (port, mut queries) = recording_clickhouse().await;
+        let config = serde_json::from_value::<Configuraion>(serde_json::json!({
+            "source": { "source_type": "postgres" },
+            "target": { "target_type": "clickhouse" },
+            "dry_run_clickhouse_ddl": true
+        }))
+        .unwrap();
+        let mut clickhouse_config = target("127.0.0.1", true).config;
+        clickhouse_config.connection.port = port;
+        let connection = ClickhouseConnection::new(&clickhouse_config.connection).unwrap();
+
+        let result = execute_ddl(&config, &connection, "CREATE TABLE db.users (id Int64)").await;
+        assert!(
+            matches!(result, Err(Errors::DdlDisabled(ref query)) if query.starts_with("CREATE TABLE")),
+            "{result:?}"
+        );
+
+        execute_write(&clickhouse_config, &connection, &[], |_| {
+            "INSERT INTO db.users (id) VALUES (1)".to_string()
+        })
+        .await
+        .unwrap();
+
+        let query = queries.recv().await.unwrap();
+        assert!(query.contains("INSERT INTO db.users"), "{query}");
+        assert!(queries.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn fan_out_writes_to_every_target() {
+        let targets = vec![target("prod", true), target("analytics", true)];
+        let written = std::sync::Mutex::new(vec![]);
+
+        let result = fan_out(&targets, |target| {
+            written
+                .lock()
+                .unwrap()
+                .push(target.config.connection.host.clone());
+            async { Ok(()) }
+        })
+        .await;
+
+        assert!(result.is_ok());
+        assert_eq!(*written.lock().unwrap(), vec!["prod", "analytics"]);
+    }
+
+    #[tokio::test]
+    async fn fan_out_failure_on_optional_target_does_not_block_others() {
+        let targets = vec![target("analytics", false), target("prod", true)];
+        let written = std::sync::Mutex::new(vec![]);
+
+        let result = fan_out(&targets, |target| {
+            let host = target.config.connection.host.clone();
+            let written = &written;
+            async move {
+                if host == "analytics" {
+                    return Err(Errors::DatabaseQueryError("connection refused".to_string()));
+                }
+                written.lock().unwrap().push(host);
+                Ok(())
+            }
+        })
+        .await;
+
+        assert!(result.is_ok());
+        assert_eq!(*written.lock().unwrap(), vec!["prod"]);
+    }
+
+    #[tokio::test]
+    async fn fan_out_failure_on_required_target_is_reported() {
+        let targets = vec![target("prod", true), target("analytics", true)];
+
+        let result = fan_out(&targets, |target| {
+            let host = target.config.connection.host.clone();
+            async move {
+                if host == "analytics" {
+                    return Err(Errors::DatabaseQueryError("connection refused".to_string()));
+                }
+                Ok(())
+            }
+        })
+        .await;
+
+        assert!(
+            matches!(result, Err(Errors::FanOutWriteFailed(ref targets)) if targets == "analytics:8123/db")
+        );
+    }
+
+    async fn connect(port: u16) -> Result<tokio::net::TcpStream, Errors> {
+        tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .map_err(|error| Errors::DatabaseConnectionError(error.to_string()))
+    }
+
+    #[tokio::test]
+    async fn database_available_after_a_delay_is_connected_at_startup() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let server = tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(700)).await;
+            let listener = tokio::net::TcpListener::bind(("127.0.0.1", port))
+                .await
+                .unwrap();
+            listener.accept().await.unwrap();
+        });
+
+        let result = retry_at_startup("test database", 10000, || connect(port)).await;
+
+        assert!(result.is_ok());
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn startup_connection_gives_up_after_the_retry_window() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut attempts = 0;
+
+        let result = retry_at_startup("test database", 500, || {
+            attempts += 1;
+            connect(port)
+        })
+        .await;
+
+        assert!(matches!(result, Err(Errors::DatabaseConnectionError(_))));
+        assert!(attempts > 1);
+    }
+
+    #[tokio::test]
+    async fn initial_copy_goes_only_to_the_targets_whose_table_is_empty() {
+        let local_target = |port: u16, required: bool| {
+            let mut config = target("127.0.0.1", required).config;
+            config.connection.port = port;
+            FanOutTarget {
+                connection: ClickhouseConnection::new(&config.connection)
+                    .unwrap()
+                    .without_compression(),
+                config,
+            }
+        };
+        let unreachable_port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        // RowBinary `false`/`true` for the emptiness checks
+        let (empty_port, _) = recording_clickhouse_answering(&[0]).await;
+        let (populated_port, _) = recording_clickhouse_answering(&[1]).await;
+        let targets = vec![
+            local_target(empty_port, true),
+            local_target(populated_port, true),
+            local_target(unreachable_port, false),
+        ];
+
+        let empty_targets = super::empty_table_targets(targets.clone(), "shop.users", "users")
+            .await
+            .unwrap();
+        assert_eq!(
+            empty_targets
+                .iter()
+                .map(|target| target.config.connection.port)
+                .collect::<Vec<_>>(),
+            [empty_port]
+        );
+
+        // A required target that cannot be checked fails the copy
+        let result = super::empty_table_targets(
+            [local_target(unreachable_port, true)],
+            "shop.users",
+            "users",
+        )
+        .await;
+        assert!(result.is_err());
+    }
+}
@@ -6,13 +6,24 @@ use mongodb::change_stream::event::OperationType;
 use crate::{
     adapter::{
         self, IntoClickhouse, IntoClickhouseColumn,
-        clickhouse::{ClickhouseColumn, ClickhouseType},
-        mongodb::{MongoDBColumn, MongoDBCopyRow},
+        clickhouse::{ClickhouseColumn, ClickhouseConnection},
+        mongodb::{MongoDBColumn, MongoDBCopyRow, PeekMongoChange, ResumeTokenStorage},
+    },
+    config::{
+        ClickHouseConfig, ClickHouseTableOptions, Configuraion, IdentifierCase, MongoDBConfig,
+        MongoDBSource, bounded_by_flush_interval,
     },
-    config::Configuraion,
     errors::Errors,
     logger::ProgressLogger,
-    pipes::{IPipe, WriteCounter},
+    pipes::{
+        self, FanOutTarget, IPipe, SyncError, SyncOutcome, WriteCounter,
+        backpressure::PeekLimitController,
+        optimize::OptimizeSchedule,
+        pause::PauseSwitch,
+        sink::{ChangeKind, Sink, SinkBatch},
+        stats::SharedSyncStats,
+        watchdog::Watchdog,
+    },
 };
 
 #[derive(Debug, Clone, Default)]
@@ -30,7 +41,20 @@ impl MongoDBPipeContext {
 
 #[derive(Debug, Clone)]
 pub struct MongoDBPipeTableInfo {
+    /// Columns on the main ClickHouse target
     clickhouse_columns: Vec<ClickhouseColumn>,
+    /// Columns on each fan-out target, by target name
+    fan_out_clickhouse_columns: HashMap<String, Vec<ClickhouseColumn>>,
+}
+
+impl MongoDBPipeTableInfo {
+    /// Columns of the table on the target of `clickhouse_config`. A fan-out target can differ from
+    /// the main one, e.g. in identifier_case or in the order its columns were added.
+    fn clickhouse_columns_for(&self, clickhouse_config: &ClickHouseConfig) -> &[ClickhouseColumn] {
+        self.fan_out_clickhouse_columns
+            .get(&pipes::target_name(clickhouse_config))
+            .unwrap_or(&self.clickhouse_columns)
+    }
 }
 
 #[derive(Clone)]
@@ -45,6 +69,14 @@ pub struct MongoDBPipe {
 
     clickhouse_config: crate::config::ClickHouseConfig,
     clickhouse_connection: adapter::clickhouse::ClickhouseConnection,
+
+    fan_out_targets: Vec<FanOutTarget>,
+    sinks: Vec<std::sync::Arc<dyn Sink>>,
+
+    peek_limit_controller: PeekLimitController,
+    stats: SharedSyncStats,
+    pause_switch: PauseSwitch,
+    watchdog: Watchdog,
 }
 
 impl MongoDBPipe {
@@ -52,22 +84,80 @@ impl MongoDBPipe {
         config: Configuraion,
         mongodb_config: crate::config::MongoDBConfig,
         clickhouse_config: crate::config::ClickHouseConfig,
-    ) -> Self {
-        let mongodb_connection = adapter::mongodb::MongoDBConnection::new(&mongodb_config)
-            .await
-            .expect("Failed to create MongoDB connection");
+    ) -> Result<Self, Errors> {
+        mongodb_config.validate()?;
+
+        let mut mongodb_config = mongodb_config;
+        for collection in &mut mongodb_config.collections {
+            let mut table_options = collection.table_options.clone();
+            table_options.inherit_from(&clickhouse_config.table_options);
+
+            pipes::disable_deletes_for_aggregating_engine(
+                &collection.collection_name,
+                &table_options,
+                &mut collection.operations,
+            );
+        }
+
+        let config = config.with_sleep_overrides(&mongodb_config.sleep_overrides);
 
         let clickhouse_connection =
-            adapter::clickhouse::ClickhouseConnection::new(&clickhouse_config.connection);
+            adapter::clickhouse::ClickhouseConnection::new(&clickhouse_config.connection)?
+                .with_query_log(clickhouse_config.query_log.as_ref())?;
+
+        // The ClickHouse table of the resume token is created by setup
+        let resume_token_storage = ResumeTokenStorage::from_config(
+            &mongodb_config,
+            &clickhouse_config,
+            &clickhouse_connection,
+        );
+
+        // The MongoDB client connects lazily, so a ping is what tells whether the server is up.
+        let mongodb_connection =
+            pipes::retry_at_startup("MongoDB", config.startup_retry_millis, || async {
+                let connection = adapter::mongodb::MongoDBConnection::new(
+                    &mongodb_config,
+                    resume_token_storage.clone(),
+                )
+                .await?;
+                connection.ping().await?;
+
+                Ok(connection)
+            })
+            .await?;
+
+        let fan_out_targets = FanOutTarget::from_target(&config.target)?;
+
+        let sinks = pipes::sink::sinks_from_target(&config.target);
+
+        let peek_limit_controller = PeekLimitController::new(
+            config.min_peek_changes_limit,
+            mongodb_config
+                .batch_max_changes
+                .unwrap_or(config.peek_changes_limit),
+            config.target_write_millis_per_iteration,
+        );
+
+        let watchdog = Watchdog::new(
+            config
+                .watchdog_stall_timeout_millis
+                .map(std::time::Duration::from_millis),
+        );
 
-        MongoDBPipe {
+        Ok(MongoDBPipe {
             context: MongoDBPipeContext::default(),
             config,
             mongodb_config,
             clickhouse_config,
             mongodb_connection,
             clickhouse_connection,
-        }
+            fan_out_targets,
+            sinks,
+            peek_limit_controller,
+            stats: SharedSyncStats::default(),
+            pause_switch: PauseSwitch::default(),
+            watchdog,
+        })
     }
 }
 
@@ -84,6 +174,8 @@ impl IPipe for MongoDBPipe {
             .await
             .map_err(|e| Errors::DatabasePingError(format!("ClickHouse ping failed: {e}")))?;
 
+        pipes::fan_out(&self.fan_out_targets, |target| target.connection.ping()).await?;
+
         log::info!("MongoDB and ClickHouse connections are healthy.");
 
         Ok(())
@@ -102,8 +194,7 @@ impl IPipe for MongoDBPipe {
 
         // 1. For each table in MongoDB config
         for collection in &self.mongodb_config.collections {
-            let clickhouse_database_name = &self.clickhouse_config.connection.database;
-            let mongodb_database_name = &self.mongodb_config.connection.database;
+            let mongodb_database_name = self.mongodb_config.collection_database(collection);
             let mongodb_collection_name = &collection.collection_name;
 
             // 2. Check if skip_copy is set
@@ -115,14 +206,20 @@ impl IPipe for MongoDBPipe {
                 continue;
             }
 
-            // 3. Check if table is not empty in ClickHouse
-            // If not empty, skip the initial sync for this table
-            if self
-                .clickhouse_connection
-                .table_is_not_empty(clickhouse_database_name, mongodb_collection_name)
-                .await
-                .expect("Failed to check if table exists")
-            {
+            // 3. Check on each ClickHouse target if the table is not empty
+            // If not empty, skip the initial sync for this table on that target
+            let copy_targets = pipes::empty_table_targets(
+                std::iter::once(FanOutTarget::main(
+                    &self.clickhouse_config,
+                    &self.clickhouse_connection,
+                ))
+                .chain(self.fan_out_targets.iter().cloned()),
+                mongodb_collection_name,
+                mongodb_collection_name,
+            )
+            .await
+            .expect("Failed to check if table exists");
+            if copy_targets.is_empty() {
                 log::debug!(
                     "Collection {mongodb_collection_name} already exists in ClickHouse, skipping initial sync.",
                 );
@@ -156,8 +253,17 @@ impl IPipe for MongoDBPipe {
             );
 
             // 6. Receive copied rows in batches and insert into ClickHouse
+            // Columns are discovered while copying, so keep a local view of the ClickHouse schema
+            // that is refreshed whenever new columns get added.
+            let mut table_info = self
+                .context
+                .tables_map
+                .get(&collection.collection_name)
+                .expect("Table info not found in context")
+                .clone();
             let mut rows = Vec::new();
-            while let Some(row) = copy_receiver.recv().await {
+            while let Some(mut row) = copy_receiver.recv().await {
+                self.prepare_row(mongodb_collection_name, &mut row);
                 rows.push(row);
 
                 // If buffer size is less than threshold, continue accumulating
@@ -165,40 +271,43 @@ impl IPipe for MongoDBPipe {
                     continue;
                 }
 
-                let source_table_info = self
-                    .context
-                    .tables_map
-                    .get(&collection.collection_name)
-                    .expect("Table info not found in context");
                 let mask_columns = &collection.mask_columns;
 
                 logger.log_progress(processed_rows);
 
                 // 7. Add columns to ClickHouse table if not exists
-                self.add_columns_to_table_if_not_exists(&collection.collection_name, &rows)
+                if self
+                    .add_columns_to_table_if_not_exists(
+                        &collection.collection_name,
+                        &table_info.clickhouse_columns,
+                        &rows,
+                    )
                     .await
-                    .expect("Failed to add columns to ClickHouse table if not exists");
+                    .expect("Failed to add columns to ClickHouse table if not exists")
+                {
+                    table_info = self
+                        .fetch_table_info(mongodb_collection_name)
+                        .await
+                        .expect("Failed to reload ClickHouse columns");
+                }
 
                 log::info!(
                     "Inserting copied data into ClickHouse table {mongodb_collection_name}...",
                 );
 
                 // 8. Do Insert into ClickHouse
-                let insert_query = self.generate_insert_query(
-                    &self.clickhouse_config,
-                    &source_table_info.clickhouse_columns,
-                    &Vec::<MongoDBColumn>::new(), // MongoDB does not have a fixed schema, so we pass an empty slice here
-                    mask_columns,
-                    &collection.collection_name,
-                    &rows,
-                );
-
-                if !insert_query.is_empty() {
-                    self.clickhouse_connection
-                        .execute_query(&insert_query)
-                        .await
-                        .expect("Failed to execute insert query in ClickHouse");
-                }
+                pipes::execute_writes_on(&copy_targets, |clickhouse_config| {
+                    self.generate_insert_queries(
+                        clickhouse_config,
+                        table_info.clickhouse_columns_for(clickhouse_config),
+                        &Vec::<MongoDBColumn>::new(), // MongoDB does not have a fixed schema, so we pass an empty slice here
+                        mask_columns,
+                        &collection.collection_name,
+                        &rows,
+                    )
+                })
+                .await
+                .expect("Failed to execute insert query in ClickHouse");
 
                 processed_rows += rows.len();
                 rows.truncate(0); // Clear the buffer (without deallocating)
@@ -206,33 +315,36 @@ impl IPipe for MongoDBPipe {
 
             // Flush remaining rows that didn't reach the batch threshold
             if !rows.is_empty() {
-                let source_table_info = self
-                    .context
-                    .tables_map
-                    .get(&collection.collection_name)
-                    .expect("Table info not found in context");
                 let mask_columns = &collection.mask_columns;
 
-                self.add_columns_to_table_if_not_exists(&collection.collection_name, &rows)
+                if self
+                    .add_columns_to_table_if_not_exists(
+                        &collection.collection_name,
+                        &table_info.clickhouse_columns,
+                        &rows,
+                    )
                     .await
-                    .expect("Failed to add columns to ClickHouse table if not exists");
-
-                let insert_query = self.generate_insert_query(
-                    &self.clickhouse_config,
-                    &source_table_info.clickhouse_columns,
-                    &Vec::<MongoDBColumn>::new(),
-                    mask_columns,
-                    &collection.collection_name,
-                    &rows,
-                );
-
-                if !insert_query.is_empty() {
-                    self.clickhouse_connection
-                        .execute_query(&insert_query)
+                    .expect("Failed to add columns to ClickHouse table if not exists")
+                {
+                    table_info = self
+                        .fetch_table_info(mongodb_collection_name)
                         .await
-                        .expect("Failed to execute insert query in ClickHouse");
+                        .expect("Failed to reload ClickHouse columns");
                 }
 
+                pipes::execute_writes_on(&copy_targets, |clickhouse_config| {
+                    self.generate_insert_queries(
+                        clickhouse_config,
+                        table_info.clickhouse_columns_for(clickhouse_config),
+                        &Vec::<MongoDBColumn>::new(),
+                        mask_columns,
+                        &collection.collection_name,
+                        &rows,
+                    )
+                })
+                .await
+                .expect("Failed to execute insert query in ClickHouse");
+
                 processed_rows += rows.len();
             }
 
@@ -252,390 +364,571 @@ impl IPipe for MongoDBPipe {
 
         log::info!("Starting sync loop...");
 
-        'SYNC_LOOP: loop {
-            // 1. Peek new rows
-            let peek_result = self
-                .mongodb_connection
-                .peek_changes(
-                    &self.mongodb_config.connection.database,
-                    &self
-                        .mongodb_config
-                        .collections
-                        .iter()
-                        .map(|c| c.collection_name.as_str())
-                        .collect::<Vec<&str>>(),
-                    self.config.peek_changes_limit,
-                    self.mongodb_config.peek_timeout_millis,
-                )
-                .await;
+        pipes::optimize::spawn_optimize_task(
+            OptimizeSchedule::from_table_options(
+                self.mongodb_config.collections.iter().map(|collection| {
+                    (
+                        collection.collection_name.as_str(),
+                        &collection.table_options,
+                    )
+                }),
+                &self.clickhouse_config.table_options,
+                std::time::Instant::now(),
+            ),
+            self.clickhouse_config.clone(),
+            self.clickhouse_connection.clone(),
+            self.fan_out_targets.clone(),
+            self.pause_switch.clone(),
+        );
 
-            let peek_result = match peek_result {
-                Ok(peek) => peek,
-                Err(e) => {
-                    // 1.1. Handle peek error. wait and retry
-                    log::error!("Error peeking stream changes: {e:?}");
-                    tokio::time::sleep(std::time::Duration::from_millis(
-                        self.config.sleep_millis_when_peek_failed,
-                    ))
-                    .await;
-                    continue;
+        let max_flush_interval_millis = self.mongodb_config.max_flush_interval_millis;
+        let sleep_millis_when_peek_is_empty = bounded_by_flush_interval(
+            self.config.sleep_millis_when_peek_is_empty,
+            max_flush_interval_millis,
+        );
+        let sleep_millis_after_sync_iteration = bounded_by_flush_interval(
+            self.config.sleep_millis_after_sync_iteration,
+            max_flush_interval_millis,
+        );
+
+        loop {
+            let watchdog = self.watchdog.clone();
+            let sleep_millis = match pipes::watched_iteration(&watchdog, self.sync_once()).await {
+                Ok(SyncOutcome::NoChanges) => {
+                    log::info!("No new changes found, waiting for next iteration...");
+                    sleep_millis_when_peek_is_empty
+                }
+                Ok(SyncOutcome::Synced) => sleep_millis_after_sync_iteration,
+                Ok(SyncOutcome::Paused) => sleep_millis_when_peek_is_empty,
+                Err(error) => {
+                    self.stats.update(|stats| stats.record_error(&error));
+
+                    match error {
+                        SyncError::Peek(_) => self.config.sleep_millis_when_peek_failed,
+                        SyncError::Write(error) => {
+                            pipes::sleep_millis_after_write_error(&self.config, &error)
+                        }
+                        SyncError::Cursor(_) => self.config.sleep_millis_when_write_failed,
+                        SyncError::Stalled(_) => 0,
+                    }
                 }
             };
 
-            if peek_result.changes.is_empty() {
-                log::info!("No new changes found, waiting for next iteration...");
-                tokio::time::sleep(std::time::Duration::from_millis(
-                    self.config.sleep_millis_when_peek_is_empty,
-                ))
-                .await;
-                continue;
-            }
+            tokio::time::sleep(std::time::Duration::from_millis(sleep_millis)).await;
+        }
+    }
+}
 
-            // 2. Group by collection_name for batch insert
-            let chunk_iter = peek_result
-                .changes
-                .into_iter()
-                .chunk_by(|change| change.collection_name.clone());
+impl MongoDBPipe {
+    /// Accessor for the statistics of this pipe. The handle stays valid while the pipe runs.
+    pub fn stats(&self) -> SharedSyncStats {
+        self.stats.clone()
+    }
 
-            let mut changes_by_collection = HashMap::new();
-            for (collection_name, group) in &chunk_iter {
-                changes_by_collection.insert(collection_name.clone(), group.collect::<Vec<_>>());
-            }
+    /// Switch to pause and resume writing while the pipe runs.
+    pub fn pause_switch(&self) -> PauseSwitch {
+        self.pause_switch.clone()
+    }
 
-            // 2. Group by table and change Clickhouse table schema if needed
-            for (collection_name, rows) in &changes_by_collection {
-                let copy_rows = rows
-                    .iter()
-                    .map(|change| change.to_copy_row().unwrap_or_default())
-                    .collect::<Vec<_>>();
+    /// Runs one iteration of the sync loop: peek, write to ClickHouse, then store the resume token.
+    /// Sleeping between iterations is left to the caller.
+    pub async fn sync_once(&mut self) -> Result<SyncOutcome, SyncError> {
+        if self.pause_switch.is_paused() {
+            return Ok(SyncOutcome::Paused);
+        }
 
-                // 2.1. Add columns to ClickHouse table if not exists
-                if let Err(error) = self
-                    .add_columns_to_table_if_not_exists(collection_name, &copy_rows)
-                    .await
-                {
-                    log::error!(
-                        "Failed to add columns to ClickHouse table {}: {}",
-                        collection_name,
-                        error
-                    );
+        let batch_max_wait_millis = bounded_by_flush_interval(
+            self.mongodb_config.batch_max_wait_millis,
+            self.mongodb_config.max_flush_interval_millis,
+        );
 
-                    tokio::time::sleep(std::time::Duration::from_millis(
-                        self.config.sleep_millis_when_write_failed,
-                    ))
-                    .await;
+        // 1. Peek new rows
+        let peek_result = self
+            .mongodb_connection
+            .peek_changes(
+                &self.mongodb_config.connection.database,
+                &self
+                    .mongodb_config
+                    .collections
+                    .iter()
+                    .map(|c| {
+                        (
+                            self.mongodb_config.collection_database(c),
+                            c.collection_name.as_str(),
+                        )
+                    })
+                    .collect::<Vec<(&str, &str)>>(),
+                self.peek_limit_controller.limit(),
+                batch_max_wait_millis,
+            )
+            .await;
 
-                    continue 'SYNC_LOOP;
-                }
+        self.watchdog.touch();
 
-                // 2.2. Reload table info after schema change
-                if let Err(error) = self.load_table_table_info(collection_name).await {
-                    log::error!(
-                        "Failed to reload table info for ClickHouse table {}: {}",
-                        collection_name,
-                        error
-                    );
+        let peek_result = match peek_result {
+            Ok(peek) => peek,
+            Err(e) => {
+                log::error!("Error peeking stream changes: {e:?}");
+                return Err(SyncError::Peek(e));
+            }
+        };
 
-                    tokio::time::sleep(std::time::Duration::from_millis(
-                        self.config.sleep_millis_when_write_failed,
-                    ))
-                    .await;
+        if peek_result.changes.is_empty() {
+            return Ok(SyncOutcome::NoChanges);
+        }
 
-                    continue 'SYNC_LOOP;
-                }
-            }
+        // 2. Group by collection_name for batch insert
+        let changes_by_collection =
+            group_changes_by_collection(peek_result.changes, &self.context.tables_map);
+
+        // 2. Group by table and change Clickhouse table schema if needed
+        for (collection_name, rows) in &changes_by_collection {
+            let copy_rows = rows
+                .iter()
+                .map(|change| {
+                    let mut copy_row = change.to_copy_row().unwrap_or_default();
+                    self.prepare_row(collection_name, &mut copy_row);
+                    copy_row
+                })
+                .collect::<Vec<_>>();
+
+            let Some(table_info) = self.context.tables_map.get(collection_name) else {
+                log::error!("Table info not found in context for {collection_name}");
+                continue;
+            };
 
-            let mut table_log_map = HashMap::new();
-
-            let mut batch_insert_queue = HashMap::new();
-            let mut batch_delete_queue: HashMap<String, BatchWriteEntry<'_>> = HashMap::new();
-
-            // 3. Group by table and prepare for insert/update/delete
-            for (collection_name, rows) in changes_by_collection {
-                for row in rows {
-                    let copy_row = row.to_copy_row().unwrap_or_default();
-
-                    match row.operation_type {
-                        OperationType::Insert | OperationType::Update => {
-                            let table_info = self
-                                .context
-                                .tables_map
-                                .get(&collection_name)
-                                .expect("Table info not found in context");
-
-                            let mask_columns = self
-                                .mongodb_config
-                                .collections
-                                .iter()
-                                .find(|t| t.collection_name == collection_name.as_str())
-                                .map_or_else(Vec::new, |t| t.mask_columns.clone());
-
-                            batch_insert_queue
-                                .entry(collection_name.clone())
-                                .or_insert_with(|| BatchWriteEntry {
-                                    table_info,
-                                    mask_columns,
-                                    rows: Vec::new(),
-                                })
-                                .push(copy_row);
-
-                            let count: &mut WriteCounter = table_log_map
-                                .entry(collection_name.clone())
-                                .or_insert(WriteCounter::default());
-
-                            if row.operation_type == OperationType::Insert {
-                                count.insert_count += 1;
-                            } else {
-                                count.update_count += 1;
-                            }
-                        }
-                        OperationType::Delete => {
-                            let source_table_info = self
-                                .context
-                                .tables_map
-                                .get(&collection_name)
-                                .expect("Table info not found in context");
-
-                            batch_delete_queue
-                                .entry(collection_name.clone())
-                                .or_insert_with(|| BatchWriteEntry {
-                                    table_info: source_table_info,
-                                    mask_columns: Vec::new(),
-                                    rows: Vec::new(),
-                                })
-                                .push(copy_row);
-
-                            let count = table_log_map
-                                .entry(collection_name.clone())
-                                .or_insert(WriteCounter::default());
-
-                            count.delete_count += 1;
-                        }
-                        _ => {}
-                    }
-                }
+            // 2.1. Add columns to ClickHouse table if not exists
+            if let Err(error) = self
+                .add_columns_to_table_if_not_exists(
+                    collection_name,
+                    &table_info.clickhouse_columns,
+                    &copy_rows,
+                )
+                .await
+            {
+                log::error!(
+                    "Failed to add columns to ClickHouse table {}: {}",
+                    collection_name,
+                    error
+                );
+
+                return Err(SyncError::Write(error));
             }
 
-            // 4. Insert/Update rows in ClickHouse
-            for (table_name, batch) in batch_insert_queue.iter() {
-                let insert_query = self.generate_insert_query(
-                    &self.clickhouse_config,
-                    &batch.table_info.clickhouse_columns,
-                    &Vec::<MongoDBColumn>::new(), // MongoDB does not have a fixed schema, so we pass an empty slice here
-                    &batch.mask_columns,
-                    table_name,
-                    &batch.deduplicated_rows(),
+            // 2.2. Reload table info after schema change
+            if let Err(error) = self.load_table_table_info(collection_name).await {
+                log::error!(
+                    "Failed to reload table info for ClickHouse table {}: {}",
+                    collection_name,
+                    error
                 );
 
-                if !insert_query.is_empty() {
-                    if let Err(error) = self
-                        .clickhouse_connection
-                        .execute_query(&insert_query)
-                        .await
-                    {
-                        log::error!("Failed to execute insert query for {table_name}: {error}");
-                        tokio::time::sleep(std::time::Duration::from_millis(
-                            self.config.sleep_millis_when_write_failed,
-                        ))
-                        .await;
-
-                        continue 'SYNC_LOOP;
-                    }
-
-                    tokio::time::sleep(std::time::Duration::from_millis(
-                        self.config.sleep_millis_after_sync_write,
-                    ))
-                    .await;
-                }
+                return Err(SyncError::Write(error));
             }
+        }
 
-            // 5. Delete rows in ClickHouse
-            for (table_name, batch) in batch_delete_queue.iter() {
-                let delete_query = self.generate_delete_query(
-                    &self.clickhouse_config,
-                    &batch.table_info.clickhouse_columns,
-                    &Vec::<MongoDBColumn>::new(), // MongoDB does not have a fixed schema, so we pass an empty slice here
-                    table_name,
-                    &batch.rows,
-                );
+        let mut table_log_map = HashMap::new();
+
+        let mut batch_insert_queue = HashMap::new();
+        let mut batch_delete_queue: HashMap<String, BatchWriteEntry<'_>> = HashMap::new();
+
+        // Position of a change in the stream, kept per collection by the grouping
+        let mut sequence = 0;
+
+        // 3. Group by table and prepare for insert/update/delete
+        for (collection_name, rows) in changes_by_collection {
+            let collection = self
+                .mongodb_config
+                .collections
+                .iter()
+                .find(|t| t.collection_name == collection_name.as_str());
+            let operations = collection.map(|t| t.operations).unwrap_or_default();
+            let table_options = collection
+                .map(|t| t.table_options.clone())
+                .unwrap_or_default();
+
+            for row in rows {
+                sequence += 1;
+
+                let is_enabled = match row.operation_type {
+                    OperationType::Insert => operations.insert,
+                    OperationType::Update => operations.update,
+                    OperationType::Delete => operations.delete,
+                    _ => true,
+                };
+                if !is_enabled {
+                    continue;
+                }
 
-                if !delete_query.is_empty() {
-                    if let Err(error) = self
-                        .clickhouse_connection
-                        .execute_query(&delete_query)
-                        .await
-                    {
-                        log::error!("Failed to execute delete query for {table_name}: {error}");
-                        tokio::time::sleep(std::time::Duration::from_millis(
-                            self.config.sleep_millis_when_write_failed,
-                        ))
-                        .await;
-
-                        continue 'SYNC_LOOP;
+                let mut copy_row = row.to_copy_row().unwrap_or_default();
+                self.prepare_row(&collection_name, &mut copy_row);
+
+                match row.operation_type {
+                    OperationType::Insert | OperationType::Update => {
+                        let Some(table_info) = self.context.tables_map.get(&collection_name) else {
+                            log::warn!(
+                                "Table info not found in context for {collection_name}. Skipping change"
+                            );
+                            continue;
+                        };
+
+                        let mask_columns =
+                            collection.map_or_else(Vec::new, |t| t.mask_columns.clone());
+
+                        batch_insert_queue
+                            .entry(collection_name.clone())
+                            .or_insert_with(|| BatchWriteEntry {
+                                table_info,
+                                mask_columns,
+                                table_options: table_options.clone(),
+                                rows: Vec::new(),
+                                sequences: Vec::new(),
+                            })
+                            .push(copy_row, sequence);
+
+                        let count: &mut WriteCounter = table_log_map
+                            .entry(collection_name.clone())
+                            .or_insert(WriteCounter::default());
+
+                        if row.operation_type == OperationType::Insert {
+                            count.insert_count += 1;
+                        } else {
+                            count.update_count += 1;
+                        }
                     }
-
-                    tokio::time::sleep(std::time::Duration::from_millis(
-                        self.config.sleep_millis_after_sync_write,
-                    ))
-                    .await;
+                    OperationType::Delete => {
+                        let Some(source_table_info) = self.context.tables_map.get(&collection_name)
+                        else {
+                            log::warn!(
+                                "Table info not found in context for {collection_name}. Skipping change"
+                            );
+                            continue;
+                        };
+
+                        batch_delete_queue
+                            .entry(collection_name.clone())
+                            .or_insert_with(|| BatchWriteEntry {
+                                table_info: source_table_info,
+                                mask_columns: Vec::new(),
+                                table_options: table_options.clone(),
+                                rows: Vec::new(),
+                                sequences: Vec::new(),
+                            })
+                            .push(copy_row, sequence);
+
+                        let count = table_log_map
+                            .entry(collection_name.clone())
+                            .or_insert(WriteCounter::default());
+
+                        count.delete_count += 1;
+                    }
+                    _ => {}
                 }
             }
+        }
 
-            // 6. Move cursor for next peek
-            if let Err(error) = self
-                .mongodb_connection
-                .store_resume_token(&peek_result.resume_token)
-            {
-                log::error!("Failed to store resume token: {error}");
-                tokio::time::sleep(std::time::Duration::from_millis(
-                    self.config.sleep_millis_when_write_failed,
-                ))
+        let mut write_elapsed = std::time::Duration::ZERO;
+
+        // 4. Insert/Update rows in ClickHouse
+        for (table_name, batch) in batch_insert_queue.iter() {
+            let rows = batch.deduplicated_rows();
+
+            let write_started_at = std::time::Instant::now();
+            let write_result = self
+                .execute_writes(|clickhouse_config| {
+                    self.generate_insert_queries(
+                        clickhouse_config,
+                        batch.table_info.clickhouse_columns_for(clickhouse_config),
+                        &Vec::<MongoDBColumn>::new(), // MongoDB does not have a fixed schema, so we pass an empty slice here
+                        &batch.mask_columns,
+                        table_name,
+                        &rows,
+                    )
+                })
                 .await;
+            write_elapsed += write_started_at.elapsed();
 
-                continue 'SYNC_LOOP;
+            if let Err(error) = write_result {
+                self.peek_limit_controller.record_failure();
+                log::error!("Failed to execute insert query for {table_name}: {error}");
+                return Err(SyncError::Write(error));
             }
 
-            // 7. Log the changes
-            for (table_name, count) in table_log_map.iter() {
-                log::info!(
-                    "Table [{}]: Inserted: {}, Updated: {}, Deleted: {}",
-                    table_name,
-                    count.insert_count,
-                    count.update_count,
-                    count.delete_count
-                );
+            self.watchdog.touch();
+
+            tokio::time::sleep(std::time::Duration::from_millis(
+                self.config.sleep_millis_after_sync_write,
+            ))
+            .await;
+        }
+
+        // 5. Delete rows in ClickHouse
+        for (table_name, batch) in batch_delete_queue.iter() {
+            let write_started_at = std::time::Instant::now();
+            let write_result = self
+                .execute_write(|clickhouse_config| {
+                    let table_options =
+                        pipes::table_options_on(&batch.table_options, clickhouse_config);
+
+                    if table_options.deletes_by_marker() {
+                        self.generate_delete_marker_query(
+                            clickhouse_config,
+                            batch.table_info.clickhouse_columns_for(clickhouse_config),
+                            &Vec::<MongoDBColumn>::new(),
+                            table_options.is_deleted_column_name(),
+                            table_name,
+                            &batch.rows,
+                        )
+                    } else {
+                        self.generate_delete_query(
+                            clickhouse_config,
+                            batch.table_info.clickhouse_columns_for(clickhouse_config),
+                            &Vec::<MongoDBColumn>::new(), // MongoDB does not have a fixed schema, so we pass an empty slice here
+                            table_name,
+                            &batch.rows,
+                        )
+                    }
+                })
+                .await;
+            write_elapsed += write_started_at.elapsed();
+
+            if let Err(error) = write_result {
+                self.peek_limit_controller.record_failure();
+                log::error!("Failed to execute delete query for {table_name}: {error}");
+                return Err(SyncError::Write(error));
             }
 
+            self.watchdog.touch();
+
             tokio::time::sleep(std::time::Duration::from_millis(
-                self.config.sleep_millis_after_sync_iteration,
+                self.config.sleep_millis_after_sync_write,
             ))
             .await;
         }
+
+        self.peek_limit_controller.record_write(write_elapsed);
+
+        // 5.1. Archive the changes to the additional sinks
+        if let Err(error) = self
+            .write_to_sinks(&batch_insert_queue, &batch_delete_queue)
+            .await
+        {
+            log::error!("Failed to write changes to sinks: {error}");
+            return Err(SyncError::Write(error));
+        }
+
+        // 6. Move cursor for next peek
+        if let Err(error) = self
+            .mongodb_connection
+            .store_resume_token(&peek_result.resume_token)
+            .await
+        {
+            log::error!("Failed to store resume token: {error}");
+            return Err(SyncError::Cursor(error));
+        }
+
+        let resume_token = serde_json::to_string(&peek_result.resume_token).ok();
+        self.stats
+            .update(|stats| stats.record_iteration(&table_log_map, resume_token));
+
+        // 7. Log the changes
+        for (table_name, count) in table_log_map.iter() {
+            log::info!(
+                "Table [{}]: Inserted: {}, Updated: {}, Deleted: {}",
+                table_name,
+                count.insert_count,
+                count.update_count,
+                count.delete_count
+            );
+        }
+
+        Ok(SyncOutcome::Synced)
     }
-}
 
-impl MongoDBPipe {
     async fn setup_table(&mut self) -> Result<(), Errors> {
         log::info!("Setting up tables in ClickHouse...");
 
+        setup_resume_token_table(
+            &self.config,
+            &self.mongodb_config,
+            &self.clickhouse_config,
+            &self.clickhouse_connection,
+        )
+        .await?;
+
         let collections = self.mongodb_config.collections.clone();
 
         for collection in &collections {
-            let clickhouse_table_not_exists = self
-                .clickhouse_connection
-                .list_columns_by_tablename(
-                    &self.clickhouse_config.connection.database,
-                    &collection.collection_name,
-                )
-                .await?
-                .is_empty();
+            self.setup_clickhouse_table(
+                &self.clickhouse_config,
+                &self.clickhouse_connection,
+                collection,
+            )
+            .await?;
 
-            if clickhouse_table_not_exists {
-                log::info!(
-                    "Table {}.{} does not exist in ClickHouse, creating it",
-                    &self.clickhouse_config.connection.database,
-                    collection.collection_name
-                );
+            pipes::fan_out(&self.fan_out_targets, |target| {
+                self.setup_clickhouse_table(&target.config, &target.connection, collection)
+            })
+            .await?;
 
-                let mut table_options = collection.table_options.clone();
-                table_options.inherit_from(&self.clickhouse_config.table_options);
+            self.load_table_table_info(&collection.collection_name)
+                .await?;
+        }
 
-                let create_table_query = self.generate_create_table_query(
-                    &self.clickhouse_config,
-                    &table_options,
-                    &collection.collection_name,
-                    &[MongoDBColumn {
-                        column_name: "_id".to_string(),
-                        bson_value: mongodb::bson::Bson::ObjectId(
-                            mongodb::bson::oid::ObjectId::new(),
-                        ),
-                    }],
-                    "",
-                );
+        Ok(())
+    }
 
-                self.clickhouse_connection
-                    .execute_query(&create_table_query)
-                    .await?;
+    /// Creates the table in a ClickHouse target if it does not exist.
+    /// Columns other than _id are added while syncing.
+    async fn setup_clickhouse_table(
+        &self,
+        clickhouse_config: &ClickHouseConfig,
+        clickhouse_connection: &ClickhouseConnection,
+        collection: &MongoDBSource,
+    ) -> Result<(), Errors> {
+        let database_name = &clickhouse_config.connection.database;
 
-                log::info!(
-                    "Table {}.{} created in ClickHouse",
-                    &self.clickhouse_config.connection.database,
-                    collection.collection_name,
-                );
-            }
+        let clickhouse_table_not_exists = clickhouse_connection
+            .list_columns_by_tablename(database_name, &collection.collection_name)
+            .await?
+            .is_empty();
 
-            self.load_table_table_info(&collection.collection_name)
-                .await?;
+        if clickhouse_table_not_exists {
+            log::info!(
+                "Table {}.{} does not exist in ClickHouse, creating it",
+                database_name,
+                collection.collection_name
+            );
+
+            let create_table_query = create_table_query(self, clickhouse_config, collection);
+
+            pipes::execute_ddl(&self.config, clickhouse_connection, &create_table_query).await?;
+
+            log::info!(
+                "Table {}.{} created in ClickHouse",
+                database_name,
+                collection.collection_name,
+            );
         }
 
         Ok(())
     }
 
     async fn load_table_table_info(&mut self, table_name: &str) -> Result<(), Errors> {
-        let clickhouse_columns = self
+        let table_info = self.fetch_table_info(table_name).await?;
+
+        self.context
+            .tables_map
+            .insert(table_name.to_string(), table_info);
+
+        Ok(())
+    }
+
+    /// Current ClickHouse columns of a table on the main and the fan-out targets.
+    async fn fetch_table_info(&self, table_name: &str) -> Result<MongoDBPipeTableInfo, Errors> {
+        let mut clickhouse_columns = self
             .clickhouse_connection
-            .list_columns_by_tablename(&self.clickhouse_config.connection.database, table_name)
+            .wait_for_columns(&self.clickhouse_config.connection.database, table_name)
             .await?;
-
-        self.context.tables_map.insert(
-            table_name.to_string(),
-            MongoDBPipeTableInfo { clickhouse_columns },
+        pipes::remove_engine_columns(
+            &mut clickhouse_columns,
+            &self.table_options_on(table_name, &self.clickhouse_config),
         );
 
-        Ok(())
+        let fan_out_clickhouse_columns = std::sync::Mutex::new(HashMap::new());
+        pipes::fan_out(&self.fan_out_targets, |target| async {
+            let mut clickhouse_columns = target
+                .connection
+                .wait_for_columns(&target.config.connection.database, table_name)
+                .await?;
+            pipes::remove_engine_columns(
+                &mut clickhouse_columns,
+                &self.table_options_on(table_name, &target.config),
+            );
+
+            fan_out_clickhouse_columns
+                .lock()
+                .expect("fan-out columns lock poisoned")
+                .insert(target.name(), clickhouse_columns);
+
+            Ok(())
+        })
+        .await?;
+
+        Ok(MongoDBPipeTableInfo {
+            clickhouse_columns,
+            fan_out_clickhouse_columns: fan_out_clickhouse_columns
+                .into_inner()
+                .expect("fan-out columns lock poisoned"),
+        })
     }
 
+    /// Options of the table of a collection on the target of `clickhouse_config`
+    fn table_options_on(
+        &self,
+        collection_name: &str,
+        clickhouse_config: &ClickHouseConfig,
+    ) -> ClickHouseTableOptions {
+        let table_options = self
+            .mongodb_config
+            .collections
+            .iter()
+            .find(|collection| collection.collection_name == collection_name)
+            .map(|collection| collection.table_options.clone())
+            .unwrap_or_default();
+
+        pipes::table_options_on(&table_options, clickhouse_config)
+    }
+
+    /// Adds the columns seen in `rows` that are not in `clickhouse_columns` yet.
+    /// Returns true if the ClickHouse table schema was changed.
     async fn add_columns_to_table_if_not_exists(
         &self,
         collection_name: &str,
+        clickhouse_columns: &[ClickhouseColumn],
         rows: &[MongoDBCopyRow],
-    ) -> Result<(), Errors> {
-        let mut columns_to_add = vec![];
-        let clickhouse_columns: &MongoDBPipeTableInfo = self
-            .context
-            .tables_map
-            .get(collection_name)
-            .expect("Table info not found in context");
-
-        for row in rows {
-            for column in &row.columns {
-                if !clickhouse_columns
-                    .clickhouse_columns
-                    .iter()
-                    .any(|c| c.column_name == column.column_name)
-                    && !columns_to_add
-                        .iter()
-                        .any(|c: &MongoDBColumn| c.column_name == column.column_name)
-                {
-                    columns_to_add.push(column.clone());
-                }
-            }
-        }
+    ) -> Result<bool, Errors> {
+        let table_options = std::iter::once(&self.clickhouse_config)
+            .chain(self.fan_out_targets.iter().map(|target| &target.config))
+            .map(|clickhouse_config| self.table_options_on(collection_name, clickhouse_config))
+            .collect::<Vec<_>>();
+        let engine_column_names = table_options
+            .iter()
+            .flat_map(|table_options| table_options.engine_column_names())
+            .collect::<Vec<_>>();
+
+        let columns_to_add = addable_columns(
+            find_columns_to_add(
+                clickhouse_columns,
+                rows,
+                self.clickhouse_config.identifier_case,
+            ),
+            &engine_column_names,
+        );
+        let mut schema_changed = false;
 
         for column_to_add in columns_to_add {
-            match column_to_add.to_clickhouse_type() {
-                ClickhouseType::Unknown => {
-                    continue;
-                }
-                ClickhouseType::Nullable(inner) => {
-                    if let ClickhouseType::Unknown = *inner {
-                        continue;
-                    }
-
-                    continue;
-                }
-                _ => {}
+            // Documents have no fixed schema, so a new field does not stop the pipe.
+            // Its values are left out until the column is added
+            if self.config.dry_run_clickhouse_ddl {
+                log::warn!(
+                    "dry_run_clickhouse_ddl is set, not adding column {} to {collection_name}: {}",
+                    column_to_add.column_name,
+                    self.generate_add_column_query(
+                        &self.clickhouse_config,
+                        collection_name,
+                        &column_to_add
+                    )
+                );
+                continue;
             }
 
-            let add_column_query = self.generate_add_column_query(
-                &self.clickhouse_config,
-                collection_name,
-                &column_to_add,
-            );
+            self.execute_write(|clickhouse_config| {
+                self.generate_add_column_query(clickhouse_config, collection_name, &column_to_add)
+            })
+            .await?;
 
-            self.clickhouse_connection
-                .execute_query(&add_column_query)
-                .await?;
+            schema_changed = true;
 
             log::info!(
                 "Added column {} to ClickHouse table {}",
@@ -644,14 +937,163 @@ impl MongoDBPipe {
             );
         }
 
-        Ok(())
+        Ok(schema_changed)
+    }
+
+    /// Flattens (if configured) and truncates the embedded documents of a row before it is written.
+    fn prepare_row(&self, collection_name: &str, row: &mut MongoDBCopyRow) {
+        if let Some(collection) = self
+            .mongodb_config
+            .collections
+            .iter()
+            .find(|collection| collection.collection_name == collection_name)
+            && collection.flatten_documents
+        {
+            row.flatten_documents(collection.flatten_max_depth);
+        }
+
+        self.truncate_large_values(collection_name, row);
+    }
+
+    fn truncate_large_values(&self, collection_name: &str, row: &mut MongoDBCopyRow) {
+        let max_bytes = self.mongodb_config.max_document_value_bytes;
+
+        for column_name in row.truncate_large_values(max_bytes) {
+            log::warn!(
+                "Field {collection_name}.{column_name} of {} is larger than {max_bytes} bytes. Truncated",
+                extract_mongodb_primary_key(row)
+            );
+        }
+    }
+
+    /// Hands the changes of one iteration to the configured sinks and flushes them.
+    async fn write_to_sinks(
+        &self,
+        batch_insert_queue: &HashMap<String, BatchWriteEntry<'_>>,
+        batch_delete_queue: &HashMap<String, BatchWriteEntry<'_>>,
+    ) -> Result<(), Errors> {
+        if self.sinks.is_empty() {
+            return Ok(());
+        }
+
+        let identifier_case = self.clickhouse_config.identifier_case;
+        let batches = [
+            (ChangeKind::Upsert, batch_insert_queue),
+            (ChangeKind::Delete, batch_delete_queue),
+        ]
+        .into_iter()
+        .flat_map(|(kind, queue)| {
+            queue.iter().map(move |(table_name, batch)| {
+                SinkBatch::from_rows(
+                    table_name,
+                    kind,
+                    &batch.table_info.clickhouse_columns,
+                    &Vec::<MongoDBColumn>::new(),
+                    &batch.mask_columns,
+                    identifier_case,
+                    &batch.rows,
+                    &batch.sequences,
+                )
+            })
+        })
+        .collect();
+
+        pipes::sink::write_to_sinks(&self.sinks, batches).await
+    }
+
+    /// Executes a write on the main ClickHouse target and all fan-out targets.
+    async fn execute_write(
+        &self,
+        generate_query: impl Fn(&ClickHouseConfig) -> String,
+    ) -> Result<(), Errors> {
+        pipes::execute_write(
+            &self.clickhouse_config,
+            &self.clickhouse_connection,
+            &self.fan_out_targets,
+            generate_query,
+        )
+        .await
+    }
+
+    /// Executes a write made of several queries on the main ClickHouse target and all fan-out targets.
+    async fn execute_writes(
+        &self,
+        generate_queries: impl Fn(&ClickHouseConfig) -> Vec<String>,
+    ) -> Result<(), Errors> {
+        pipes::execute_writes(
+            &self.clickhouse_config,
+            &self.clickhouse_connection,
+            &self.fan_out_targets,
+            generate_queries,
+        )
+        .await
     }
 }
 
 impl IntoClickhouse for MongoDBPipe {}
 
+/// Groups changes by collection, keeping the order of the changes within each collection.
+/// Changes without a collection name (e.g. events without a namespace) or for collections
+/// that are not set up are skipped.
+/// Creates the table of `resume_token_storage: clickhouse` if it does not exist.
+/// An existing table (e.g. created by a DBA) needs no DDL, so it also works with dry-run DDL.
+async fn setup_resume_token_table(
+    config: &Configuraion,
+    mongodb_config: &MongoDBConfig,
+    clickhouse_config: &ClickHouseConfig,
+    clickhouse_connection: &ClickhouseConnection,
+) -> Result<(), Errors> {
+    let ResumeTokenStorage::ClickHouse(table) =
+        ResumeTokenStorage::from_config(mongodb_config, clickhouse_config, clickhouse_connection)
+    else {
+        return Ok(());
+    };
+
+    let table_exists = !clickhouse_connection
+        .list_columns_by_tablename(
+            &clickhouse_config.connection.database,
+            &mongodb_config.resume_token_table,
+        )
+        .await?
+        .is_empty();
+
+    if table_exists {
+        return Ok(());
+    }
+
+    pipes::execute_ddl(config, clickhouse_connection, &table.create_table_query()).await
+}
+
+fn group_changes_by_collection(
+    changes: Vec<PeekMongoChange>,
+    tables_map: &HashMap<String, MongoDBPipeTableInfo>,
+) -> HashMap<String, Vec<PeekMongoChange>> {
+    changes
+        .into_iter()
+        .filter(|change| {
+            if change.collection_name.is_empty() {
+                log::warn!(
+                    "Skipping {:?} change event without a collection name",
+                    change.operation_type
+                );
+                return false;
+            }
+
+            if !tables_map.contains_key(&change.collection_name) {
+                log::warn!(
+                    "Skipping change event for unknown collection {}",
+                    change.collection_name
+                );
+                return false;
+            }
+
+            true
+        })
+        .into_group_map_by(|change| change.collection_name.clone())
+}
+
 pub async fn run_mongodb_pipe(config: Configuraion) {
-    let mut pipe = MongoDBPipe::new(
+    let mut pipe = match MongoDBPipe::new(
         config.clone(),
         config.source.mongodb.expect("MongoDB config is required"),
         config
@@ -659,29 +1101,51 @@ pub async fn run_mongodb_pipe(config: Configuraion) {
             .clickhouse
             .expect("Clickhouse config is required"),
     )
-    .await;
+    .await
+    {
+        Ok(pipe) => pipe,
+        Err(error) => {
+            log::error!("Failed to start MongoDB pipe: {error}");
+            std::process::exit(1);
+        }
+    };
 
     if let Err(error) = pipe.ping().await {
         log::error!("Failed to ping MongoDB exporter: {error:?}");
-        return;
+        std::process::exit(1);
     }
 
+    pipes::pause::listen_for_pause_signals(pipe.pause_switch());
+
     tokio::select! {
         _ = pipe.run_pipe() => {
             log::info!("MongoDB pipe running...");
         }
+        _ = pipes::shutdown::wait_for_shutdown_signal() => {
+            log::info!("Shutting down MongoDB pipe...");
+        }
+    }
+
+    // The resume token is only advanced after its changes are written, so the latest one is safe to keep
+    if let Err(error) = pipe.mongodb_connection.flush_resume_token().await {
+        log::error!("Failed to store resume token on shutdown: {error}");
     }
 }
 
 pub struct BatchWriteEntry<'a> {
     pub table_info: &'a MongoDBPipeTableInfo,
     pub mask_columns: Vec<String>,
+    /// Options of the table, not inherited yet. Deletes depend on the engine of each target
+    pub table_options: ClickHouseTableOptions,
     pub rows: Vec<MongoDBCopyRow>,
+    /// Position of each row in the changes of the iteration, for the sinks
+    pub sequences: Vec<u64>,
 }
 
 impl BatchWriteEntry<'_> {
-    pub fn push(&mut self, row: MongoDBCopyRow) {
+    pub fn push(&mut self, row: MongoDBCopyRow, sequence: u64) {
         self.rows.push(row);
+        self.sequences.push(sequence);
     }
 
     pub fn deduplicated_rows(&self) -> Vec<MongoDBCopyRow> {
@@ -689,6 +1153,173 @@ impl BatchWriteEntry<'_> {
     }
 }
 
+/// Orders columns the way they are added to ClickHouse: `_id` first, then by name.
+/// Document field order differs between documents, so it is not used.
+fn sort_schema_columns(columns: &mut [MongoDBColumn]) {
+    columns.sort_by(|a, b| {
+        (a.column_name != "_id", &a.column_name).cmp(&(b.column_name != "_id", &b.column_name))
+    });
+}
+
+/// CREATE TABLE query run by setup (and printed by `print-create`) for a collection missing in ClickHouse.
+/// The table starts with `_id` only; the other columns are added from the copied documents.
+fn create_table_query(
+    ddl: &impl IntoClickhouse,
+    clickhouse_config: &ClickHouseConfig,
+    collection: &MongoDBSource,
+) -> String {
+    let mut table_options = collection.table_options.clone();
+    table_options.inherit_from(&clickhouse_config.table_options);
+
+    let mut columns = vec![MongoDBColumn {
+        column_name: "_id".to_string(),
+        bson_value: mongodb::bson::Bson::ObjectId(mongodb::bson::oid::ObjectId::new()),
+    }];
+    sort_schema_columns(&mut columns);
+
+    ddl.generate_create_table_query(
+        clickhouse_config,
+        &table_options,
+        &collection.collection_name,
+        &columns,
+        "",
+    )
+}
+
+/// Columns whose ClickHouse type can be created from their values.
+/// A field that only had NULL values (or empty arrays) so far is added once a value is seen.
+/// Fields named like a column of the table engine (see `engine_column_names`) are left out.
+fn addable_columns(
+    columns: Vec<MongoDBColumn>,
+    engine_column_names: &[&str],
+) -> Vec<MongoDBColumn> {
+    columns
+        .into_iter()
+        .filter(|column| {
+            if engine_column_names
+                .iter()
+                .any(|name| name.eq_ignore_ascii_case(&column.column_name))
+            {
+                log::error!(
+                    "Field {} has a name reserved by clockpipe for the table engine. It is not replicated",
+                    column.column_name
+                );
+                return false;
+            }
+
+            let is_unknown = column.to_clickhouse_type().contains_unknown();
+            if is_unknown {
+                log::info!(
+                    "Field {} has no value to infer its type from yet. Its column is added once a value is seen",
+                    column.column_name
+                );
+            }
+
+            !is_unknown
+        })
+        .collect()
+}
+
+/// Prints the queries setup and the first copy would run to create the ClickHouse tables,
+/// with columns inferred from up to `sample_size` documents per collection. ClickHouse is not touched.
+pub async fn run_print_create(config: Configuraion, sample_size: i64) -> Result<(), Errors> {
+    let mongodb_config = config
+        .source
+        .mongodb
+        .clone()
+        .expect("MongoDB config is required");
+    let clickhouse_config = config
+        .target
+        .clickhouse
+        .clone()
+        .expect("Clickhouse config is required");
+
+    let pipe = MongoDBPipe::new(config, mongodb_config, clickhouse_config).await?;
+
+    for collection in &pipe.mongodb_config.collections {
+        let rows = pipe
+            .mongodb_connection
+            .sample_documents(
+                pipe.mongodb_config.collection_database(collection),
+                &collection.collection_name,
+                sample_size,
+            )
+            .await?;
+
+        println!("-- {}", collection.collection_name);
+        for query in print_create_queries(&pipe, &pipe.clickhouse_config, collection, &rows) {
+            println!("{query}");
+        }
+        println!();
+    }
+
+    Ok(())
+}
+
+fn print_create_queries(
+    ddl: &impl IntoClickhouse,
+    clickhouse_config: &ClickHouseConfig,
+    collection: &MongoDBSource,
+    rows: &[MongoDBCopyRow],
+) -> Vec<String> {
+    // Right after the CREATE TABLE the table only has `_id`
+    let created_columns = [ClickhouseColumn {
+        column_index: 1,
+        column_name: "_id".to_string(),
+        data_type: String::new(),
+        is_in_primary_key: true,
+        comment: String::new(),
+    }];
+    let columns_to_add = addable_columns(
+        find_columns_to_add(&created_columns, rows, clickhouse_config.identifier_case),
+        &pipes::table_options_on(&collection.table_options, clickhouse_config)
+            .engine_column_names(),
+    );
+
+    std::iter::once(create_table_query(ddl, clickhouse_config, collection))
+        .chain(columns_to_add.iter().map(|column| {
+            ddl.generate_add_column_query(clickhouse_config, &collection.collection_name, column)
+        }))
+        .collect()
+}
+
+/// Columns seen in `rows` that are not in `clickhouse_columns` yet, in schema order.
+fn find_columns_to_add(
+    clickhouse_columns: &[ClickhouseColumn],
+    rows: &[MongoDBCopyRow],
+    identifier_case: IdentifierCase,
+) -> Vec<MongoDBColumn> {
+    let mut columns_to_add: Vec<MongoDBColumn> = vec![];
+
+    for row in rows {
+        for column in &row.columns {
+            if clickhouse_columns
+                .iter()
+                .any(|c| identifier_case.matches(&column.column_name, &c.column_name))
+            {
+                continue;
+            }
+
+            match columns_to_add
+                .iter_mut()
+                .find(|c| c.column_name == column.column_name)
+            {
+                // The type is taken from the first value it can be inferred from, not from a NULL
+                Some(sample) => {
+                    if sample.to_clickhouse_type().contains_unknown() {
+                        *sample = column.clone();
+                    }
+                }
+                None => columns_to_add.push(column.clone()),
+            }
+        }
+    }
+
+    sort_schema_columns(&mut columns_to_add);
+
+    columns_to_add
+}
+
 fn extract_mongodb_primary_key(row: &MongoDBCopyRow) -> String {
     row.columns
         .iter()
@@ -696,3 +1327,348 @@ fn extract_mongodb_primary_key(row: &MongoDBCopyRow) -> String {
         .map(|col| format!("{:?}", col.bson_value))
         .unwrap_or_default()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use mongodb::{bson::doc, change_stream::event::OperationType};
+
+    use super::{
+        MongoDBPipeTableInfo, addable_columns, create_table_query, find_columns_to_add,
+        group_changes_by_collection, print_create_queries, setup_resume_token_table,
+    };
+    use crate::{
+        adapter::{
+            IntoClickhouse, IntoClickhouseColumn,
+            mongodb::{MongoDBColumn, MongoDBCopyRow, PeekMongoChange},
+            tests::{TestClickhouse, clickhouse_column, clickhouse_config},
+        },
+        config::{ClickHouseConfig, ClickHouseTableOptions, IdentifierCase, MissingValues},
+    };
+
+    fn change(collection_name: &str, id: i32) -> PeekMongoChange {
+        PeekMongoChange {
+            operation_type: OperationType::Insert,
+            document_key: Some(doc! { "_id": id }),
+            full_document: Some(doc! { "_id": id }),
+            collection_name: collection_name.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn resume_token_table_is_only_created_when_missing() {
+        use crate::{
+            adapter::clickhouse::ClickhouseConnection,
+            config::{Configuraion, MongoDBConfig},
+            errors::Errors,
+        };
+
+        let mongodb_config = serde_json::from_value::<MongoDBConfig>(serde_json::json!({
+            "connection": {
+                "host": "localhost",
+                "username": "user",
+                "password": "password",
+                "database": "shop"
+            },
+            "collections": [],
+            "resume_token_storage": "clickhouse"
+        }))
+        .unwrap();
+        let dry_run = serde_json::from_value::<Configuraion>(serde_json::json!({
+            "source": { "source_type": "mongodb" },
+            "target": { "target_type": "clickhouse" },
+            "dry_run_clickhouse_ddl": true
+        }))
+        .unwrap();
+        let setup = |port: u16| {
+            let mut clickhouse_config = clickhouse_config();
+            clickhouse_config.connection.host = "127.0.0.1".to_string();
+            clickhouse_config.connection.port = port;
+            let connection = ClickhouseConnection::new(&clickhouse_config.connection)
+                .unwrap()
+                .without_compression();
+            let (dry_run, mongodb_config) = (dry_run.clone(), mongodb_config.clone());
+
+            async move {
+                setup_resume_token_table(&dry_run, &mongodb_config, &clickhouse_config, &connection)
+                    .await
+            }
+        };
+
+        // RowBinary of one system.columns row: the table was created by a DBA
+        let (port, mut queries) = crate::pipes::tests::recording_clickhouse_answering(
+            b"\x01\0\0\0\0\0\0\0\x03key\x06String\x01\0",
+        )
+        .await;
+        assert!(setup(port).await.is_ok());
+        assert_eq!(queries.recv().await.unwrap(), "");
+        assert!(queries.try_recv().is_err());
+
+        let (port, _queries) = crate::pipes::tests::recording_clickhouse().await;
+        let result = setup(port).await;
+        assert!(matches!(result, Err(Errors::DdlDisabled(_))), "{result:?}");
+    }
+
+    #[test]
+    fn changes_without_namespace_are_skipped_before_grouping() {
+        let tables_map = HashMap::from([(
+            "users".to_string(),
+            MongoDBPipeTableInfo {
+                clickhouse_columns: vec![],
+                fan_out_clickhouse_columns: HashMap::new(),
+            },
+        )]);
+
+        // e.g. an admin event without `ns`, which peek_changes reports with an empty name
+        let changes = vec![
+            change("users", 1),
+            change("", 2),
+            change("orders", 3),
+            change("users", 4),
+        ];
+
+        let grouped = group_changes_by_collection(changes, &tables_map);
+
+        assert_eq!(grouped.len(), 1);
+        let ids = grouped["users"]
+            .iter()
+            .map(|change| {
+                change
+                    .full_document
+                    .as_ref()
+                    .unwrap()
+                    .get_i32("_id")
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![1, 4]);
+    }
+
+    fn row(column_names: &[&str]) -> MongoDBCopyRow {
+        MongoDBCopyRow {
+            columns: column_names
+                .iter()
+                .map(|column_name| MongoDBColumn {
+                    column_name: column_name.to_string(),
+                    bson_value: mongodb::bson::Bson::String("value".to_string()),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn columns_to_add_do_not_depend_on_document_order() {
+        let first_run = vec![row(&["name", "_id", "age"]), row(&["email", "_id"])];
+        let second_run = vec![row(&["email", "age"]), row(&["_id", "name", "age"])];
+
+        let column_names = |rows: &[MongoDBCopyRow]| {
+            find_columns_to_add(&[], rows, IdentifierCase::Preserve)
+                .into_iter()
+                .map(|column| column.column_name)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            column_names(&first_run),
+            vec!["_id", "age", "email", "name"]
+        );
+        assert_eq!(column_names(&first_run), column_names(&second_run));
+    }
+
+    #[test]
+    fn integer_id_is_deleted_with_the_inserted_value() {
+        let clickhouse_config = clickhouse_config();
+        let clickhouse_columns = vec![clickhouse_column(0, "_id", "String", true)];
+        let rows = vec![
+            MongoDBCopyRow::from_document(&doc! { "_id": 42 }),
+            MongoDBCopyRow::from_document(&doc! { "_id": 7_000_000_000_i64 }),
+        ];
+
+        let insert_query = TestClickhouse.generate_insert_query(
+            &clickhouse_config,
+            &clickhouse_columns,
+            &Vec::<MongoDBColumn>::new(),
+            &[],
+            "events",
+            &rows,
+        );
+        let delete_query = TestClickhouse.generate_delete_query(
+            &clickhouse_config,
+            &clickhouse_columns,
+            &Vec::<MongoDBColumn>::new(),
+            "events",
+            &rows,
+        );
+
+        assert!(insert_query.contains("('42')"), "{insert_query}");
+        assert!(insert_query.contains("('7000000000')"), "{insert_query}");
+        assert_eq!(
+            delete_query,
+            "ALTER TABLE db.events DELETE WHERE (`_id` = '42') OR (`_id` = '7000000000')"
+        );
+    }
+
+    #[test]
+    fn masked_field_is_written_as_null() {
+        let clickhouse_config = clickhouse_config();
+        let clickhouse_columns = vec![
+            clickhouse_column(0, "_id", "String", true),
+            clickhouse_column(1, "password", "Nullable(String)", false),
+        ];
+        let rows = vec![MongoDBCopyRow::from_document(
+            &doc! { "_id": "u1", "password": "secret" },
+        )];
+
+        for mask_key in ["mask_columns", "mask_fields"] {
+            let collection = serde_json::from_value::<crate::config::MongoDBSource>(
+                serde_json::json!({ "collection_name": "users", mask_key: ["password"] }),
+            )
+            .unwrap();
+
+            let insert_query = TestClickhouse.generate_insert_query(
+                &clickhouse_config,
+                &clickhouse_columns,
+                &Vec::<MongoDBColumn>::new(),
+                &collection.mask_columns,
+                "users",
+                &rows,
+            );
+
+            assert!(
+                insert_query.ends_with("VALUES('u1',NULL)"),
+                "{insert_query}"
+            );
+        }
+    }
+
+    #[test]
+    fn null_only_and_reserved_fields_are_not_added() {
+        let clickhouse_config = clickhouse_config();
+        let rows = [
+            MongoDBCopyRow::from_document(
+                &doc! { "_id": "a", "name": "x", "age": null, "nickname": null },
+            ),
+            MongoDBCopyRow::from_document(&doc! { "_id": "b", "age": 30 }),
+            // Reserved for replacing_deleted_merge_tree
+            MongoDBCopyRow::from_document(&doc! { "_id": "c", "_clockpipe_version": 1 }),
+        ];
+
+        let columns = addable_columns(
+            find_columns_to_add(&[], &rows, IdentifierCase::Preserve),
+            &ClickHouseTableOptions::default().engine_column_names(),
+        );
+        let add_column_queries = columns
+            .iter()
+            .map(|column| {
+                TestClickhouse.generate_add_column_query(&clickhouse_config, "users", column)
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            columns
+                .iter()
+                .map(|column| (
+                    column.column_name.as_str(),
+                    column.to_clickhouse_type().to_type_text()
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                ("_id", "Nullable(String)".to_string()),
+                ("age", "Nullable(Int32)".to_string()),
+                ("name", "Nullable(String)".to_string()),
+            ]
+        );
+        assert!(
+            add_column_queries
+                .iter()
+                .all(|query| !query.contains("Unknown")),
+            "{add_column_queries:?}"
+        );
+    }
+
+    #[test]
+    fn null_array_fields_are_written_as_empty_arrays() {
+        // ClickHouse rejects Nullable(Array(T)), so array fields are created as Array(T)
+        let columns = addable_columns(
+            find_columns_to_add(
+                &[],
+                &[MongoDBCopyRow::from_document(
+                    &doc! { "scores": [1, 2], "tags": ["a"], "empty": [] },
+                )],
+                IdentifierCase::Preserve,
+            ),
+            &[],
+        );
+        let column_types = columns
+            .iter()
+            .map(|column| column.to_clickhouse_type().to_type_text())
+            .collect::<Vec<_>>();
+        assert_eq!(column_types, vec!["Array(Int64)", "Array(String)"]);
+
+        let clickhouse_config = ClickHouseConfig {
+            missing_values: MissingValues::SkipRow,
+            ..clickhouse_config()
+        };
+        let clickhouse_columns = vec![
+            clickhouse_column(0, "_id", "String", true),
+            clickhouse_column(0, "tags", "Array(String)", false),
+        ];
+        let rows = vec![
+            MongoDBCopyRow::from_document(&doc! { "_id": "a", "tags": ["x", 1] }),
+            MongoDBCopyRow::from_document(&doc! { "_id": "b", "tags": null }),
+            MongoDBCopyRow::from_document(&doc! { "_id": "c" }),
+        ];
+
+        let insert_query = TestClickhouse.generate_insert_query(
+            &clickhouse_config,
+            &clickhouse_columns,
+            &Vec::<MongoDBColumn>::new(),
+            &[],
+            "events",
+            &rows,
+        );
+
+        assert!(
+            insert_query.ends_with("VALUES('a',['x', '1']), ('b',[]), ('c',[])"),
+            "{insert_query}"
+        );
+    }
+
+    #[test]
+    fn printed_queries_start_with_the_setup_create_table_query() {
+        let clickhouse_config = ClickHouseConfig {
+            table_options: serde_json::from_value(
+                serde_json::json!({ "storage_policy": "s3", "granularity": 8192 }),
+            )
+            .unwrap(),
+            ..clickhouse_config()
+        };
+        let collection = serde_json::from_value::<crate::config::MongoDBSource>(
+            serde_json::json!({ "collection_name": "events" }),
+        )
+        .unwrap();
+        let rows = vec![MongoDBCopyRow {
+            columns: vec![MongoDBColumn {
+                column_name: "_id".to_string(),
+                bson_value: mongodb::bson::Bson::ObjectId(mongodb::bson::oid::ObjectId::new()),
+            }],
+        }];
+
+        let queries = print_create_queries(&TestClickhouse, &clickhouse_config, &collection, &rows);
+
+        assert_eq!(
+            queries,
+            vec![create_table_query(
+                &TestClickhouse,
+                &clickhouse_config,
+                &collection
+            )]
+        );
+        assert!(
+            queries[0].starts_with("CREATE TABLE db.events(`_id` String"),
+            "{}",
+            queries[0]
+        );
+    }
+}
@@ -0,0 +1,228 @@
+use std::time::{Duration, Instant};
+
+use crate::{
+    adapter::clickhouse::ClickhouseConnection,
+    config::{ClickHouseConfig, ClickHouseTableOptions},
+    pipes::{self, FanOutTarget, pause::PauseSwitch},
+};
+
+/// When each table gets its next `OPTIMIZE TABLE ... FINAL`, which makes ReplacingMergeTree
+/// collapse the versions of a row without waiting for a merge, so reads without FINAL see one row.
+/// A table is not scheduled again until its running OPTIMIZE finished, so they never overlap.
+#[derive(Debug, Clone, Default)]
+pub struct OptimizeSchedule {
+    tables: Vec<ScheduledTable>,
+}
+
+#[derive(Debug, Clone)]
+struct ScheduledTable {
+    table_name: String,
+    interval: Duration,
+    /// None while the OPTIMIZE of the table runs
+    next_run_at: Option<Instant>,
+}
+
+impl OptimizeSchedule {
+    /// The first OPTIMIZE of each table runs one interval after `now`.
+    pub fn new(tables: impl IntoIterator<Item = (String, Duration)>, now: Instant) -> Self {
+        let tables = tables
+            .into_iter()
+            .map(|(table_name, interval)| ScheduledTable {
+                table_name,
+                interval,
+                next_run_at: Some(now + interval),
+            })
+            .collect();
+
+        Self { tables }
+    }
+
+    /// Tables with an `optimize_interval_seconds` in their own or the global table options.
+    pub fn from_table_options<'a>(
+        tables: impl IntoIterator<Item = (&'a str, &'a ClickHouseTableOptions)>,
+        global_table_options: &ClickHouseTableOptions,
+        now: Instant,
+    ) -> Self {
+        let tables = tables
+            .into_iter()
+            .filter_map(|(table_name, table_options)| {
+                let mut table_options = table_options.clone();
+                table_options.inherit_from(global_table_options);
+
+                let interval_seconds =
+                    table_options.optimize_interval_seconds.filter(|s| *s > 0)?;
+
+                Some((
+                    table_name.to_string(),
+                    Duration::from_secs(interval_seconds),
+                ))
+            });
+
+        Self::new(tables, now)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tables.is_empty()
+    }
+
+    /// The earliest time a table is due, if any table is waiting.
+    pub fn next_run_at(&self) -> Option<Instant> {
+        self.tables
+            .iter()
+            .filter_map(|table| table.next_run_at)
+            .min()
+    }
+
+    /// Tables that are due at `now`. They count as running until `finished` is called.
+    pub fn take_due(&mut self, now: Instant) -> Vec<String> {
+        self.tables
+            .iter_mut()
+            .filter(|table| {
+                table
+                    .next_run_at
+                    .is_some_and(|next_run_at| next_run_at <= now)
+            })
+            .map(|table| {
+                table.next_run_at = None;
+                table.table_name.clone()
+            })
+            .collect()
+    }
+
+    /// Schedules the next OPTIMIZE of the table one interval after its last one finished.
+    pub fn finished(&mut self, table_name: &str, now: Instant) {
+        if let Some(table) = self
+            .tables
+            .iter_mut()
+            .find(|table| table.table_name == table_name)
+        {
+            table.next_run_at = Some(now + table.interval);
+        }
+    }
+}
+
+fn optimize_query(clickhouse_config: &ClickHouseConfig, table_name: &str) -> String {
+    let database_name = &clickhouse_config.connection.database;
+
+    format!("OPTIMIZE TABLE {database_name}.{table_name} FINAL DEDUPLICATE")
+}
+
+/// Runs the scheduled OPTIMIZE queries in the background, one at a time, on the main ClickHouse
+/// target and all fan-out targets. Nothing is run while the pipe is paused.
+pub fn spawn_optimize_task(
+    mut schedule: OptimizeSchedule,
+    clickhouse_config: ClickHouseConfig,
+    clickhouse_connection: ClickhouseConnection,
+    fan_out_targets: Vec<FanOutTarget>,
+    pause_switch: PauseSwitch,
+) {
+    if schedule.is_empty() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        while let Some(next_run_at) = schedule.next_run_at() {
+            tokio::time::sleep_until(next_run_at.into()).await;
+
+            for table_name in schedule.take_due(Instant::now()) {
+                if pause_switch.is_paused() {
+                    log::info!("OPTIMIZE of {table_name} skipped while the pipe is paused");
+                } else {
+                    let started_at = Instant::now();
+                    let result = pipes::execute_write(
+                        &clickhouse_config,
+                        &clickhouse_connection,
+                        &fan_out_targets,
+                        |clickhouse_config| optimize_query(clickhouse_config, &table_name),
+                    )
+                    .await;
+
+                    match result {
+                        Ok(()) => log::info!(
+                            "OPTIMIZE of {table_name} finished in {}ms",
+                            started_at.elapsed().as_millis()
+                        ),
+                        Err(error) => log::warn!("OPTIMIZE of {table_name} failed: {error}"),
+                    }
+                }
+
+                schedule.finished(&table_name, Instant::now());
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::OptimizeSchedule;
+
+    #[test]
+    fn tables_inherit_the_global_optimize_interval() {
+        use crate::config::ClickHouseTableOptions;
+
+        let start = Instant::now();
+        let global_table_options = ClickHouseTableOptions {
+            optimize_interval_seconds: Some(600),
+            ..Default::default()
+        };
+        let own_interval = ClickHouseTableOptions {
+            optimize_interval_seconds: Some(60),
+            ..Default::default()
+        };
+        let disabled = ClickHouseTableOptions {
+            optimize_interval_seconds: Some(0),
+            ..Default::default()
+        };
+        let inherited = ClickHouseTableOptions::default();
+
+        let mut schedule = OptimizeSchedule::from_table_options(
+            [
+                ("accounts", &own_interval),
+                ("events", &disabled),
+                ("orders", &inherited),
+            ],
+            &global_table_options,
+            start,
+        );
+
+        assert_eq!(
+            schedule.take_due(start + Duration::from_secs(60)),
+            ["accounts"]
+        );
+        assert_eq!(
+            schedule.take_due(start + Duration::from_secs(600)),
+            ["orders"]
+        );
+        assert!(schedule.next_run_at().is_none());
+    }
+
+    #[test]
+    fn optimize_is_issued_once_per_interval_and_never_overlaps() {
+        let start = Instant::now();
+        let at = |seconds: u64| start + Duration::from_secs(seconds);
+
+        let mut schedule = OptimizeSchedule::new(
+            [
+                ("accounts".to_string(), Duration::from_secs(60)),
+                ("orders".to_string(), Duration::from_secs(120)),
+            ],
+            start,
+        );
+
+        assert_eq!(schedule.next_run_at(), Some(at(60)));
+        assert!(schedule.take_due(at(59)).is_empty());
+        assert_eq!(schedule.take_due(at(60)), ["accounts"]);
+
+        // Still running: not due again, however late it gets
+        assert!(schedule.take_due(at(119)).is_empty());
+        assert_eq!(schedule.next_run_at(), Some(at(120)));
+
+        // The next run is one interval after the last one finished
+        schedule.finished("accounts", at(125));
+        assert_eq!(schedule.take_due(at(125)), ["orders"]);
+        assert!(schedule.take_due(at(184)).is_empty());
+        assert_eq!(schedule.take_due(at(185)), ["accounts"]);
+    }
+}
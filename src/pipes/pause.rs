@@ -0,0 +1,76 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+
+/// Stops a pipe from writing while ClickHouse is under maintenance.
+/// The check happens at the start of each sync iteration, so an iteration that already started
+/// finishes (writes and cursor advance together). While paused nothing is peeked and the
+/// replication slot / resume token stays where it is, so no change is lost on resume.
+#[derive(Debug, Clone, Default)]
+pub struct PauseSwitch(Arc<AtomicBool>);
+
+impl PauseSwitch {
+    pub fn pause(&self) {
+        if !self.0.swap(true, Ordering::SeqCst) {
+            log::warn!("Sync paused. No changes are written until the pipe is resumed");
+        }
+    }
+
+    pub fn resume(&self) {
+        if self.0.swap(false, Ordering::SeqCst) {
+            log::info!("Sync resumed");
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Pauses on SIGUSR1 and resumes on SIGUSR2.
+#[cfg(unix)]
+pub fn listen_for_pause_signals(pause_switch: PauseSwitch) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let (Ok(mut pause_signal), Ok(mut resume_signal)) = (
+        signal(SignalKind::user_defined1()),
+        signal(SignalKind::user_defined2()),
+    ) else {
+        log::warn!("Failed to register SIGUSR1/SIGUSR2 handlers. Pausing by signal is disabled");
+        return;
+    };
+
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                Some(_) = pause_signal.recv() => pause_switch.pause(),
+                Some(_) = resume_signal.recv() => pause_switch.resume(),
+                else => break,
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn listen_for_pause_signals(_: PauseSwitch) {}
+
+#[cfg(test)]
+mod tests {
+    use super::PauseSwitch;
+
+    #[test]
+    fn pause_is_shared_between_clones() {
+        let pause_switch = PauseSwitch::default();
+        let pipe_side = pause_switch.clone();
+
+        assert!(!pipe_side.is_paused());
+
+        pause_switch.pause();
+        pause_switch.pause();
+        assert!(pipe_side.is_paused());
+
+        pause_switch.resume();
+        assert!(!pipe_side.is_paused());
+    }
+}
@@ -1,24 +1,49 @@
 use std::collections::HashMap;
 
+use itertools::Itertools;
+
 use crate::{
     adapter::{
-        self, IntoClickhouse,
-        clickhouse::ClickhouseColumn,
+        self, IntoClickhouse, IntoClickhouseColumn, IntoClickhouseValue,
+        clickhouse::{ClickhouseColumn, ClickhouseConnection, accepts_values_of},
         postgres::{
-            PostgresColumn, PostgresCopyRow,
-            pgoutput::{MessageType, parse_pg_output},
+            PeekWalChangeResult, PostgresColumn, PostgresCopyRow, PublicationTable,
+            ReplicationSlot,
+            copy_buffer::CopyBatch,
+            create_publication_query, parse_lsn,
+            pgoutput::{
+                MessageType, PgOutput, PgOutputValue, PgRelation, PgTupleType, parse_pg_output,
+                parse_pg_relation, parse_pg_truncate,
+            },
         },
     },
-    config::Configuraion,
+    command::run::ConfigOptions,
+    config::{
+        ClickHouseConfig, ClickHouseTableOptions, ColumnTypeOverride, Configuraion, IdentifierCase,
+        PostgresConfig, PostgresSource, UpdateMode, bounded_by_flush_interval,
+        default::postgres::REPLICATION_SLOT_PREFIX,
+    },
     errors::Errors,
     logger::ProgressLogger,
-    pipes::{IPipe, WriteCounter},
+    pipes::{
+        self, FanOutTarget, IPipe, SyncError, SyncOutcome, WriteCounter,
+        applied_lsn::AppliedLsn,
+        backpressure::PeekLimitController,
+        optimize::OptimizeSchedule,
+        pause::PauseSwitch,
+        reload::{PendingConfig, added_entries},
+        sink::{ChangeKind, Sink, SinkBatch},
+        stats::SharedSyncStats,
+        watchdog::Watchdog,
+    },
 };
 
 #[derive(Debug, Clone, Default)]
 pub struct PostgresTableRelation {
     pub schema_name: String,
     pub table_name: String,
+    /// A partition of the configured (partitioned) table, not the table itself
+    pub is_partition: bool,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -40,15 +65,52 @@ impl PostgresPipeContext {
             PostgresPipeTableInfo {
                 postgres_columns,
                 clickhouse_columns,
+                fan_out_clickhouse_columns: HashMap::new(),
             },
         );
     }
+
+    /// Columns of a table on the fan-out targets, by target name. Set after `set_table`.
+    pub fn set_fan_out_columns(
+        &mut self,
+        schema_name: &str,
+        table_name: &str,
+        fan_out_clickhouse_columns: HashMap<String, Vec<ClickhouseColumn>>,
+    ) {
+        if let Some(table_info) = self
+            .tables_map
+            .get_mut(&format!("{schema_name}.{table_name}"))
+        {
+            table_info.fan_out_clickhouse_columns = fan_out_clickhouse_columns;
+        }
+    }
+
+    /// Stops syncing a table: its changes are skipped as not set up.
+    pub fn remove_table(&mut self, schema_name: &str, table_name: &str) {
+        self.tables_map
+            .remove(&format!("{schema_name}.{table_name}"));
+        self.table_relation_map.retain(|_, relation| {
+            relation.schema_name != schema_name || relation.table_name != table_name
+        });
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct PostgresPipeTableInfo {
     postgres_columns: Vec<PostgresColumn>,
+    /// Columns on the main ClickHouse target
     clickhouse_columns: Vec<ClickhouseColumn>,
+    fan_out_clickhouse_columns: HashMap<String, Vec<ClickhouseColumn>>,
+}
+
+impl PostgresPipeTableInfo {
+    /// Columns of the table on the target of `clickhouse_config`. A fan-out target can differ from
+    /// the main one, e.g. in identifier_case or in the types of a table created earlier.
+    fn clickhouse_columns_for(&self, clickhouse_config: &ClickHouseConfig) -> &[ClickhouseColumn] {
+        self.fan_out_clickhouse_columns
+            .get(&pipes::target_name(clickhouse_config))
+            .unwrap_or(&self.clickhouse_columns)
+    }
 }
 
 #[derive(Clone)]
@@ -62,6 +124,18 @@ pub struct PostgresPipe {
 
     clickhouse_config: crate::config::ClickHouseConfig,
     clickhouse_connection: adapter::clickhouse::ClickhouseConnection,
+
+    fan_out_targets: Vec<FanOutTarget>,
+    sinks: Vec<std::sync::Arc<dyn Sink>>,
+
+    peek_limit_controller: PeekLimitController,
+    stats: SharedSyncStats,
+    pause_switch: PauseSwitch,
+    watchdog: Watchdog,
+    pending_config: PendingConfig,
+    applied_lsn: AppliedLsn,
+    /// Columns of each relation from the last Relation message, in the order of the tuple values
+    relations: HashMap<u32, PgRelation>,
 }
 
 impl PostgresPipe {
@@ -69,23 +143,59 @@ impl PostgresPipe {
         config: Configuraion,
         postgres_config: crate::config::PostgresConfig,
         clickhouse_config: crate::config::ClickHouseConfig,
-    ) -> Self {
+    ) -> Result<Self, Errors> {
+        postgres_config.validate()?;
+
+        let mut postgres_config = postgres_config;
+        disable_deletes_for_aggregating_engines(&mut postgres_config.tables, &clickhouse_config);
+
+        let config = config.with_sleep_overrides(&postgres_config.sleep_overrides);
+
         let postgres_connection =
-            adapter::postgres::PostgresConnection::new(&postgres_config.connection)
-                .await
-                .expect("Failed to create Postgres connection");
+            pipes::retry_at_startup("Postgres", config.startup_retry_millis, || {
+                adapter::postgres::PostgresConnection::new(&postgres_config.connection)
+            })
+            .await?;
 
         let clickhouse_connection =
-            adapter::clickhouse::ClickhouseConnection::new(&clickhouse_config.connection);
+            adapter::clickhouse::ClickhouseConnection::new(&clickhouse_config.connection)?
+                .with_query_log(clickhouse_config.query_log.as_ref())?;
 
-        PostgresPipe {
+        let fan_out_targets = FanOutTarget::from_target(&config.target)?;
+
+        let sinks = pipes::sink::sinks_from_target(&config.target);
+
+        let peek_limit_controller = PeekLimitController::new(
+            config.min_peek_changes_limit,
+            config.peek_changes_limit,
+            config.target_write_millis_per_iteration,
+        );
+
+        let watchdog = Watchdog::new(
+            config
+                .watchdog_stall_timeout_millis
+                .map(std::time::Duration::from_millis),
+        );
+
+        let applied_lsn = AppliedLsn::load(postgres_config.applied_lsn_path.as_deref())?;
+
+        Ok(PostgresPipe {
             context: PostgresPipeContext::default(),
             config,
             postgres_config,
             clickhouse_config,
             postgres_connection,
             clickhouse_connection,
-        }
+            fan_out_targets,
+            sinks,
+            peek_limit_controller,
+            stats: SharedSyncStats::default(),
+            pause_switch: PauseSwitch::default(),
+            watchdog,
+            pending_config: PendingConfig::default(),
+            applied_lsn,
+            relations: HashMap::new(),
+        })
     }
 }
 
@@ -102,6 +212,8 @@ impl IPipe for PostgresPipe {
             .await
             .map_err(|e| Errors::DatabasePingError(format!("ClickHouse ping failed: {e}")))?;
 
+        pipes::fan_out(&self.fan_out_targets, |target| target.connection.ping()).await?;
+
         log::info!("Postgres and ClickHouse connections are healthy.");
 
         Ok(())
@@ -120,419 +232,724 @@ impl IPipe for PostgresPipe {
     }
 
     async fn first_sync(&self) {
-        log::info!("Starting initial sync...");
+        self.copy_tables(&self.postgres_config.tables)
+            .await
+            .expect("Failed to copy tables");
+    }
 
-        // 1. For each table in Postgres config
-        for table in &self.postgres_config.tables {
-            let schema_name = &table.schema_name;
-            let table_name = &table.table_name;
-            let mask_columns = &table.mask_columns;
-            let source_table_info = self
-                .context
-                .tables_map
-                .get(&format!("{schema_name}.{table_name}"))
-                .expect("Table info not found in context");
+    async fn sync_loop(&mut self) {
+        if !self.clickhouse_config.enable_sync_loop() {
+            log::info!("Sync loop disabled. Exiting...");
+            return;
+        }
 
-            // 2. Check if skip_copy is set
-            // If set, skip the initial sync for this table
-            if table.skip_copy {
-                log::debug!(
-                    "Skipping initial sync for {schema_name}.{table_name} as skip_copy is set to true"
-                );
-                continue;
-            }
+        log::info!("Starting sync loop...");
 
-            // 3. Check if table is not empty in ClickHouse
-            // If not empty, skip the initial sync for this table
-            if self
-                .clickhouse_connection
-                .table_is_not_empty(
-                    &self.clickhouse_config.connection.database,
-                    &table.table_name,
-                )
-                .await
-                .expect("Failed to check if table exists")
+        pipes::optimize::spawn_optimize_task(
+            OptimizeSchedule::from_table_options(
+                self.postgres_config
+                    .tables
+                    .iter()
+                    .map(|table| (table.table_name.as_str(), &table.table_options)),
+                &self.clickhouse_config.table_options,
+                std::time::Instant::now(),
+            ),
+            self.clickhouse_config.clone(),
+            self.clickhouse_connection.clone(),
+            self.fan_out_targets.clone(),
+            self.pause_switch.clone(),
+        );
+
+        let max_flush_interval_millis = self.postgres_config.max_flush_interval_millis;
+        let sleep_millis_when_peek_is_empty = bounded_by_flush_interval(
+            self.config.sleep_millis_when_peek_is_empty,
+            max_flush_interval_millis,
+        );
+        let sleep_millis_after_sync_iteration = bounded_by_flush_interval(
+            self.config.sleep_millis_after_sync_iteration,
+            max_flush_interval_millis,
+        );
+
+        loop {
+            if let Some(config) = self.pending_config.take()
+                && let Err(error) = self.reload_tables(config).await
             {
-                log::info!(
-                    "Table {schema_name}.{table_name} already exists in ClickHouse, skipping initial sync.",
-                );
-                continue;
+                log::error!("Failed to apply reloaded configuration: {error}");
             }
 
-            // 4. get total row count in Postgres table (for progress logging only)
-            let total_count =
-                self.postgres_connection
-                    .count_table_rows(schema_name, table_name)
-                    .await
-                    .expect("Failed to count table rows in Postgres") as usize;
+            let watchdog = self.watchdog.clone();
+            let sleep_millis = match pipes::watched_iteration(&watchdog, self.sync_once()).await {
+                Ok(SyncOutcome::NoChanges) => {
+                    log::info!("No new changes found, waiting for next iteration...");
+                    sleep_millis_when_peek_is_empty
+                }
+                Ok(SyncOutcome::Synced) => sleep_millis_after_sync_iteration,
+                Ok(SyncOutcome::Paused) => sleep_millis_when_peek_is_empty,
+                Err(error) => {
+                    self.stats.update(|stats| stats.record_error(&error));
 
-            // 5. Start copying data from Postgres to ClickHouse
-            log::info!(
-                "Copying data from Postgres table {schema_name}.{table_name}... ({total_count} rows)",
-            );
-            let mut copy_receiver = self
-                .postgres_connection
-                .copy_table_to_stdout(&table.schema_name, &table.table_name)
-                .await
-                .expect("Failed to copy table data from Postgres");
-
-            let mut processed_rows = 0_usize;
-            let logger = ProgressLogger::new(
-                &format!(
-                    "Inserting copied data into ClickHouse table {schema_name}.{table_name}..."
-                ),
-                total_count,
-            );
+                    match error {
+                        SyncError::Peek(_) => self.config.sleep_millis_when_peek_failed,
+                        SyncError::Write(error) => {
+                            pipes::sleep_millis_after_write_error(&self.config, &error)
+                        }
+                        SyncError::Cursor(_) => 0,
+                        SyncError::Stalled(_) => 0,
+                    }
+                }
+            };
 
-            // 6. Receive copied rows in batches and insert into ClickHouse
-            let mut rows = Vec::new();
-            while let Some(row_chunks) = copy_receiver.recv().await {
-                rows.extend(row_chunks);
+            tokio::time::sleep(std::time::Duration::from_millis(sleep_millis)).await;
+        }
+    }
+}
 
-                // If buffer size is less than threshold, continue accumulating
-                if rows.len() < self.config.copy_batch_size {
-                    continue;
-                }
+impl PostgresPipe {
+    /// Accessor for the statistics of this pipe. The handle stays valid while the pipe runs.
+    pub fn stats(&self) -> SharedSyncStats {
+        self.stats.clone()
+    }
 
-                logger.log_progress(processed_rows);
+    /// Switch to pause and resume writing while the pipe runs.
+    pub fn pause_switch(&self) -> PauseSwitch {
+        self.pause_switch.clone()
+    }
 
-                // 7. Do Insert into ClickHouse
-                let insert_query = self.generate_insert_query(
-                    &self.clickhouse_config,
-                    &source_table_info.clickhouse_columns,
-                    &source_table_info.postgres_columns,
-                    mask_columns,
-                    &table.table_name,
-                    &rows,
-                );
+    /// Slot for a reloaded configuration. Applied by the sync loop between iterations.
+    pub fn pending_config(&self) -> PendingConfig {
+        self.pending_config.clone()
+    }
 
-                if !insert_query.is_empty() {
-                    self.clickhouse_connection
-                        .execute_query(&insert_query)
-                        .await
-                        .expect("Failed to execute insert query in ClickHouse");
-                }
+    /// Starts syncing the tables that were added to the reloaded configuration, without
+    /// restarting the stream: publication, ClickHouse table and initial copy.
+    /// Other changes (removed tables, options of existing tables, connections) need a restart.
+    async fn reload_tables(&mut self, config: Configuraion) -> Result<(), Errors> {
+        let Some(postgres_config) = config.source.postgres else {
+            return Err(Errors::ConfigValidationError(
+                "Reloaded configuration has no Postgres source".to_string(),
+            ));
+        };
 
-                processed_rows += rows.len();
-                rows.clear();
-            }
+        let table_key =
+            |table: &PostgresSource| format!("{}.{}", table.schema_name, table.table_name);
 
-            // Flush remaining rows that didn't reach the batch threshold
-            if !rows.is_empty() {
-                let insert_query = self.generate_insert_query(
-                    &self.clickhouse_config,
-                    &source_table_info.clickhouse_columns,
-                    &source_table_info.postgres_columns,
-                    mask_columns,
-                    &table.table_name,
-                    &rows,
-                );
+        let removed_tables = added_entries(
+            &postgres_config.tables,
+            &self.postgres_config.tables,
+            table_key,
+        );
+        if !removed_tables.is_empty() {
+            log::warn!(
+                "Tables {:?} were removed from the configuration. They keep syncing until restart",
+                removed_tables.iter().map(table_key).collect::<Vec<_>>()
+            );
+        }
 
-                if !insert_query.is_empty() {
-                    self.clickhouse_connection
-                        .execute_query(&insert_query)
-                        .await
-                        .expect("Failed to execute insert query in ClickHouse");
-                }
+        let mut new_tables = added_entries(
+            &self.postgres_config.tables,
+            &postgres_config.tables,
+            table_key,
+        );
+        disable_deletes_for_aggregating_engines(&mut new_tables, &self.clickhouse_config);
+        if new_tables.is_empty() {
+            log::info!("No new tables in the reloaded configuration");
+            return Ok(());
+        }
 
-                processed_rows += rows.len();
-            }
+        log::info!(
+            "Adding tables {:?} from the reloaded configuration",
+            new_tables.iter().map(table_key).collect::<Vec<_>>()
+        );
 
-            logger.clean();
+        // setup_publication works on the configured tables
+        let table_count = self.postgres_config.tables.len();
+        self.postgres_config
+            .tables
+            .extend(new_tables.iter().cloned());
 
-            log::info!(
-                "Copy completed for table {schema_name}.{table_name} ({processed_rows} rows)"
-            );
+        let setup_result = match self.setup_publication().await {
+            Ok(()) => self.setup_tables(&new_tables).await,
+            Err(error) => Err(error),
+        };
+
+        if let Err(error) = setup_result {
+            self.postgres_config.tables.truncate(table_count);
+            return Err(error);
         }
+
+        self.copy_added_tables(&new_tables).await;
+
+        Ok(())
     }
 
-    async fn sync_loop(&mut self) {
-        if !self.clickhouse_config.enable_sync_loop() {
-            log::info!("Sync loop disabled. Exiting...");
-            return;
+    /// Initial copy of the tables added by a reload. A table whose copy failed is dropped again,
+    /// so that the next reload adds it anew.
+    async fn copy_added_tables(&mut self, new_tables: &[PostgresSource]) {
+        let table_key =
+            |table: &PostgresSource| format!("{}.{}", table.schema_name, table.table_name);
+
+        for table in copy_sequence(new_tables) {
+            if let Err(error) = self.copy_tables(std::slice::from_ref(table)).await {
+                log::error!(
+                    "Initial copy of {} failed, the table is not synced until it is added by another reload: {error}",
+                    table_key(table)
+                );
+
+                self.postgres_config
+                    .tables
+                    .retain(|configured| table_key(configured) != table_key(table));
+                self.context
+                    .remove_table(&table.schema_name, &table.table_name);
+            }
         }
+    }
 
-        log::info!("Starting sync loop...");
+    /// Runs one iteration of the sync loop: peek, write to ClickHouse, then advance the slot.
+    /// Sleeping between iterations is left to the caller.
+    pub async fn sync_once(&mut self) -> Result<SyncOutcome, SyncError> {
+        if self.pause_switch.is_paused() {
+            return Ok(SyncOutcome::Paused);
+        }
 
-        let publication_name = &self.postgres_config.publication_name;
-        let replication_slot_name = &self.postgres_config.replication_slot_name;
+        let publication_name = &self.postgres_config.publication_name();
+        let replication_slot_name = &self.postgres_config.replication_slot_name();
 
-        'SYNC_LOOP: loop {
-            // 1. Peek new rows
-            let peek_result = self
-                .postgres_connection
-                .peek_wal_changes(
-                    publication_name,
-                    replication_slot_name,
-                    self.config.peek_changes_limit,
-                )
-                .await;
+        // 1. Peek new rows
+        let peek_result = self
+            .postgres_connection
+            .peek_wal_changes(
+                publication_name,
+                replication_slot_name,
+                None,
+                self.peek_limit_controller.limit(),
+            )
+            .await;
 
-            let peek_result = match peek_result {
-                Ok(peek) => peek,
-                Err(e) => {
-                    // Handle peek error. wait and retry
-                    log::error!("Error peeking WAL changes: {e:?}");
-                    tokio::time::sleep(std::time::Duration::from_millis(
-                        self.config.sleep_millis_when_peek_failed,
-                    ))
-                    .await;
-                    continue;
-                }
-            };
+        self.watchdog.touch();
 
-            if peek_result.is_empty() {
-                log::info!("No new changes found, waiting for next iteration...");
-                tokio::time::sleep(std::time::Duration::from_millis(
-                    self.config.sleep_millis_when_peek_is_empty,
-                ))
-                .await;
-                continue 'SYNC_LOOP;
+        let peek_result = match peek_result {
+            Ok(peek) => peek,
+            Err(e) => {
+                log::error!("Error peeking WAL changes: {e:?}");
+                return Err(SyncError::Peek(e));
+            }
+        };
+
+        if peek_result.is_empty() {
+            return Ok(SyncOutcome::NoChanges);
+        }
+
+        let mut peek_result = peek_result;
+        if let Some(max_tables) = self.postgres_config.max_tables_per_iteration {
+            let deferred_count = defer_transactions_beyond_tables(&mut peek_result, max_tables);
+            if deferred_count > 0 {
+                log::info!(
+                    "Deferring {deferred_count} changes to the next iteration, max_tables_per_iteration ({max_tables}) reached"
+                );
             }
+        }
+
+        let mut pending_writes = PendingWrites::default();
 
-            let mut table_log_map = HashMap::new();
+        let applied_rows = applied_rows(&peek_result, self.applied_lsn.lsn());
+        let replayed_count = applied_rows.iter().filter(|applied| **applied).count();
+        if replayed_count > 0 {
+            log::info!(
+                "Skipping {replayed_count} changes that were already written before a restart"
+            );
+        }
 
-            let mut batch_insert_queue = HashMap::new();
-            let mut batch_delete_queue = HashMap::new();
+        // 2. Parse peeked rows, group by table and prepare for insert/update/delete
+        for (row, is_applied) in peek_result.iter().zip(applied_rows) {
+            if row.data.first() == Some(&(MessageType::Relation as u8)) {
+                match parse_pg_relation(&row.data) {
+                    Ok(relation) => {
+                        let needs_old_values = self.postgres_config.tables.iter().any(|table| {
+                            table.schema_name == relation.schema_name
+                                && table.table_name == relation.table_name
+                                && table.update_mode == UpdateMode::ChangedColumns
+                        });
+                        if needs_old_values && relation.replica_identity != b'f' {
+                            log::warn!(
+                                "[{}.{}] update_mode changed_columns requires REPLICA IDENTITY FULL. Updates set all columns until it is set",
+                                relation.schema_name,
+                                relation.table_name
+                            );
+                        }
 
-            // 2. Parse peeked rows, group by table and prepare for insert/update/delete
-            for row in peek_result.iter() {
-                let parsed_row = match parse_pg_output(&row.data) {
-                    Ok(Some(parsed)) => parsed,
-                    Ok(None) => continue,
+                        self.relations.insert(relation.relation_id, relation);
+                    }
                     Err(e) => {
-                        log::error!(
-                            "Failed to parse PgOutput: {e:?}. Raw data (hex): {}",
-                            row.data
-                                .iter()
-                                .map(|b| format!("{b:02x}"))
-                                .collect::<Vec<_>>()
-                                .join(" ")
-                        );
+                        log::error!("Failed to parse Relation message: {e:?}");
                         panic!("Aborting due to PgOutput parse failure");
                     }
-                };
+                }
+                continue;
+            }
 
-                let Some(PostgresTableRelation {
-                    schema_name,
-                    table_name,
-                }) = self.context.table_relation_map.get(&parsed_row.relation_id)
-                else {
-                    log::warn!(
-                        "Relation ID {} not found in context table relation map",
-                        parsed_row.relation_id
-                    );
-                    continue;
+            // Relations are still read, the columns of later changes depend on them
+            if is_applied {
+                continue;
+            }
+
+            // Truncate is handled separately, no need to queue
+            if row.data.first() == Some(&(MessageType::Truncate as u8)) {
+                let relation_ids = match parse_pg_truncate(&row.data) {
+                    Ok(relation_ids) => relation_ids,
+                    Err(e) => {
+                        log::error!("Failed to parse Truncate message: {e:?}");
+                        panic!("Aborting due to PgOutput parse failure");
+                    }
                 };
 
-                match parsed_row.message_type {
-                    MessageType::Insert | MessageType::Update => {
-                        let table_info = self
-                            .context
-                            .tables_map
-                            .get(&format!("{schema_name}.{table_name}"))
-                            .expect("Table info not found in context");
+                let tables = truncated_tables(&self.context, &relation_ids);
+                // The earlier changes of the tables are still queued and would be written after
+                // the TRUNCATE
+                if self.postgres_config.propagate_truncate {
+                    pending_writes.discard_tables(&tables);
+                }
+
+                self.truncate_relations(&tables)
+                    .await
+                    .map_err(SyncError::Write)?;
+                continue;
+            }
 
-                        let mask_columns = self
-                            .postgres_config
-                            .tables
+            let mut parsed_row = match parse_pg_output(&row.data) {
+                Ok(Some(parsed)) => parsed,
+                Ok(None) => continue,
+                Err(e) => {
+                    log::error!(
+                        "Failed to parse PgOutput: {e:?}. Raw data (hex): {}",
+                        row.data
                             .iter()
-                            .find(|t| {
-                                t.table_name == table_name.as_str()
-                                    && t.schema_name == schema_name.as_str()
-                            })
-                            .map_or_else(Vec::new, |t| t.mask_columns.clone());
-
-                        batch_insert_queue
-                            .entry(table_name)
-                            .or_insert_with(|| BatchWriteEntry {
-                                table_info,
-                                mask_columns,
-                                rows: Vec::new(),
-                            })
-                            .push(PostgresCopyRow {
-                                columns: parsed_row.payload,
-                            });
-
-                        let count = table_log_map
-                            .entry(format!("{schema_name}.{table_name}"))
-                            .or_insert(WriteCounter::default());
-
-                        if parsed_row.message_type == MessageType::Insert {
-                            count.insert_count += 1;
-                        } else {
-                            count.update_count += 1;
-                        }
-                    }
-                    MessageType::Delete => {
-                        let source_table_info = self
+                            .map(|b| format!("{b:02x}"))
+                            .collect::<Vec<_>>()
+                            .join(" ")
+                    );
+                    panic!("Aborting due to PgOutput parse failure");
+                }
+            };
+
+            let Some(PostgresTableRelation {
+                schema_name,
+                table_name,
+                ..
+            }) = self.context.table_relation_map.get(&parsed_row.relation_id)
+            else {
+                log::warn!(
+                    "Relation ID {} not found in context table relation map",
+                    parsed_row.relation_id
+                );
+                continue;
+            };
+
+            match parsed_row.message_type {
+                MessageType::Insert | MessageType::Update | MessageType::Delete => {
+                    if let Some(relation) = self.relations.get(&parsed_row.relation_id)
+                        && let Some(table_info) = self
                             .context
                             .tables_map
                             .get(&format!("{schema_name}.{table_name}"))
-                            .expect("Table info not found in context");
-
-                        batch_delete_queue
-                            .entry(table_name)
-                            .or_insert_with(|| BatchWriteEntry {
-                                table_info: source_table_info,
-                                mask_columns: Vec::new(),
-                                rows: Vec::new(),
-                            })
-                            .push(PostgresCopyRow {
-                                columns: parsed_row.payload,
-                            });
-
-                        let count = table_log_map
-                            .entry(format!("{schema_name}.{table_name}"))
-                            .or_insert(WriteCounter::default());
-
-                        count.delete_count += 1;
+                    {
+                        parsed_row.payload = align_to_columns(
+                            relation,
+                            &table_info.postgres_columns,
+                            std::mem::take(&mut parsed_row.payload),
+                        );
+                        parsed_row.old_values = parsed_row.old_values.take().map(|old_values| {
+                            align_to_columns(relation, &table_info.postgres_columns, old_values)
+                        });
                     }
-                    MessageType::Truncate => {
-                        // Truncate is handled separately, no need to queue
-
-                        let database = &self.clickhouse_config.connection.database;
-
-                        if let Err(error) = self
-                            .clickhouse_connection
-                            .truncate_table(database, table_name)
-                            .await
-                        {
-                            log::error!(
-                                "Failed to truncate table {}.{}: {}",
-                                schema_name,
-                                table_name,
-                                error
-                            );
-
-                            tokio::time::sleep(std::time::Duration::from_millis(
-                                self.config.sleep_millis_when_write_failed,
-                            ))
-                            .await;
-
-                            continue 'SYNC_LOOP;
-                        }
 
-                        log::info!("Table {}.{} was truncated.", schema_name, table_name);
-                    }
-                    _ => {}
+                    pending_writes.push(
+                        &self.context,
+                        &self.postgres_config.tables,
+                        schema_name,
+                        table_name,
+                        parsed_row,
+                    );
                 }
+                _ => {}
             }
+        }
 
-            // 3. Insert/Update rows in ClickHouse
-            for (table_name, batch) in batch_insert_queue.iter() {
-                let insert_query = self.generate_insert_query(
-                    &self.clickhouse_config,
-                    &batch.table_info.clickhouse_columns,
-                    &batch.table_info.postgres_columns,
-                    &batch.mask_columns,
-                    table_name,
-                    &batch.deduplicated_rows(),
-                );
+        let mut write_elapsed = std::time::Duration::ZERO;
 
-                if !insert_query.is_empty() {
-                    if let Err(error) = self
-                        .clickhouse_connection
-                        .execute_query(&insert_query)
-                        .await
-                    {
-                        log::error!("Failed to execute insert query for {table_name}: {error}");
-                        tokio::time::sleep(std::time::Duration::from_millis(
-                            self.config.sleep_millis_when_write_failed,
-                        ))
-                        .await;
+        // 3. Insert/Update rows in ClickHouse
+        for (table_name, batch) in pending_writes.inserts.iter() {
+            let (rows, rows_with_unchanged) = batch.deduplicated_rows_split_by_unchanged();
 
-                        continue 'SYNC_LOOP;
-                    }
+            let write_started_at = std::time::Instant::now();
+            let write_result = self
+                .execute_writes(|clickhouse_config| {
+                    let mut queries = self.generate_insert_queries(
+                        clickhouse_config,
+                        batch.table_info.clickhouse_columns_for(clickhouse_config),
+                        &batch.table_info.postgres_columns,
+                        &batch.mask_columns,
+                        table_name,
+                        &rows,
+                    );
 
-                    tokio::time::sleep(std::time::Duration::from_millis(
-                        self.config.sleep_millis_after_sync_write,
-                    ))
-                    .await;
-                }
+                    queries.extend(rows_with_unchanged.iter().map(|row| {
+                        self.generate_insert_keeping_unchanged_query(
+                            clickhouse_config,
+                            batch.table_info.clickhouse_columns_for(clickhouse_config),
+                            &batch.table_info.postgres_columns,
+                            &batch.mask_columns,
+                            table_name,
+                            row,
+                        )
+                    }));
+
+                    queries
+                })
+                .await;
+            write_elapsed += write_started_at.elapsed();
+
+            if let Err(error) = write_result {
+                self.peek_limit_controller.record_failure();
+                log::error!("Failed to execute insert query for {table_name}: {error}");
+                return Err(SyncError::Write(error));
             }
 
-            // 4. Delete rows in ClickHouse
-            for (table_name, batch) in batch_delete_queue.iter() {
-                let delete_query = self.generate_delete_query(
-                    &self.clickhouse_config,
-                    &batch.table_info.clickhouse_columns,
-                    &batch.table_info.postgres_columns,
-                    table_name,
-                    &batch.rows,
-                );
+            self.watchdog.touch();
 
-                if !delete_query.is_empty() {
-                    if let Err(error) = self
-                        .clickhouse_connection
-                        .execute_query(&delete_query)
-                        .await
-                    {
-                        log::error!("Failed to execute delete query for {table_name}: {error}");
-                        tokio::time::sleep(std::time::Duration::from_millis(
-                            self.config.sleep_millis_when_write_failed,
-                        ))
-                        .await;
+            tokio::time::sleep(std::time::Duration::from_millis(
+                self.config.sleep_millis_after_sync_write,
+            ))
+            .await;
+        }
 
-                        continue 'SYNC_LOOP;
-                    }
+        // 3.1. Apply lightweight updates, after the inserts they may refer to
+        for (table_name, batch) in pending_writes.updates.iter() {
+            let rows = batch.deduplicated_rows();
 
-                    tokio::time::sleep(std::time::Duration::from_millis(
-                        self.config.sleep_millis_after_sync_write,
-                    ))
-                    .await;
-                }
+            let write_started_at = std::time::Instant::now();
+            let write_result = self
+                .execute_writes(|clickhouse_config| {
+                    rows.iter()
+                        .map(|row| {
+                            self.generate_update_query(
+                                clickhouse_config,
+                                batch.table_info.clickhouse_columns_for(clickhouse_config),
+                                &batch.table_info.postgres_columns,
+                                &batch.mask_columns,
+                                table_name,
+                                row,
+                            )
+                        })
+                        .collect()
+                })
+                .await;
+            write_elapsed += write_started_at.elapsed();
+
+            if let Err(error) = write_result {
+                self.peek_limit_controller.record_failure();
+                log::error!("Failed to execute update query for {table_name}: {error}");
+                return Err(SyncError::Write(error));
             }
 
-            // 5. Move cursor for next peek
-            if let Some(last) = peek_result.last() {
-                let advance_key = &last.lsn;
+            self.watchdog.touch();
 
-                if let Err(e) = self
-                    .postgres_connection
-                    .advance_replication_slot(replication_slot_name, advance_key)
-                    .await
-                {
-                    log::error!("Error advancing exporter: {e:?}");
-                    continue 'SYNC_LOOP;
-                }
-            }
+            tokio::time::sleep(std::time::Duration::from_millis(
+                self.config.sleep_millis_after_sync_write,
+            ))
+            .await;
+        }
 
-            // 6. Log the changes
-            for (table_name, count) in table_log_map.iter() {
-                log::info!(
-                    "Table [{}]: Inserted: {}, Updated: {}, Deleted: {}",
-                    table_name,
-                    count.insert_count,
-                    count.update_count,
-                    count.delete_count
-                );
+        // 4. Delete rows in ClickHouse
+        for (table_name, batch) in pending_writes.deletes.iter() {
+            let write_started_at = std::time::Instant::now();
+            let write_result = self
+                .execute_write(|clickhouse_config| {
+                    let table_options =
+                        pipes::table_options_on(&batch.table_options, clickhouse_config);
+
+                    if table_options.deletes_by_marker() {
+                        self.generate_delete_marker_query(
+                            clickhouse_config,
+                            batch.table_info.clickhouse_columns_for(clickhouse_config),
+                            &batch.table_info.postgres_columns,
+                            table_options.is_deleted_column_name(),
+                            table_name,
+                            &batch.rows,
+                        )
+                    } else {
+                        self.generate_delete_query(
+                            clickhouse_config,
+                            batch.table_info.clickhouse_columns_for(clickhouse_config),
+                            &batch.table_info.postgres_columns,
+                            table_name,
+                            &batch.rows,
+                        )
+                    }
+                })
+                .await;
+            write_elapsed += write_started_at.elapsed();
+
+            if let Err(error) = write_result {
+                self.peek_limit_controller.record_failure();
+                log::error!("Failed to execute delete query for {table_name}: {error}");
+                return Err(SyncError::Write(error));
             }
 
+            self.watchdog.touch();
+
             tokio::time::sleep(std::time::Duration::from_millis(
-                self.config.sleep_millis_after_sync_iteration,
+                self.config.sleep_millis_after_sync_write,
             ))
             .await;
         }
-    }
-}
 
-impl PostgresPipe {
-    async fn setup_publication(&self) -> Result<(), Errors> {
-        if !self.clickhouse_config.enable_sync_loop() {
-            log::info!("Sync loop disabled. Not setting up publication and replication slot.");
-            return Ok(());
-        }
+        self.peek_limit_controller.record_write(write_elapsed);
 
-        log::info!("Setup publication and replication slot...");
+        // 4.1. Archive the changes to the additional sinks
+        if let Err(error) = self.write_to_sinks(&pending_writes).await {
+            log::error!("Failed to write changes to sinks: {error}");
+            return Err(SyncError::Write(error));
+        }
 
-        let publication_name = &self.postgres_config.publication_name;
+        // 5. Move cursor for next peek
+        if let Some(last) = peek_result.last() {
+            let advance_key = &last.lsn;
 
-        // 1. Publication Create Step
-        let publication = self
-            .postgres_connection
-            .find_publication_by_name(publication_name)
-            .await?;
+            if let Err(e) = parse_lsn(advance_key).and_then(|lsn| self.applied_lsn.store(lsn)) {
+                log::error!("Error storing the applied LSN: {e:?}");
+                return Err(SyncError::Cursor(e));
+            }
+
+            if let Err(e) = self
+                .postgres_connection
+                .advance_replication_slot(replication_slot_name, advance_key)
+                .await
+            {
+                log::error!("Error advancing exporter: {e:?}");
+                return Err(SyncError::Cursor(e));
+            }
+        }
+
+        let last_lsn = peek_result.last().map(|last| last.lsn.clone());
+        let lag_bytes = self
+            .postgres_connection
+            .get_replication_lag_bytes(replication_slot_name)
+            .await
+            .inspect_err(|e| log::warn!("Failed to get replication lag: {e}"))
+            .ok();
+
+        self.stats.update(|stats| {
+            stats.record_iteration(&pending_writes.counters, last_lsn);
+            stats.lag_bytes = lag_bytes;
+        });
+
+        // 6. Log the changes
+        for (table_name, count) in pending_writes.counters.iter() {
+            log::info!(
+                "Table [{}]: Inserted: {}, Updated: {}, Deleted: {}",
+                table_name,
+                count.insert_count,
+                count.update_count,
+                count.delete_count
+            );
+        }
+
+        Ok(SyncOutcome::Synced)
+    }
+
+    /// Copies the current rows of the given tables (initial sync), into each ClickHouse target
+    /// whose table is still empty. A target with rows was copied before and is left alone.
+    /// If a copy fails, the partially copied tables are truncated before the error is returned,
+    /// on the targets this copy wrote to. Otherwise the next copy would skip them as not empty
+    /// and never copy the rest.
+    async fn copy_tables(&self, tables: &[PostgresSource]) -> Result<(), Errors> {
+        log::info!("Starting initial sync...");
+
+        // 1. For each table in Postgres config, in copy order
+        for table in copy_sequence(tables) {
+            let schema_name = &table.schema_name;
+            let table_name = &table.table_name;
+            let Some(source_table_info) = self
+                .context
+                .tables_map
+                .get(&format!("{schema_name}.{table_name}"))
+            else {
+                return Err(Errors::TableNotFoundError(format!(
+                    "Table info of {schema_name}.{table_name} not found in context"
+                )));
+            };
+
+            // 2. Check if skip_copy is set
+            // If set, skip the initial sync for this table
+            if table.skip_copy {
+                log::debug!(
+                    "Skipping initial sync for {schema_name}.{table_name} as skip_copy is set to true"
+                );
+                continue;
+            }
+
+            // 3. Check on each ClickHouse target if the table is not empty
+            // If not empty, skip the initial sync for this table on that target
+            let copy_targets = self.empty_table_targets(table).await?;
+            if copy_targets.is_empty() {
+                log::info!(
+                    "Table {schema_name}.{table_name} already exists in ClickHouse, skipping initial sync.",
+                );
+                continue;
+            }
+
+            if let Err(error) = self
+                .copy_table(table, source_table_info, &copy_targets)
+                .await
+            {
+                log::error!(
+                    "Failed to copy table {schema_name}.{table_name}: {error}. Truncating the partially copied ClickHouse table"
+                );
+
+                if let Err(truncate_error) =
+                    pipes::execute_writes_on(&copy_targets, |clickhouse_config| {
+                        vec![self.generate_truncate_query(clickhouse_config, table_name)]
+                    })
+                    .await
+                {
+                    log::error!(
+                        "Failed to truncate the partially copied table {schema_name}.{table_name}: {truncate_error}"
+                    );
+                }
+
+                return Err(error);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Main and fan-out targets on which the ClickHouse table of `table` has no rows yet.
+    async fn empty_table_targets(
+        &self,
+        table: &PostgresSource,
+    ) -> Result<Vec<FanOutTarget>, Errors> {
+        pipes::empty_table_targets(
+            std::iter::once(FanOutTarget::main(
+                &self.clickhouse_config,
+                &self.clickhouse_connection,
+            ))
+            .chain(self.fan_out_targets.iter().cloned()),
+            &format!("{}.{}", table.schema_name, table.table_name),
+            &table.table_name,
+        )
+        .await
+    }
+
+    /// Copies the rows of one table into its empty ClickHouse table on each of `targets`.
+    async fn copy_table(
+        &self,
+        table: &PostgresSource,
+        source_table_info: &PostgresPipeTableInfo,
+        targets: &[FanOutTarget],
+    ) -> Result<(), Errors> {
+        let schema_name = &table.schema_name;
+        let table_name = &table.table_name;
+        let mask_columns = &table.mask_columns;
+
+        // 4. get total row count in Postgres table (for progress logging only)
+        let total_count = self
+            .postgres_connection
+            .count_table_rows(schema_name, table_name)
+            .await? as usize;
+
+        // 5. Start copying data from Postgres to ClickHouse
+        log::info!(
+            "Copying data from Postgres table {schema_name}.{table_name}... ({total_count} rows)",
+        );
+        let mut copy_receiver = self
+            .postgres_connection
+            .copy_table_to_stdout(
+                &table.schema_name,
+                &table.table_name,
+                self.config.copy_max_in_flight_bytes,
+            )
+            .await?;
+
+        let mut processed_rows = 0_usize;
+        let logger = ProgressLogger::new(
+            &format!("Inserting copied data into ClickHouse table {schema_name}.{table_name}..."),
+            total_count,
+        );
+
+        // 6. Receive copied rows in batches and insert into ClickHouse
+        let mut batch = CopyBatch::default();
+        while let Some(chunk) = copy_receiver.recv().await {
+            batch.push(chunk);
+
+            // If buffer size is less than threshold, continue accumulating
+            if batch.rows.len() < self.config.copy_batch_size && !copy_receiver.should_flush(&batch)
+            {
+                continue;
+            }
+
+            logger.log_progress(processed_rows);
+
+            // 7. Do Insert into ClickHouse
+            pipes::execute_writes_on(targets, |clickhouse_config| {
+                self.generate_insert_queries(
+                    clickhouse_config,
+                    source_table_info.clickhouse_columns_for(clickhouse_config),
+                    &source_table_info.postgres_columns,
+                    mask_columns,
+                    &table.table_name,
+                    &batch.rows,
+                )
+            })
+            .await?;
+
+            processed_rows += batch.rows.len();
+            batch.clear();
+        }
+
+        // Flush remaining rows that didn't reach the batch threshold
+        if !batch.rows.is_empty() {
+            pipes::execute_writes_on(targets, |clickhouse_config| {
+                self.generate_insert_queries(
+                    clickhouse_config,
+                    source_table_info.clickhouse_columns_for(clickhouse_config),
+                    &source_table_info.postgres_columns,
+                    mask_columns,
+                    &table.table_name,
+                    &batch.rows,
+                )
+            })
+            .await?;
+
+            processed_rows += batch.rows.len();
+        }
+
+        logger.clean();
+
+        log::info!("Copy completed for table {schema_name}.{table_name} ({processed_rows} rows)");
+
+        Ok(())
+    }
+
+    async fn setup_publication(&self) -> Result<(), Errors> {
+        if !self.clickhouse_config.enable_sync_loop() {
+            log::info!("Sync loop disabled. Not setting up publication and replication slot.");
+            return Ok(());
+        }
+
+        if !self.postgres_config.manage_replication {
+            return self.verify_publication().await;
+        }
+
+        log::info!("Setup publication and replication slot...");
+
+        let publication_name = &self.postgres_config.publication_name();
+
+        // 1. Publication Create Step
+        let publication = self
+            .postgres_connection
+            .find_publication_by_name(publication_name)
+            .await?;
 
         if publication.is_none() {
             log::info!("Publication {publication_name} does not exist, creating a new one");
@@ -553,12 +970,22 @@ impl PostgresPipe {
             log::debug!("Source Tables: {source_tables:?}");
 
             self.postgres_connection
-                .create_publication(publication_name, &source_tables)
+                .create_publication(
+                    publication_name,
+                    &source_tables,
+                    self.postgres_config.publish_via_partition_root,
+                )
                 .await?;
 
             log::info!("Publication {publication_name} created successfully");
         } else {
             log::info!("Publication {publication_name} already exists, skipping creation.");
+
+            if self.postgres_config.publish_via_partition_root {
+                self.postgres_connection
+                    .set_publish_via_partition_root(publication_name)
+                    .await?;
+            }
         }
 
         // 2. Publication Tables Add Step
@@ -574,7 +1001,7 @@ impl PostgresPipe {
 
             if !publication_tables
                 .iter()
-                .any(|t| t.table_name == table.table_name && t.schema_name == table.schema_name)
+                .any(|t| t.publishes(&table.schema_name, &table.table_name))
             {
                 log::info!("Adding table {table_name} to publication");
                 self.postgres_connection
@@ -589,7 +1016,14 @@ impl PostgresPipe {
         // 3. Replication Slot Create Step
         log::info!("Setup Replication Slot...");
 
-        let replication_slot_name = &self.postgres_config.replication_slot_name;
+        let replication_slot_name = &self.postgres_config.replication_slot_name();
+
+        let replication_slots = self.postgres_connection.list_replication_slots().await?;
+        if let Some(warning) =
+            shared_replication_slot_warning(&self.postgres_config, &replication_slots)
+        {
+            log::warn!("{warning}");
+        }
 
         let replication_slot = self
             .postgres_connection
@@ -611,117 +1045,117 @@ impl PostgresPipe {
         Ok(())
     }
 
-    async fn setup_table(&mut self) -> Result<(), Errors> {
-        log::info!("Setting up tables in ClickHouse...");
+    /// With `manage_replication` disabled: checks that the publication (with all configured
+    /// tables) and the replication slot were provisioned, without running any DDL.
+    async fn verify_publication(&self) -> Result<(), Errors> {
+        log::info!("Checking the provisioned publication and replication slot...");
 
-        for table in &self.postgres_config.tables {
-            let clickhouse_table_not_exists = self
-                .clickhouse_connection
-                .list_columns_by_tablename(
-                    &self.clickhouse_config.connection.database,
-                    &table.table_name,
-                )
-                .await?
-                .is_empty();
+        let publication_name = &self.postgres_config.publication_name();
+        let replication_slot_name = &self.postgres_config.replication_slot_name();
 
-            let postgres_columns = self
-                .postgres_connection
-                .list_columns_by_tablename(&table.schema_name, &table.table_name)
-                .await?;
+        let publication_tables = match self
+            .postgres_connection
+            .find_publication_by_name(publication_name)
+            .await?
+        {
+            Some(_) => Some(
+                self.postgres_connection
+                    .get_publication_tables(publication_name)
+                    .await?,
+            ),
+            None => None,
+        };
 
-            let table_comment = self
-                .postgres_connection
-                .get_comment_from_table(&table.schema_name, &table.table_name)
-                .await?;
+        let replication_slot = self
+            .postgres_connection
+            .find_replication_slot_by_name(replication_slot_name)
+            .await?;
 
-            if clickhouse_table_not_exists {
-                log::info!(
-                    "Table {}.{} does not exist in ClickHouse, creating it",
-                    table.schema_name,
-                    table.table_name
-                );
+        check_provisioned_replication(
+            publication_name,
+            publication_tables.as_deref(),
+            &self.postgres_config.tables,
+            self.postgres_config.publish_via_partition_root,
+            replication_slot_name,
+            replication_slot.is_some(),
+        )?;
 
-                let mut table_options = table.table_options.clone();
-                table_options.inherit_from(&self.clickhouse_config.table_options);
+        log::info!(
+            "Publication {publication_name} and replication slot {replication_slot_name} are provisioned"
+        );
 
-                let create_table_query = self.generate_create_table_query(
-                    &self.clickhouse_config,
-                    &table_options,
-                    &table.table_name,
-                    &postgres_columns,
-                    &table_comment,
-                );
+        Ok(())
+    }
 
-                self.clickhouse_connection
-                    .execute_query(&create_table_query)
-                    .await?;
+    async fn setup_table(&mut self) -> Result<(), Errors> {
+        let tables = self.postgres_config.tables.clone();
 
-                log::info!(
-                    "Table {}.{} created in ClickHouse",
-                    table.schema_name,
-                    table.table_name
-                );
-            }
+        self.setup_tables(&tables).await
+    }
+
+    async fn setup_tables(&mut self, tables: &[PostgresSource]) -> Result<(), Errors> {
+        log::info!("Setting up tables in ClickHouse...");
+
+        for table in tables {
+            let postgres_columns = self.load_postgres_columns(table).await?;
+
+            let table_comment = self
+                .postgres_connection
+                .get_comment_from_table(&table.schema_name, &table.table_name)
+                .await?;
 
             let relation_id = self
                 .postgres_connection
                 .get_relation_id_by_table_name(&table.schema_name, &table.table_name)
                 .await?;
+            let partition_relation_ids = self
+                .postgres_connection
+                .get_partition_relation_ids(relation_id)
+                .await?;
 
-            let mut clickhouse_columns = self
-                .clickhouse_connection
-                .list_columns_by_tablename(
-                    &self.clickhouse_config.connection.database,
-                    &table.table_name,
+            let clickhouse_columns = self
+                .setup_clickhouse_table(
+                    &self.clickhouse_config,
+                    &self.clickhouse_connection,
+                    table,
+                    &postgres_columns,
+                    &table_comment,
                 )
                 .await?;
 
-            // Check if all Postgres columns exist in ClickHouse
-            let mut need_refresh_columns = false;
-
-            for postgres_column in &postgres_columns {
-                if !clickhouse_columns
-                    .iter()
-                    .any(|c| c.column_name == postgres_column.column_name)
-                {
-                    log::info!(
-                        "[{}.{}] Column {} does not exist in ClickHouse. Try to add it",
-                        table.schema_name,
-                        table.table_name,
-                        postgres_column.column_name,
-                    );
-
-                    let add_column_query = self.generate_add_column_query(
-                        &self.clickhouse_config,
-                        table.table_name.as_str(),
-                        postgres_column,
-                    );
-
-                    self.clickhouse_connection
-                        .execute_query(&add_column_query)
-                        .await?;
-
-                    log::info!(
-                        "[{}.{}] Column {} added to ClickHouse",
-                        table.schema_name,
-                        table.table_name,
-                        postgres_column.column_name,
-                    );
+            let fan_out_clickhouse_columns = std::sync::Mutex::new(HashMap::new());
+            pipes::fan_out(&self.fan_out_targets, |target| async {
+                let clickhouse_columns = self
+                    .setup_clickhouse_table(
+                        &target.config,
+                        &target.connection,
+                        table,
+                        &postgres_columns,
+                        &table_comment,
+                    )
+                    .await?;
 
-                    need_refresh_columns = true;
+                fan_out_clickhouse_columns
+                    .lock()
+                    .expect("fan-out columns lock poisoned")
+                    .insert(target.name(), clickhouse_columns);
 
-                    continue;
-                }
-            }
+                Ok(())
+            })
+            .await?;
 
-            if need_refresh_columns {
-                clickhouse_columns = self
-                    .clickhouse_connection
-                    .list_columns_by_tablename(
-                        &self.clickhouse_config.connection.database,
-                        &table.table_name,
-                    )
-                    .await?;
+            let missing_columns = find_missing_columns(
+                &postgres_columns,
+                &clickhouse_columns,
+                self.clickhouse_config.identifier_case,
+            );
+            if !missing_columns.is_empty() {
+                log::warn!(
+                    "[{}.{}] Columns {:?} are still missing in ClickHouse and will not be synchronized",
+                    table.schema_name,
+                    table.table_name,
+                    missing_columns
+                );
             }
 
             self.context.set_table(
@@ -730,73 +1164,2959 @@ impl PostgresPipe {
                 postgres_columns,
                 clickhouse_columns,
             );
-            self.context.table_relation_map.insert(
-                relation_id as u32,
-                PostgresTableRelation {
-                    schema_name: table.schema_name.clone(),
-                    table_name: table.table_name.clone(),
-                },
+            self.context.set_fan_out_columns(
+                table.schema_name.as_str(),
+                table.table_name.as_str(),
+                fan_out_clickhouse_columns
+                    .into_inner()
+                    .expect("fan-out columns lock poisoned"),
             );
+            // Changes of a partitioned table arrive under the ids of its partitions
+            let relation_ids = std::iter::once((relation_id, false))
+                .chain(partition_relation_ids.into_iter().map(|id| (id, true)));
+            for (relation_id, is_partition) in relation_ids {
+                self.context.table_relation_map.insert(
+                    relation_id,
+                    PostgresTableRelation {
+                        schema_name: table.schema_name.clone(),
+                        table_name: table.table_name.clone(),
+                        is_partition,
+                    },
+                );
+            }
         }
 
         Ok(())
     }
-}
 
-impl IntoClickhouse for PostgresPipe {}
+    /// Source columns of a table with the per-table column options applied.
+    async fn load_postgres_columns(
+        &self,
+        table: &PostgresSource,
+    ) -> Result<Vec<PostgresColumn>, Errors> {
+        let mut postgres_columns = self
+            .postgres_connection
+            .list_columns_by_tablename(&table.schema_name, &table.table_name)
+            .await?;
 
-pub async fn run_postgres_pipe(config: Configuraion) {
-    let mut pipe = PostgresPipe::new(
-        config.clone(),
-        config.source.postgres.expect("Postgres config is required"),
-        config
-            .target
-            .clickhouse
-            .expect("Clickhouse config is required"),
-    )
-    .await;
+        if self.postgres_config.bool_as_uint8 {
+            apply_bool_as_uint8(&mut postgres_columns);
+        }
+        apply_column_type_overrides(table, &mut postgres_columns);
+        apply_column_defaults(table, &mut postgres_columns);
+        apply_dedup_key(table, &mut postgres_columns)?;
+        for clickhouse_config in std::iter::once(&self.clickhouse_config)
+            .chain(self.fan_out_targets.iter().map(|target| &target.config))
+        {
+            reject_engine_column_names(
+                table,
+                &postgres_columns,
+                &pipes::table_options_on(&table.table_options, clickhouse_config),
+            )?;
+        }
 
-    if let Err(error) = pipe.ping().await {
-        log::error!("Failed to ping Postgres exporter: {error:?}");
-        return;
+        if !postgres_columns.iter().any(|column| column.is_primary_key) {
+            log::warn!(
+                "[{}.{}] Table has no primary key (or dedup_key). It is created as a MergeTree table, updates are appended as new rows and deletes are not replicated",
+                table.schema_name,
+                table.table_name
+            );
+        }
+
+        Ok(postgres_columns)
     }
 
-    tokio::select! {
-        _ = pipe.run_pipe() => {
-            log::info!("Postgres pipe running...");
+    /// Creates the table in a ClickHouse target if it does not exist and adds missing columns.
+    /// Returns the ClickHouse columns after the changes.
+    async fn setup_clickhouse_table(
+        &self,
+        clickhouse_config: &ClickHouseConfig,
+        clickhouse_connection: &ClickhouseConnection,
+        table: &PostgresSource,
+        postgres_columns: &[PostgresColumn],
+        table_comment: &str,
+    ) -> Result<Vec<ClickhouseColumn>, Errors> {
+        let database_name = &clickhouse_config.connection.database;
+
+        let clickhouse_table_not_exists = clickhouse_connection
+            .list_columns_by_tablename(database_name, &table.table_name)
+            .await?
+            .is_empty();
+
+        if clickhouse_table_not_exists {
+            log::info!(
+                "Table {}.{} does not exist in ClickHouse, creating it",
+                table.schema_name,
+                table.table_name
+            );
+
+            let create_table_query = create_table_query(
+                self,
+                clickhouse_config,
+                table,
+                postgres_columns,
+                table_comment,
+            );
+
+            pipes::execute_ddl(&self.config, clickhouse_connection, &create_table_query).await?;
+
+            log::info!(
+                "Table {}.{} created in ClickHouse",
+                table.schema_name,
+                table.table_name
+            );
         }
-    }
-}
 
-pub struct BatchWriteEntry<'a> {
-    pub table_info: &'a PostgresPipeTableInfo,
-    pub mask_columns: Vec<String>,
-    pub rows: Vec<PostgresCopyRow>,
-}
+        let mut clickhouse_columns = clickhouse_connection
+            .wait_for_columns(database_name, &table.table_name)
+            .await?;
 
-impl BatchWriteEntry<'_> {
-    pub fn push(&mut self, row: PostgresCopyRow) {
-        self.rows.push(row);
-    }
+        // Check if all Postgres columns exist in ClickHouse
+        let mut need_refresh_columns = false;
 
-    pub fn deduplicated_rows(&self) -> Vec<PostgresCopyRow> {
-        adapter::deduplicate_rows_keeping_last(self.rows.clone(), |row| {
-            extract_postgres_primary_key(row, &self.table_info.postgres_columns)
-        })
-    }
-}
+        let identifier_case = clickhouse_config.identifier_case;
 
-fn extract_postgres_primary_key(row: &PostgresCopyRow, columns: &[PostgresColumn]) -> String {
-    columns
-        .iter()
-        .filter(|col| col.is_primary_key)
-        .map(|col| {
-            let index = (col.column_index - 1) as usize;
-            match row.columns.get(index) {
-                Some(value) => format!("{value:?}"),
-                None => "NULL".to_string(),
-            }
+        for postgres_column in postgres_columns {
+            if !clickhouse_columns
+                .iter()
+                .any(|c| identifier_case.matches(&postgres_column.column_name, &c.column_name))
+            {
+                log::info!(
+                    "[{}.{}] Column {} does not exist in ClickHouse. Try to add it",
+                    table.schema_name,
+                    table.table_name,
+                    postgres_column.column_name,
+                );
+
+                let add_column_query = self.generate_add_column_query(
+                    clickhouse_config,
+                    table.table_name.as_str(),
+                    postgres_column,
+                );
+
+                pipes::execute_ddl(&self.config, clickhouse_connection, &add_column_query).await?;
+
+                log::info!(
+                    "[{}.{}] Column {} added to ClickHouse",
+                    table.schema_name,
+                    table.table_name,
+                    postgres_column.column_name,
+                );
+
+                need_refresh_columns = true;
+
+                continue;
+            }
+        }
+
+        if need_refresh_columns {
+            clickhouse_columns = clickhouse_connection
+                .wait_for_columns(database_name, &table.table_name)
+                .await?;
+        }
+
+        pipes::remove_engine_columns(
+            &mut clickhouse_columns,
+            &pipes::table_options_on(&table.table_options, clickhouse_config),
+        );
+
+        // Deletes and deduplication rely on both sides using the same key
+        validate_primary_keys(
+            &format!("{database_name}.{}", table.table_name),
+            postgres_columns,
+            &clickhouse_columns,
+            identifier_case,
+        )?;
+
+        validate_column_types(
+            &format!("{database_name}.{}", table.table_name),
+            postgres_columns,
+            &clickhouse_columns,
+            &table.mask_columns,
+            identifier_case,
+        )?;
+
+        // Comments are only copied when a column is created, so later changes are applied here
+        for (clickhouse_column, postgres_column) in
+            find_changed_comments(postgres_columns, &clickhouse_columns, identifier_case)
+        {
+            let comment_column_query = self.generate_comment_column_query(
+                clickhouse_config,
+                table.table_name.as_str(),
+                &clickhouse_column.column_name,
+                postgres_column,
+            );
+
+            // A stale comment does not affect the data, so it never stops the pipe
+            if self.config.dry_run_clickhouse_ddl {
+                log::warn!(
+                    "dry_run_clickhouse_ddl is set, not updating the comment of {}.{}: {comment_column_query}",
+                    table.table_name,
+                    clickhouse_column.column_name
+                );
+                continue;
+            }
+
+            clickhouse_connection
+                .execute_query(&comment_column_query)
+                .await?;
+
+            log::info!(
+                "[{}.{}] Comment of column {} updated in ClickHouse",
+                table.schema_name,
+                table.table_name,
+                clickhouse_column.column_name,
+            );
+        }
+
+        Ok(clickhouse_columns)
+    }
+
+    /// Hands the changes of one iteration to the configured sinks and flushes them.
+    async fn write_to_sinks(&self, pending_writes: &PendingWrites<'_>) -> Result<(), Errors> {
+        if self.sinks.is_empty() {
+            return Ok(());
+        }
+
+        let identifier_case = self.clickhouse_config.identifier_case;
+        let batches = [
+            (ChangeKind::Upsert, &pending_writes.inserts),
+            (ChangeKind::Upsert, &pending_writes.updates),
+            (ChangeKind::Delete, &pending_writes.deletes),
+        ]
+        .into_iter()
+        .flat_map(|(kind, queue)| {
+            queue.iter().map(move |(table_name, batch)| {
+                SinkBatch::from_rows(
+                    table_name,
+                    kind,
+                    &batch.table_info.clickhouse_columns,
+                    &batch.table_info.postgres_columns,
+                    &batch.mask_columns,
+                    identifier_case,
+                    &batch.rows,
+                    &batch.sequences,
+                )
+            })
         })
-        .collect::<Vec<_>>()
-        .join("|")
+        .collect();
+
+        pipes::sink::write_to_sinks(&self.sinks, batches).await
+    }
+
+    /// Truncates the ClickHouse tables of a Truncate message (see `truncated_tables`),
+    /// unless `propagate_truncate` is disabled.
+    async fn truncate_relations(&self, tables: &[&PostgresTableRelation]) -> Result<(), Errors> {
+        if !self.postgres_config.propagate_truncate {
+            log::info!(
+                "TRUNCATE of {} tables not applied (propagate_truncate is disabled)",
+                tables.len()
+            );
+            return Ok(());
+        }
+
+        for relation in tables {
+            if let Err(error) = self
+                .execute_write(|clickhouse_config| {
+                    self.generate_truncate_query(clickhouse_config, &relation.table_name)
+                })
+                .await
+            {
+                log::error!(
+                    "Failed to truncate table {}.{}: {}",
+                    relation.schema_name,
+                    relation.table_name,
+                    error
+                );
+
+                return Err(error);
+            }
+
+            log::info!(
+                "Table {}.{} was truncated.",
+                relation.schema_name,
+                relation.table_name
+            );
+        }
+
+        log::info!("TRUNCATE applied to {} tables", tables.len());
+
+        Ok(())
+    }
+
+    /// Executes a write on the main ClickHouse target and all fan-out targets.
+    async fn execute_write(
+        &self,
+        generate_query: impl Fn(&ClickHouseConfig) -> String,
+    ) -> Result<(), Errors> {
+        pipes::execute_write(
+            &self.clickhouse_config,
+            &self.clickhouse_connection,
+            &self.fan_out_targets,
+            generate_query,
+        )
+        .await
+    }
+
+    /// Executes a write made of several queries on the main ClickHouse target and all fan-out targets.
+    async fn execute_writes(
+        &self,
+        generate_queries: impl Fn(&ClickHouseConfig) -> Vec<String>,
+    ) -> Result<(), Errors> {
+        pipes::execute_writes(
+            &self.clickhouse_config,
+            &self.clickhouse_connection,
+            &self.fan_out_targets,
+            generate_queries,
+        )
+        .await
+    }
+}
+
+impl IntoClickhouse for PostgresPipe {}
+
+pub async fn run_postgres_pipe(config: Configuraion, config_options: ConfigOptions) {
+    let mut pipe = match PostgresPipe::new(
+        config.clone(),
+        config.source.postgres.expect("Postgres config is required"),
+        config
+            .target
+            .clickhouse
+            .expect("Clickhouse config is required"),
+    )
+    .await
+    {
+        Ok(pipe) => pipe,
+        Err(error) => {
+            log::error!("Failed to start Postgres pipe: {error}");
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(error) = pipe.ping().await {
+        log::error!("Failed to ping Postgres exporter: {error:?}");
+        std::process::exit(1);
+    }
+
+    pipes::pause::listen_for_pause_signals(pipe.pause_switch());
+    pipes::reload::listen_for_reload_signal(pipe.pending_config(), config_options);
+
+    tokio::select! {
+        _ = pipe.run_pipe() => {
+            log::info!("Postgres pipe running...");
+        }
+    }
+}
+
+/// CREATE TABLE query run by setup (and printed by `print-create`) for a table missing in ClickHouse.
+fn create_table_query(
+    ddl: &impl IntoClickhouse,
+    clickhouse_config: &ClickHouseConfig,
+    table: &PostgresSource,
+    postgres_columns: &[PostgresColumn],
+    table_comment: &str,
+) -> String {
+    let mut table_options = table.table_options.clone();
+    table_options.inherit_from(&clickhouse_config.table_options);
+    table_options.lightweight_updates = table.update_mode.is_lightweight();
+
+    ddl.generate_create_table_query(
+        clickhouse_config,
+        &table_options,
+        &table.table_name,
+        postgres_columns,
+        table_comment,
+    )
+}
+
+/// Errors with the statements an admin has to run, if the publication, one of its tables or
+/// the replication slot is missing.
+fn check_provisioned_replication(
+    publication_name: &str,
+    publication_tables: Option<&[PublicationTable]>,
+    tables: &[PostgresSource],
+    publish_via_partition_root: bool,
+    replication_slot_name: &str,
+    replication_slot_exists: bool,
+) -> Result<(), Errors> {
+    let table_names = |tables: &[&PostgresSource]| {
+        tables
+            .iter()
+            .map(|table| format!("{}.{}", table.schema_name, table.table_name))
+            .collect::<Vec<_>>()
+    };
+
+    let Some(publication_tables) = publication_tables else {
+        let query = create_publication_query(
+            publication_name,
+            &table_names(&tables.iter().collect::<Vec<_>>()),
+            publish_via_partition_root,
+        );
+
+        return Err(Errors::PublicationFindFailed(format!(
+            "Publication {publication_name} does not exist. With manage_replication disabled, it must be created by an admin: {query}"
+        )));
+    };
+
+    let missing_tables = tables
+        .iter()
+        .filter(|table| {
+            !publication_tables.iter().any(|publication_table| {
+                publication_table.publishes(&table.schema_name, &table.table_name)
+            })
+        })
+        .collect::<Vec<_>>();
+
+    if !missing_tables.is_empty() {
+        let missing_tables = table_names(&missing_tables).join(", ");
+
+        return Err(Errors::PublicationFindFailed(format!(
+            "Tables {missing_tables} are not in publication {publication_name}. With manage_replication disabled, they must be added by an admin: ALTER PUBLICATION {publication_name} ADD TABLE {missing_tables}"
+        )));
+    }
+
+    if !replication_slot_exists {
+        return Err(Errors::ReplicationNotFound(format!(
+            "Replication slot {replication_slot_name} does not exist. With manage_replication disabled, it must be created by an admin: SELECT pg_create_logical_replication_slot('{replication_slot_name}', 'pgoutput')"
+        )));
+    }
+
+    Ok(())
+}
+
+fn disable_deletes_for_aggregating_engines(
+    tables: &mut [PostgresSource],
+    clickhouse_config: &ClickHouseConfig,
+) {
+    for table in tables {
+        let mut table_options = table.table_options.clone();
+        table_options.inherit_from(&clickhouse_config.table_options);
+
+        pipes::disable_deletes_for_aggregating_engine(
+            &format!("{}.{}", table.schema_name, table.table_name),
+            &table_options,
+            &mut table.operations,
+        );
+    }
+}
+
+/// Uses the configured `dedup_key` columns as the primary key of a table without one.
+fn apply_dedup_key(
+    table: &PostgresSource,
+    postgres_columns: &mut [PostgresColumn],
+) -> Result<(), Errors> {
+    if table.dedup_key.is_empty() {
+        return Ok(());
+    }
+
+    if postgres_columns.iter().any(|column| column.is_primary_key) {
+        return Err(Errors::ConfigValidationError(format!(
+            "[{}.{}] dedup_key is only allowed for tables without a primary key",
+            table.schema_name, table.table_name
+        )));
+    }
+
+    for column_name in &table.dedup_key {
+        let Some(column) = postgres_columns
+            .iter_mut()
+            .find(|column| &column.column_name == column_name)
+        else {
+            return Err(Errors::ConfigValidationError(format!(
+                "[{}.{}] Column {} in dedup_key does not exist",
+                table.schema_name, table.table_name, column_name
+            )));
+        };
+
+        // ClickHouse does not allow Nullable columns in the sorting key
+        if column.nullable {
+            return Err(Errors::ConfigValidationError(format!(
+                "[{}.{}] Column {} in dedup_key must be NOT NULL",
+                table.schema_name, table.table_name, column_name
+            )));
+        }
+
+        column.is_primary_key = true;
+    }
+
+    Ok(())
+}
+
+/// Source columns named like a column of the table engine (`version_column`, `is_deleted_column`)
+/// would be created twice or be mistaken for the engine column. Compared ignoring case, since
+/// `identifier_case` can change it.
+fn reject_engine_column_names(
+    table: &PostgresSource,
+    postgres_columns: &[PostgresColumn],
+    table_options: &ClickHouseTableOptions,
+) -> Result<(), Errors> {
+    let engine_column_names = table_options.engine_column_names();
+
+    match postgres_columns.iter().find(|column| {
+        engine_column_names
+            .iter()
+            .any(|name| name.eq_ignore_ascii_case(&column.column_name))
+    }) {
+        Some(column) => Err(Errors::ConfigValidationError(format!(
+            "[{}.{}] Column {} has a name reserved by clockpipe for the table engine. Rename the column or leave the table out",
+            table.schema_name, table.table_name, column.column_name
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// `bool_as_uint8`: bool columns are created as UInt8. Arrays of bool stay `Array(Bool)`
+fn apply_bool_as_uint8(postgres_columns: &mut [PostgresColumn]) {
+    for column in postgres_columns
+        .iter_mut()
+        .filter(|column| column.data_type == "bool")
+    {
+        column.type_override = Some(ColumnTypeOverride::UInt8);
+    }
+}
+
+/// Sets the configured type overrides on the source columns.
+/// Existing ClickHouse columns keep their type; overrides only apply to CREATE/ADD COLUMN.
+fn apply_column_type_overrides(table: &PostgresSource, postgres_columns: &mut [PostgresColumn]) {
+    for (column_name, type_override) in &table.column_type_overrides {
+        let Some(column) = postgres_columns
+            .iter_mut()
+            .find(|column| &column.column_name == column_name)
+        else {
+            log::warn!(
+                "[{}.{}] Column {} in column_type_overrides does not exist",
+                table.schema_name,
+                table.table_name,
+                column_name
+            );
+            continue;
+        };
+
+        if !column.supports_type_override(*type_override) {
+            log::warn!(
+                "[{}.{}] Type override {:?} is not supported for this column type. Ignored for column {} ({})",
+                table.schema_name,
+                table.table_name,
+                type_override,
+                column.column_name,
+                column.data_type
+            );
+            continue;
+        }
+
+        column.type_override = Some(*type_override);
+    }
+}
+
+fn apply_column_defaults(table: &PostgresSource, postgres_columns: &mut [PostgresColumn]) {
+    for (column_name, expression) in &table.column_defaults {
+        let Some(column) = postgres_columns
+            .iter_mut()
+            .find(|column| &column.column_name == column_name)
+        else {
+            log::warn!(
+                "[{}.{}] Column {} in column_defaults does not exist",
+                table.schema_name,
+                table.table_name,
+                column_name
+            );
+            continue;
+        };
+
+        column.default_expression = Some(expression.clone());
+    }
+}
+
+/// Returns the names of source columns that have no matching ClickHouse column.
+fn find_missing_columns(
+    postgres_columns: &[PostgresColumn],
+    clickhouse_columns: &[ClickhouseColumn],
+    identifier_case: IdentifierCase,
+) -> Vec<String> {
+    postgres_columns
+        .iter()
+        .filter(|postgres_column| {
+            !clickhouse_columns
+                .iter()
+                .any(|c| identifier_case.matches(&postgres_column.column_name, &c.column_name))
+        })
+        .map(|postgres_column| postgres_column.column_name.clone())
+        .collect()
+}
+
+/// ClickHouse columns whose comment differs from the comment of their source column.
+fn find_changed_comments<'a>(
+    postgres_columns: &'a [PostgresColumn],
+    clickhouse_columns: &'a [ClickhouseColumn],
+    identifier_case: IdentifierCase,
+) -> Vec<(&'a ClickhouseColumn, &'a PostgresColumn)> {
+    clickhouse_columns
+        .iter()
+        .filter_map(|clickhouse_column| {
+            postgres_columns
+                .iter()
+                .find(|postgres_column| {
+                    identifier_case
+                        .matches(&postgres_column.column_name, &clickhouse_column.column_name)
+                })
+                // system.columns returns the comment unescaped
+                .filter(|postgres_column| postgres_column.comment != clickhouse_column.comment)
+                .map(|postgres_column| (clickhouse_column, postgres_column))
+        })
+        .collect()
+}
+
+/// Checks that the ClickHouse primary key columns are the same set as the Postgres primary key.
+/// With a subset (e.g. ORDER BY (a) for a (a, b) source key), deletes would match too many rows.
+fn validate_primary_keys(
+    clickhouse_table_name: &str,
+    postgres_columns: &[PostgresColumn],
+    clickhouse_columns: &[ClickhouseColumn],
+    identifier_case: IdentifierCase,
+) -> Result<(), Errors> {
+    let postgres_primary_keys: Vec<String> = postgres_columns
+        .iter()
+        .filter(|column| column.is_primary_key)
+        .map(|column| identifier_case.apply(&column.column_name))
+        .sorted()
+        .collect();
+
+    let clickhouse_primary_keys: Vec<String> = clickhouse_columns
+        .iter()
+        .filter(|column| column.is_in_primary_key)
+        .map(|column| column.column_name.clone())
+        .sorted()
+        .collect();
+
+    if postgres_primary_keys != clickhouse_primary_keys {
+        return Err(Errors::PrimaryKeyMismatch(format!(
+            "ClickHouse table {clickhouse_table_name} has primary key {clickhouse_primary_keys:?}, but the Postgres primary key is {postgres_primary_keys:?}. Recreate the ClickHouse table so that its ORDER BY matches the source primary key."
+        )));
+    }
+
+    Ok(())
+}
+
+/// Checks that every existing ClickHouse column can store the values of its Postgres column,
+/// e.g. a ClickHouse `Int32` column for a column that is `text` in Postgres now.
+/// Masked columns are skipped, since only default values are written to them.
+fn validate_column_types(
+    clickhouse_table_name: &str,
+    postgres_columns: &[PostgresColumn],
+    clickhouse_columns: &[ClickhouseColumn],
+    mask_columns: &[String],
+    identifier_case: IdentifierCase,
+) -> Result<(), Errors> {
+    let mismatches: Vec<String> = postgres_columns
+        .iter()
+        .filter(|postgres_column| !mask_columns.contains(&postgres_column.column_name))
+        .filter_map(|postgres_column| {
+            let clickhouse_column = clickhouse_columns.iter().find(|column| {
+                identifier_case.matches(&postgres_column.column_name, &column.column_name)
+            })?;
+            let source_type = postgres_column.to_clickhouse_type().to_type_text();
+
+            if accepts_values_of(&clickhouse_column.data_type, &source_type) {
+                return None;
+            }
+
+            Some(format!(
+                "column {} is {} in ClickHouse, but {} ({source_type}) in Postgres. Change it with ALTER TABLE {clickhouse_table_name} MODIFY COLUMN `{}` {source_type} or recreate the table",
+                clickhouse_column.column_name,
+                clickhouse_column.data_type,
+                postgres_column.data_type,
+                clickhouse_column.column_name,
+            ))
+        })
+        .collect();
+
+    if !mismatches.is_empty() {
+        return Err(Errors::ColumnTypeMismatch(format!(
+            "ClickHouse table {clickhouse_table_name}: {}",
+            mismatches.join("; ")
+        )));
+    }
+
+    Ok(())
+}
+
+/// Peeks the slot (without advancing it) and prints every decoded change whose LSN is within
+/// `[start_lsn, end_lsn]`. Nothing is written to ClickHouse.
+pub async fn run_replay_wal(
+    config: Configuraion,
+    start_lsn: Option<&str>,
+    end_lsn: Option<&str>,
+    limit: u64,
+) -> Result<(), Errors> {
+    let start_lsn = start_lsn.map(parse_lsn).transpose()?;
+    let end_lsn = end_lsn.map(parse_lsn).transpose()?;
+
+    print_wal_changes(config, start_lsn, end_lsn, limit).await
+}
+
+/// Peeks the next `limit` changes (without advancing the slot) and pretty-prints them.
+pub async fn run_decode_wal(config: Configuraion, limit: u64) -> Result<(), Errors> {
+    print_wal_changes(config, None, None, limit).await
+}
+
+async fn print_wal_changes(
+    config: Configuraion,
+    start_lsn: Option<u64>,
+    end_lsn: Option<u64>,
+    limit: u64,
+) -> Result<(), Errors> {
+    let postgres_config = config.source.postgres.expect("Postgres config is required");
+
+    let postgres_connection =
+        adapter::postgres::PostgresConnection::new(&postgres_config.connection).await?;
+
+    let peek_result = postgres_connection
+        .peek_wal_changes(
+            &postgres_config.publication_name(),
+            &postgres_config.replication_slot_name(),
+            end_lsn,
+            limit,
+        )
+        .await?;
+
+    let changes = decode_wal_changes(&peek_result, start_lsn, end_lsn)?;
+
+    let mut table_names = HashMap::new();
+    for (_, parsed_row) in &changes {
+        if table_names.contains_key(&parsed_row.relation_id) {
+            continue;
+        }
+
+        let table_name = postgres_connection
+            .get_table_name_by_relation_id(parsed_row.relation_id as i64)
+            .await
+            .ok();
+
+        table_names.insert(parsed_row.relation_id, table_name);
+    }
+
+    for (row, parsed_row) in &changes {
+        let table_name = table_names
+            .get(&parsed_row.relation_id)
+            .and_then(|name| name.as_deref());
+
+        println!("{}", format_wal_change(row, parsed_row, table_name));
+    }
+
+    println!("{} change(s) decoded", changes.len());
+
+    Ok(())
+}
+
+/// Prints the CREATE TABLE queries setup would run for the configured tables, without connecting to ClickHouse.
+pub async fn run_print_create(config: Configuraion) -> Result<(), Errors> {
+    let postgres_config = config
+        .source
+        .postgres
+        .clone()
+        .expect("Postgres config is required");
+    let clickhouse_config = config
+        .target
+        .clickhouse
+        .clone()
+        .expect("Clickhouse config is required");
+
+    let pipe = PostgresPipe::new(config, postgres_config, clickhouse_config).await?;
+
+    for table in &pipe.postgres_config.tables {
+        let postgres_columns = pipe.load_postgres_columns(table).await?;
+        let table_comment = pipe
+            .postgres_connection
+            .get_comment_from_table(&table.schema_name, &table.table_name)
+            .await?;
+
+        println!("-- {}.{}", table.schema_name, table.table_name);
+        println!(
+            "{}\n",
+            create_table_query(
+                &pipe,
+                &pipe.clickhouse_config,
+                table,
+                &postgres_columns,
+                &table_comment,
+            )
+        );
+    }
+
+    Ok(())
+}
+
+/// Fills one column of the rows that already exist in ClickHouse from the source table,
+/// without copying the other columns again (e.g. after a column was added to the source table).
+pub async fn run_backfill_column(
+    config: Configuraion,
+    table_name: &str,
+    column_name: &str,
+    batch_size: usize,
+) -> Result<(), Errors> {
+    let postgres_config = config
+        .source
+        .postgres
+        .clone()
+        .expect("Postgres config is required");
+    let table = find_configured_table(&postgres_config.tables, table_name)?;
+
+    let mut pipe = PostgresPipe::new(
+        config.clone(),
+        postgres_config,
+        config
+            .target
+            .clickhouse
+            .clone()
+            .expect("Clickhouse config is required"),
+    )
+    .await?;
+
+    pipe.ping().await?;
+    pipe.backfill_column(&table, column_name, batch_size).await
+}
+
+impl PostgresPipe {
+    async fn backfill_column(
+        &mut self,
+        table: &PostgresSource,
+        column_name: &str,
+        batch_size: usize,
+    ) -> Result<(), Errors> {
+        // Adds the column to ClickHouse if it is not there yet
+        self.setup_tables(std::slice::from_ref(table)).await?;
+
+        let table_key = format!("{}.{}", table.schema_name, table.table_name);
+        let table_info = self
+            .context
+            .tables_map
+            .get(&table_key)
+            .ok_or_else(|| Errors::TableNotFoundError(table_key.clone()))?;
+
+        let source_columns = backfill_source_columns(
+            table,
+            &table_info.postgres_columns,
+            column_name,
+            self.clickhouse_config.identifier_case,
+        )?;
+        let column_names: Vec<&str> = source_columns
+            .iter()
+            .map(|column| column.column_name.as_str())
+            .collect();
+
+        log::info!("Backfilling column {column_name} of {table_key}...");
+
+        let mut copy_receiver = self
+            .postgres_connection
+            .copy_columns_to_stdout(
+                &table.schema_name,
+                &table.table_name,
+                &column_names,
+                self.config.copy_max_in_flight_bytes,
+            )
+            .await?;
+
+        let mut processed_rows = 0_usize;
+        let mut batch = CopyBatch::default();
+        while let Some(chunk) = copy_receiver.recv().await {
+            batch.push(chunk);
+
+            if batch.rows.len() < batch_size && !copy_receiver.should_flush(&batch) {
+                continue;
+            }
+
+            self.write_backfill_batch(table_info, &source_columns, table, column_name, &batch.rows)
+                .await?;
+
+            processed_rows += batch.rows.len();
+            batch.clear();
+            log::info!("[{table_key}] {processed_rows} rows backfilled");
+        }
+
+        // Flush remaining rows that didn't reach the batch threshold
+        if !batch.rows.is_empty() {
+            self.write_backfill_batch(table_info, &source_columns, table, column_name, &batch.rows)
+                .await?;
+
+            processed_rows += batch.rows.len();
+        }
+
+        log::info!("Column {column_name} of {table_key} backfilled ({processed_rows} rows)");
+
+        Ok(())
+    }
+
+    async fn write_backfill_batch(
+        &self,
+        table_info: &PostgresPipeTableInfo,
+        source_columns: &[PostgresColumn],
+        table: &PostgresSource,
+        column_name: &str,
+        rows: &[PostgresCopyRow],
+    ) -> Result<(), Errors> {
+        self.execute_writes(|clickhouse_config| {
+            self.generate_update_column_queries(
+                clickhouse_config,
+                table_info.clickhouse_columns_for(clickhouse_config),
+                source_columns,
+                &table.table_name,
+                column_name,
+                rows,
+            )
+        })
+        .await
+    }
+}
+
+/// Finds a configured table by `schema.table` or by table name alone.
+fn find_configured_table(tables: &[PostgresSource], name: &str) -> Result<PostgresSource, Errors> {
+    let matches: Vec<&PostgresSource> = tables
+        .iter()
+        .filter(|table| {
+            format!("{}.{}", table.schema_name, table.table_name) == name
+                || table.table_name == name
+        })
+        .collect();
+
+    match matches.as_slice() {
+        [table] => Ok((*table).clone()),
+        [] => Err(Errors::TableNotFoundError(format!(
+            "Table {name} is not in the configured tables"
+        ))),
+        _ => Err(Errors::ConfigValidationError(format!(
+            "Table name {name} is ambiguous. Use schema_name.table_name"
+        ))),
+    }
+}
+
+/// Source columns read for a backfill: the primary key and the backfilled column,
+/// numbered in the order they are selected.
+fn backfill_source_columns(
+    table: &PostgresSource,
+    postgres_columns: &[PostgresColumn],
+    column_name: &str,
+    identifier_case: IdentifierCase,
+) -> Result<Vec<PostgresColumn>, Errors> {
+    let Some(column) = postgres_columns
+        .iter()
+        .find(|column| column.column_name == column_name)
+    else {
+        return Err(Errors::ConfigValidationError(format!(
+            "[{}.{}] Column {column_name} does not exist",
+            table.schema_name, table.table_name
+        )));
+    };
+
+    if column.is_primary_key {
+        return Err(Errors::ConfigValidationError(format!(
+            "[{}.{}] Column {column_name} is part of the primary key and cannot be backfilled",
+            table.schema_name, table.table_name
+        )));
+    }
+
+    // Matched the way inserts mask columns
+    if table
+        .mask_columns
+        .iter()
+        .any(|name| identifier_case.matches(name, &identifier_case.apply(column_name)))
+    {
+        return Err(Errors::ConfigValidationError(format!(
+            "[{}.{}] Column {column_name} is masked",
+            table.schema_name, table.table_name
+        )));
+    }
+
+    let mut source_columns: Vec<PostgresColumn> = postgres_columns
+        .iter()
+        .filter(|column| column.is_primary_key)
+        .cloned()
+        .collect();
+
+    if source_columns.is_empty() {
+        return Err(Errors::ConfigValidationError(format!(
+            "[{}.{}] Backfilling requires a primary key (or dedup_key)",
+            table.schema_name, table.table_name
+        )));
+    }
+
+    source_columns.push(column.clone());
+
+    for (index, column) in source_columns.iter_mut().enumerate() {
+        column.column_index = index as i32 + 1;
+    }
+
+    Ok(source_columns)
+}
+
+/// Marks the peeked rows of transactions committed at or before `applied_lsn`.
+/// Changes of interleaved transactions can have lower LSNs than those of a transaction committed
+/// earlier, so each row is judged by the LSN of its commit, which increases in the peek order.
+fn applied_rows(peek_result: &[PeekWalChangeResult], applied_lsn: Option<u64>) -> Vec<bool> {
+    let Some(applied_lsn) = applied_lsn else {
+        return vec![false; peek_result.len()];
+    };
+
+    let mut commit_lsn = None;
+    let mut applied_rows: Vec<bool> = peek_result
+        .iter()
+        .rev()
+        .map(|row| {
+            if row.data.first() == Some(&(MessageType::Commit as u8)) {
+                commit_lsn = parse_lsn(&row.lsn).ok();
+            }
+
+            commit_lsn.is_some_and(|commit_lsn| commit_lsn <= applied_lsn)
+        })
+        .collect();
+    applied_rows.reverse();
+
+    applied_rows
+}
+
+/// Keeps the peeked transactions until the next one would bring the number of distinct tables over
+/// `max_tables`, and returns how many rows were dropped. A transaction is never split, so the first
+/// one is kept whole even if it touches more tables. The slot is only advanced to the last kept
+/// commit, so the dropped transactions are peeked again in the next iteration.
+fn defer_transactions_beyond_tables(
+    peek_result: &mut Vec<PeekWalChangeResult>,
+    max_tables: usize,
+) -> usize {
+    let tables_of = |row: &PeekWalChangeResult| -> Vec<u32> {
+        match row.data.first().copied() {
+            Some(message_type)
+                if message_type == MessageType::Insert as u8
+                    || message_type == MessageType::Update as u8
+                    || message_type == MessageType::Delete as u8 =>
+            {
+                row.data
+                    .get(1..5)
+                    .map(|id| vec![u32::from_be_bytes([id[0], id[1], id[2], id[3]])])
+                    .unwrap_or_default()
+            }
+            Some(message_type) if message_type == MessageType::Truncate as u8 => {
+                parse_pg_truncate(&row.data).unwrap_or_default()
+            }
+            _ => vec![],
+        }
+    };
+
+    let mut tables = std::collections::HashSet::new();
+    let mut transaction_tables = std::collections::HashSet::new();
+    let mut kept_rows = 0;
+
+    for (index, row) in peek_result.iter().enumerate() {
+        transaction_tables.extend(tables_of(row));
+
+        if row.data.first() != Some(&(MessageType::Commit as u8)) {
+            continue;
+        }
+
+        if kept_rows > 0 && tables.union(&transaction_tables).count() > max_tables {
+            let deferred_count = peek_result.len() - kept_rows;
+            peek_result.truncate(kept_rows);
+
+            return deferred_count;
+        }
+
+        tables.extend(transaction_tables.drain());
+        kept_rows = index + 1;
+    }
+
+    0
+}
+
+/// Decodes peeked WAL rows whose LSN is within `[start_lsn, end_lsn]`.
+/// Transaction boundaries (Begin/Commit/Relation...) are skipped, like in the sync loop.
+pub fn decode_wal_changes(
+    rows: &[PeekWalChangeResult],
+    start_lsn: Option<u64>,
+    end_lsn: Option<u64>,
+) -> Result<Vec<(&PeekWalChangeResult, PgOutput)>, Errors> {
+    let mut changes = vec![];
+
+    for row in rows {
+        let lsn = parse_lsn(&row.lsn)?;
+
+        if start_lsn.is_some_and(|start| lsn < start) || end_lsn.is_some_and(|end| lsn > end) {
+            continue;
+        }
+
+        if let Some(parsed_row) = parse_pg_output(&row.data)? {
+            changes.push((row, parsed_row));
+        }
+    }
+
+    Ok(changes)
+}
+
+/// Tables in the order of the initial copy: by `copy_order`, then the others in config order.
+fn copy_sequence(tables: &[PostgresSource]) -> Vec<&PostgresSource> {
+    tables
+        .iter()
+        .sorted_by_key(|table| (table.copy_order.is_none(), table.copy_order))
+        .collect()
+}
+
+/// Warning if this instance may share its replication slot with another clockpipe instance:
+/// its slot is in use by another connection, or it uses the default names while other clockpipe
+/// slots exist. Instances with their own `instance_id` (or slot name) do not warn about each other.
+fn shared_replication_slot_warning(
+    postgres_config: &PostgresConfig,
+    replication_slots: &[ReplicationSlot],
+) -> Option<String> {
+    let replication_slot_name = postgres_config.replication_slot_name();
+
+    if replication_slots
+        .iter()
+        .any(|slot| slot.slot_name == replication_slot_name && slot.active)
+    {
+        return Some(format!(
+            "Replication slot {replication_slot_name} is in use by another connection, e.g. another clockpipe instance. Each instance needs its own instance_id or replication_slot_name"
+        ));
+    }
+
+    if postgres_config.instance_id.is_some() || postgres_config.replication_slot_name.is_some() {
+        return None;
+    }
+
+    let other_slots: Vec<&str> = replication_slots
+        .iter()
+        .map(|slot| slot.slot_name.as_str())
+        .filter(|slot_name| {
+            slot_name.starts_with(REPLICATION_SLOT_PREFIX) && *slot_name != replication_slot_name
+        })
+        .collect();
+
+    (!other_slots.is_empty()).then(|| {
+        format!(
+            "Found other clockpipe replication slots: {other_slots:?}, while this instance uses the default {replication_slot_name}. If multiple clockpipe instances share this database, give each one its own instance_id"
+        )
+    })
+}
+
+/// Synced tables of the truncated relations, once per table.
+/// A partition is skipped: its rows cannot be told apart in the ClickHouse table of the
+/// partitioned table, which also holds the rows of the other partitions. A TRUNCATE of the
+/// partitioned table itself carries the id of the partitioned table.
+fn truncated_tables<'a>(
+    context: &'a PostgresPipeContext,
+    relation_ids: &[u32],
+) -> Vec<&'a PostgresTableRelation> {
+    relation_ids
+        .iter()
+        .filter_map(|relation_id| {
+            let relation = context.table_relation_map.get(relation_id);
+            match relation {
+                None => {
+                    log::warn!("Relation ID {relation_id} of TRUNCATE is not synced. Skipped");
+                    None
+                }
+                Some(relation) if relation.is_partition => {
+                    log::warn!(
+                        "TRUNCATE of a partition (relation ID {relation_id}) of {}.{} not applied, it would truncate the rows of all partitions in ClickHouse",
+                        relation.schema_name,
+                        relation.table_name
+                    );
+                    None
+                }
+                relation => relation,
+            }
+        })
+        .unique_by(|relation| (&relation.schema_name, &relation.table_name))
+        .collect()
+}
+
+/// Puts the tuple values of a relation in the order of the source columns (`column_index`),
+/// matched by name. The tuple has no values for dropped columns, and the columns of a partition
+/// or of a table altered after startup can be in another order than the source columns.
+/// A source column that is not in the relation anymore gets NULL.
+fn align_to_columns(
+    relation: &PgRelation,
+    postgres_columns: &[PostgresColumn],
+    values: Vec<PgOutputValue>,
+) -> Vec<PgOutputValue> {
+    let column_count = postgres_columns
+        .iter()
+        .map(|column| column.column_index as usize)
+        .max()
+        .unwrap_or_default();
+    let mut aligned = vec![PgOutputValue::Null; column_count];
+
+    for (relation_column, value) in relation.columns.iter().zip(values) {
+        let slot = postgres_columns
+            .iter()
+            .find(|column| column.column_name == relation_column.name)
+            .and_then(|column| aligned.get_mut((column.column_index as usize).checked_sub(1)?));
+
+        if let Some(slot) = slot {
+            *slot = value;
+        }
+    }
+
+    aligned
+}
+
+/// Marks the values of an update that are the same as in the old row as unchanged, so that the
+/// lightweight `UPDATE` sets only the changed columns. Only an 'O' tuple (REPLICA IDENTITY FULL)
+/// has the old values of all columns, otherwise every value is kept.
+/// Key columns are kept, the row is looked up by them.
+fn leave_out_unchanged_values(parsed_row: &mut PgOutput, postgres_columns: &[PostgresColumn]) {
+    if parsed_row.old_tuple_type != Some(PgTupleType::Old) {
+        return;
+    }
+    let Some(old_values) = &parsed_row.old_values else {
+        return;
+    };
+
+    for (index, (value, old_value)) in parsed_row.payload.iter_mut().zip(old_values).enumerate() {
+        let is_key = postgres_columns
+            .iter()
+            .any(|column| column.column_index as usize == index + 1 && column.is_primary_key);
+        if is_key {
+            continue;
+        }
+
+        let is_same = match (&*value, old_value) {
+            (PgOutputValue::Null, PgOutputValue::Null) => true,
+            (PgOutputValue::Text(new), PgOutputValue::Text(old)) => new == old,
+            (PgOutputValue::Binary(new), PgOutputValue::Binary(old)) => new == old,
+            _ => false,
+        };
+        if is_same {
+            *value = PgOutputValue::Unchanged;
+        }
+    }
+}
+
+pub fn format_wal_change(
+    row: &PeekWalChangeResult,
+    parsed_row: &PgOutput,
+    table_name: Option<&str>,
+) -> String {
+    let mut text = format!(
+        "[{}] xid={} {:?} {} (relation_id={})",
+        row.lsn,
+        row.xid,
+        parsed_row.message_type,
+        table_name.unwrap_or("<unknown>"),
+        parsed_row.relation_id
+    );
+
+    for (index, value) in parsed_row.payload.iter().enumerate() {
+        text.push_str(&format!("\n  #{} {:?}", index + 1, value));
+    }
+
+    if let Some(old_values) = &parsed_row.old_values {
+        for (index, value) in old_values.iter().enumerate() {
+            text.push_str(&format!("\n  old #{} {:?}", index + 1, value));
+        }
+    }
+
+    text
+}
+
+/// Changes of one sync iteration, grouped by table
+#[derive(Default)]
+struct PendingWrites<'a> {
+    inserts: HashMap<&'a str, BatchWriteEntry<'a>>,
+    /// Updates of tables with a lightweight `update_mode`. Other updates are in `inserts`
+    updates: HashMap<&'a str, BatchWriteEntry<'a>>,
+    deletes: HashMap<&'a str, BatchWriteEntry<'a>>,
+    /// Keyed by `schema.table`
+    counters: HashMap<String, WriteCounter>,
+    /// Number of queued changes, so each change knows its position in commit order
+    sequence: u64,
+}
+
+impl<'a> PendingWrites<'a> {
+    /// Queues an insert, update or delete. Changes for a table that is not set up in the context
+    /// (e.g. added to the publication after startup) are skipped with a warning.
+    /// Operations that are disabled for the table are skipped.
+    fn push(
+        &mut self,
+        context: &'a PostgresPipeContext,
+        tables: &[PostgresSource],
+        schema_name: &str,
+        table_name: &'a str,
+        parsed_row: PgOutput,
+    ) {
+        let table_key = format!("{schema_name}.{table_name}");
+
+        let Some(table_info) = context.tables_map.get(&table_key) else {
+            log::warn!("Table info not found in context for {table_key}. Skipping change");
+            return;
+        };
+
+        let table = tables
+            .iter()
+            .find(|t| t.table_name == table_name && t.schema_name == schema_name);
+        let operations = table.map(|t| t.operations).unwrap_or_default();
+
+        let is_enabled = match parsed_row.message_type {
+            MessageType::Insert => operations.insert,
+            MessageType::Update => operations.update,
+            MessageType::Delete => operations.delete,
+            _ => true,
+        };
+        if !is_enabled {
+            return;
+        }
+
+        let update_mode = table.map(|t| t.update_mode).unwrap_or_default();
+
+        let mut parsed_row = parsed_row;
+        if parsed_row.message_type == MessageType::Update
+            && update_mode == UpdateMode::ChangedColumns
+        {
+            leave_out_unchanged_values(&mut parsed_row, &table_info.postgres_columns);
+        }
+
+        let row = PostgresCopyRow {
+            columns: parsed_row.payload,
+        };
+        let sequence = self.sequence;
+        self.sequence += 1;
+        let count = self.counters.entry(table_key).or_default();
+
+        match parsed_row.message_type {
+            MessageType::Insert | MessageType::Update => {
+                let mask_columns = table.map_or_else(Vec::new, |t| t.mask_columns.clone());
+                let table_options = table.map(|t| t.table_options.clone()).unwrap_or_default();
+
+                let queue = if parsed_row.message_type == MessageType::Update
+                    && update_mode.is_lightweight()
+                {
+                    &mut self.updates
+                } else {
+                    &mut self.inserts
+                };
+
+                queue
+                    .entry(table_name)
+                    .or_insert_with(|| BatchWriteEntry {
+                        table_info,
+                        mask_columns,
+                        table_options,
+                        rows: Vec::new(),
+                        sequences: Vec::new(),
+                    })
+                    .push(row, sequence);
+
+                if parsed_row.message_type == MessageType::Insert {
+                    count.insert_count += 1;
+                } else {
+                    count.update_count += 1;
+                }
+            }
+            MessageType::Delete => {
+                let table_options = table.map(|t| t.table_options.clone()).unwrap_or_default();
+
+                self.deletes
+                    .entry(table_name)
+                    .or_insert_with(|| BatchWriteEntry {
+                        table_info,
+                        mask_columns: Vec::new(),
+                        table_options,
+                        rows: Vec::new(),
+                        sequences: Vec::new(),
+                    })
+                    .push(row, sequence);
+
+                count.delete_count += 1;
+            }
+            _ => {}
+        }
+    }
+}
+
+impl PendingWrites<'_> {
+    /// Drops the queued changes of truncated tables. Changes after the TRUNCATE are queued anew.
+    fn discard_tables(&mut self, tables: &[&PostgresTableRelation]) {
+        for relation in tables {
+            let table_name = relation.table_name.as_str();
+            self.inserts.remove(table_name);
+            self.updates.remove(table_name);
+            self.deletes.remove(table_name);
+        }
+    }
+}
+
+pub struct BatchWriteEntry<'a> {
+    pub table_info: &'a PostgresPipeTableInfo,
+    pub mask_columns: Vec<String>,
+    /// Options of the table, not inherited yet. Deletes depend on the engine of each target
+    pub table_options: ClickHouseTableOptions,
+    pub rows: Vec<PostgresCopyRow>,
+    /// Position of each row in the changes of the iteration, for the sinks
+    pub sequences: Vec<u64>,
+}
+
+impl BatchWriteEntry<'_> {
+    pub fn push(&mut self, row: PostgresCopyRow, sequence: u64) {
+        self.rows.push(row);
+        self.sequences.push(sequence);
+    }
+
+    /// The last change per primary key. Unchanged (TOASTed) values are taken from an earlier
+    /// change of the same row in the batch, if there is one.
+    /// Without a primary key the rows cannot be told apart and are all kept.
+    pub fn deduplicated_rows(&self) -> Vec<PostgresCopyRow> {
+        if !self
+            .table_info
+            .postgres_columns
+            .iter()
+            .any(|column| column.is_primary_key)
+        {
+            return self.rows.clone();
+        }
+
+        let primary_key = |row: &PostgresCopyRow| {
+            extract_postgres_primary_key(row, &self.table_info.postgres_columns)
+        };
+
+        let mut latest_rows: HashMap<String, PostgresCopyRow> = HashMap::new();
+        let rows = self
+            .rows
+            .iter()
+            .map(|row| {
+                let key = primary_key(row);
+                let mut row = row.clone();
+
+                if let Some(previous) = latest_rows.get(&key) {
+                    for (value, previous_value) in row.columns.iter_mut().zip(&previous.columns) {
+                        if value.is_unchanged() {
+                            *value = previous_value.clone();
+                        }
+                    }
+                }
+
+                latest_rows.insert(key, row.clone());
+                row
+            })
+            .collect();
+
+        adapter::deduplicate_rows_keeping_last(rows, primary_key)
+    }
+
+    /// `deduplicated_rows`, with the rows that still have unchanged values separated, so that
+    /// those values can be kept from the stored row. Without a primary key they cannot be looked
+    /// up and are written as NULL.
+    pub fn deduplicated_rows_split_by_unchanged(
+        &self,
+    ) -> (Vec<PostgresCopyRow>, Vec<PostgresCopyRow>) {
+        let has_primary_key = self
+            .table_info
+            .postgres_columns
+            .iter()
+            .any(|column| column.is_primary_key);
+
+        self.deduplicated_rows().into_iter().partition(|row| {
+            !has_primary_key || !row.columns.iter().any(|value| value.is_unchanged())
+        })
+    }
+}
+
+fn extract_postgres_primary_key(row: &PostgresCopyRow, columns: &[PostgresColumn]) -> String {
+    columns
+        .iter()
+        .filter(|col| col.is_primary_key)
+        .map(|col| {
+            let index = (col.column_index - 1) as usize;
+            match row.columns.get(index) {
+                Some(value) => format!("{value:?}"),
+                None => "NULL".to_string(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("|")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        adapter::{
+            IntoClickhouse,
+            postgres::PeekWalChangeResult,
+            tests::{TestClickhouse, clickhouse_column, clickhouse_config, postgres_column},
+        },
+        config::{ClickHouseTableOptions, IdentifierCase},
+        pipes::{SyncError, SyncOutcome},
+    };
+
+    use super::{
+        PendingWrites, PostgresPipe, PostgresPipeContext, PostgresTableRelation, align_to_columns,
+        applied_rows, apply_dedup_key, backfill_source_columns, check_provisioned_replication,
+        copy_sequence, create_table_query, decode_wal_changes, defer_transactions_beyond_tables,
+        find_changed_comments, find_missing_columns, format_wal_change, reject_engine_column_names,
+        shared_replication_slot_warning, truncated_tables, validate_column_types,
+        validate_primary_keys,
+    };
+
+    /// Pipe writing to the (recording) ClickHouse on `clickhouse_port`. Nothing listens on the
+    /// Postgres port, so every query to Postgres fails.
+    fn test_pipe(clickhouse_port: u16, tables: serde_json::Value) -> PostgresPipe {
+        use crate::{
+            adapter::{clickhouse::ClickhouseConnection, postgres::PostgresConnection},
+            config::{ClickHouseConfig, Configuraion, PostgresConfig},
+            pipes::{backpressure::PeekLimitController, watchdog::Watchdog},
+        };
+
+        let config = serde_json::from_value::<Configuraion>(serde_json::json!({
+            "source": { "source_type": "postgres" },
+            "target": { "target_type": "clickhouse" }
+        }))
+        .unwrap();
+        let postgres_config = serde_json::from_value::<PostgresConfig>(serde_json::json!({
+            "connection": {
+                "host": "127.0.0.1",
+                "port": 1,
+                "username": "postgres",
+                "password": "",
+                "database": "postgres"
+            },
+            "tables": tables
+        }))
+        .unwrap();
+        let clickhouse_config = serde_json::from_value::<ClickHouseConfig>(serde_json::json!({
+            "connection": {
+                "host": "127.0.0.1",
+                "port": clickhouse_port,
+                "username": "default",
+                "password": "",
+                "database": "db"
+            }
+        }))
+        .unwrap();
+
+        PostgresPipe {
+            context: PostgresPipeContext::default(),
+            peek_limit_controller: PeekLimitController::new(
+                config.min_peek_changes_limit,
+                config.peek_changes_limit,
+                config.target_write_millis_per_iteration,
+            ),
+            config,
+            postgres_connection: PostgresConnection::lazy(&postgres_config.connection),
+            postgres_config,
+            clickhouse_connection: ClickhouseConnection::new(&clickhouse_config.connection)
+                .unwrap()
+                .without_compression(),
+            clickhouse_config,
+            fan_out_targets: vec![],
+            sinks: vec![],
+            stats: Default::default(),
+            pause_switch: Default::default(),
+            watchdog: Watchdog::new(None),
+            pending_config: Default::default(),
+            applied_lsn: Default::default(),
+            relations: Default::default(),
+        }
+    }
+
+    // Captured pgoutput INSERT into relation 16384: (1, 'foo')
+    fn insert_message() -> Vec<u8> {
+        let mut data = vec![b'I'];
+        data.extend(16384_u32.to_be_bytes());
+        data.push(b'N');
+        data.extend(2_u16.to_be_bytes());
+        for value in ["1", "foo"] {
+            data.push(b't');
+            data.extend((value.len() as u32).to_be_bytes());
+            data.extend(value.as_bytes());
+        }
+        data
+    }
+
+    fn wal_row(lsn: &str, data: Vec<u8>) -> PeekWalChangeResult {
+        PeekWalChangeResult {
+            lsn: lsn.to_string(),
+            xid: "750".to_string(),
+            data,
+        }
+    }
+
+    #[test]
+    fn decode_wal_changes_decodes_captured_sample() {
+        let rows = vec![
+            wal_row("0/16B3748", b"B".to_vec()),
+            wal_row("0/16B3750", insert_message()),
+            wal_row("0/16B3800", b"C".to_vec()),
+        ];
+
+        let changes = decode_wal_changes(&rows, None, None).unwrap();
+
+        assert_eq!(changes.len(), 1);
+
+        let (row, parsed_row) = &changes[0];
+        assert_eq!(
+            format_wal_change(row, parsed_row, Some("bean_policy")),
+            "[0/16B3750] xid=750 Insert bean_policy (relation_id=16384)\n  #1 Text(\"1\")\n  #2 Text(\"foo\")"
+        );
+    }
+
+    #[test]
+    fn format_wal_change_marks_unresolved_relation() {
+        let rows = vec![wal_row("0/16B3750", insert_message())];
+        let changes = decode_wal_changes(&rows, None, None).unwrap();
+
+        let (row, parsed_row) = &changes[0];
+        assert!(format_wal_change(row, parsed_row, None).contains("Insert <unknown>"));
+    }
+
+    #[test]
+    fn replayed_delete_is_not_issued_again() {
+        use crate::adapter::postgres::pgoutput::parse_pg_output;
+
+        let mut delete_message = insert_message();
+        delete_message[0] = b'D';
+        delete_message[5] = b'K';
+
+        // The delete was written before a crash, the insert of the next transaction was not.
+        // Its change LSN is lower, because it started before the first transaction committed.
+        let rows = vec![
+            wal_row("0/100", vec![b'B']),
+            wal_row("0/110", delete_message),
+            wal_row("0/120", vec![b'C']),
+            wal_row("0/105", vec![b'B']),
+            wal_row("0/108", insert_message()),
+            wal_row("0/130", vec![b'C']),
+        ];
+
+        let applied = applied_rows(&rows, Some(0x120));
+        assert_eq!(applied, [true, true, true, false, false, false]);
+        assert_eq!(applied_rows(&rows, None), [false; 6]);
+
+        let mut context = PostgresPipeContext::default();
+        context.set_table("public", "foo", vec![], vec![]);
+
+        let mut pending_writes = PendingWrites::default();
+        for (row, is_applied) in rows.iter().zip(applied) {
+            if !is_applied && let Some(change) = parse_pg_output(&row.data).unwrap() {
+                pending_writes.push(&context, &[], "public", "foo", change);
+            }
+        }
+
+        assert!(pending_writes.deletes.is_empty());
+        assert_eq!(pending_writes.inserts["foo"].rows.len(), 1);
+    }
+
+    #[test]
+    fn transactions_beyond_max_tables_are_synced_in_later_iterations() {
+        let insert_into = |relation_id: u32| {
+            let mut data = insert_message();
+            data[1..5].copy_from_slice(&relation_id.to_be_bytes());
+            data
+        };
+        let transaction = |commit_lsn: u32, relation_ids: &[u32]| {
+            let mut rows = vec![wal_row(&format!("0/{:X}", commit_lsn - 10), vec![b'B'])];
+            for (offset, relation_id) in relation_ids.iter().enumerate() {
+                rows.push(wal_row(
+                    &format!("0/{:X}", commit_lsn - 9 + offset as u32),
+                    insert_into(*relation_id),
+                ));
+            }
+            rows.push(wal_row(&format!("0/{commit_lsn:X}"), vec![b'C']));
+            rows
+        };
+
+        // One transaction touching three tables, then smaller ones
+        let rows = [
+            transaction(0x100, &[1, 2, 3]),
+            transaction(0x200, &[4]),
+            transaction(0x300, &[1, 1]),
+            transaction(0x400, &[5, 6]),
+        ]
+        .concat();
+
+        // The slot is advanced to the last kept row of each iteration
+        let mut slot = rows.clone();
+        let mut synced_lsns = vec![];
+        let mut iterations = vec![];
+        while !slot.is_empty() {
+            let mut peek_result = slot.clone();
+            defer_transactions_beyond_tables(&mut peek_result, 2);
+
+            iterations.push(peek_result.last().unwrap().lsn.clone());
+            synced_lsns.extend(peek_result.iter().map(|row| row.lsn.clone()));
+            slot.drain(..peek_result.len());
+        }
+
+        assert_eq!(iterations, ["0/100", "0/300", "0/400"]);
+        assert_eq!(
+            synced_lsns,
+            rows.iter().map(|row| row.lsn.clone()).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn unprovisioned_replication_is_left_to_an_admin() {
+        use crate::adapter::postgres::PublicationTable;
+
+        let tables =
+            serde_json::from_value::<Vec<crate::config::PostgresSource>>(serde_json::json!([
+                { "schema_name": "public", "table_name": "orders" },
+                { "schema_name": "public", "table_name": "users" }
+            ]))
+            .unwrap();
+        let publication_table = |table_name: &str| PublicationTable {
+            schema_name: "public".to_string(),
+            table_name: table_name.to_string(),
+            root_schema_name: "public".to_string(),
+            root_table_name: table_name.to_string(),
+        };
+        let check = |publication_tables: Option<&[PublicationTable]>, slot_exists: bool| {
+            check_provisioned_replication(
+                "clockpipe_publication",
+                publication_tables,
+                &tables,
+                false,
+                "clockpipe_replication_slot",
+                slot_exists,
+            )
+            .map_err(|error| error.to_string())
+        };
+
+        let error = check(None, true).unwrap_err();
+        assert!(error.contains("must be created by an admin"), "{error}");
+        assert!(
+            error.contains(
+                "CREATE PUBLICATION clockpipe_publication FOR TABLE public.orders, public.users"
+            ),
+            "{error}"
+        );
+
+        let error = check(Some(&[publication_table("orders")]), true).unwrap_err();
+        assert!(
+            error.contains("ALTER PUBLICATION clockpipe_publication ADD TABLE public.users"),
+            "{error}"
+        );
+
+        let all_tables = [publication_table("orders"), publication_table("users")];
+        let error = check(Some(&all_tables), false).unwrap_err();
+        assert!(error.contains("must be created by an admin"), "{error}");
+        assert!(
+            error.contains("pg_create_logical_replication_slot('clockpipe_replication_slot'"),
+            "{error}"
+        );
+
+        assert!(check(Some(&all_tables), true).is_ok());
+
+        // orders is partitioned, published without publish_via_partition_root
+        let partitioned_tables = [
+            PublicationTable {
+                root_table_name: "orders".to_string(),
+                ..publication_table("orders_2025")
+            },
+            publication_table("users"),
+        ];
+        assert!(check(Some(&partitioned_tables), true).is_ok());
+    }
+
+    #[tokio::test]
+    async fn failed_copy_truncates_the_partially_copied_table() {
+        // RowBinary `false` for the emptiness check, so the copy starts
+        let (port, mut queries) = crate::pipes::tests::recording_clickhouse_answering(&[0]).await;
+        let mut pipe = test_pipe(
+            port,
+            serde_json::json!([{ "schema_name": "public", "table_name": "users" }]),
+        );
+        pipe.context.set_table("public", "users", vec![], vec![]);
+        let tables = pipe.postgres_config.tables.clone();
+
+        // Postgres is not reachable, so the copy fails after it started
+        let result = pipe.copy_tables(&tables).await;
+        assert!(result.is_err());
+
+        // The emptiness check is sent in the URL
+        assert_eq!(queries.recv().await.unwrap(), "");
+        assert_eq!(queries.recv().await.unwrap(), "TRUNCATE TABLE db.users");
+    }
+
+    #[tokio::test]
+    async fn reload_keeps_the_configured_tables_when_the_publication_setup_fails() {
+        let (port, _queries) = crate::pipes::tests::recording_clickhouse().await;
+        let mut pipe = test_pipe(
+            port,
+            serde_json::json!([{ "schema_name": "public", "table_name": "users" }]),
+        );
+
+        let mut config = pipe.config.clone();
+        let mut postgres_config = pipe.postgres_config.clone();
+        postgres_config.tables.extend(
+            serde_json::from_value::<Vec<crate::config::PostgresSource>>(serde_json::json!([
+                { "schema_name": "public", "table_name": "orders" }
+            ]))
+            .unwrap(),
+        );
+        config.source.postgres = Some(postgres_config);
+
+        // Postgres is not reachable, so the publication cannot be set up
+        let result = pipe.reload_tables(config).await;
+        assert!(result.is_err(), "{result:?}");
+        assert_eq!(
+            pipe.postgres_config
+                .tables
+                .iter()
+                .map(|table| table.table_name.as_str())
+                .collect::<Vec<_>>(),
+            ["users"]
+        );
+    }
+
+    #[tokio::test]
+    async fn added_table_whose_copy_fails_is_dropped_until_the_next_reload() {
+        // RowBinary `false` for the emptiness check, so the copy starts
+        let (port, mut queries) = crate::pipes::tests::recording_clickhouse_answering(&[0]).await;
+        let mut pipe = test_pipe(
+            port,
+            serde_json::json!([
+                { "schema_name": "public", "table_name": "users" },
+                { "schema_name": "public", "table_name": "orders" }
+            ]),
+        );
+        // As left by the setup of the reload
+        pipe.context.set_table("public", "users", vec![], vec![]);
+        pipe.context.set_table("public", "orders", vec![], vec![]);
+        let new_tables = pipe.postgres_config.tables[1..].to_vec();
+
+        // Postgres is not reachable, so the copy fails after it started
+        pipe.copy_added_tables(&new_tables).await;
+
+        assert_eq!(queries.recv().await.unwrap(), "");
+        assert_eq!(queries.recv().await.unwrap(), "TRUNCATE TABLE db.orders");
+        assert_eq!(
+            pipe.postgres_config
+                .tables
+                .iter()
+                .map(|table| table.table_name.as_str())
+                .collect::<Vec<_>>(),
+            ["users"]
+        );
+        assert!(pipe.context.tables_map.contains_key("public.users"));
+        assert!(!pipe.context.tables_map.contains_key("public.orders"));
+    }
+
+    #[tokio::test]
+    async fn copy_skips_and_keeps_a_populated_fan_out_table() {
+        use crate::{adapter::clickhouse::ClickhouseConnection, pipes::FanOutTarget};
+
+        let fan_out_target = |port: u16| {
+            let mut config = clickhouse_config();
+            config.connection.host = "127.0.0.1".to_string();
+            config.connection.port = port;
+            FanOutTarget {
+                connection: ClickhouseConnection::new(&config.connection)
+                    .unwrap()
+                    .without_compression(),
+                config,
+            }
+        };
+
+        // RowBinary `false`/`true` for the emptiness checks
+        let (port, mut queries) = crate::pipes::tests::recording_clickhouse_answering(&[0]).await;
+        let (populated_port, mut populated_queries) =
+            crate::pipes::tests::recording_clickhouse_answering(&[1]).await;
+        let (empty_port, mut empty_queries) =
+            crate::pipes::tests::recording_clickhouse_answering(&[0]).await;
+        let mut pipe = test_pipe(
+            port,
+            serde_json::json!([{ "schema_name": "public", "table_name": "users" }]),
+        );
+        pipe.fan_out_targets = vec![fan_out_target(populated_port), fan_out_target(empty_port)];
+        pipe.context.set_table("public", "users", vec![], vec![]);
+        let tables = pipe.postgres_config.tables.clone();
+
+        // Postgres is not reachable, so the copy fails after it started
+        let result = pipe.copy_tables(&tables).await;
+        assert!(result.is_err());
+
+        // The tables this copy wrote to are truncated, the populated one only checked
+        for queries in [&mut queries, &mut empty_queries] {
+            assert_eq!(queries.recv().await.unwrap(), "");
+            assert_eq!(queries.recv().await.unwrap(), "TRUNCATE TABLE db.users");
+        }
+        assert_eq!(populated_queries.recv().await.unwrap(), "");
+        assert!(populated_queries.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn setup_fails_instead_of_syncing_with_an_empty_column_list() {
+        // The created table never shows up in system.columns
+        let (port, mut queries) = crate::pipes::tests::recording_clickhouse().await;
+        let pipe = test_pipe(
+            port,
+            serde_json::json!([{ "schema_name": "public", "table_name": "users" }]),
+        );
+        let postgres_columns = vec![postgres_column(1, "id", "int8", false, true)];
+
+        let result = pipe
+            .setup_clickhouse_table(
+                &pipe.clickhouse_config,
+                &pipe.clickhouse_connection,
+                &pipe.postgres_config.tables[0],
+                &postgres_columns,
+                "",
+            )
+            .await;
+
+        // An empty column list would make first_sync copy rows into no columns
+        assert!(
+            matches!(result, Err(crate::errors::Errors::TableNotFoundError(_))),
+            "{result:?}"
+        );
+        assert_eq!(queries.recv().await.unwrap(), "");
+        assert!(
+            queries
+                .recv()
+                .await
+                .unwrap()
+                .starts_with("CREATE TABLE db.users")
+        );
+    }
+
+    #[tokio::test]
+    async fn paused_sync_neither_peeks_nor_writes() {
+        let (port, mut queries) = crate::pipes::tests::recording_clickhouse().await;
+        let mut pipe = test_pipe(
+            port,
+            serde_json::json!([{ "schema_name": "public", "table_name": "users" }]),
+        );
+        pipe.pause_switch().pause();
+
+        // Postgres is not reachable, so a peek would fail
+        let outcome = pipe.sync_once().await;
+        assert!(matches!(outcome, Ok(SyncOutcome::Paused)), "{outcome:?}");
+        assert_eq!(pipe.applied_lsn.lsn(), None);
+        assert!(queries.try_recv().is_err());
+
+        pipe.pause_switch().resume();
+        let outcome = pipe.sync_once().await;
+        assert!(matches!(outcome, Err(SyncError::Peek(_))), "{outcome:?}");
+    }
+
+    #[test]
+    fn decode_wal_changes_filters_by_lsn_range() {
+        let rows = vec![
+            wal_row("0/100", insert_message()),
+            wal_row("0/200", insert_message()),
+            wal_row("0/300", insert_message()),
+        ];
+
+        let changes = decode_wal_changes(&rows, Some(0x200), Some(0x200)).unwrap();
+
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].0.lsn, "0/200");
+    }
+
+    #[test]
+    fn change_for_unknown_table_is_skipped() {
+        use crate::adapter::postgres::pgoutput::{MessageType, PgOutput, PgOutputValue};
+
+        let mut context = PostgresPipeContext::default();
+        context.set_table("public", "known", vec![], vec![]);
+
+        let insert = |relation_id| PgOutput {
+            message_type: MessageType::Insert,
+            relation_id,
+            tuple_type: None,
+            payload: vec![PgOutputValue::Text("1".to_string())],
+            old_values: None,
+            old_tuple_type: None,
+        };
+
+        let mut pending_writes = PendingWrites::default();
+        // e.g. a table added to the publication after startup
+        pending_writes.push(&context, &[], "public", "unknown", insert(2));
+        pending_writes.push(&context, &[], "public", "known", insert(1));
+
+        assert_eq!(pending_writes.inserts.len(), 1);
+        assert_eq!(pending_writes.inserts["known"].rows.len(), 1);
+        assert!(pending_writes.deletes.is_empty());
+        assert_eq!(
+            pending_writes.counters.keys().collect::<Vec<_>>(),
+            vec!["public.known"]
+        );
+    }
+
+    #[test]
+    fn unchanged_toast_values_are_taken_from_an_earlier_change_in_the_batch() {
+        use crate::adapter::postgres::pgoutput::{MessageType, PgOutput, PgOutputValue};
+
+        let text_column = |index: i32, name: &str, is_primary_key: bool| {
+            postgres_column(index, name, "text", !is_primary_key, is_primary_key)
+        };
+
+        let mut context = PostgresPipeContext::default();
+        context.set_table(
+            "public",
+            "documents",
+            vec![text_column(1, "id", true), text_column(2, "body", false)],
+            vec![],
+        );
+
+        let change = |message_type, id: &str, body: PgOutputValue| PgOutput {
+            message_type,
+            relation_id: 1,
+            tuple_type: None,
+            payload: vec![PgOutputValue::Text(id.to_string()), body],
+            old_values: None,
+            old_tuple_type: None,
+        };
+
+        let mut pending_writes = PendingWrites::default();
+        for parsed_row in [
+            change(
+                MessageType::Insert,
+                "1",
+                PgOutputValue::Text("long body".to_string()),
+            ),
+            change(MessageType::Update, "1", PgOutputValue::Unchanged),
+            // Inserted before this batch
+            change(MessageType::Update, "2", PgOutputValue::Unchanged),
+        ] {
+            pending_writes.push(&context, &[], "public", "documents", parsed_row);
+        }
+
+        let (rows, rows_with_unchanged) =
+            pending_writes.inserts["documents"].deduplicated_rows_split_by_unchanged();
+
+        assert_eq!(rows.len(), 1);
+        assert!(matches!(&rows[0].columns[1], PgOutputValue::Text(body) if body == "long body"));
+        assert_eq!(rows_with_unchanged.len(), 1);
+        assert!(matches!(
+            rows_with_unchanged[0].columns[1],
+            PgOutputValue::Unchanged
+        ));
+    }
+
+    #[test]
+    fn tables_are_copied_in_copy_order_then_in_config_order() {
+        let tables: Vec<crate::config::PostgresSource> = serde_json::from_str(
+            r#"[
+                { "schema_name": "public", "table_name": "audit_log" },
+                { "schema_name": "public", "table_name": "order_items", "copy_order": 3 },
+                { "schema_name": "public", "table_name": "settings" },
+                { "schema_name": "public", "table_name": "customers", "copy_order": 1 },
+                { "schema_name": "public", "table_name": "orders", "copy_order": 2 }
+            ]"#,
+        )
+        .unwrap();
+
+        let order = copy_sequence(&tables)
+            .into_iter()
+            .map(|table| table.table_name.as_str())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            order,
+            [
+                "customers",
+                "orders",
+                "order_items",
+                "audit_log",
+                "settings"
+            ]
+        );
+    }
+
+    #[test]
+    fn replication_slot_warning_only_for_shared_or_busy_slots() {
+        use crate::{adapter::postgres::ReplicationSlot, config::PostgresConfig};
+
+        let postgres_config = |names: serde_json::Value| {
+            let mut config = serde_json::json!({
+                "connection": {
+                    "host": "127.0.0.1",
+                    "port": 5432,
+                    "username": "postgres",
+                    "password": "",
+                    "database": "postgres"
+                },
+                "tables": []
+            });
+            config
+                .as_object_mut()
+                .unwrap()
+                .extend(names.as_object().unwrap().clone());
+            serde_json::from_value::<PostgresConfig>(config).unwrap()
+        };
+        let slot = |slot_name: &str, active: bool| ReplicationSlot {
+            slot_name: slot_name.to_string(),
+            active,
+            ..Default::default()
+        };
+
+        let default_names = postgres_config(serde_json::json!({}));
+        let analytics = postgres_config(serde_json::json!({ "instance_id": "analytics" }));
+        let custom_slot = postgres_config(serde_json::json!({ "replication_slot_name": "cdc" }));
+
+        let slots = [
+            slot(&default_names.replication_slot_name(), false),
+            slot("clockpipe_billing_replication_slot", true),
+            slot("debezium", true),
+        ];
+
+        // Another instance with its own instance_id is not a conflict
+        assert_eq!(shared_replication_slot_warning(&analytics, &slots), None);
+        assert_eq!(shared_replication_slot_warning(&custom_slot, &slots), None);
+        assert!(shared_replication_slot_warning(&default_names, &slots).is_some());
+        assert_eq!(
+            shared_replication_slot_warning(&default_names, &slots[..1]),
+            None
+        );
+
+        // The slot of this instance is consumed by someone else
+        let busy_slot = [slot("cdc", true)];
+        assert!(shared_replication_slot_warning(&custom_slot, &busy_slot).is_some());
+        assert_eq!(
+            shared_replication_slot_warning(&custom_slot, &[slot("cdc", false)]),
+            None
+        );
+    }
+
+    #[test]
+    fn truncate_of_several_relations_truncates_each_table_once() {
+        let mut context = PostgresPipeContext::default();
+        let relation = |table_name: &str, is_partition: bool| PostgresTableRelation {
+            schema_name: "public".to_string(),
+            table_name: table_name.to_string(),
+            is_partition,
+        };
+        context
+            .table_relation_map
+            .insert(1, relation("accounts", false));
+        context
+            .table_relation_map
+            .insert(2, relation("events", false));
+        context
+            .table_relation_map
+            .insert(4, relation("audit_log", false));
+        // Partitions of the partitioned table events
+        context
+            .table_relation_map
+            .insert(3, relation("events", true));
+        context
+            .table_relation_map
+            .insert(5, relation("events", true));
+
+        let truncated = |relation_ids: &[u32]| {
+            truncated_tables(&context, relation_ids)
+                .into_iter()
+                .map(|relation| relation.table_name.as_str())
+                .collect::<Vec<_>>()
+        };
+
+        // TRUNCATE events, accounts, audit_log: the partitions come along with their parent
+        assert_eq!(
+            truncated(&[3, 2, 5, 1, 4, 4, 99]),
+            ["events", "accounts", "audit_log"]
+        );
+        // TRUNCATE events_2024: the other partitions keep their rows
+        assert!(truncated(&[3]).is_empty());
+    }
+
+    #[test]
+    fn truncate_discards_the_changes_queued_before_it() {
+        use crate::adapter::postgres::pgoutput::{MessageType, PgOutput, PgOutputValue};
+
+        let mut context = PostgresPipeContext::default();
+        for (relation_id, table_name) in [(1, "accounts"), (2, "orders")] {
+            context.set_table(
+                "public",
+                table_name,
+                vec![postgres_column(1, "id", "int4", false, true)],
+                vec![clickhouse_column(1, "id", "Int32", true)],
+            );
+            context.table_relation_map.insert(
+                relation_id,
+                PostgresTableRelation {
+                    schema_name: "public".to_string(),
+                    table_name: table_name.to_string(),
+                    is_partition: false,
+                },
+            );
+        }
+
+        let change = |message_type, id: &str| PgOutput {
+            message_type,
+            relation_id: 1,
+            tuple_type: None,
+            payload: vec![PgOutputValue::Text(id.to_string())],
+            old_values: None,
+            old_tuple_type: None,
+        };
+
+        // INSERT INTO accounts ...; DELETE FROM accounts ...; INSERT INTO orders ...;
+        // TRUNCATE accounts; INSERT INTO accounts ...
+        let mut pending_writes = PendingWrites::default();
+        pending_writes.push(
+            &context,
+            &[],
+            "public",
+            "accounts",
+            change(MessageType::Insert, "1"),
+        );
+        pending_writes.push(
+            &context,
+            &[],
+            "public",
+            "accounts",
+            change(MessageType::Delete, "2"),
+        );
+        pending_writes.push(
+            &context,
+            &[],
+            "public",
+            "orders",
+            change(MessageType::Insert, "1"),
+        );
+
+        pending_writes.discard_tables(&truncated_tables(&context, &[1]));
+        pending_writes.push(
+            &context,
+            &[],
+            "public",
+            "accounts",
+            change(MessageType::Insert, "3"),
+        );
+
+        let accounts = &pending_writes.inserts["accounts"].rows;
+        assert_eq!(accounts.len(), 1);
+        assert!(matches!(&accounts[0].columns[0], PgOutputValue::Text(id) if id == "3"));
+        assert!(pending_writes.deletes.is_empty());
+        assert_eq!(pending_writes.inserts["orders"].rows.len(), 1);
+    }
+
+    #[test]
+    fn tuple_values_are_matched_to_columns_by_relation_column_name() {
+        use crate::adapter::postgres::pgoutput::{PgOutputValue, PgRelation, PgRelationColumn};
+
+        let postgres_columns = vec![
+            postgres_column(1, "id", "text", false, true),
+            postgres_column(2, "title", "text", true, false),
+            postgres_column(3, "body", "text", true, false),
+            // Dropped after startup
+            postgres_column(4, "legacy", "text", true, false),
+        ];
+
+        // A partition with another column order, and a column added after startup
+        let relation_column = |name: &str| PgRelationColumn {
+            name: name.to_string(),
+            type_oid: 25,
+            is_key: name == "id",
+        };
+        let relation = PgRelation {
+            relation_id: 7,
+            schema_name: "public".to_string(),
+            table_name: "documents_2025".to_string(),
+            replica_identity: b'd',
+            columns: ["body", "id", "added", "title"]
+                .into_iter()
+                .map(relation_column)
+                .collect(),
+        };
+        let values = ["long body", "1", "new", "hello"]
+            .into_iter()
+            .map(|value| PgOutputValue::Text(value.to_string()))
+            .collect();
+
+        let aligned = align_to_columns(&relation, &postgres_columns, values);
+
+        let texts = aligned
+            .iter()
+            .map(|value| value.text_ref_or("NULL"))
+            .collect::<Vec<_>>();
+        assert_eq!(texts, ["1", "hello", "long body", "NULL"]);
+    }
+
+    #[test]
+    fn lightweight_update_mode_queues_updates_separately() {
+        use crate::adapter::postgres::pgoutput::{MessageType, PgOutput, PgOutputValue};
+
+        let mut context = PostgresPipeContext::default();
+        context.set_table("public", "accounts", vec![], vec![]);
+
+        let tables: Vec<crate::config::PostgresSource> = serde_json::from_str(
+            r#"[{
+                "schema_name": "public",
+                "table_name": "accounts",
+                "update_mode": "lightweight_update"
+            }]"#,
+        )
+        .unwrap();
+
+        let change = |message_type| PgOutput {
+            message_type,
+            relation_id: 1,
+            tuple_type: None,
+            payload: vec![PgOutputValue::Text("1".to_string())],
+            old_values: None,
+            old_tuple_type: None,
+        };
+
+        let mut pending_writes = PendingWrites::default();
+        for message_type in [
+            MessageType::Insert,
+            MessageType::Update,
+            MessageType::Update,
+        ] {
+            pending_writes.push(
+                &context,
+                &tables,
+                "public",
+                "accounts",
+                change(message_type),
+            );
+        }
+
+        assert_eq!(pending_writes.inserts["accounts"].rows.len(), 1);
+        assert_eq!(pending_writes.updates["accounts"].rows.len(), 2);
+        assert_eq!(pending_writes.counters["public.accounts"].update_count, 2);
+    }
+
+    #[test]
+    fn changed_columns_mode_sets_only_the_changed_column() {
+        use crate::adapter::postgres::pgoutput::{
+            MessageType, PgOutput, PgOutputValue, PgTupleType,
+        };
+
+        let mut context = PostgresPipeContext::default();
+        context.set_table(
+            "public",
+            "customers",
+            vec![
+                postgres_column(1, "id", "text", false, true),
+                postgres_column(2, "name", "text", true, false),
+                postgres_column(3, "email", "text", true, false),
+                postgres_column(4, "note", "text", true, false),
+            ],
+            vec![
+                clickhouse_column(1, "id", "String", true),
+                clickhouse_column(2, "name", "String", false),
+                clickhouse_column(3, "email", "String", false),
+                clickhouse_column(4, "note", "String", false),
+            ],
+        );
+
+        let tables: Vec<crate::config::PostgresSource> = serde_json::from_str(
+            r#"[{
+                "schema_name": "public",
+                "table_name": "customers",
+                "update_mode": "changed_columns"
+            }]"#,
+        )
+        .unwrap();
+
+        let values = |values: [Option<&str>; 4]| {
+            values
+                .into_iter()
+                .map(|value| value.map_or(PgOutputValue::Null, |v| PgOutputValue::Text(v.into())))
+                .collect::<Vec<_>>()
+        };
+
+        // ALTER TABLE customers REPLICA IDENTITY FULL;
+        // UPDATE customers SET email = 'new@example.com' WHERE id = '1'
+        let update = PgOutput {
+            message_type: MessageType::Update,
+            relation_id: 1,
+            tuple_type: Some(PgTupleType::New),
+            payload: values([Some("1"), Some("kim"), Some("new@example.com"), None]),
+            old_values: Some(values([
+                Some("1"),
+                Some("kim"),
+                Some("old@example.com"),
+                None,
+            ])),
+            old_tuple_type: Some(PgTupleType::Old),
+        };
+
+        let mut pending_writes = PendingWrites::default();
+        pending_writes.push(&context, &tables, "public", "customers", update);
+
+        let batch = &pending_writes.updates["customers"];
+
+        let update_query = TestClickhouse.generate_update_query(
+            &clickhouse_config(),
+            &batch.table_info.clickhouse_columns,
+            &batch.table_info.postgres_columns,
+            &[],
+            "customers",
+            &batch.rows[0],
+        );
+        assert_eq!(
+            update_query,
+            "UPDATE db.customers SET `email` = 'new@example.com' WHERE (`id` = '1')"
+        );
+    }
+
+    #[test]
+    fn inserts_only_table_ignores_updates_and_deletes() {
+        use crate::adapter::postgres::pgoutput::{MessageType, PgOutput, PgOutputValue};
+
+        let mut context = PostgresPipeContext::default();
+        context.set_table("public", "events", vec![], vec![]);
+
+        let tables: Vec<crate::config::PostgresSource> = serde_json::from_str(
+            r#"[{
+                "schema_name": "public",
+                "table_name": "events",
+                "operations": { "update": false, "delete": false }
+            }]"#,
+        )
+        .unwrap();
+
+        let change = |message_type| PgOutput {
+            message_type,
+            relation_id: 1,
+            tuple_type: None,
+            payload: vec![PgOutputValue::Text("1".to_string())],
+            old_values: None,
+            old_tuple_type: None,
+        };
+
+        let mut pending_writes = PendingWrites::default();
+        pending_writes.push(
+            &context,
+            &tables,
+            "public",
+            "events",
+            change(MessageType::Insert),
+        );
+        pending_writes.push(
+            &context,
+            &tables,
+            "public",
+            "events",
+            change(MessageType::Update),
+        );
+        pending_writes.push(
+            &context,
+            &tables,
+            "public",
+            "events",
+            change(MessageType::Delete),
+        );
+
+        assert_eq!(pending_writes.inserts["events"].rows.len(), 1);
+        assert!(pending_writes.deletes.is_empty());
+
+        let counter = &pending_writes.counters["public.events"];
+        assert_eq!(
+            (
+                counter.insert_count,
+                counter.update_count,
+                counter.delete_count
+            ),
+            (1, 0, 0)
+        );
+    }
+
+    #[test]
+    fn fan_out_writes_use_the_columns_of_their_target() {
+        let mut context = PostgresPipeContext::default();
+        context.set_table(
+            "public",
+            "users",
+            vec![
+                postgres_column(1, "id", "int4", false, true),
+                postgres_column(2, "NickName", "text", true, false),
+            ],
+            vec![
+                clickhouse_column(1, "id", "Int32", true),
+                clickhouse_column(2, "nickname", "Nullable(String)", false),
+            ],
+        );
+
+        let main_config = clickhouse_config();
+        // A replica that keeps the source names
+        let mut replica_config = clickhouse_config();
+        replica_config.connection.host = "replica".to_string();
+        replica_config.identifier_case = IdentifierCase::Preserve;
+        context.set_fan_out_columns(
+            "public",
+            "users",
+            std::collections::HashMap::from([(
+                crate::pipes::target_name(&replica_config),
+                vec![
+                    clickhouse_column(1, "id", "Int32", true),
+                    clickhouse_column(2, "NickName", "Nullable(String)", false),
+                ],
+            )]),
+        );
+
+        let table_info = &context.tables_map["public.users"];
+        let column_names = |clickhouse_config| {
+            table_info
+                .clickhouse_columns_for(clickhouse_config)
+                .iter()
+                .map(|column| column.column_name.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(column_names(&main_config), vec!["id", "nickname"]);
+        assert_eq!(column_names(&replica_config), vec!["id", "NickName"]);
+    }
+
+    #[test]
+    fn deletes_of_a_table_are_batched_into_one_query() {
+        use crate::adapter::postgres::pgoutput::{MessageType, PgOutput, PgOutputValue};
+
+        let mut context = PostgresPipeContext::default();
+        context.set_table(
+            "public",
+            "accounts",
+            vec![postgres_column(1, "id", "int4", false, true)],
+            vec![clickhouse_column(1, "id", "Int32", true)],
+        );
+
+        let delete = |id: &str| PgOutput {
+            message_type: MessageType::Delete,
+            relation_id: 1,
+            tuple_type: None,
+            payload: vec![PgOutputValue::Text(id.to_string())],
+            old_values: None,
+            old_tuple_type: None,
+        };
+
+        let mut pending_writes = PendingWrites::default();
+        for id in ["1", "2", "3"] {
+            pending_writes.push(&context, &[], "public", "accounts", delete(id));
+        }
+
+        assert_eq!(pending_writes.deletes.len(), 1);
+        assert_eq!(pending_writes.counters["public.accounts"].delete_count, 3);
+
+        let batch = &pending_writes.deletes["accounts"];
+
+        let delete_query = TestClickhouse.generate_delete_query(
+            &clickhouse_config(),
+            &batch.table_info.clickhouse_columns,
+            &batch.table_info.postgres_columns,
+            "accounts",
+            &batch.rows,
+        );
+        assert_eq!(delete_query.matches("DELETE").count(), 1, "{delete_query}");
+        assert_eq!(delete_query.matches(" OR ").count(), 2, "{delete_query}");
+    }
+
+    #[test]
+    fn table_without_primary_key_keeps_every_row_and_skips_deletes() {
+        use crate::adapter::postgres::pgoutput::{MessageType, PgOutput, PgOutputValue};
+
+        // CREATE TABLE page_views (path text NOT NULL, viewed_at timestamptz)
+        let mut context = PostgresPipeContext::default();
+        context.set_table(
+            "public",
+            "page_views",
+            vec![
+                postgres_column(1, "path", "text", false, false),
+                postgres_column(2, "viewed_at", "timestamptz", true, false),
+            ],
+            vec![
+                clickhouse_column(1, "path", "String", false),
+                clickhouse_column(2, "viewed_at", "Nullable(DateTime64(6))", false),
+            ],
+        );
+
+        let change = |message_type, path: &str| PgOutput {
+            message_type,
+            relation_id: 1,
+            tuple_type: None,
+            payload: vec![PgOutputValue::Text(path.to_string()), PgOutputValue::Null],
+            old_values: None,
+            old_tuple_type: None,
+        };
+
+        let mut pending_writes = PendingWrites::default();
+        for parsed_row in [
+            change(MessageType::Insert, "/"),
+            change(MessageType::Insert, "/about"),
+            change(MessageType::Insert, "/"),
+            change(MessageType::Delete, "/about"),
+        ] {
+            pending_writes.push(&context, &[], "public", "page_views", parsed_row);
+        }
+
+        let (rows, rows_with_unchanged) =
+            pending_writes.inserts["page_views"].deduplicated_rows_split_by_unchanged();
+        assert_eq!(rows.len(), 3);
+        assert!(rows_with_unchanged.is_empty());
+
+        let batch = &pending_writes.inserts["page_views"];
+        let insert_query = TestClickhouse.generate_insert_query(
+            &clickhouse_config(),
+            &batch.table_info.clickhouse_columns,
+            &batch.table_info.postgres_columns,
+            &[],
+            "page_views",
+            &rows,
+        );
+        assert_eq!(insert_query.matches("'/'").count(), 2, "{insert_query}");
+
+        // Not `DELETE WHERE ()`, which ClickHouse rejects on every retry
+        let batch = &pending_writes.deletes["page_views"];
+        let delete_query = TestClickhouse.generate_delete_query(
+            &clickhouse_config(),
+            &batch.table_info.clickhouse_columns,
+            &batch.table_info.postgres_columns,
+            "page_views",
+            &batch.rows,
+        );
+        assert_eq!(delete_query, "");
+    }
+
+    #[test]
+    fn find_missing_columns_reports_columns_absent_in_clickhouse() {
+        let postgres_columns = vec![
+            postgres_column(0, "id", "int4", false, false),
+            postgres_column(0, "bean_count", "int4", false, false),
+        ];
+
+        assert_eq!(
+            find_missing_columns(
+                &postgres_columns,
+                &[clickhouse_column(0, "id", "Int32", false)],
+                IdentifierCase::Preserve
+            ),
+            vec!["bean_count".to_string()]
+        );
+        assert_eq!(
+            find_missing_columns(&postgres_columns, &[], IdentifierCase::Preserve),
+            vec!["id".to_string(), "bean_count".to_string()]
+        );
+    }
+
+    #[test]
+    fn changed_source_comment_is_applied_to_the_clickhouse_column() {
+        let commented_postgres_column =
+            |name: &str, comment: &str| crate::adapter::postgres::PostgresColumn {
+                comment: comment.to_string(),
+                ..postgres_column(0, name, "int4", false, false)
+            };
+        let commented_clickhouse_column =
+            |name: &str, comment: &str| crate::adapter::clickhouse::ClickhouseColumn {
+                comment: comment.to_string(),
+                ..clickhouse_column(0, name, "Int32", false)
+            };
+
+        let postgres_columns = vec![
+            commented_postgres_column("id", "user id"),
+            commented_postgres_column("BeanCount", r"number of 'beans' \ day"),
+        ];
+        let clickhouse_columns = vec![
+            commented_clickhouse_column("id", "user id"),
+            commented_clickhouse_column("beancount", "number of beans"),
+        ];
+
+        let changed = find_changed_comments(
+            &postgres_columns,
+            &clickhouse_columns,
+            IdentifierCase::Lowercase,
+        );
+
+        assert_eq!(changed.len(), 1);
+        let (changed_column, source_column) = changed[0];
+        assert_eq!(
+            TestClickhouse.generate_comment_column_query(
+                &clickhouse_config(),
+                "bean_policy",
+                &changed_column.column_name,
+                source_column,
+            ),
+            r"ALTER TABLE db.bean_policy COMMENT COLUMN `beancount` 'number of ''beans'' \\ day';"
+        );
+
+        // Created with the same comment, so nothing changed
+        let clickhouse_columns = vec![
+            commented_clickhouse_column("id", "user id"),
+            commented_clickhouse_column("beancount", r"number of 'beans' \ day"),
+        ];
+        assert!(
+            find_changed_comments(
+                &postgres_columns,
+                &clickhouse_columns,
+                IdentifierCase::Lowercase
+            )
+            .is_empty()
+        );
+    }
+
+    #[test]
+    fn validate_column_types_detects_int_column_for_text_source() {
+        let postgres_columns = vec![
+            postgres_column(0, "code", "text", true, false),
+            postgres_column(0, "count", "int4", true, false),
+            postgres_column(0, "secret", "text", true, false),
+        ];
+        let clickhouse_columns = vec![
+            clickhouse_column(0, "code", "Int32", false),
+            // Wider than the source type
+            clickhouse_column(0, "count", "Nullable(Int64)", false),
+            clickhouse_column(0, "secret", "Int32", false),
+        ];
+
+        let result = validate_column_types(
+            "db.t",
+            &postgres_columns,
+            &clickhouse_columns,
+            &["secret".to_string()],
+            IdentifierCase::Preserve,
+        );
+
+        match result {
+            Err(crate::errors::Errors::ColumnTypeMismatch(message)) => {
+                assert!(
+                    message.contains(
+                        "column code is Int32 in ClickHouse, but text (Nullable(String))"
+                    ),
+                    "{message}"
+                );
+                assert!(
+                    message.contains("MODIFY COLUMN `code` Nullable(String)"),
+                    "{message}"
+                );
+                assert!(!message.contains("count"), "{message}");
+                assert!(!message.contains("secret"), "{message}");
+            }
+            other => panic!("expected a column type mismatch, got {other:?}"),
+        }
+
+        let result = validate_column_types(
+            "db.t",
+            &postgres_columns[1..2],
+            &clickhouse_columns,
+            &[],
+            IdentifierCase::Preserve,
+        );
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn validate_primary_keys_rejects_clickhouse_key_subset() {
+        let int_column = |name: &str, is_primary_key: bool| {
+            postgres_column(0, name, "int4", false, is_primary_key)
+        };
+        let key_column = |name: &str, is_in_primary_key: bool| {
+            clickhouse_column(0, name, "Int32", is_in_primary_key)
+        };
+
+        let postgres_columns = vec![
+            int_column("a", true),
+            int_column("b", true),
+            int_column("c", false),
+        ];
+
+        let result = validate_primary_keys(
+            "db.t",
+            &postgres_columns,
+            &[
+                key_column("a", true),
+                key_column("b", false),
+                key_column("c", false),
+            ],
+            IdentifierCase::Preserve,
+        );
+        assert!(
+            matches!(result, Err(crate::errors::Errors::PrimaryKeyMismatch(ref message)) if message.contains(r#"["a"]"#) && message.contains(r#"["a", "b"]"#)),
+            "{result:?}"
+        );
+
+        // Same set in a different order is fine
+        let result = validate_primary_keys(
+            "db.t",
+            &postgres_columns,
+            &[
+                key_column("b", true),
+                key_column("a", true),
+                key_column("c", false),
+            ],
+            IdentifierCase::Preserve,
+        );
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn dedup_key_becomes_the_key_of_a_table_without_primary_key() {
+        let table = |dedup_key: &[&str]| {
+            serde_json::from_value::<crate::config::PostgresSource>(serde_json::json!({
+                "schema_name": "public",
+                "table_name": "orders",
+                "dedup_key": dedup_key,
+            }))
+            .unwrap()
+        };
+
+        let mut postgres_columns = vec![
+            postgres_column(0, "order_no", "int8", false, false),
+            postgres_column(0, "amount", "int8", true, false),
+        ];
+        apply_dedup_key(&table(&["order_no"]), &mut postgres_columns).unwrap();
+
+        // The key columns end up in ORDER BY and in the delete conditions
+        assert_eq!(
+            postgres_columns
+                .iter()
+                .map(|column| column.is_primary_key)
+                .collect::<Vec<_>>(),
+            vec![true, false]
+        );
+
+        for dedup_key in [&["missing"][..], &["amount"][..]] {
+            let mut postgres_columns = vec![
+                postgres_column(0, "order_no", "int8", false, false),
+                postgres_column(0, "amount", "int8", true, false),
+            ];
+            let result = apply_dedup_key(&table(dedup_key), &mut postgres_columns);
+            assert!(
+                matches!(result, Err(crate::errors::Errors::ConfigValidationError(_))),
+                "{result:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn source_columns_named_like_engine_columns_are_rejected() {
+        let table = serde_json::from_value::<crate::config::PostgresSource>(serde_json::json!({
+            "schema_name": "public",
+            "table_name": "orders",
+        }))
+        .unwrap();
+
+        let columns = |name: &str| {
+            vec![
+                postgres_column(1, "id", "int8", false, true),
+                postgres_column(2, name, "int8", true, false),
+            ]
+        };
+
+        let default_names = ClickHouseTableOptions::default();
+        assert!(reject_engine_column_names(&table, &columns("version"), &default_names).is_ok());
+        for name in [
+            "_clockpipe_version",
+            "_clockpipe_is_deleted",
+            "_CLOCKPIPE_VERSION",
+        ] {
+            let result = reject_engine_column_names(&table, &columns(name), &default_names);
+            assert!(
+                matches!(result, Err(crate::errors::Errors::ConfigValidationError(_))),
+                "{result:?}"
+            );
+        }
+
+        // Configured names replace the default ones
+        let custom_names = ClickHouseTableOptions {
+            version_column: Some("row_version".to_string()),
+            is_deleted_column: Some("is_deleted".to_string()),
+            ..Default::default()
+        };
+        assert!(
+            reject_engine_column_names(&table, &columns("_clockpipe_version"), &custom_names)
+                .is_ok()
+        );
+        for name in ["row_version", "IS_DELETED"] {
+            let result = reject_engine_column_names(&table, &columns(name), &custom_names);
+            assert!(
+                matches!(result, Err(crate::errors::Errors::ConfigValidationError(_))),
+                "{result:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn backfill_reads_only_the_primary_key_and_the_backfilled_column() {
+        let int_column = |index: i32, name: &str, is_primary_key: bool| {
+            postgres_column(index, name, "int4", !is_primary_key, is_primary_key)
+        };
+        let table = serde_json::from_value::<crate::config::PostgresSource>(serde_json::json!({
+            "schema_name": "public",
+            "table_name": "users",
+            "mask_columns": ["password", "Token"],
+        }))
+        .unwrap();
+
+        // CREATE TABLE users (name text, id int4 PRIMARY KEY, password text, nickname text, token text)
+        let postgres_columns = vec![
+            int_column(1, "name", false),
+            int_column(2, "id", true),
+            int_column(3, "password", false),
+            int_column(4, "nickname", false),
+            int_column(5, "token", false),
+        ];
+
+        let source_columns = backfill_source_columns(
+            &table,
+            &postgres_columns,
+            "nickname",
+            IdentifierCase::Preserve,
+        )
+        .unwrap();
+        assert_eq!(
+            source_columns
+                .iter()
+                .map(|column| (column.column_index, column.column_name.as_str()))
+                .collect::<Vec<_>>(),
+            vec![(1, "id"), (2, "nickname")]
+        );
+
+        for column_name in ["missing", "id", "password"] {
+            let result = backfill_source_columns(
+                &table,
+                &postgres_columns,
+                column_name,
+                IdentifierCase::Preserve,
+            );
+            assert!(
+                matches!(result, Err(crate::errors::Errors::ConfigValidationError(_))),
+                "{column_name}: {result:?}"
+            );
+        }
+
+        // "Token" masks the ClickHouse column token, as it does on insert
+        assert!(
+            backfill_source_columns(&table, &postgres_columns, "token", IdentifierCase::Preserve)
+                .is_ok()
+        );
+        assert!(
+            backfill_source_columns(
+                &table,
+                &postgres_columns,
+                "token",
+                IdentifierCase::Lowercase
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn create_table_query_applies_table_options_over_global_ones() {
+        let clickhouse_config = crate::config::ClickHouseConfig {
+            table_options: serde_json::from_value(
+                serde_json::json!({ "storage_policy": "s3", "granularity": 8192 }),
+            )
+            .unwrap(),
+            ..clickhouse_config()
+        };
+        let table = serde_json::from_value::<crate::config::PostgresSource>(serde_json::json!({
+            "schema_name": "public",
+            "table_name": "users",
+            "table_options": { "granularity": 1024 }
+        }))
+        .unwrap();
+        let postgres_columns = vec![postgres_column(1, "id", "int8", false, true)];
+
+        let query = create_table_query(
+            &TestClickhouse,
+            &clickhouse_config,
+            &table,
+            &postgres_columns,
+            "users table",
+        );
+
+        assert!(
+            query.starts_with("CREATE TABLE db.users(`id` Int64"),
+            "{query}"
+        );
+        assert!(query.contains("ORDER BY (`id`)"), "{query}");
+        assert!(query.contains("index_granularity = 1024"), "{query}");
+        assert!(query.contains("storage_policy = 's3'"), "{query}");
+        assert!(query.ends_with("COMMENT 'users table'\n;"), "{query}");
+    }
 }
@@ -0,0 +1,89 @@
+use std::sync::{Arc, Mutex};
+
+use crate::{command::run::ConfigOptions, config::Configuraion};
+
+/// Configuration read again from the config file, waiting to be applied by the pipe
+/// between two sync iterations.
+#[derive(Debug, Clone, Default)]
+pub struct PendingConfig(Arc<Mutex<Option<Configuraion>>>);
+
+impl PendingConfig {
+    pub fn submit(&self, config: Configuraion) {
+        if let Ok(mut pending) = self.0.lock() {
+            *pending = Some(config);
+        }
+    }
+
+    pub fn take(&self) -> Option<Configuraion> {
+        self.0.lock().ok().and_then(|mut pending| pending.take())
+    }
+}
+
+/// Reads the config file again on SIGHUP.
+#[cfg(unix)]
+pub fn listen_for_reload_signal(pending_config: PendingConfig, config_options: ConfigOptions) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let Ok(mut reload_signal) = signal(SignalKind::hangup()) else {
+        log::warn!("Failed to register SIGHUP handler. Config reload is disabled");
+        return;
+    };
+
+    tokio::spawn(async move {
+        while reload_signal.recv().await.is_some() {
+            match config_options.read_config_from_file() {
+                Ok(config) => {
+                    log::info!("Configuration reloaded. Applying it after the current iteration");
+                    pending_config.submit(config);
+                }
+                Err(error) => {
+                    log::error!("Failed to reload configuration, keeping the current one: {error}");
+                }
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn listen_for_reload_signal(_: PendingConfig, _: ConfigOptions) {}
+
+/// Entries of `reloaded` whose key is not in `current`, in config order.
+pub fn added_entries<T: Clone>(
+    current: &[T],
+    reloaded: &[T],
+    key: impl Fn(&T) -> String,
+) -> Vec<T> {
+    reloaded
+        .iter()
+        .filter(|entry| !current.iter().any(|existing| key(existing) == key(entry)))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::added_entries;
+
+    #[test]
+    fn added_entries_only_reports_new_keys() {
+        let current = vec!["public.users", "public.orders"];
+        let reloaded = vec![
+            "public.orders",
+            "public.payments",
+            "public.users",
+            "audit.logs",
+        ];
+        let key = |table: &&str| table.to_string();
+
+        assert_eq!(
+            added_entries(&current, &reloaded, key),
+            vec!["public.payments", "audit.logs"]
+        );
+
+        // Removed tables are the additions of the reverse comparison
+        assert_eq!(
+            added_entries(&reloaded[1..], &current, key),
+            vec!["public.orders"]
+        );
+    }
+}
@@ -0,0 +1,141 @@
+use crate::{
+    adapter::quote_string,
+    config::{ClickHouseConfig, Configuraion},
+    errors::Errors,
+    pipes::FanOutTarget,
+};
+
+/// Written and read back by the self-test. Quotes and backslashes check the value escaping.
+const CANARY_TEXT: &str = "clockpipe canary 'quoted' \\ backslash";
+
+/// Queries of one self-test run against `table_name`, in order.
+struct CanaryQueries {
+    create: String,
+    insert: String,
+    select: String,
+    drop: String,
+}
+
+impl CanaryQueries {
+    fn new(clickhouse_config: &ClickHouseConfig, table_name: &str) -> Self {
+        let table = format!("{}.{table_name}", clickhouse_config.connection.database);
+
+        Self {
+            create: format!(
+                "CREATE TABLE {table} (`id` UInt64, `text` String, `created_at` DateTime64(3)) ENGINE = MergeTree ORDER BY id"
+            ),
+            insert: format!(
+                "INSERT INTO {table} (`id`, `text`, `created_at`) VALUES (1, {}, now64(3))",
+                quote_string(CANARY_TEXT)
+            ),
+            select: format!("SELECT `text` FROM {table} WHERE `id` = 1"),
+            drop: format!("DROP TABLE IF EXISTS {table}"),
+        }
+    }
+}
+
+/// Creates a canary table, inserts a row with the same `execute_query` path as the pipes,
+/// reads it back and drops the table. The table is dropped even if a step fails.
+pub async fn round_trip_canary_row(target: &FanOutTarget) -> Result<(), Errors> {
+    let table_name = format!(
+        "clockpipe_self_test_{}_{}",
+        std::process::id(),
+        chrono::Utc::now().timestamp_millis()
+    );
+    let queries = CanaryQueries::new(&target.config, &table_name);
+
+    target.connection.execute_query(&queries.create).await?;
+
+    let result = async {
+        target.connection.execute_query(&queries.insert).await?;
+
+        let rows = target.connection.fetch_strings(&queries.select).await?;
+        if rows != [CANARY_TEXT] {
+            return Err(Errors::DatabaseQueryError(format!(
+                "Canary row was not read back as written: {rows:?}"
+            )));
+        }
+
+        Ok(())
+    }
+    .await;
+
+    let drop_result = target.connection.execute_query(&queries.drop).await;
+
+    result.and(drop_result)
+}
+
+/// Runs the self-test against the main ClickHouse target and every fan-out target.
+/// Returns an error if any target failed.
+pub async fn run_self_test(config: Configuraion) -> Result<(), Errors> {
+    let clickhouse_config = config
+        .target
+        .clickhouse
+        .clone()
+        .ok_or_else(|| Errors::ConfigValidationError("target.clickhouse is required".into()))?;
+
+    let targets = std::iter::once(clickhouse_config)
+        .chain(config.target.additional_clickhouse.iter().cloned())
+        .map(FanOutTarget::new)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut failed_targets = vec![];
+
+    for target in &targets {
+        match round_trip_canary_row(target).await {
+            Ok(()) => println!("OK     {}", target.name()),
+            Err(error) => {
+                println!("FAILED {}: {error}", target.name());
+                failed_targets.push(target.name());
+            }
+        }
+    }
+
+    if !failed_targets.is_empty() {
+        return Err(Errors::DatabaseQueryError(format!(
+            "Self-test failed for {}",
+            failed_targets.join(", ")
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CanaryQueries;
+
+    #[test]
+    fn canary_queries_use_one_table_and_escape_the_text() {
+        let clickhouse_config =
+            serde_json::from_value::<crate::config::ClickHouseConfig>(serde_json::json!({
+                "connection": {
+                    "host": "localhost",
+                    "port": 8123,
+                    "username": "default",
+                    "password": "",
+                    "database": "db"
+                }
+            }))
+            .unwrap();
+
+        let queries = CanaryQueries::new(&clickhouse_config, "canary");
+
+        for query in [
+            &queries.create,
+            &queries.insert,
+            &queries.select,
+            &queries.drop,
+        ] {
+            assert!(query.contains(" db.canary"), "{query}");
+        }
+        assert!(
+            queries
+                .insert
+                .contains(r"'clockpipe canary ''quoted'' \\ backslash'"),
+            "{}",
+            queries.insert
+        );
+        assert_eq!(queries.drop, "DROP TABLE IF EXISTS db.canary");
+    }
+}
@@ -0,0 +1,25 @@
+/// Waits for SIGTERM or SIGINT, so the pipe can persist its cursor before exiting.
+#[cfg(unix)]
+pub async fn wait_for_shutdown_signal() {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let (Ok(mut terminate_signal), Ok(mut interrupt_signal)) = (
+        signal(SignalKind::terminate()),
+        signal(SignalKind::interrupt()),
+    ) else {
+        log::warn!("Failed to register SIGTERM/SIGINT handlers. Graceful shutdown is disabled");
+        return std::future::pending().await;
+    };
+
+    tokio::select! {
+        _ = terminate_signal.recv() => log::info!("SIGTERM received"),
+        _ = interrupt_signal.recv() => log::info!("SIGINT received"),
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn wait_for_shutdown_signal() {
+    if tokio::signal::ctrl_c().await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
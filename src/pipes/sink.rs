@@ -0,0 +1,489 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use parquet::{
+    basic::{LogicalType, Repetition, Type as PhysicalType},
+    data_type::{ByteArray, ByteArrayType, Int64Type},
+    file::{properties::WriterProperties, writer::SerializedFileWriter},
+    schema::types::Type,
+};
+
+use crate::{
+    adapter::{
+        IntoClickhouseColumn, IntoClickhouseRow, IntoClickhouseValue, clickhouse::ClickhouseColumn,
+    },
+    config::{IdentifierCase, ParquetSinkConfig, Target},
+    errors::Errors,
+};
+
+/// Name of the column that holds the kind of change in archived rows
+pub const OPERATION_COLUMN: &str = "_clockpipe_operation";
+/// Name of the column that holds the position of the change in source commit order
+pub const SEQUENCE_COLUMN: &str = "_clockpipe_sequence";
+/// Name of the column that holds the time (unix millis) the row was archived
+pub const ARCHIVED_AT_COLUMN: &str = "_clockpipe_archived_at";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ChangeKind {
+    /// Insert or update
+    Upsert,
+    Delete,
+}
+
+impl ChangeKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChangeKind::Upsert => "upsert",
+            ChangeKind::Delete => "delete",
+        }
+    }
+}
+
+/// Changes of one table in a sync iteration, as plain text values (NULL is None).
+#[derive(Debug, Clone)]
+pub struct SinkBatch {
+    pub table_name: String,
+    pub kind: ChangeKind,
+    pub column_names: Vec<String>,
+    /// (sequence, values). The sequence orders the changes of an iteration in source commit
+    /// order, across the upsert and delete batches of a table
+    pub rows: Vec<(u64, Vec<Option<String>>)>,
+}
+
+impl SinkBatch {
+    /// Takes the values of the ClickHouse columns from the source rows, the same way inserts do.
+    /// Masked columns are archived as NULL.
+    #[allow(clippy::too_many_arguments)]
+    pub fn from_rows(
+        table_name: &str,
+        kind: ChangeKind,
+        clickhouse_columns: &[ClickhouseColumn],
+        source_columns: &[impl IntoClickhouseColumn],
+        mask_columns: &[String],
+        identifier_case: IdentifierCase,
+        rows: &[impl IntoClickhouseRow],
+        sequences: &[u64],
+    ) -> Self {
+        let rows = rows
+            .iter()
+            .zip(sequences)
+            .map(|(row, sequence)| {
+                let values = clickhouse_columns
+                    .iter()
+                    .map(|clickhouse_column| {
+                        if mask_columns.iter().any(|mask_column| {
+                            identifier_case.matches(mask_column, &clickhouse_column.column_name)
+                        }) {
+                            return None;
+                        }
+
+                        row.find_value_by_column_name(
+                            source_columns,
+                            &clickhouse_column.column_name,
+                            identifier_case,
+                        )
+                        .and_then(|value| value.to_plain_text())
+                    })
+                    .collect();
+
+                (*sequence, values)
+            })
+            .collect();
+
+        SinkBatch {
+            table_name: table_name.to_string(),
+            kind,
+            column_names: clickhouse_columns
+                .iter()
+                .map(|column| column.column_name.clone())
+                .collect(),
+            rows,
+        }
+    }
+}
+
+/// A destination that receives every change in addition to ClickHouse (e.g. cold storage).
+/// ClickHouse itself stays on the typed query path (`IntoClickhouse`), which needs the source
+/// values rather than plain text.
+#[async_trait::async_trait]
+pub trait Sink: Send + Sync {
+    fn name(&self) -> String;
+
+    /// Buffers a batch.
+    async fn write(&self, batch: SinkBatch) -> Result<(), Errors>;
+
+    /// Persists everything buffered so far. Called before the source cursor is advanced.
+    async fn flush(&self) -> Result<(), Errors>;
+}
+
+pub fn sinks_from_target(target: &Target) -> Vec<Arc<dyn Sink>> {
+    let mut sinks: Vec<Arc<dyn Sink>> = vec![];
+
+    if let Some(parquet_config) = &target.parquet {
+        sinks.push(Arc::new(ParquetSink::new(parquet_config)));
+    }
+
+    sinks
+}
+
+/// Writes the batches to every sink and flushes them.
+pub async fn write_to_sinks(
+    sinks: &[Arc<dyn Sink>],
+    batches: Vec<SinkBatch>,
+) -> Result<(), Errors> {
+    if sinks.is_empty() {
+        return Ok(());
+    }
+
+    for sink in sinks {
+        for batch in &batches {
+            sink.write(batch.clone()).await?;
+        }
+
+        sink.flush().await?;
+    }
+
+    Ok(())
+}
+
+type ArchivedRow = (u64, ChangeKind, Vec<Option<String>>);
+/// Buffered rows keyed by (table name, column names)
+type ParquetBuffer = BTreeMap<(String, Vec<String>), Vec<ArchivedRow>>;
+
+/// Appends changes to local Parquet files, one file per table and flush:
+/// `<directory>/<table>/<table>-<unix millis>-<sequence>.parquet`.
+/// Every column is an optional UTF8 string, followed by the operation, sequence and archive time
+/// columns. Rows are written in sequence order, so upserts and deletes keep their commit order.
+pub struct ParquetSink {
+    directory: PathBuf,
+    buffer: Mutex<ParquetBuffer>,
+    sequence: std::sync::atomic::AtomicU64,
+}
+
+impl ParquetSink {
+    pub fn new(config: &ParquetSinkConfig) -> Self {
+        ParquetSink {
+            directory: PathBuf::from(&config.directory),
+            buffer: Mutex::new(BTreeMap::new()),
+            sequence: std::sync::atomic::AtomicU64::new(0),
+        }
+    }
+
+    async fn write_file(
+        &self,
+        table_name: &str,
+        column_names: Vec<String>,
+        mut rows: Vec<ArchivedRow>,
+    ) -> Result<PathBuf, Errors> {
+        let table_directory = self.directory.join(table_name);
+        tokio::fs::create_dir_all(&table_directory).await?;
+
+        let archived_at = chrono::Utc::now().timestamp_millis();
+        let sequence = self
+            .sequence
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        let path = table_directory.join(format!("{table_name}-{archived_at}-{sequence}.parquet"));
+
+        rows.sort_by_key(|(sequence, _, _)| *sequence);
+
+        // The parquet writer is blocking
+        let file_path = path.clone();
+        tokio::task::spawn_blocking(move || {
+            write_parquet_file(&file_path, &column_names, &rows, archived_at)
+        })
+        .await
+        .map_err(|e| parquet_error(&path, e))??;
+
+        Ok(path)
+    }
+}
+
+#[async_trait::async_trait]
+impl Sink for ParquetSink {
+    fn name(&self) -> String {
+        format!("parquet:{}", self.directory.display())
+    }
+
+    async fn write(&self, batch: SinkBatch) -> Result<(), Errors> {
+        let mut buffer = self
+            .buffer
+            .lock()
+            .map_err(|e| Errors::SinkWriteFailed(format!("Parquet buffer is poisoned: {e}")))?;
+
+        buffer
+            .entry((batch.table_name, batch.column_names))
+            .or_default()
+            .extend(
+                batch
+                    .rows
+                    .into_iter()
+                    .map(|(sequence, row)| (sequence, batch.kind, row)),
+            );
+
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<(), Errors> {
+        let buffered = {
+            let mut buffer = self
+                .buffer
+                .lock()
+                .map_err(|e| Errors::SinkWriteFailed(format!("Parquet buffer is poisoned: {e}")))?;
+
+            std::mem::take(&mut *buffer)
+        };
+
+        for ((table_name, column_names), rows) in buffered {
+            let row_count = rows.len();
+            let path = self.write_file(&table_name, column_names, rows).await?;
+
+            log::debug!(
+                "Archived {row_count} rows of {table_name} to {}",
+                path.display()
+            );
+        }
+
+        Ok(())
+    }
+}
+
+fn parquet_error(path: &Path, error: impl std::fmt::Display) -> Errors {
+    Errors::SinkWriteFailed(format!("Failed to write {}: {error}", path.display()))
+}
+
+fn write_parquet_file(
+    path: &Path,
+    column_names: &[String],
+    rows: &[ArchivedRow],
+    archived_at: i64,
+) -> Result<(), Errors> {
+    let mut fields = column_names
+        .iter()
+        .map(|column_name| {
+            Type::primitive_type_builder(column_name, PhysicalType::BYTE_ARRAY)
+                .with_repetition(Repetition::OPTIONAL)
+                .with_logical_type(Some(LogicalType::String))
+                .build()
+        })
+        .collect::<Vec<_>>();
+    fields.push(
+        Type::primitive_type_builder(OPERATION_COLUMN, PhysicalType::BYTE_ARRAY)
+            .with_repetition(Repetition::REQUIRED)
+            .with_logical_type(Some(LogicalType::String))
+            .build(),
+    );
+    fields.push(
+        Type::primitive_type_builder(SEQUENCE_COLUMN, PhysicalType::INT64)
+            .with_repetition(Repetition::REQUIRED)
+            .with_logical_type(Some(LogicalType::Integer {
+                bit_width: 64,
+                is_signed: false,
+            }))
+            .build(),
+    );
+    fields.push(
+        Type::primitive_type_builder(ARCHIVED_AT_COLUMN, PhysicalType::INT64)
+            .with_repetition(Repetition::REQUIRED)
+            .build(),
+    );
+
+    let fields = fields
+        .into_iter()
+        .map(|field| field.map(Arc::new))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| parquet_error(path, e))?;
+
+    let schema = Type::group_type_builder("schema")
+        .with_fields(fields)
+        .build()
+        .map_err(|e| parquet_error(path, e))?;
+
+    let file = std::fs::File::create(path)?;
+    let mut writer = SerializedFileWriter::new(
+        file,
+        Arc::new(schema),
+        Arc::new(WriterProperties::builder().build()),
+    )
+    .map_err(|e| parquet_error(path, e))?;
+
+    let mut row_group = writer
+        .next_row_group()
+        .map_err(|e| parquet_error(path, e))?;
+
+    for column_index in 0..column_names.len() {
+        let values = rows
+            .iter()
+            .filter_map(|(_, _, row)| row.get(column_index).cloned().flatten())
+            .map(|value| ByteArray::from(value.into_bytes()))
+            .collect::<Vec<_>>();
+        let definition_levels = rows
+            .iter()
+            .map(|(_, _, row)| i16::from(matches!(row.get(column_index), Some(Some(_)))))
+            .collect::<Vec<_>>();
+
+        let Some(mut column) = row_group
+            .next_column()
+            .map_err(|e| parquet_error(path, e))?
+        else {
+            break;
+        };
+        column
+            .typed::<ByteArrayType>()
+            .write_batch(&values, Some(&definition_levels), None)
+            .map_err(|e| parquet_error(path, e))?;
+        column.close().map_err(|e| parquet_error(path, e))?;
+    }
+
+    if let Some(mut column) = row_group
+        .next_column()
+        .map_err(|e| parquet_error(path, e))?
+    {
+        let operations = rows
+            .iter()
+            .map(|(_, kind, _)| ByteArray::from(kind.as_str()))
+            .collect::<Vec<_>>();
+        column
+            .typed::<ByteArrayType>()
+            .write_batch(&operations, None, None)
+            .map_err(|e| parquet_error(path, e))?;
+        column.close().map_err(|e| parquet_error(path, e))?;
+    }
+
+    if let Some(mut column) = row_group
+        .next_column()
+        .map_err(|e| parquet_error(path, e))?
+    {
+        // Stored as the bits of the unsigned value, see the logical type
+        let sequences = rows
+            .iter()
+            .map(|(sequence, _, _)| *sequence as i64)
+            .collect::<Vec<_>>();
+        column
+            .typed::<Int64Type>()
+            .write_batch(&sequences, None, None)
+            .map_err(|e| parquet_error(path, e))?;
+        column.close().map_err(|e| parquet_error(path, e))?;
+    }
+
+    if let Some(mut column) = row_group
+        .next_column()
+        .map_err(|e| parquet_error(path, e))?
+    {
+        column
+            .typed::<Int64Type>()
+            .write_batch(&vec![archived_at; rows.len()], None, None)
+            .map_err(|e| parquet_error(path, e))?;
+        column.close().map_err(|e| parquet_error(path, e))?;
+    }
+
+    row_group.close().map_err(|e| parquet_error(path, e))?;
+    writer.close().map_err(|e| parquet_error(path, e))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use parquet::{
+        basic::Repetition,
+        file::reader::{FileReader, SerializedFileReader},
+        record::Field,
+    };
+
+    use super::{
+        ARCHIVED_AT_COLUMN, ChangeKind, OPERATION_COLUMN, ParquetSink, SEQUENCE_COLUMN, Sink,
+        SinkBatch,
+    };
+    use crate::config::ParquetSinkConfig;
+
+    #[tokio::test]
+    async fn flush_writes_parquet_file_with_table_schema() {
+        let directory =
+            std::env::temp_dir().join(format!("clockpipe-parquet-sink-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+
+        let sink = ParquetSink::new(&ParquetSinkConfig {
+            directory: directory.to_string_lossy().to_string(),
+        });
+
+        let column_names = vec!["id".to_string(), "name".to_string()];
+        sink.write(SinkBatch {
+            table_name: "users".to_string(),
+            kind: ChangeKind::Upsert,
+            column_names: column_names.clone(),
+            rows: vec![
+                (0, vec![Some("1".to_string()), Some("alice".to_string())]),
+                (2, vec![Some("2".to_string()), None]),
+            ],
+        })
+        .await
+        .unwrap();
+        sink.write(SinkBatch {
+            table_name: "users".to_string(),
+            kind: ChangeKind::Delete,
+            column_names,
+            // Deleted between the two upserts
+            rows: vec![(1, vec![Some("1".to_string()), None])],
+        })
+        .await
+        .unwrap();
+        sink.flush().await.unwrap();
+
+        let files = std::fs::read_dir(directory.join("users"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        assert_eq!(files.len(), 1);
+
+        let reader = SerializedFileReader::try_from(files[0].as_path()).unwrap();
+        let schema = reader.metadata().file_metadata().schema_descr();
+        let columns = (0..schema.num_columns())
+            .map(|index| {
+                let column = schema.column(index);
+                (
+                    column.name().to_string(),
+                    column.self_type().get_basic_info().repetition(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            columns,
+            vec![
+                ("id".to_string(), Repetition::OPTIONAL),
+                ("name".to_string(), Repetition::OPTIONAL),
+                (OPERATION_COLUMN.to_string(), Repetition::REQUIRED),
+                (SEQUENCE_COLUMN.to_string(), Repetition::REQUIRED),
+                (ARCHIVED_AT_COLUMN.to_string(), Repetition::REQUIRED),
+            ]
+        );
+
+        let rows = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| {
+                let row = row.unwrap();
+                row.get_column_iter()
+                    .take(4)
+                    .map(|(_, field)| match field {
+                        Field::Str(value) => value.clone(),
+                        Field::Null => "NULL".to_string(),
+                        other => other.to_string(),
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rows,
+            vec![
+                vec!["1", "alice", "upsert", "0"],
+                vec!["1", "NULL", "delete", "1"],
+                vec!["2", "NULL", "upsert", "2"],
+            ]
+        );
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,146 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, RwLock},
+};
+
+use chrono::{DateTime, Utc};
+
+use crate::pipes::WriteCounter;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TableSyncStats {
+    pub insert_count: u64,
+    pub update_count: u64,
+    pub delete_count: u64,
+    pub last_write_at: Option<DateTime<Utc>>,
+}
+
+/// Statistics of a running pipe, updated after each sync iteration.
+#[derive(Debug, Clone, Default)]
+pub struct SyncStats {
+    /// Keyed by `schema.table` (Postgres) or collection name (MongoDB)
+    pub tables: BTreeMap<String, TableSyncStats>,
+    pub iterations: u64,
+    /// Last committed source position (LSN for Postgres, resume token for MongoDB)
+    pub last_position: Option<String>,
+    /// Bytes of WAL not yet confirmed by the replication slot (Postgres only)
+    pub lag_bytes: Option<i64>,
+    pub last_synced_at: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<DateTime<Utc>>,
+}
+
+impl SyncStats {
+    /// Adds the writes of one committed iteration.
+    pub fn record_iteration(
+        &mut self,
+        writes: &HashMap<String, WriteCounter>,
+        position: Option<String>,
+    ) {
+        let now = Utc::now();
+
+        for (table_name, counter) in writes {
+            let table = self.tables.entry(table_name.clone()).or_default();
+            table.insert_count += counter.insert_count as u64;
+            table.update_count += counter.update_count as u64;
+            table.delete_count += counter.delete_count as u64;
+            table.last_write_at = Some(now);
+        }
+
+        self.iterations += 1;
+        if position.is_some() {
+            self.last_position = position;
+        }
+        self.last_synced_at = Some(now);
+    }
+
+    pub fn record_error(&mut self, error: &impl std::fmt::Display) {
+        self.last_error = Some(error.to_string());
+        self.last_error_at = Some(Utc::now());
+    }
+}
+
+/// Handle to the statistics of a pipe, shared between the pipe and its readers.
+#[derive(Debug, Clone, Default)]
+pub struct SharedSyncStats(Arc<RwLock<SyncStats>>);
+
+impl SharedSyncStats {
+    pub fn snapshot(&self) -> SyncStats {
+        self.0.read().map(|stats| stats.clone()).unwrap_or_default()
+    }
+
+    pub fn update(&self, f: impl FnOnce(&mut SyncStats)) {
+        if let Ok(mut stats) = self.0.write() {
+            f(&mut stats);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{SharedSyncStats, TableSyncStats};
+    use crate::{errors::Errors, pipes::WriteCounter};
+
+    fn writes(
+        table_name: &str,
+        insert: usize,
+        update: usize,
+        delete: usize,
+    ) -> HashMap<String, WriteCounter> {
+        HashMap::from([(
+            table_name.to_string(),
+            WriteCounter {
+                insert_count: insert,
+                update_count: update,
+                delete_count: delete,
+            },
+        )])
+    }
+
+    #[test]
+    fn counts_accumulate_across_iterations() {
+        let stats = SharedSyncStats::default();
+
+        stats.update(|stats| {
+            stats.record_iteration(
+                &writes("public.bean_policy", 2, 1, 0),
+                Some("0/16B3748".to_string()),
+            )
+        });
+        stats.update(|stats| stats.record_error(&Errors::PeekChangesFailed("timeout".to_string())));
+        stats.update(|stats| {
+            stats.record_iteration(
+                &writes("public.bean_policy", 3, 0, 1),
+                Some("0/16B3800".to_string()),
+            )
+        });
+        stats.update(|stats| stats.record_iteration(&writes("public.orders", 1, 0, 0), None));
+
+        let snapshot = stats.snapshot();
+
+        assert_eq!(snapshot.iterations, 3);
+        assert_eq!(snapshot.last_position.as_deref(), Some("0/16B3800"));
+        assert_eq!(
+            snapshot.last_error.as_deref(),
+            Some("Failed to peek changes: timeout")
+        );
+
+        let bean_policy = &snapshot.tables["public.bean_policy"];
+        assert_eq!(
+            TableSyncStats {
+                last_write_at: None,
+                ..bean_policy.clone()
+            },
+            TableSyncStats {
+                insert_count: 5,
+                update_count: 1,
+                delete_count: 1,
+                last_write_at: None,
+            }
+        );
+        assert!(bean_policy.last_write_at.is_some());
+        assert_eq!(snapshot.tables["public.orders"].insert_count, 1);
+    }
+}
@@ -0,0 +1,112 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+/// Detects a sync loop that stopped making progress, e.g. a query that never returns.
+/// The loop touches the watchdog after each step of an iteration. `stalled` resolves once
+/// nothing touched it within the stall timeout, so the loop can drop the hung iteration and
+/// start a new one. Only the streaming loop is watched, not the initial copy.
+#[derive(Debug, Clone)]
+pub struct Watchdog {
+    stall_timeout: Option<Duration>,
+    started_at: Instant,
+    last_progress_millis: Arc<AtomicU64>,
+}
+
+impl Watchdog {
+    /// `None` disables the watchdog: `stalled` never resolves.
+    pub fn new(stall_timeout: Option<Duration>) -> Self {
+        Self {
+            stall_timeout,
+            started_at: Instant::now(),
+            last_progress_millis: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn touch(&self) {
+        self.last_progress_millis
+            .store(self.elapsed_millis(), Ordering::SeqCst);
+    }
+
+    /// Time since the last touch
+    pub fn idle_time(&self) -> Duration {
+        let last_progress_millis = self.last_progress_millis.load(Ordering::SeqCst);
+
+        Duration::from_millis(self.elapsed_millis().saturating_sub(last_progress_millis))
+    }
+
+    /// Resolves with the idle time once the loop has not made progress within the stall timeout.
+    pub async fn stalled(&self) -> Duration {
+        let Some(stall_timeout) = self.stall_timeout else {
+            return std::future::pending().await;
+        };
+
+        loop {
+            let idle_time = self.idle_time();
+
+            if idle_time >= stall_timeout {
+                return idle_time;
+            }
+
+            tokio::time::sleep(stall_timeout - idle_time).await;
+        }
+    }
+
+    fn elapsed_millis(&self) -> u64 {
+        self.started_at.elapsed().as_millis() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Watchdog;
+
+    #[tokio::test]
+    async fn stalled_loop_is_detected() {
+        let watchdog = Watchdog::new(Some(Duration::from_millis(50)));
+        watchdog.touch();
+
+        let hung_iteration = std::future::pending::<()>();
+
+        let idle_time = tokio::select! {
+            _ = hung_iteration => unreachable!(),
+            idle_time = watchdog.stalled() => idle_time,
+        };
+
+        assert!(idle_time >= Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn loop_making_progress_is_not_stalled() {
+        let watchdog = Watchdog::new(Some(Duration::from_millis(200)));
+        watchdog.touch();
+
+        // Takes longer than the stall timeout in total, but touches the watchdog after each step
+        let iteration = async {
+            for _ in 0..10 {
+                tokio::time::sleep(Duration::from_millis(40)).await;
+                watchdog.touch();
+            }
+        };
+
+        tokio::select! {
+            _ = iteration => {}
+            _ = watchdog.stalled() => panic!("iteration making progress was reported as stalled"),
+        }
+    }
+
+    #[tokio::test]
+    async fn disabled_watchdog_never_fires() {
+        let watchdog = Watchdog::new(None);
+
+        let fired = tokio::time::timeout(Duration::from_millis(50), watchdog.stalled()).await;
+
+        assert!(fired.is_err());
+    }
+}